@@ -0,0 +1,777 @@
+//! The automation engine: runs the rules from the automations directory on
+//! top of the event bus and the timer service.
+//!
+//! The engine is a subscriber on the bus like a plugin. Event and state
+//! triggers match the events it receives; time-based triggers are timers it
+//! owns, whose ticks come back over the bus. Each rule runs at most once at a
+//! time, and every run leaves a [`Trace`] of what it checked and did.
+
+use chrono::Utc;
+use parking_lot::Mutex;
+use plugin_api::{
+    device::{StateChanged, STATE_CHANGED},
+    RpcError,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, VecDeque},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+use crate::{
+    events::{topic_matches, Event},
+    plugin_host::CoreHandle,
+    services::{
+        parse,
+        timer::{Home, Target, TICK_TOPIC},
+    },
+};
+
+pub mod rule;
+
+pub use rule::{load_dir, Action, Condition, Loaded, Rule, Trigger};
+
+/// Id of the engine on the event bus and as the owner of its timers.
+pub const SUBSCRIBER: &str = "$automation";
+
+/// Traces kept per rule.
+pub const TRACE_LIMIT: usize = 20;
+
+/// How a run ended, or that it is still going.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Running,
+    /// Every action succeeded.
+    Completed,
+    /// A condition did not hold, so no action ran.
+    ConditionsFailed,
+    /// An action failed; the ones after it did not run.
+    Failed,
+    /// The rule was still running from an earlier trigger.
+    Skipped,
+    /// The rule was disabled or reloaded while running.
+    Cancelled,
+}
+
+/// A checked condition or an executed action in a trace.
+#[derive(Debug, Clone, Serialize)]
+pub struct Step {
+    pub step: String,
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<RpcError>,
+    pub at_ms: i64,
+}
+
+impl Step {
+    fn new(step: String, ok: bool) -> Self {
+        Self {
+            step,
+            ok,
+            result: None,
+            error: None,
+            at_ms: now_ms(),
+        }
+    }
+}
+
+/// What one run of a rule did.
+#[derive(Debug, Clone, Serialize)]
+pub struct Trace {
+    pub run: u64,
+    pub rule_id: String,
+    /// The trigger that fired, or `manual` for `automation.trigger`.
+    pub trigger: String,
+    /// The payload of the triggering event.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<Value>,
+    pub started_ms: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub finished_ms: Option<i64>,
+    pub conditions: Vec<Step>,
+    pub actions: Vec<Step>,
+    pub outcome: Outcome,
+}
+
+/// A rule as reported by `automation.list`.
+#[derive(Debug, Clone, Serialize)]
+pub struct RuleInfo {
+    pub id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub file: PathBuf,
+    pub enabled: bool,
+    pub running: bool,
+    pub triggers: Vec<String>,
+    pub conditions: Vec<String>,
+    pub actions: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_run_ms: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_outcome: Option<Outcome>,
+}
+
+struct RuleState {
+    rule: Rule,
+    /// Set by `automation.enable` and `automation.disable`; wins over the
+    /// rule file until the core restarts.
+    toggled: Option<bool>,
+    /// The run in progress.
+    run: Option<(u64, JoinHandle<()>)>,
+    traces: VecDeque<Trace>,
+}
+
+impl RuleState {
+    fn enabled(&self) -> bool {
+        self.toggled.unwrap_or(self.rule.enabled)
+    }
+
+    fn trace(&mut self, run: u64) -> Option<&mut Trace> {
+        self.traces.iter_mut().rev().find(|t| t.run == run)
+    }
+
+    fn push(&mut self, trace: Trace) {
+        self.traces.push_back(trace);
+        if self.traces.len() > TRACE_LIMIT {
+            self.traces.pop_front();
+        }
+    }
+
+    fn cancel(&mut self) {
+        if let Some((run, task)) = self.run.take() {
+            task.abort();
+            if let Some(trace) = self.trace(run) {
+                trace.outcome = Outcome::Cancelled;
+                trace.finished_ms = Some(now_ms());
+            }
+        }
+    }
+}
+
+struct Inner {
+    core: CoreHandle,
+    home: Home,
+    rules: Mutex<BTreeMap<String, RuleState>>,
+    /// State triggers waiting out their `for`, by rule id and trigger index.
+    /// Lock after `rules` when both are needed.
+    waits: Mutex<HashMap<(String, usize), JoinHandle<()>>>,
+    runs: AtomicU64,
+}
+
+/// The running automation engine. Cheap to clone.
+#[derive(Clone)]
+pub struct Automations(Arc<Inner>);
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct IdParams {
+    id: String,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TracesParams {
+    #[serde(default)]
+    id: Option<String>,
+}
+
+impl Automations {
+    /// Start the engine with `rules`. Must be called within a Tokio runtime.
+    pub fn start(core: CoreHandle, home: Home, rules: Vec<Rule>) -> Self {
+        let mut events = core.bus().lock().register(SUBSCRIBER);
+        let automations = Self(Arc::new(Inner {
+            core,
+            home,
+            rules: Mutex::new(BTreeMap::new()),
+            waits: Mutex::new(HashMap::new()),
+            runs: AtomicU64::new(0),
+        }));
+        automations.load(rules);
+        let engine = automations.clone();
+        tokio::spawn(async move {
+            while let Some(event) = events.recv().await {
+                engine.on_event(event);
+            }
+        });
+        automations
+    }
+
+    /// Replace the rules, as on SIGHUP. Runs in progress are cancelled, and
+    /// rules enabled or disabled at runtime keep that setting and their
+    /// traces.
+    pub fn load(&self, rules: Vec<Rule>) {
+        let mut states = self.0.rules.lock();
+        self.0.core.timers().clear(SUBSCRIBER);
+        for (_, wait) in self.0.waits.lock().drain() {
+            wait.abort();
+        }
+        let mut old = std::mem::take(&mut *states);
+        for state in old.values_mut() {
+            state.cancel();
+        }
+        for rule in rules {
+            let (toggled, traces) = match old.remove(&rule.id) {
+                Some(prev) => (prev.toggled, prev.traces),
+                None => (None, VecDeque::new()),
+            };
+            let state = RuleState {
+                rule,
+                toggled,
+                run: None,
+                traces,
+            };
+            if state.enabled() {
+                self.arm(&state.rule);
+            }
+            states.insert(state.rule.id.clone(), state);
+        }
+
+        let mut patterns: BTreeSet<&str> = BTreeSet::from([STATE_CHANGED]);
+        for state in states.values() {
+            for trigger in &state.rule.triggers {
+                if let Trigger::Event { topic } = trigger {
+                    patterns.insert(topic);
+                }
+            }
+        }
+        let mut bus = self.0.core.bus().lock();
+        for pattern in bus.subscriptions(SUBSCRIBER) {
+            bus.unsubscribe(SUBSCRIBER, &pattern);
+        }
+        for pattern in patterns {
+            bus.subscribe(SUBSCRIBER, pattern);
+        }
+    }
+
+    /// Set the timers of a rule's time-based triggers.
+    fn arm(&self, rule: &Rule) {
+        for (idx, trigger) in rule.triggers.iter().enumerate() {
+            match trigger.schedule(&self.0.home) {
+                Ok(Some(schedule)) => {
+                    let target = Target {
+                        plugin_id: SUBSCRIBER.to_string(),
+                        writer: None,
+                        bus: self.0.core.bus().clone(),
+                    };
+                    self.0
+                        .core
+                        .timers()
+                        .set(target, timer_id(&rule.id, idx), schedule);
+                }
+                Ok(None) => {}
+                Err(e) => warn!("automation {}: trigger {}: {e}", rule.id, idx + 1),
+            }
+        }
+    }
+
+    /// Stop everything a rule is waiting for.
+    fn disarm(&self, rule: &Rule) {
+        let timers = self.0.core.timers();
+        let mut waits = self.0.waits.lock();
+        for idx in 0..rule.triggers.len() {
+            timers.cancel(SUBSCRIBER, &timer_id(&rule.id, idx));
+            if let Some(wait) = waits.remove(&(rule.id.clone(), idx)) {
+                wait.abort();
+            }
+        }
+    }
+
+    /// Enable or disable a rule until the next restart. Disabling cancels
+    /// its run in progress.
+    pub fn set_enabled(&self, id: &str, enabled: bool) -> Result<(), RpcError> {
+        let mut rules = self.0.rules.lock();
+        let state = rules.get_mut(id).ok_or_else(|| unknown(id))?;
+        let was = state.enabled();
+        state.toggled = Some(enabled);
+        if enabled && !was {
+            self.arm(&state.rule);
+        } else if !enabled && was {
+            self.disarm(&state.rule);
+            state.cancel();
+        }
+        info!(
+            "automation {id} {}",
+            if enabled { "enabled" } else { "disabled" }
+        );
+        Ok(())
+    }
+
+    /// The loaded rules.
+    pub fn list(&self) -> Vec<RuleInfo> {
+        let rules = self.0.rules.lock();
+        rules
+            .values()
+            .map(|s| {
+                let last = s.traces.back();
+                RuleInfo {
+                    id: s.rule.id.clone(),
+                    name: s.rule.name.clone(),
+                    file: s.rule.file.clone(),
+                    enabled: s.enabled(),
+                    running: s.run.is_some(),
+                    triggers: s.rule.triggers.iter().map(|t| t.to_string()).collect(),
+                    conditions: s.rule.conditions.iter().map(|c| c.to_string()).collect(),
+                    actions: s.rule.actions.iter().map(|a| a.to_string()).collect(),
+                    last_run_ms: last.map(|t| t.started_ms),
+                    last_outcome: last.map(|t| t.outcome),
+                }
+            })
+            .collect()
+    }
+
+    /// Traces of one rule, or of all rules, oldest first.
+    pub fn traces(&self, id: Option<&str>) -> Result<Vec<Trace>, RpcError> {
+        let rules = self.0.rules.lock();
+        let mut traces: Vec<Trace> = match id {
+            Some(id) => {
+                let state = rules.get(id).ok_or_else(|| unknown(id))?;
+                state.traces.iter().cloned().collect()
+            }
+            None => rules
+                .values()
+                .flat_map(|s| s.traces.iter().cloned())
+                .collect(),
+        };
+        traces.sort_by_key(|t| t.run);
+        Ok(traces)
+    }
+
+    /// Run a rule now, checking its conditions as if a trigger fired.
+    /// Returns the run number, or `None` if the rule is disabled.
+    pub fn trigger(&self, id: &str) -> Result<Option<u64>, RpcError> {
+        if !self.0.rules.lock().contains_key(id) {
+            return Err(unknown(id));
+        }
+        Ok(self.fire(id, "manual".to_string(), None))
+    }
+
+    /// Serve an `automation.*` request.
+    pub fn handle(&self, method: &str, params: Option<Value>) -> Result<Value, RpcError> {
+        match method {
+            "automation.list" => Ok(json!({ "rules": self.list() })),
+            "automation.enable" | "automation.disable" => {
+                let p: IdParams = parse(params)?;
+                self.set_enabled(&p.id, method == "automation.enable")?;
+                Ok(json!({ "ok": true }))
+            }
+            "automation.trigger" => {
+                let p: IdParams = parse(params)?;
+                let run = self.trigger(&p.id)?;
+                Ok(json!({ "ok": run.is_some(), "run": run }))
+            }
+            "automation.traces" => {
+                let p: TracesParams = parse(params)?;
+                Ok(json!({ "traces": self.traces(p.id.as_deref())? }))
+            }
+            _ => Err(RpcError::new(
+                RpcError::METHOD_NOT_FOUND,
+                format!("unknown method {}", method),
+            )),
+        }
+    }
+
+    fn on_event(&self, event: Event) {
+        if event.topic == TICK_TOPIC && event.source.is_none() {
+            self.on_tick(event.payload);
+            return;
+        }
+        let change = match (&event.source, event.topic.as_str()) {
+            (None, STATE_CHANGED) => event
+                .payload
+                .clone()
+                .and_then(|p| serde_json::from_value::<StateChanged>(p).ok()),
+            _ => None,
+        };
+        let mut fired = Vec::new();
+        let rules = self.0.rules.lock();
+        for (id, state) in rules.iter().filter(|(_, s)| s.enabled()) {
+            for (idx, trigger) in state.rule.triggers.iter().enumerate() {
+                match trigger {
+                    Trigger::Event { topic } if topic_matches(topic, &event.topic) => {
+                        fired.push((id.clone(), trigger.to_string(), event.payload.clone()));
+                    }
+                    Trigger::State { .. } => {
+                        if let Some(change) = &change {
+                            self.on_state(id, idx, trigger, change, &mut fired);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+        drop(rules);
+        for (id, trigger, data) in fired {
+            self.fire(&id, trigger, data);
+        }
+    }
+
+    /// Match a state change against a state trigger. A match without `for`
+    /// fires at once; with `for`, it fires unless the entity changes again
+    /// first.
+    fn on_state(
+        &self,
+        id: &str,
+        idx: usize,
+        trigger: &Trigger,
+        change: &StateChanged,
+        fired: &mut Vec<(String, String, Option<Value>)>,
+    ) {
+        let Trigger::State {
+            entity_id,
+            from,
+            to,
+            hold,
+        } = trigger
+        else {
+            return;
+        };
+        let old = change.old_state.as_ref().map(|s| &s.state);
+        let new = change.new_state.as_ref().map(|s| &s.state);
+        // attribute updates leave the state as it was
+        if change.entity_id != *entity_id || old == new {
+            return;
+        }
+        let key = (id.to_string(), idx);
+        let mut waits = self.0.waits.lock();
+        if let Some(wait) = waits.remove(&key) {
+            wait.abort();
+        }
+        let matches = new.is_some()
+            && from.as_ref().is_none_or(|f| old == Some(f))
+            && to.as_ref().is_none_or(|t| new == Some(t));
+        if !matches {
+            return;
+        }
+        let data = serde_json::to_value(change).ok();
+        let Some(hold) = *hold else {
+            fired.push((id.to_string(), trigger.to_string(), data));
+            return;
+        };
+        let engine = self.clone();
+        let description = trigger.to_string();
+        let wait_key = key.clone();
+        let wait = tokio::spawn(async move {
+            tokio::time::sleep(hold).await;
+            engine.0.waits.lock().remove(&wait_key);
+            engine.fire(&wait_key.0, description, data);
+        });
+        waits.insert(key, wait);
+    }
+
+    fn on_tick(&self, payload: Option<Value>) {
+        let Some(timer) = payload.as_ref().and_then(|p| p.get("id")?.as_str()) else {
+            return;
+        };
+        let Some((id, idx)) = timer.rsplit_once('/') else {
+            return;
+        };
+        let trigger = {
+            let rules = self.0.rules.lock();
+            let trigger = idx
+                .parse::<usize>()
+                .ok()
+                .and_then(|idx| rules.get(id)?.rule.triggers.get(idx));
+            match trigger {
+                Some(trigger) => trigger.to_string(),
+                None => return,
+            }
+        };
+        self.fire(id, trigger, None);
+    }
+
+    /// Check the rule's conditions and start its actions. Returns the run
+    /// number unless the rule is unknown or disabled.
+    fn fire(&self, id: &str, trigger: String, data: Option<Value>) -> Option<u64> {
+        let mut rules = self.0.rules.lock();
+        let state = rules.get_mut(id).filter(|s| s.enabled())?;
+        let run = self.0.runs.fetch_add(1, Ordering::Relaxed) + 1;
+        let started_ms = now_ms();
+        let mut trace = Trace {
+            run,
+            rule_id: id.to_string(),
+            trigger,
+            data,
+            started_ms,
+            finished_ms: None,
+            conditions: Vec::new(),
+            actions: Vec::new(),
+            outcome: Outcome::Running,
+        };
+        if state.run.is_some() {
+            info!("automation {id} skipped, still running");
+            trace.outcome = Outcome::Skipped;
+            trace.finished_ms = Some(started_ms);
+            state.push(trace);
+            return Some(run);
+        }
+
+        let now = self.0.home.zone.local(Utc::now());
+        let lookup = |entity_id: &str| self.0.core.state(entity_id).map(|s| s.state);
+        for condition in &state.rule.conditions {
+            let ok = condition.holds(lookup, now);
+            trace.conditions.push(Step::new(condition.to_string(), ok));
+            if !ok {
+                trace.outcome = Outcome::ConditionsFailed;
+                trace.finished_ms = Some(now_ms());
+                state.push(trace);
+                return Some(run);
+            }
+        }
+
+        info!("automation {id} triggered by {}", trace.trigger);
+        state.push(trace);
+        let engine = self.clone();
+        let rule_id = id.to_string();
+        let actions = state.rule.actions.clone();
+        // the task waits on the lock held here before it records anything
+        let task = tokio::spawn(async move { engine.execute(&rule_id, run, actions).await });
+        state.run = Some((run, task));
+        Some(run)
+    }
+
+    async fn execute(&self, id: &str, run: u64, actions: Vec<Action>) {
+        let mut outcome = Outcome::Completed;
+        for action in actions {
+            let result = match &action {
+                Action::Command(command) => self.0.core.command(command).await.map(Some),
+                Action::Publish { topic, payload } => {
+                    self.0.core.publish(topic, payload.clone());
+                    Ok(None)
+                }
+                Action::Call {
+                    method,
+                    params,
+                    timeout,
+                } => {
+                    let params = params.clone().unwrap_or_else(|| json!({}));
+                    self.0.core.call(method, params, *timeout).await.map(Some)
+                }
+                Action::Delay { duration } => {
+                    tokio::time::sleep(*duration).await;
+                    Ok(None)
+                }
+            };
+            let mut step = Step::new(action.to_string(), result.is_ok());
+            match result {
+                Ok(value) => step.result = value,
+                Err(e) => {
+                    warn!("automation {id}: {action} failed: {}", e.message);
+                    step.error = Some(e);
+                    outcome = Outcome::Failed;
+                }
+            }
+            self.record(id, run, |t| t.actions.push(step));
+            if outcome == Outcome::Failed {
+                break;
+            }
+        }
+        let mut rules = self.0.rules.lock();
+        let Some(state) = rules.get_mut(id) else {
+            return;
+        };
+        if state.run.as_ref().is_some_and(|(r, _)| *r == run) {
+            state.run = None;
+        }
+        if let Some(trace) = state.trace(run) {
+            trace.outcome = outcome;
+            trace.finished_ms = Some(now_ms());
+        }
+    }
+
+    fn record(&self, id: &str, run: u64, f: impl FnOnce(&mut Trace)) {
+        let mut rules = self.0.rules.lock();
+        if let Some(trace) = rules.get_mut(id).and_then(|s| s.trace(run)) {
+            f(trace);
+        }
+    }
+}
+
+fn timer_id(rule_id: &str, idx: usize) -> String {
+    format!("{rule_id}/{idx}")
+}
+
+fn unknown(id: &str) -> RpcError {
+    RpcError::new(RpcError::INVALID_PARAMS, format!("unknown rule {id}"))
+}
+
+fn now_ms() -> i64 {
+    Utc::now().timestamp_millis()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PluginManager;
+    use plugin_api::device::EntityState;
+    use std::time::Duration;
+    use tokio::sync::mpsc::UnboundedReceiver;
+
+    const RULES: &str = r#"
+        [[rule]]
+        id = "echo"
+        [[rule.trigger]]
+        type = "event"
+        topic = "door.#"
+        [[rule.action]]
+        type = "publish"
+        topic = "seen.door"
+
+        [[rule]]
+        id = "desk_on"
+        [[rule.trigger]]
+        type = "state"
+        entity_id = "light.desk"
+        to = "on"
+        for = "100ms"
+        [[rule.action]]
+        type = "publish"
+        topic = "seen.desk"
+
+        [[rule]]
+        id = "slow"
+        [[rule.trigger]]
+        type = "event"
+        topic = "slow.go"
+        [[rule.action]]
+        type = "delay"
+        duration = "10s"
+
+        [[rule]]
+        id = "guarded"
+        [[rule.trigger]]
+        type = "interval"
+        every = "50ms"
+        [[rule.condition]]
+        type = "state"
+        entity_id = "switch.missing"
+        state = "on"
+        [[rule.action]]
+        type = "publish"
+        topic = "seen.guarded"
+    "#;
+
+    fn start(dir: &std::path::Path) -> (Automations, CoreHandle) {
+        std::fs::write(dir.join("rules.toml"), RULES).unwrap();
+        let loaded = load_dir(dir, &Home::default());
+        assert!(loaded.errors.is_empty(), "{:?}", loaded.errors);
+        let manager = PluginManager::discover(dir.into(), dir.into()).unwrap();
+        let core = manager.core_handle();
+        let automations = Automations::start(core.clone(), Home::default(), loaded.rules);
+        (automations, core)
+    }
+
+    fn listen(core: &CoreHandle) -> UnboundedReceiver<Event> {
+        let mut bus = core.bus().lock();
+        let rx = bus.register("test");
+        bus.subscribe("test", "seen.#");
+        rx
+    }
+
+    async fn next(rx: &mut UnboundedReceiver<Event>) -> Option<String> {
+        let event = tokio::time::timeout(Duration::from_millis(500), rx.recv()).await;
+        event.ok().flatten().map(|e| e.topic)
+    }
+
+    fn change(entity_id: &str, old: &str, new: &str) -> Option<Value> {
+        let state = |s: &str| EntityState {
+            entity_id: entity_id.to_string(),
+            state: json!(s),
+            attributes: Default::default(),
+            last_changed_ms: 0,
+            last_updated_ms: 0,
+        };
+        serde_json::to_value(StateChanged {
+            entity_id: entity_id.to_string(),
+            old_state: Some(state(old)),
+            new_state: Some(state(new)),
+        })
+        .ok()
+    }
+
+    fn outcomes(automations: &Automations, id: &str) -> Vec<Outcome> {
+        let traces = automations.traces(Some(id)).unwrap();
+        traces.iter().map(|t| t.outcome).collect()
+    }
+
+    #[tokio::test]
+    async fn runs_rules_on_events_and_state_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let (automations, core) = start(dir.path());
+        let mut seen = listen(&core);
+
+        core.publish("door.front.opened", Some(json!({"by": "key"})));
+        assert_eq!(next(&mut seen).await.as_deref(), Some("seen.door"));
+        let trace = &automations.traces(Some("echo")).unwrap()[0];
+        assert_eq!(trace.trigger, "event door.#");
+        assert_eq!(trace.data, Some(json!({"by": "key"})));
+        assert_eq!(trace.actions[0].step, "publish seen.door");
+
+        // turned off again before the hold time is up
+        core.publish(STATE_CHANGED, change("light.desk", "off", "on"));
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        core.publish(STATE_CHANGED, change("light.desk", "on", "off"));
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert!(automations.traces(Some("desk_on")).unwrap().is_empty());
+
+        core.publish(STATE_CHANGED, change("light.desk", "off", "on"));
+        assert_eq!(next(&mut seen).await.as_deref(), Some("seen.desk"));
+        assert_eq!(outcomes(&automations, "desk_on"), [Outcome::Completed]);
+
+        // the interval ticks, but the condition never holds
+        tokio::time::sleep(Duration::from_millis(120)).await;
+        let traces = automations.traces(Some("guarded")).unwrap();
+        assert!(!traces.is_empty());
+        assert_eq!(traces[0].outcome, Outcome::ConditionsFailed);
+        assert_eq!(traces[0].trigger, "every 50ms");
+        assert!(!traces[0].conditions[0].ok);
+        assert_eq!(next(&mut seen).await, None);
+    }
+
+    #[tokio::test]
+    async fn skips_busy_rules_and_cancels_on_disable() {
+        let dir = tempfile::tempdir().unwrap();
+        let (automations, core) = start(dir.path());
+
+        core.publish("slow.go", None);
+        core.publish("slow.go", None);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(
+            outcomes(&automations, "slow"),
+            [Outcome::Running, Outcome::Skipped]
+        );
+
+        automations.set_enabled("slow", false).unwrap();
+        assert_eq!(
+            outcomes(&automations, "slow"),
+            [Outcome::Cancelled, Outcome::Skipped]
+        );
+        assert_eq!(automations.trigger("slow"), Ok(None));
+        let err = automations.handle("automation.enable", Some(json!({"id": "nope"})));
+        assert_eq!(err.unwrap_err().code, RpcError::INVALID_PARAMS);
+
+        // a reload keeps the runtime toggle and the traces
+        automations.load(load_dir(dir.path(), &Home::default()).rules);
+        let list = automations.list();
+        let slow = list.iter().find(|r| r.id == "slow").unwrap();
+        assert!(!slow.enabled);
+        assert_eq!(slow.last_outcome, Some(Outcome::Skipped));
+
+        automations
+            .handle("automation.enable", Some(json!({"id": "slow"})))
+            .unwrap();
+        let run = automations.trigger("slow").unwrap();
+        assert!(run.is_some());
+        let traces = automations.traces(Some("slow")).unwrap();
+        assert_eq!(traces.last().unwrap().trigger, "manual");
+    }
+}
@@ -0,0 +1,710 @@
+//! Automation rules as written in the rule files: what starts a rule, what
+//! must hold for it to run, and what it does.
+//!
+//! Rules live in TOML files in the automations directory. Each file holds
+//! any number of `[[rule]]` tables, with `[[rule.trigger]]`,
+//! `[[rule.condition]]` and `[[rule.action]]` tables below them, each picked
+//! by its `type`.
+
+use chrono::{Datelike, NaiveDateTime, NaiveTime, Weekday};
+use plugin_api::device::{self, DeviceCommand};
+use serde::{Deserialize, Deserializer};
+use serde_json::Value;
+use std::{
+    collections::BTreeSet,
+    fmt,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use crate::{
+    events,
+    services::timer::{Home, Schedule, Zone},
+};
+
+/// An automation rule.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    /// Unique among all rules; letters, digits, `_` and `-`.
+    pub id: String,
+    #[serde(default)]
+    pub name: Option<String>,
+    /// Disabled rules are loaded but never run.
+    #[serde(default = "enabled")]
+    pub enabled: bool,
+    /// The rule runs when any trigger fires.
+    #[serde(rename = "trigger")]
+    pub triggers: Vec<Trigger>,
+    /// All conditions must hold when a trigger fires for the rule to run.
+    #[serde(default, rename = "condition")]
+    pub conditions: Vec<Condition>,
+    /// Run one after another; a failing action ends the run.
+    #[serde(rename = "action")]
+    pub actions: Vec<Action>,
+    /// The file the rule was read from.
+    #[serde(skip)]
+    pub file: PathBuf,
+}
+
+fn enabled() -> bool {
+    true
+}
+
+/// What starts a rule.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Trigger {
+    /// An event on the bus whose topic matches `topic`, which may use the
+    /// `*` and `#` wildcards.
+    Event { topic: String },
+    /// A change of an entity's state, only from `from` and to `to` if
+    /// given. With `for`, the rule runs once the new state has held that
+    /// long.
+    State {
+        entity_id: String,
+        #[serde(default)]
+        from: Option<Value>,
+        #[serde(default)]
+        to: Option<Value>,
+        #[serde(default, rename = "for", deserialize_with = "optional_duration")]
+        hold: Option<Duration>,
+    },
+    /// Every `every`, counted from when the rule is loaded.
+    Interval {
+        #[serde(deserialize_with = "duration")]
+        every: Duration,
+    },
+    /// At the times matching a five-field cron expression, in the time zone
+    /// `tz` or the home zone.
+    Cron {
+        cron: String,
+        #[serde(default)]
+        tz: Option<String>,
+    },
+    /// Daily at a solar event at the home location, such as `sunset-30m`.
+    Solar { at: String },
+}
+
+impl Trigger {
+    /// The timer schedule of a time-based trigger, `None` for the others.
+    pub fn schedule(&self, home: &Home) -> Result<Option<Schedule>, String> {
+        Ok(Some(match self {
+            Trigger::Event { .. } | Trigger::State { .. } => return Ok(None),
+            Trigger::Interval { every } => Schedule::Interval(*every),
+            Trigger::Cron { cron, tz } => {
+                let zone = match tz {
+                    Some(name) => Zone::parse(name)?,
+                    None => home.zone,
+                };
+                Schedule::cron(cron, zone)?
+            }
+            Trigger::Solar { at } => {
+                let location = home
+                    .location
+                    .ok_or("solar triggers need the home latitude and longitude")?;
+                Schedule::solar(at, location, home.zone)?
+            }
+        }))
+    }
+
+    fn check(&self, home: &Home) -> Result<(), String> {
+        match self {
+            Trigger::Event { topic } if !events::valid_pattern(topic) => {
+                Err(format!("invalid topic pattern {topic:?}"))
+            }
+            Trigger::State { entity_id, .. } => device::parse_entity_id(entity_id).map(|_| ()),
+            Trigger::Interval { every } if every.is_zero() => {
+                Err("interval must be greater than 0".into())
+            }
+            _ => self.schedule(home).map(|_| ()),
+        }
+    }
+}
+
+impl fmt::Display for Trigger {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Trigger::Event { topic } => write!(f, "event {topic}"),
+            Trigger::State {
+                entity_id,
+                from,
+                to,
+                hold,
+            } => {
+                write!(f, "state {entity_id}")?;
+                if let Some(from) = from {
+                    write!(f, " from {from}")?;
+                }
+                if let Some(to) = to {
+                    write!(f, " to {to}")?;
+                }
+                if let Some(hold) = hold {
+                    write!(f, " for {}", DurationDisplay(*hold))?;
+                }
+                Ok(())
+            }
+            Trigger::Interval { every } => write!(f, "every {}", DurationDisplay(*every)),
+            Trigger::Cron { cron, tz: None } => write!(f, "cron {cron}"),
+            Trigger::Cron { cron, tz: Some(tz) } => write!(f, "cron {cron} in {tz}"),
+            Trigger::Solar { at } => write!(f, "solar {at}"),
+        }
+    }
+}
+
+/// What must hold for a triggered rule to run.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Condition {
+    /// The entity is in `state`, or in one of the states if it is a list.
+    State { entity_id: String, state: Value },
+    /// The home wall-clock time is at or after `after` and before `before`.
+    /// A window with `after` later than `before` spans midnight.
+    Time {
+        #[serde(default, deserialize_with = "optional_time")]
+        after: Option<NaiveTime>,
+        #[serde(default, deserialize_with = "optional_time")]
+        before: Option<NaiveTime>,
+    },
+    /// Today is one of `days`, such as `mon` or `saturday`, in the home
+    /// zone.
+    Weekday {
+        #[serde(deserialize_with = "weekdays")]
+        days: Vec<Weekday>,
+    },
+}
+
+impl Condition {
+    /// Whether the condition holds at the home wall-clock time `now`, with
+    /// `state` looking up the current state of an entity.
+    pub fn holds(&self, state: impl Fn(&str) -> Option<Value>, now: NaiveDateTime) -> bool {
+        match self {
+            Condition::State {
+                entity_id,
+                state: expected,
+            } => {
+                let Some(current) = state(entity_id) else {
+                    return false;
+                };
+                match expected {
+                    Value::Array(options) => options.contains(&current),
+                    expected => *expected == current,
+                }
+            }
+            Condition::Time { after, before } => {
+                let t = now.time();
+                match (after, before) {
+                    (Some(after), Some(before)) if after > before => t >= *after || t < *before,
+                    _ => after.is_none_or(|a| t >= a) && before.is_none_or(|b| t < b),
+                }
+            }
+            Condition::Weekday { days } => days.contains(&now.weekday()),
+        }
+    }
+
+    fn check(&self) -> Result<(), String> {
+        match self {
+            Condition::State { entity_id, .. } => device::parse_entity_id(entity_id).map(|_| ()),
+            Condition::Time {
+                after: None,
+                before: None,
+            } => Err("time condition needs after or before".into()),
+            Condition::Weekday { days } if days.is_empty() => {
+                Err("weekday condition needs at least one day".into())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Condition::State { entity_id, state } => write!(f, "state {entity_id} is {state}"),
+            Condition::Time { after, before } => {
+                f.write_str("time")?;
+                if let Some(after) = after {
+                    write!(f, " after {after}")?;
+                }
+                if let Some(before) = before {
+                    write!(f, " before {before}")?;
+                }
+                Ok(())
+            }
+            Condition::Weekday { days } => {
+                let days: Vec<String> = days.iter().map(Weekday::to_string).collect();
+                write!(f, "weekday {}", days.join(", "))
+            }
+        }
+    }
+}
+
+/// What a rule does.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Action {
+    /// Send a device command, with the fields of `device.command`.
+    Command(DeviceCommand),
+    /// Publish an event from the core.
+    Publish {
+        topic: String,
+        #[serde(default)]
+        payload: Option<Value>,
+    },
+    /// Call a method served by a plugin.
+    Call {
+        method: String,
+        #[serde(default)]
+        params: Option<Value>,
+        #[serde(default, deserialize_with = "optional_duration")]
+        timeout: Option<Duration>,
+    },
+    /// Wait before the next action.
+    Delay {
+        #[serde(deserialize_with = "duration")]
+        duration: Duration,
+    },
+}
+
+impl Action {
+    fn check(&self) -> Result<(), String> {
+        match self {
+            Action::Command(command) => {
+                // capabilities are only known once the entity is registered,
+                // so allow any the domain has
+                let (domain, _) = device::parse_entity_id(&command.entity_id)?;
+                let all: Vec<String> = domain
+                    .capabilities()
+                    .iter()
+                    .map(|c| c.to_string())
+                    .collect();
+                command.command.check(domain, &all)
+            }
+            Action::Publish { topic, .. } if !events::valid_topic(topic) => {
+                Err(format!("invalid topic {topic:?}"))
+            }
+            Action::Call { method, .. } if !method.contains('.') => Err(format!(
+                "invalid method {method:?}, expected <namespace>.<name>"
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Action::Command(command) => {
+                write!(
+                    f,
+                    "command {} {}",
+                    command.command.name(),
+                    command.entity_id
+                )
+            }
+            Action::Publish { topic, .. } => write!(f, "publish {topic}"),
+            Action::Call { method, .. } => write!(f, "call {method}"),
+            Action::Delay { duration } => write!(f, "delay {}", DurationDisplay(*duration)),
+        }
+    }
+}
+
+impl Rule {
+    /// Check what deserializing cannot: ids, topics, schedules and
+    /// commands.
+    pub fn check(&self, home: &Home) -> Result<(), String> {
+        if self.id.is_empty()
+            || !self
+                .id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        {
+            return Err(format!(
+                "invalid rule id {:?}, ids use letters, digits, _ and -",
+                self.id
+            ));
+        }
+        if self.triggers.is_empty() {
+            return Err("a rule needs at least one trigger".into());
+        }
+        if self.actions.is_empty() {
+            return Err("a rule needs at least one action".into());
+        }
+        for (i, trigger) in self.triggers.iter().enumerate() {
+            trigger
+                .check(home)
+                .map_err(|e| format!("trigger {}: {e}", i + 1))?;
+        }
+        for (i, condition) in self.conditions.iter().enumerate() {
+            condition
+                .check()
+                .map_err(|e| format!("condition {}: {e}", i + 1))?;
+        }
+        for (i, action) in self.actions.iter().enumerate() {
+            action
+                .check()
+                .map_err(|e| format!("action {}: {e}", i + 1))?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleFile {
+    #[serde(default)]
+    rule: Vec<Rule>,
+}
+
+/// The rules read from the automations directory, and what was wrong with
+/// the files. Rules with errors are left out.
+#[derive(Debug, Default)]
+pub struct Loaded {
+    pub rules: Vec<Rule>,
+    pub errors: Vec<String>,
+    pub warnings: Vec<String>,
+}
+
+/// Read every `*.toml` file in `dir`, in file name order.
+///
+/// YAML files are reported but not read: the core has no YAML parser.
+pub fn load_dir(dir: &Path, home: &Home) -> Loaded {
+    let mut loaded = Loaded::default();
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            loaded.warnings.push(format!(
+                "automations directory {} does not exist",
+                dir.display()
+            ));
+            return loaded;
+        }
+        Err(e) => {
+            loaded
+                .errors
+                .push(format!("reading {}: {e}", dir.display()));
+            return loaded;
+        }
+    };
+    let mut files: Vec<PathBuf> = entries.filter_map(|e| Some(e.ok()?.path())).collect();
+    files.sort();
+    let mut ids = BTreeSet::new();
+    for file in files {
+        match file.extension().and_then(|e| e.to_str()) {
+            Some("toml") => {}
+            Some("yaml" | "yml") => {
+                loaded.warnings.push(format!(
+                    "{}: YAML rule files are not supported, use TOML",
+                    file.display()
+                ));
+                continue;
+            }
+            _ => continue,
+        }
+        let parsed = std::fs::read_to_string(&file)
+            .map_err(|e| e.to_string())
+            .and_then(|text| toml::from_str::<RuleFile>(&text).map_err(|e| e.to_string()));
+        let rules = match parsed {
+            Ok(parsed) => parsed.rule,
+            Err(e) => {
+                loaded.errors.push(format!("{}: {e}", file.display()));
+                continue;
+            }
+        };
+        for mut rule in rules {
+            if let Err(e) = rule.check(home) {
+                loaded
+                    .errors
+                    .push(format!("{}: rule {}: {e}", file.display(), rule.id));
+                continue;
+            }
+            if !ids.insert(rule.id.clone()) {
+                loaded.errors.push(format!(
+                    "{}: rule {}: another rule has the same id",
+                    file.display(),
+                    rule.id
+                ));
+                continue;
+            }
+            rule.file = file.clone();
+            loaded.rules.push(rule);
+        }
+    }
+    loaded
+}
+
+/// Parse a duration such as `500ms`, `30s`, `5m` or `1h30m`.
+pub fn parse_duration(s: &str) -> Option<Duration> {
+    let mut total = Duration::ZERO;
+    let mut rest = s.trim();
+    if rest.is_empty() {
+        return None;
+    }
+    while !rest.is_empty() {
+        let digits = rest.find(|c: char| !c.is_ascii_digit())?;
+        let n: u64 = rest[..digits].parse().ok()?;
+        rest = &rest[digits..];
+        let (unit, len) = if rest.starts_with("ms") {
+            (Duration::from_millis(1), 2)
+        } else {
+            let unit = match rest.chars().next()? {
+                'h' => Duration::from_secs(3600),
+                'm' => Duration::from_secs(60),
+                's' => Duration::from_secs(1),
+                _ => return None,
+            };
+            (unit, 1)
+        };
+        total = total.checked_add(unit.checked_mul(u32::try_from(n).ok()?)?)?;
+        rest = &rest[len..];
+    }
+    Some(total)
+}
+
+/// Formats a duration the way [`parse_duration`] reads it.
+pub struct DurationDisplay(pub Duration);
+
+impl fmt::Display for DurationDisplay {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let millis = self.0.as_millis();
+        if millis == 0 || !millis.is_multiple_of(1000) {
+            return write!(f, "{millis}ms");
+        }
+        let secs = millis / 1000;
+        let (h, m, s) = (secs / 3600, secs / 60 % 60, secs % 60);
+        for (n, unit) in [(h, "h"), (m, "m"), (s, "s")] {
+            if n > 0 {
+                write!(f, "{n}{unit}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn duration<'de, D: Deserializer<'de>>(d: D) -> Result<Duration, D::Error> {
+    let s = String::deserialize(d)?;
+    parse_duration(&s).ok_or_else(|| {
+        serde::de::Error::custom(format!(
+            "invalid duration {s:?}, expected e.g. 30s, 5m or 1h30m"
+        ))
+    })
+}
+
+fn optional_duration<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Duration>, D::Error> {
+    duration(d).map(Some)
+}
+
+fn optional_time<'de, D: Deserializer<'de>>(d: D) -> Result<Option<NaiveTime>, D::Error> {
+    let s = String::deserialize(d)?;
+    NaiveTime::parse_from_str(&s, "%H:%M")
+        .or_else(|_| NaiveTime::parse_from_str(&s, "%H:%M:%S"))
+        .map(Some)
+        .map_err(|_| serde::de::Error::custom(format!("invalid time {s:?}, expected HH:MM")))
+}
+
+fn weekdays<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<Weekday>, D::Error> {
+    Vec::<String>::deserialize(d)?
+        .iter()
+        .map(|day| {
+            day.parse()
+                .map_err(|_| serde::de::Error::custom(format!("unknown weekday {day:?}")))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::solar::Location;
+    use chrono::NaiveDate;
+    use plugin_api::device::Command;
+    use serde_json::json;
+
+    const RULES: &str = r#"
+        [[rule]]
+        id = "porch_light"
+        name = "Porch light at sunset"
+
+        [[rule.trigger]]
+        type = "solar"
+        at = "sunset-30m"
+
+        [[rule.trigger]]
+        type = "state"
+        entity_id = "binary_sensor.porch_motion"
+        to = "on"
+        for = "1m30s"
+
+        [[rule.condition]]
+        type = "weekday"
+        days = ["mon", "friday"]
+
+        [[rule.condition]]
+        type = "time"
+        after = "18:00"
+        before = "02:00"
+
+        [[rule.action]]
+        type = "command"
+        entity_id = "light.porch"
+        command = "turn_on"
+        brightness = 200
+
+        [[rule.action]]
+        type = "delay"
+        duration = "10m"
+
+        [[rule.action]]
+        type = "call"
+        method = "chat.send"
+        params = { text = "porch light on" }
+    "#;
+
+    fn home() -> Home {
+        Home {
+            zone: Zone::parse("Europe/Berlin").unwrap(),
+            location: Some(Location::new(52.52, 13.40).unwrap()),
+        }
+    }
+
+    fn write(dir: &Path, name: &str, text: &str) {
+        std::fs::write(dir.join(name), text).unwrap();
+    }
+
+    #[test]
+    fn loads_rule_files() {
+        let dir = tempfile::tempdir().unwrap();
+        write(dir.path(), "10-porch.toml", RULES);
+        write(dir.path(), "notes.txt", "not a rule");
+        write(dir.path(), "20-other.yaml", "rule: []");
+        let loaded = load_dir(dir.path(), &home());
+        assert!(loaded.errors.is_empty(), "{:?}", loaded.errors);
+        assert_eq!(loaded.warnings.len(), 1);
+
+        let rule = &loaded.rules[0];
+        assert!(rule.enabled);
+        assert_eq!(
+            rule.triggers[1],
+            Trigger::State {
+                entity_id: "binary_sensor.porch_motion".into(),
+                from: None,
+                to: Some(json!("on")),
+                hold: Some(Duration::from_secs(90)),
+            }
+        );
+        assert_eq!(
+            rule.triggers[1].to_string(),
+            "state binary_sensor.porch_motion to \"on\" for 1m30s"
+        );
+        let Action::Command(command) = &rule.actions[0] else {
+            panic!("expected a command, got {:?}", rule.actions[0]);
+        };
+        assert_eq!(
+            command.command,
+            Command::TurnOn {
+                brightness: Some(200),
+                color_temp: None
+            }
+        );
+        assert_eq!(rule.actions[1].to_string(), "delay 10m");
+        assert!(rule.file.ends_with("10-porch.toml"));
+    }
+
+    #[test]
+    fn reports_invalid_rules() {
+        let dir = tempfile::tempdir().unwrap();
+        write(dir.path(), "a.toml", RULES);
+        // same id again, and solar without a home location below
+        write(dir.path(), "b.toml", RULES);
+        write(
+            dir.path(),
+            "c.toml",
+            r#"
+            [[rule]]
+            id = "bad"
+            [[rule.trigger]]
+            type = "state"
+            entity_id = "lamp.kitchen"
+            [[rule.action]]
+            type = "delay"
+            duration = "1s"
+            "#,
+        );
+        write(
+            dir.path(),
+            "d.toml",
+            r#"
+            [[rule]]
+            id = "typo"
+            [[rule.trigger]]
+            type = "event"
+            topik = "chat.#"
+            "#,
+        );
+        let loaded = load_dir(dir.path(), &home());
+        assert_eq!(loaded.rules.len(), 1);
+        let errors = loaded.errors.join("\n");
+        assert!(errors.contains("rule porch_light: another rule has the same id"));
+        assert!(errors.contains("rule bad: trigger 1: unknown domain \"lamp\""));
+        assert!(errors.contains("unknown field `topik`"), "{errors}");
+
+        let no_location = Home::default();
+        let loaded = load_dir(dir.path(), &no_location);
+        assert!(loaded
+            .errors
+            .iter()
+            .any(|e| e.contains("solar triggers need the home latitude and longitude")));
+    }
+
+    #[test]
+    fn evaluates_conditions() {
+        // a Friday
+        let at = |h, m| {
+            NaiveDate::from_ymd_opt(2024, 6, 21)
+                .unwrap()
+                .and_hms_opt(h, m, 0)
+                .unwrap()
+        };
+        let time = |after: &str, before: &str| Condition::Time {
+            after: NaiveTime::parse_from_str(after, "%H:%M").ok(),
+            before: NaiveTime::parse_from_str(before, "%H:%M").ok(),
+        };
+        let none = |_: &str| None;
+        assert!(time("22:00", "06:00").holds(none, at(23, 0)));
+        assert!(time("22:00", "06:00").holds(none, at(5, 59)));
+        assert!(!time("22:00", "06:00").holds(none, at(6, 0)));
+        assert!(time("08:00", "").holds(none, at(8, 0)));
+        assert!(!time("", "08:00").holds(none, at(8, 0)));
+
+        let weekend = Condition::Weekday {
+            days: vec![Weekday::Sat, Weekday::Sun],
+        };
+        assert!(!weekend.holds(none, at(12, 0)));
+
+        let open = Condition::State {
+            entity_id: "cover.garage".into(),
+            state: json!(["open", "opening"]),
+        };
+        assert!(open.holds(|_| Some(json!("opening")), at(12, 0)));
+        assert!(!open.holds(|_| Some(json!("closed")), at(12, 0)));
+        assert!(!open.holds(none, at(12, 0)));
+    }
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("1h30m"), Some(Duration::from_secs(5400)));
+        assert_eq!(parse_duration("250ms"), Some(Duration::from_millis(250)));
+        assert_eq!(parse_duration("2m5s"), Some(Duration::from_secs(125)));
+        assert_eq!(parse_duration("10"), None);
+        assert_eq!(parse_duration("5 min"), None);
+        assert_eq!(parse_duration(""), None);
+        assert_eq!(
+            DurationDisplay(Duration::from_secs(3725)).to_string(),
+            "1h2m5s"
+        );
+        assert_eq!(
+            DurationDisplay(Duration::from_millis(1500)).to_string(),
+            "1500ms"
+        );
+    }
+}
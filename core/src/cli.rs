@@ -1,16 +1,40 @@
 use clap::{Parser, Subcommand};
 use std::path::PathBuf;
 
+use crate::logging::LogFormat;
+
 /// Command line interface for the homecore application.
 #[derive(Parser, Debug)]
 #[command(author, version, about)]
 pub struct Cli {
-    /// Directory containing plugin manifests.
+    /// Config file to read instead of `homecore.toml` in the working
+    /// directory. Also settable with `HOMECORE_CONFIG`.
+    #[arg(long)]
+    pub config: Option<PathBuf>,
+    /// Directory containing plugin manifests. Replaces the configured
+    /// plugin directories.
     #[arg(long)]
     pub plugins_dir: Option<PathBuf>,
+    /// Directory of automation rule files. Defaults to `automations` in the
+    /// working directory.
+    #[arg(long)]
+    pub automations_dir: Option<PathBuf>,
+    /// Directory of Rhai scripts. Defaults to `scripts` in the working
+    /// directory.
+    #[arg(long)]
+    pub scripts_dir: Option<PathBuf>,
+    /// Directory for plugin storage, logs and status files.
+    #[arg(long)]
+    pub data_dir: Option<PathBuf>,
     /// Start without loading any plugins.
     #[arg(long)]
     pub safe_mode: bool,
+    /// Log filter, e.g. `debug` or `homecore=debug,plugin=warn`.
+    #[arg(long)]
+    pub log_level: Option<String>,
+    /// Format of the core's log output.
+    #[arg(long, value_enum)]
+    pub log_format: Option<LogFormat>,
     #[command(subcommand)]
     pub command: Command,
 }
@@ -18,16 +42,112 @@ pub struct Cli {
 #[derive(Subcommand, Debug)]
 pub enum Command {
     /// Run the core application normally.
-    Run,
+    Run {
+        /// Seconds each plugin gets to stop on shutdown before it is killed.
+        /// Defaults to 10.
+        #[arg(long)]
+        shutdown_grace: Option<u64>,
+        /// Latitude of the home in degrees, north positive.
+        #[arg(long, requires = "longitude", allow_negative_numbers = true)]
+        latitude: Option<f64>,
+        /// Longitude of the home in degrees, east positive.
+        #[arg(long, requires = "latitude", allow_negative_numbers = true)]
+        longitude: Option<f64>,
+        /// IANA time zone of the home, e.g. `Europe/Berlin`. Defaults to the
+        /// system's local zone.
+        #[arg(long)]
+        time_zone: Option<String>,
+        /// Keep plugin logs in memory only, without writing log files.
+        #[arg(long)]
+        no_log_files: bool,
+    },
     /// Operations on plugins.
     Plugin {
         #[command(subcommand)]
         command: PluginCommand,
     },
+    /// Operations on the configuration.
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+    /// Operations on automation rules.
+    Automation {
+        #[command(subcommand)]
+        command: AutomationCommand,
+    },
+    /// Operations on scripts.
+    Script {
+        #[command(subcommand)]
+        command: ScriptCommand,
+    },
+    /// Operations on the scenes of the running core.
+    Scene {
+        #[command(subcommand)]
+        command: SceneCommand,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum AutomationCommand {
+    /// Load the automation rules and report errors in them.
+    Check,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ScriptCommand {
+    /// Compile the scripts and report syntax errors in them.
+    Check,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum SceneCommand {
+    /// List the scenes.
+    List,
+    /// Set the entities of a scene to their target states.
+    Activate {
+        /// Id of the scene.
+        id: String,
+    },
+    /// Restore the states from before the last activated scene.
+    Undo,
+    /// Save the current states of entities as a scene.
+    Capture {
+        /// Id of the scene; an existing scene with it is replaced.
+        id: String,
+        /// Entities to capture, e.g. `light.living_room`.
+        #[arg(required = true)]
+        entities: Vec<String>,
+        /// Name to show for the scene.
+        #[arg(long)]
+        name: Option<String>,
+    },
+    /// Delete a scene.
+    Delete {
+        /// Id of the scene.
+        id: String,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ConfigCommand {
+    /// Validate the configuration and print the resolved settings.
+    Check,
 }
 
 #[derive(Subcommand, Debug)]
 pub enum PluginCommand {
     /// List discovered plugins.
     List,
+    /// Show what a plugin logged, from the running core or its log files.
+    Logs {
+        /// Id of the plugin.
+        id: String,
+        /// Number of lines to show.
+        #[arg(short = 'n', long, default_value_t = 100)]
+        lines: usize,
+        /// Keep printing new lines as they are logged.
+        #[arg(short, long)]
+        follow: bool,
+    },
 }
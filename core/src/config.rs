@@ -0,0 +1,489 @@
+//! Core configuration, resolved from `homecore.toml`, `HOMECORE_*`
+//! environment variables and command line flags, each overriding the
+//! previous.
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tracing_subscriber::EnvFilter;
+
+use crate::{
+    cli::{Cli, Command},
+    logging::LogFormat,
+    plugin_host::RestartPolicy,
+    script,
+    services::{
+        solar::Location,
+        storage,
+        timer::{Home, Zone},
+    },
+};
+
+/// Config file used when neither `--config` nor `HOMECORE_CONFIG` is set.
+pub const DEFAULT_CONFIG_FILE: &str = "homecore.toml";
+
+/// Directory automation rules are read from unless configured otherwise.
+pub const DEFAULT_AUTOMATIONS_DIR: &str = "automations";
+
+/// Directory scripts are read from unless configured otherwise.
+pub const DEFAULT_SCRIPTS_DIR: &str = "scripts";
+
+/// Settings for one plugin, from its `[plugins.<id>]` table.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PluginConfig {
+    /// Disabled plugins are discovered but never started.
+    #[serde(default = "enabled")]
+    pub enabled: bool,
+    /// Passed to the plugin in the `plugin.init` response, after checking
+    /// them against the plugin's settings schema.
+    #[serde(default)]
+    pub settings: Map<String, Value>,
+}
+
+impl Default for PluginConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            settings: Map::new(),
+        }
+    }
+}
+
+fn enabled() -> bool {
+    true
+}
+
+/// Runtime configuration of the core.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// The config file that was read, if any.
+    pub file: Option<PathBuf>,
+    /// Log filter, e.g. `info` or `homecore=debug,plugin=warn`.
+    pub log_level: String,
+    pub log_format: LogFormat,
+    /// Whether plugin logs are also written to files.
+    pub log_files: bool,
+    /// Directory for plugin storage, logs, status and audit files.
+    pub data_dir: PathBuf,
+    /// Directories searched for plugins; earlier ones win on duplicate ids.
+    pub plugin_dirs: Vec<PathBuf>,
+    /// Directory of automation rule files.
+    pub automations_dir: PathBuf,
+    /// Directory of Rhai scripts.
+    pub scripts_dir: PathBuf,
+    pub script_limits: script::Limits,
+    pub plugins: BTreeMap<String, PluginConfig>,
+    pub restart: RestartPolicy,
+    /// Time each plugin gets to stop on shutdown before it is killed.
+    pub shutdown_grace: Duration,
+    pub home: Home,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct FileConfig {
+    data_dir: Option<PathBuf>,
+    plugin_dirs: Option<Vec<PathBuf>>,
+    automations_dir: Option<PathBuf>,
+    scripts_dir: Option<PathBuf>,
+    shutdown_grace_secs: Option<u64>,
+    #[serde(default)]
+    log: FileLog,
+    #[serde(default)]
+    home: FileHome,
+    #[serde(default)]
+    restart: FileRestart,
+    #[serde(default)]
+    scripts: FileScripts,
+    #[serde(default)]
+    plugins: BTreeMap<String, PluginConfig>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct FileLog {
+    level: Option<String>,
+    format: Option<LogFormat>,
+    files: Option<bool>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct FileHome {
+    latitude: Option<f64>,
+    longitude: Option<f64>,
+    time_zone: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct FileRestart {
+    initial_backoff_ms: Option<u64>,
+    max_backoff_secs: Option<u64>,
+    max_restarts: Option<usize>,
+    window_secs: Option<u64>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct FileScripts {
+    max_operations: Option<u64>,
+    timeout_ms: Option<u64>,
+}
+
+impl Config {
+    /// Resolve the configuration for `cli`. Plugins are looked for in
+    /// `plugins/` under `workspace` unless configured otherwise.
+    pub fn load(cli: &Cli, workspace: &Path) -> Result<Self> {
+        // config file path precedence: CLI -> ENV -> default
+        let explicit = cli
+            .config
+            .clone()
+            .or_else(|| env("CONFIG").map(PathBuf::from));
+        let path = explicit
+            .clone()
+            .unwrap_or_else(|| PathBuf::from(DEFAULT_CONFIG_FILE));
+        let (file, file_cfg) = match std::fs::read_to_string(&path) {
+            Ok(text) => {
+                let cfg: FileConfig = toml::from_str(&text)
+                    .with_context(|| format!("invalid config file {}", path.display()))?;
+                (Some(path), cfg)
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound && explicit.is_none() => {
+                (None, FileConfig::default())
+            }
+            Err(e) => {
+                return Err(e).with_context(|| format!("reading {}", path.display()));
+            }
+        };
+        // relative paths in the file are relative to the file
+        let base = file
+            .as_deref()
+            .and_then(Path::parent)
+            .map(Path::to_path_buf)
+            .unwrap_or_default();
+
+        let defaults = RestartPolicy::default();
+        let script_defaults = script::Limits::default();
+        let mut cfg = Config {
+            log_level: file_cfg.log.level.unwrap_or_else(|| "info".into()),
+            log_format: file_cfg.log.format.unwrap_or_default(),
+            log_files: file_cfg.log.files.unwrap_or(true),
+            data_dir: file_cfg
+                .data_dir
+                .map(|d| base.join(d))
+                .unwrap_or_else(storage::default_data_dir),
+            plugin_dirs: file_cfg
+                .plugin_dirs
+                .map(|dirs| dirs.iter().map(|d| base.join(d)).collect())
+                .unwrap_or_else(|| vec![workspace.join("plugins")]),
+            automations_dir: file_cfg
+                .automations_dir
+                .map(|d| base.join(d))
+                .unwrap_or_else(|| PathBuf::from(DEFAULT_AUTOMATIONS_DIR)),
+            scripts_dir: file_cfg
+                .scripts_dir
+                .map(|d| base.join(d))
+                .unwrap_or_else(|| PathBuf::from(DEFAULT_SCRIPTS_DIR)),
+            script_limits: script::Limits {
+                max_operations: file_cfg
+                    .scripts
+                    .max_operations
+                    .unwrap_or(script_defaults.max_operations),
+                timeout: file_cfg
+                    .scripts
+                    .timeout_ms
+                    .map_or(script_defaults.timeout, Duration::from_millis),
+            },
+            plugins: file_cfg.plugins,
+            restart: RestartPolicy {
+                initial_backoff: file_cfg
+                    .restart
+                    .initial_backoff_ms
+                    .map_or(defaults.initial_backoff, Duration::from_millis),
+                max_backoff: file_cfg
+                    .restart
+                    .max_backoff_secs
+                    .map_or(defaults.max_backoff, Duration::from_secs),
+                max_restarts: file_cfg
+                    .restart
+                    .max_restarts
+                    .unwrap_or(defaults.max_restarts),
+                window: file_cfg
+                    .restart
+                    .window_secs
+                    .map_or(defaults.window, Duration::from_secs),
+            },
+            shutdown_grace: Duration::from_secs(file_cfg.shutdown_grace_secs.unwrap_or(10)),
+            home: Home::default(),
+            file,
+        };
+        let mut latitude = file_cfg.home.latitude;
+        let mut longitude = file_cfg.home.longitude;
+        let mut time_zone = file_cfg.home.time_zone;
+
+        // environment overrides
+        if let Some(level) = env("LOG") {
+            cfg.log_level = level;
+        }
+        if let Some(format) = env("LOG_FORMAT") {
+            cfg.log_format = parse_env("LOG_FORMAT", &format, |s| {
+                <LogFormat as clap::ValueEnum>::from_str(s, true)
+            })?;
+        }
+        if let Some(dir) = env("DATA_DIR") {
+            cfg.data_dir = dir.into();
+        }
+        if let Some(dirs) = std::env::var_os("HOMECORE_PLUGIN_DIRS") {
+            cfg.plugin_dirs = std::env::split_paths(&dirs).collect();
+        }
+        if let Some(dir) = env("AUTOMATIONS_DIR") {
+            cfg.automations_dir = dir.into();
+        }
+        if let Some(dir) = env("SCRIPTS_DIR") {
+            cfg.scripts_dir = dir.into();
+        }
+        if let Some(secs) = env("SHUTDOWN_GRACE_SECS") {
+            let secs = parse_env("SHUTDOWN_GRACE_SECS", &secs, str::parse)?;
+            cfg.shutdown_grace = Duration::from_secs(secs);
+        }
+        if let Some(lat) = env("LATITUDE") {
+            latitude = Some(parse_env("LATITUDE", &lat, str::parse)?);
+        }
+        if let Some(lon) = env("LONGITUDE") {
+            longitude = Some(parse_env("LONGITUDE", &lon, str::parse)?);
+        }
+        if let Some(tz) = env("TIME_ZONE") {
+            time_zone = Some(tz);
+        }
+
+        // CLI overrides
+        if let Some(level) = &cli.log_level {
+            cfg.log_level = level.clone();
+        }
+        if let Some(format) = cli.log_format {
+            cfg.log_format = format;
+        }
+        if let Some(dir) = &cli.data_dir {
+            cfg.data_dir = dir.clone();
+        }
+        if let Some(dir) = &cli.plugins_dir {
+            cfg.plugin_dirs = vec![dir.clone()];
+        }
+        if let Some(dir) = &cli.automations_dir {
+            cfg.automations_dir = dir.clone();
+        }
+        if let Some(dir) = &cli.scripts_dir {
+            cfg.scripts_dir = dir.clone();
+        }
+        if let Command::Run {
+            shutdown_grace,
+            latitude: lat,
+            longitude: lon,
+            time_zone: tz,
+            no_log_files,
+        } = &cli.command
+        {
+            if let Some(secs) = shutdown_grace {
+                cfg.shutdown_grace = Duration::from_secs(*secs);
+            }
+            if let (Some(lat), Some(lon)) = (lat, lon) {
+                latitude = Some(*lat);
+                longitude = Some(*lon);
+            }
+            if tz.is_some() {
+                time_zone = tz.clone();
+            }
+            if *no_log_files {
+                cfg.log_files = false;
+            }
+        }
+
+        // plugins run in their own directory, so hand them absolute paths
+        cfg.data_dir = std::path::absolute(&cfg.data_dir)
+            .with_context(|| format!("invalid data dir {}", cfg.data_dir.display()))?;
+
+        // validate
+        EnvFilter::try_new(&cfg.log_level)
+            .with_context(|| format!("invalid log level {:?}", cfg.log_level))?;
+        if cfg.restart.initial_backoff.is_zero() {
+            bail!("restart.initial_backoff_ms must be greater than 0");
+        }
+        if cfg.script_limits.max_operations == 0 || cfg.script_limits.timeout.is_zero() {
+            bail!("scripts.max_operations and scripts.timeout_ms must be greater than 0");
+        }
+        cfg.home = Home {
+            zone: match &time_zone {
+                Some(name) => Zone::parse(name).map_err(anyhow::Error::msg)?,
+                None => Zone::Local,
+            },
+            location: match (latitude, longitude) {
+                (Some(lat), Some(lon)) => {
+                    Some(Location::new(lat, lon).map_err(anyhow::Error::msg)?)
+                }
+                (None, None) => None,
+                _ => bail!("home latitude and longitude must be set together"),
+            },
+        };
+        Ok(cfg)
+    }
+
+    /// The configured settings of every plugin that has some.
+    pub fn settings(&self) -> impl Iterator<Item = (String, Map<String, Value>)> + '_ {
+        self.plugins
+            .iter()
+            .filter(|(_, p)| !p.settings.is_empty())
+            .map(|(id, p)| (id.clone(), p.settings.clone()))
+    }
+
+    /// Whether the plugin `id` may be started.
+    pub fn enabled(&self, id: &str) -> bool {
+        self.plugins.get(id).is_none_or(|p| p.enabled)
+    }
+
+    /// Problems that do not stop the core from running but are likely
+    /// mistakes, given the ids of the discovered plugins.
+    pub fn warnings<'a>(&self, discovered: impl IntoIterator<Item = &'a str>) -> Vec<String> {
+        let discovered: Vec<&str> = discovered.into_iter().collect();
+        let mut warnings = Vec::new();
+        for dir in &self.plugin_dirs {
+            if !dir.is_dir() {
+                warnings.push(format!("plugin directory {} does not exist", dir.display()));
+            }
+        }
+        for id in self.plugins.keys() {
+            if !discovered.contains(&id.as_str()) {
+                warnings.push(format!(
+                    "[plugins.{id}] does not match any discovered plugin"
+                ));
+            }
+        }
+        warnings
+    }
+}
+
+/// The value of `HOMECORE_<name>`, if set.
+fn env(name: &str) -> Option<String> {
+    std::env::var(format!("HOMECORE_{name}")).ok()
+}
+
+fn parse_env<T, E: std::fmt::Display>(
+    name: &str,
+    value: &str,
+    parse: impl FnOnce(&str) -> Result<T, E>,
+) -> Result<T> {
+    parse(value).map_err(|e| anyhow::anyhow!("invalid HOMECORE_{name} {value:?}: {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    fn load(args: &[&str], file: &str) -> Result<Config> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("homecore.toml");
+        std::fs::write(&path, file).unwrap();
+        let mut argv = vec!["homecore", "--config", path.to_str().unwrap()];
+        argv.extend_from_slice(args);
+        let cli = Cli::try_parse_from(argv).unwrap();
+        let cfg = Config::load(&cli, Path::new("/ws"))?;
+        assert_eq!(cfg.file.as_deref(), Some(path.as_path()));
+        Ok(cfg)
+    }
+
+    #[test]
+    fn layers_file_and_cli() {
+        let file = r#"
+            data_dir = "data"
+            plugin_dirs = ["plugins", "/opt/plugins"]
+            shutdown_grace_secs = 3
+
+            [log]
+            level = "debug"
+            format = "json"
+
+            [home]
+            latitude = 52.5
+            longitude = 13.4
+            time_zone = "Europe/Berlin"
+
+            [restart]
+            max_restarts = 2
+
+            [plugins.family_chat]
+            enabled = false
+
+            [plugins.sample_plugin.settings]
+            greeting = "hi"
+        "#;
+        let cfg = load(&["run"], file).unwrap();
+        assert_eq!(cfg.log_level, "debug");
+        assert_eq!(cfg.log_format, LogFormat::Json);
+        assert!(cfg.data_dir.ends_with("data") && cfg.data_dir.is_absolute());
+        assert_eq!(cfg.plugin_dirs[1], PathBuf::from("/opt/plugins"));
+        assert_eq!(cfg.shutdown_grace, Duration::from_secs(3));
+        assert_eq!(cfg.restart.max_restarts, 2);
+        assert_eq!(cfg.restart.window, RestartPolicy::default().window);
+        assert_eq!(cfg.home.zone.name(), "Europe/Berlin");
+        assert!(!cfg.enabled("family_chat"));
+        assert!(cfg.enabled("sample_plugin"));
+        let settings: Vec<_> = cfg.settings().collect();
+        assert_eq!(settings.len(), 1);
+        assert_eq!(settings[0].1["greeting"], "hi");
+
+        let cfg = load(
+            &[
+                "--log-level",
+                "warn",
+                "--plugins-dir",
+                "/p",
+                "run",
+                "--shutdown-grace",
+                "1",
+                "--latitude",
+                "-33.9",
+                "--longitude",
+                "18.4",
+                "--no-log-files",
+            ],
+            file,
+        )
+        .unwrap();
+        assert_eq!(cfg.log_level, "warn");
+        assert_eq!(cfg.plugin_dirs, vec![PathBuf::from("/p")]);
+        assert_eq!(cfg.shutdown_grace, Duration::from_secs(1));
+        assert_eq!(cfg.home.location.unwrap().latitude, -33.9);
+        assert!(!cfg.log_files);
+    }
+
+    #[test]
+    fn defaults_without_a_file() {
+        let cli = Cli::try_parse_from(["homecore", "plugin", "list"]).unwrap();
+        let cfg = Config::load(&cli, Path::new("/ws")).unwrap();
+        assert_eq!(cfg.log_level, "info");
+        assert_eq!(cfg.plugin_dirs, vec![PathBuf::from("/ws/plugins")]);
+        assert_eq!(cfg.restart, RestartPolicy::default());
+        assert!(cfg.home.location.is_none());
+    }
+
+    #[test]
+    fn rejects_invalid_files() {
+        let err = |file: &str| format!("{:#}", load(&["config", "check"], file).unwrap_err());
+        assert!(err("shutdown_grace = 3").contains("unknown field"));
+        assert!(err("[plugins.x]\nenable = false").contains("unknown field"));
+        assert!(err("[home]\nlatitude = 95.0\nlongitude = 0.0").contains("latitude"));
+        assert!(err("[home]\nlatitude = 5.0").contains("together"));
+        assert!(err("[home]\ntime_zone = \"Mars/Olympus\"").contains("Mars"));
+        assert!(err("[log]\nformat = \"xml\"").contains("xml"));
+        let cfg = load(&["config", "check"], "[plugins.ghost]\nenabled = true").unwrap();
+        let warnings = cfg.warnings(["sample_plugin"]);
+        assert!(warnings.iter().any(|w| w.contains("ghost")), "{warnings:?}");
+    }
+}
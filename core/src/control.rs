@@ -0,0 +1,335 @@
+//! Control socket through which the command line reaches the running core.
+//!
+//! The core listens on `control.sock` in its data directory and answers
+//! line-delimited request envelopes, like a plugin's stdio, for the methods
+//! meant to be driven by hand. Only the user running the core may connect.
+//! Unix only.
+
+use anyhow::Result;
+use plugin_api::{Envelope, RpcError};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+#[cfg(unix)]
+use tracing::warn;
+
+use crate::{
+    scene::Scenes,
+    services::{log::PluginLogs, parse},
+};
+
+/// Socket in the data directory the core listens on.
+pub const SOCKET_FILE: &str = "control.sock";
+
+/// Topic of the events `log.follow` sends, with the new `records`.
+pub const LOG_RECORDS: &str = "log.records";
+
+pub fn socket_path(data_dir: &Path) -> PathBuf {
+    data_dir.join(SOCKET_FILE)
+}
+
+/// Nothing listens on the control socket, so the core is not running, or
+/// runs with another data directory.
+#[derive(Debug)]
+pub struct NotRunning(pub PathBuf);
+
+impl fmt::Display for NotRunning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "cannot reach the core at {}, is it running?",
+            self.0.display()
+        )
+    }
+}
+
+impl std::error::Error for NotRunning {}
+
+#[derive(Deserialize)]
+struct LogParams {
+    plugin: String,
+    #[serde(default)]
+    lines: usize,
+}
+
+/// Answer a request read from the socket.
+async fn handle(logs: &PluginLogs, scenes: Option<&Scenes>, env: Envelope) -> Envelope {
+    let result = match env.method.as_deref() {
+        Some("log.tail") => parse::<LogParams>(env.params)
+            .map(|p| json!({"records": logs.tail(&p.plugin, p.lines)})),
+        Some(m) if m.starts_with("scene.") => match scenes {
+            Some(scenes) => scenes.handle(m, env.params).await,
+            None => Err(RpcError::new(
+                RpcError::METHOD_NOT_FOUND,
+                "scenes are not loaded",
+            )),
+        },
+        m => Err(RpcError::new(
+            RpcError::METHOD_NOT_FOUND,
+            format!("unknown method {}", m.unwrap_or_default()),
+        )),
+    };
+    Envelope::response(env.id, result)
+}
+
+/// Answer `log.follow` with the last `lines` of the plugin's log, then send
+/// each new line as a [`LOG_RECORDS`] event until the client disconnects or
+/// sends anything else.
+#[cfg(unix)]
+async fn follow_logs(
+    logs: &PluginLogs,
+    env: Envelope,
+    reader: &mut tokio::io::BufReader<tokio::net::unix::OwnedReadHalf>,
+    writer: &mut tokio::net::unix::OwnedWriteHalf,
+) {
+    use crate::ipc::{read_envelope, write_envelope};
+    use tokio::sync::broadcast::error::RecvError;
+
+    let p = match parse::<LogParams>(env.params) {
+        Ok(p) => p,
+        Err(e) => {
+            let _ = write_envelope(writer, &Envelope::response(env.id, Err(e))).await;
+            return;
+        }
+    };
+    let (records, mut live) = logs.tail_and_follow(&p.plugin, p.lines);
+    let response = Envelope::response(env.id, Ok(json!({ "records": records })));
+    if write_envelope(writer, &response).await.is_err() {
+        return;
+    }
+    loop {
+        let record = tokio::select! {
+            record = live.recv() => record,
+            _ = read_envelope(reader) => return,
+        };
+        let record = match record {
+            Ok(record) if record.plugin == p.plugin => record,
+            Ok(_) => continue,
+            Err(RecvError::Lagged(n)) => {
+                warn!("log follower of {} skipped {n} lines", p.plugin);
+                continue;
+            }
+            Err(RecvError::Closed) => return,
+        };
+        let event = Envelope::event(LOG_RECORDS, Some(json!({ "records": [record] })));
+        if write_envelope(writer, &event).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Listen on the socket at `path` until the task is dropped, replacing a
+/// socket left behind by an earlier run. The socket is only accessible to the
+/// owner, and connections from other users are dropped.
+#[cfg(unix)]
+pub async fn serve(path: PathBuf, logs: Arc<PluginLogs>, scenes: Option<Scenes>) -> Result<()> {
+    use crate::ipc::{read_envelope, write_envelope};
+    use anyhow::Context;
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+    use tokio::{io::BufReader, net::UnixListener};
+
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    match std::fs::remove_file(&path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            return Err(e).with_context(|| format!("removing {}", path.display()))
+        }
+        _ => {}
+    }
+    let listener =
+        UnixListener::bind(&path).with_context(|| format!("binding {}", path.display()))?;
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))
+        .with_context(|| format!("restricting {}", path.display()))?;
+    // the socket belongs to the user the core runs as
+    let owner = std::fs::metadata(&path)?.uid();
+    loop {
+        let (stream, _) = listener.accept().await?;
+        // covers connections made before the permissions were set
+        match stream.peer_cred() {
+            Ok(cred) if cred.uid() == owner => {}
+            Ok(cred) => {
+                warn!("refusing control connection from uid {}", cred.uid());
+                continue;
+            }
+            Err(e) => {
+                warn!("refusing control connection: {e}");
+                continue;
+            }
+        }
+        let logs = logs.clone();
+        let scenes = scenes.clone();
+        tokio::spawn(async move {
+            let (reader, mut writer) = stream.into_split();
+            let mut reader = BufReader::new(reader);
+            while let Ok(env) = read_envelope(&mut reader).await {
+                if env.method.as_deref() == Some("log.follow") {
+                    follow_logs(&logs, env, &mut reader, &mut writer).await;
+                    break;
+                }
+                let response = handle(&logs, scenes.as_ref(), env).await;
+                if write_envelope(&mut writer, &response).await.is_err() {
+                    break;
+                }
+            }
+        });
+    }
+}
+
+#[cfg(not(unix))]
+pub async fn serve(_path: PathBuf, _logs: Arc<PluginLogs>, _scenes: Option<Scenes>) -> Result<()> {
+    std::future::pending().await
+}
+
+/// Connect to the core at `path`, send a request and read its response,
+/// returning the connection for whatever follows.
+#[cfg(unix)]
+async fn send(
+    path: &Path,
+    method: &str,
+    params: Value,
+) -> Result<(Value, tokio::io::BufReader<tokio::net::UnixStream>)> {
+    use crate::ipc::{read_envelope, write_envelope};
+    use std::io::ErrorKind;
+    use tokio::{io::BufReader, net::UnixStream};
+
+    let stream = match UnixStream::connect(path).await {
+        Ok(stream) => stream,
+        Err(e) if matches!(e.kind(), ErrorKind::NotFound | ErrorKind::ConnectionRefused) => {
+            return Err(NotRunning(path.into()).into())
+        }
+        Err(e) => anyhow::bail!("connecting to {}: {e}", path.display()),
+    };
+    let mut stream = BufReader::new(stream);
+    write_envelope(&mut stream, &Envelope::request("1", method, params)).await?;
+    let response = read_envelope(&mut stream).await?;
+    if let Some(err) = response.error {
+        anyhow::bail!("{} (error {})", err.message, err.code);
+    }
+    Ok((response.result.unwrap_or(Value::Null), stream))
+}
+
+/// Send one request to the core listening at `path` and return its result.
+/// Fails with [`NotRunning`] if nothing listens there.
+#[cfg(unix)]
+pub async fn request(path: &Path, method: &str, params: Value) -> Result<Value> {
+    Ok(send(path, method, params).await?.0)
+}
+
+#[cfg(not(unix))]
+pub async fn request(path: &Path, _method: &str, _params: Value) -> Result<Value> {
+    Err(NotRunning(path.into()).into())
+}
+
+/// Send a request after whose response the core keeps sending events, such
+/// as `log.follow`. `on_message` gets the result and then the payload of
+/// each event, until the core closes the connection.
+#[cfg(unix)]
+pub async fn follow(
+    path: &Path,
+    method: &str,
+    params: Value,
+    mut on_message: impl FnMut(Value),
+) -> Result<()> {
+    let (result, mut stream) = send(path, method, params).await?;
+    on_message(result);
+    while let Ok(env) = crate::ipc::read_envelope(&mut stream).await {
+        on_message(env.payload.unwrap_or(Value::Null));
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub async fn follow(
+    path: &Path,
+    _method: &str,
+    _params: Value,
+    _on_message: impl FnMut(Value),
+) -> Result<()> {
+    Err(NotRunning(path.into()).into())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::PluginManager;
+    use std::{os::unix::fs::PermissionsExt, time::Duration};
+
+    /// Wait until the server at `path` answers.
+    async fn connect(path: &Path) {
+        for _ in 0..100 {
+            if request(path, "ping", json!({}))
+                .await
+                .is_err_and(|e| !e.is::<NotRunning>())
+            {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("nothing listens at {}", path.display());
+    }
+
+    #[tokio::test]
+    async fn socket_is_private_to_its_owner() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager =
+            PluginManager::discover(dir.path().into(), dir.path().join("plugins")).unwrap();
+        let scenes = Scenes::load(manager.core_handle(), dir.path()).unwrap();
+        let path = socket_path(dir.path());
+        let server = tokio::spawn(serve(path.clone(), manager.plugin_logs(), Some(scenes)));
+
+        connect(&path).await;
+        let result = request(&path, "scene.list", json!({})).await.unwrap();
+        assert_eq!(result, json!({"scenes": []}));
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn serves_plugin_logs() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = socket_path(dir.path());
+        let err = request(&path, "log.tail", json!({})).await.unwrap_err();
+        assert!(err.is::<NotRunning>(), "{err:#}");
+
+        let logs = Arc::new(PluginLogs::default());
+        logs.write("lamp", "INFO", "one");
+        logs.write("lamp", "INFO", "two");
+        logs.write("panel", "INFO", "other");
+        let server = tokio::spawn(serve(path.clone(), logs.clone(), None));
+        connect(&path).await;
+
+        let result = request(&path, "log.tail", json!({"plugin": "lamp", "lines": 1}))
+            .await
+            .unwrap();
+        assert_eq!(result["records"][0]["message"], "two");
+        assert_eq!(result["records"].as_array().unwrap().len(), 1);
+
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let follower = tokio::spawn({
+            let path = path.clone();
+            async move {
+                let params = json!({"plugin": "lamp", "lines": 2});
+                follow(&path, "log.follow", params, |message| {
+                    for record in message["records"].as_array().unwrap() {
+                        tx.send(record["message"].as_str().unwrap().to_string())
+                            .unwrap();
+                    }
+                })
+                .await
+            }
+        });
+        assert_eq!(rx.recv().await.unwrap(), "one");
+        assert_eq!(rx.recv().await.unwrap(), "two");
+        logs.write("panel", "INFO", "not lamp");
+        logs.write("lamp", "INFO", "three");
+        assert_eq!(rx.recv().await.unwrap(), "three");
+        follower.abort();
+        server.abort();
+    }
+}
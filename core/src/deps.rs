@@ -0,0 +1,153 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::services::SERVICES;
+
+/// Order in which plugins can be started, and the plugins that cannot be
+/// started at all because of their declared dependencies.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct StartPlan {
+    /// Plugins in dependency order: every plugin comes after the plugins it
+    /// needs.
+    pub order: Vec<String>,
+    /// Plugins that must not be started, with the reason. Plugins on a
+    /// dependency cycle are also left out of `order`.
+    pub blocked: BTreeMap<String, String>,
+}
+
+/// Build a start plan from each plugin's `needs` list. Entries naming a core
+/// service are always satisfied; anything else must be another plugin.
+pub fn plan(needs: &BTreeMap<String, Vec<String>>) -> StartPlan {
+    let mut result = StartPlan::default();
+    let mut deps: BTreeMap<&str, BTreeSet<&str>> = BTreeMap::new();
+    for (id, list) in needs {
+        let mut plugin_deps = BTreeSet::new();
+        for need in list {
+            if SERVICES.contains(&need.as_str()) {
+                continue;
+            }
+            if needs.contains_key(need) {
+                plugin_deps.insert(need.as_str());
+            } else {
+                // the plugin stays in the order, so its dependents are
+                // skipped at start time with a reason naming it
+                result.blocked.insert(
+                    id.clone(),
+                    format!("needs {need}, which is neither a plugin nor a core service"),
+                );
+            }
+        }
+        deps.insert(id.as_str(), plugin_deps);
+    }
+
+    // Kahn's algorithm; the sorted ready set keeps the order deterministic
+    let mut remaining = deps.clone();
+    let mut ready: BTreeSet<&str> = remaining
+        .iter()
+        .filter(|(_, d)| d.is_empty())
+        .map(|(id, _)| *id)
+        .collect();
+    while let Some(id) = ready.pop_first() {
+        remaining.remove(id);
+        result.order.push(id.to_string());
+        for (other, d) in remaining.iter_mut() {
+            if d.remove(id) && d.is_empty() {
+                ready.insert(other);
+            }
+        }
+    }
+
+    // whatever is left is part of a cycle or depends on one
+    for id in remaining.keys() {
+        if let Some(cycle) = find_cycle(&deps, id) {
+            result
+                .blocked
+                .entry(id.to_string())
+                .or_insert_with(|| format!("dependency cycle: {}", cycle.join(" -> ")));
+        }
+    }
+    for id in remaining.keys() {
+        if !result.blocked.contains_key(*id) {
+            let dep = deps[id]
+                .iter()
+                .find(|d| remaining.contains_key(*d))
+                .copied()
+                .unwrap_or_default();
+            result
+                .blocked
+                .insert(id.to_string(), format!("needs {dep}, which cannot start"));
+        }
+    }
+    result
+}
+
+/// A dependency path from `start` back to itself, if `start` is on a cycle.
+fn find_cycle<'a>(
+    deps: &BTreeMap<&'a str, BTreeSet<&'a str>>,
+    start: &'a str,
+) -> Option<Vec<&'a str>> {
+    fn walk<'a>(
+        deps: &BTreeMap<&'a str, BTreeSet<&'a str>>,
+        start: &'a str,
+        path: &mut Vec<&'a str>,
+    ) -> bool {
+        let current = *path.last().unwrap();
+        for &next in deps.get(current).into_iter().flatten() {
+            if next == start {
+                path.push(next);
+                return true;
+            }
+            if !path.contains(&next) {
+                path.push(next);
+                if walk(deps, start, path) {
+                    return true;
+                }
+                path.pop();
+            }
+        }
+        false
+    }
+    let mut path = vec![start];
+    walk(deps, start, &mut path).then_some(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn needs(spec: &[(&str, &[&str])]) -> BTreeMap<String, Vec<String>> {
+        spec.iter()
+            .map(|(id, n)| (id.to_string(), n.iter().map(|s| s.to_string()).collect()))
+            .collect()
+    }
+
+    #[test]
+    fn orders_dependencies_first() {
+        let p = plan(&needs(&[
+            ("chat", &["auth", "storage", "log"]),
+            ("auth", &["db"]),
+            ("db", &[]),
+            ("other", &[]),
+        ]));
+        assert!(p.blocked.is_empty(), "{:?}", p.blocked);
+        let pos = |id: &str| p.order.iter().position(|x| x == id).unwrap();
+        assert!(pos("db") < pos("auth"));
+        assert!(pos("auth") < pos("chat"));
+        assert_eq!(p.order.len(), 4);
+    }
+
+    #[test]
+    fn blocks_cycles_unknown_needs_and_their_dependents() {
+        let p = plan(&needs(&[
+            ("a", &["b"]),
+            ("b", &["a"]),
+            ("c", &["a"]),
+            ("d", &["nope"]),
+            ("e", &[]),
+        ]));
+        assert_eq!(p.order, vec!["d", "e"]);
+        assert_eq!(p.blocked["a"], "dependency cycle: a -> b -> a");
+        assert_eq!(p.blocked["b"], "dependency cycle: b -> a -> b");
+        assert_eq!(p.blocked["c"], "needs a, which cannot start");
+        assert!(p.blocked["d"].contains("nope"));
+    }
+}
@@ -1,10 +1,43 @@
-use std::collections::HashMap;
+use serde_json::Value;
+use std::collections::{BTreeSet, HashMap};
 use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
 
-/// Very small event bus used internally by the core.  It is intentionally
-/// minimal and only supports broadcasting string payloads.
+pub use plugin_api::topic_matches;
+
+/// An event travelling over the bus.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Event {
+    pub topic: String,
+    /// Id of the publishing subscriber, if any. Events are never delivered
+    /// back to their source.
+    pub source: Option<String>,
+    pub payload: Option<Value>,
+}
+
+struct Subscriber {
+    tx: UnboundedSender<Event>,
+    patterns: BTreeSet<String>,
+}
+
+/// Event bus used by the core to fan events out to plugins and internal
+/// services. Topics are dot separated; subscription patterns may use `*` to
+/// match exactly one segment and `#` to match any number of segments,
+/// including none.
 pub struct EventBus {
-    subscribers: HashMap<String, Vec<UnboundedSender<String>>>,
+    subscribers: HashMap<String, Subscriber>,
+}
+
+/// Check that a subscription pattern only uses wildcards as whole segments.
+pub fn valid_pattern(pattern: &str) -> bool {
+    !pattern.is_empty()
+        && pattern
+            .split('.')
+            .all(|seg| !seg.is_empty() && (seg == "*" || seg == "#" || !seg.contains(['*', '#'])))
+}
+
+/// Check that a topic is concrete enough to be published.
+pub fn valid_topic(topic: &str) -> bool {
+    valid_pattern(topic) && !topic.contains(['*', '#'])
 }
 
 impl EventBus {
@@ -14,22 +47,97 @@ impl EventBus {
         }
     }
 
-    /// Subscribe to a topic, returning a receiver for events.
-    pub fn subscribe(&mut self, topic: &str) -> UnboundedReceiver<String> {
+    /// Register a subscriber under `id`, returning the receiver its events
+    /// are delivered to. Registering an id again replaces the previous
+    /// receiver but keeps its subscriptions.
+    pub fn register(&mut self, id: &str) -> UnboundedReceiver<Event> {
         let (tx, rx) = unbounded_channel();
+        let patterns = self
+            .subscribers
+            .remove(id)
+            .map(|s| s.patterns)
+            .unwrap_or_default();
         self.subscribers
-            .entry(topic.to_string())
-            .or_default()
-            .push(tx);
+            .insert(id.to_string(), Subscriber { tx, patterns });
         rx
     }
 
-    /// Publish a message on a topic.
-    pub fn publish(&mut self, topic: &str, payload: String) {
-        if let Some(list) = self.subscribers.get_mut(topic) {
-            list.retain(|tx| tx.send(payload.clone()).is_ok());
+    /// Drop a subscriber and all of its subscriptions.
+    pub fn remove(&mut self, id: &str) {
+        self.subscribers.remove(id);
+    }
+
+    /// Subscribe a registered subscriber to a topic pattern. Returns `false`
+    /// if the subscriber is unknown.
+    pub fn subscribe(&mut self, id: &str, pattern: &str) -> bool {
+        match self.subscribers.get_mut(id) {
+            Some(s) => {
+                s.patterns.insert(pattern.to_string());
+                true
+            }
+            None => false,
         }
     }
+
+    /// Remove a subscription, returning whether it existed.
+    pub fn unsubscribe(&mut self, id: &str, pattern: &str) -> bool {
+        self.subscribers
+            .get_mut(id)
+            .map(|s| s.patterns.remove(pattern))
+            .unwrap_or(false)
+    }
+
+    /// Patterns a subscriber is currently subscribed to.
+    pub fn subscriptions(&self, id: &str) -> Vec<String> {
+        self.subscribers
+            .get(id)
+            .map(|s| s.patterns.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Whether any subscription of `id` matches `topic`.
+    pub fn is_subscribed(&self, id: &str, topic: &str) -> bool {
+        self.subscribers
+            .get(id)
+            .is_some_and(|s| s.patterns.iter().any(|p| topic_matches(p, topic)))
+    }
+
+    /// Deliver an event to subscriber `id` alone, whatever its
+    /// subscriptions. Returns `false` if there is no such subscriber.
+    pub fn send_to(&mut self, id: &str, event: Event) -> bool {
+        let Some(sub) = self.subscribers.get(id) else {
+            return false;
+        };
+        if sub.tx.send(event).is_err() {
+            self.subscribers.remove(id);
+            return false;
+        }
+        true
+    }
+
+    /// Deliver an event once to every subscriber other than its source that
+    /// has a matching subscription. Returns the number of recipients.
+    pub fn publish(&mut self, event: Event) -> usize {
+        let mut delivered = 0;
+        let mut closed = Vec::new();
+        for (id, sub) in &self.subscribers {
+            if event.source.as_deref() == Some(id.as_str()) {
+                continue;
+            }
+            if !sub.patterns.iter().any(|p| topic_matches(p, &event.topic)) {
+                continue;
+            }
+            if sub.tx.send(event.clone()).is_ok() {
+                delivered += 1;
+            } else {
+                closed.push(id.clone());
+            }
+        }
+        for id in closed {
+            self.subscribers.remove(&id);
+        }
+        delivered
+    }
 }
 
 impl Default for EventBus {
@@ -37,3 +145,72 @@ impl Default for EventBus {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn event(topic: &str, source: Option<&str>) -> Event {
+        Event {
+            topic: topic.into(),
+            source: source.map(Into::into),
+            payload: Some(json!({"t": topic})),
+        }
+    }
+
+    #[test]
+    fn wildcard_matching() {
+        assert!(topic_matches("chat.message", "chat.message"));
+        assert!(topic_matches("chat.*", "chat.message"));
+        assert!(!topic_matches("chat.*", "chat"));
+        assert!(!topic_matches("chat.*", "chat.room.created"));
+        assert!(topic_matches("device.#", "device"));
+        assert!(topic_matches("device.#", "device.light.kitchen"));
+        assert!(topic_matches("#.pressed", "doorbell.pressed"));
+        assert!(topic_matches("*.#.on", "a.b.c.on"));
+        assert!(!topic_matches("device.#", "devices.x"));
+
+        assert!(valid_pattern("chat.*"));
+        assert!(!valid_pattern("chat.mess*"));
+        assert!(!valid_pattern("chat..x"));
+        assert!(valid_topic("doorbell.pressed"));
+        assert!(!valid_topic("doorbell.*"));
+    }
+
+    #[test]
+    fn fans_out_to_other_subscribers_once() {
+        let mut bus = EventBus::new();
+        let mut a = bus.register("a");
+        let mut b = bus.register("b");
+        let mut c = bus.register("c");
+        bus.subscribe("a", "doorbell.#");
+        bus.subscribe("b", "doorbell.*");
+        bus.subscribe("b", "doorbell.pressed");
+        bus.subscribe("c", "chat.*");
+
+        assert_eq!(bus.publish(event("doorbell.pressed", Some("a"))), 1);
+        assert!(a.try_recv().is_err());
+        assert_eq!(b.try_recv().unwrap().topic, "doorbell.pressed");
+        assert!(b.try_recv().is_err());
+        assert!(c.try_recv().is_err());
+    }
+
+    #[test]
+    fn unsubscribe_and_closed_receivers() {
+        let mut bus = EventBus::new();
+        let mut a = bus.register("a");
+        let b = bus.register("b");
+        bus.subscribe("a", "x.*");
+        bus.subscribe("b", "x.*");
+        drop(b);
+        assert_eq!(bus.publish(event("x.y", None)), 1);
+        assert!(bus.subscriptions("b").is_empty());
+
+        assert!(bus.unsubscribe("a", "x.*"));
+        assert!(!bus.unsubscribe("a", "x.*"));
+        assert_eq!(bus.publish(event("x.y", None)), 0);
+        assert_eq!(a.try_recv().unwrap().topic, "x.y");
+        assert!(a.try_recv().is_err());
+    }
+}
@@ -1,8 +1,18 @@
+pub mod automation;
 pub mod cli;
+pub mod config;
+pub mod control;
+pub mod deps;
 pub mod events;
 pub mod ipc;
+pub mod logging;
+pub mod permissions;
 pub mod plugin_host;
+pub mod router;
+pub mod scene;
+pub mod script;
 pub mod services;
+pub mod settings;
 
 pub use plugin_host::PluginManager;
 
@@ -1,46 +1,456 @@
 use anyhow::Result;
 use clap::Parser;
-use tracing::{info, warn};
+use serde_json::{json, Value};
+use std::{path::Path, time::Duration};
+use tracing::{error, info, warn};
 
 use homecore::{
-    cli::{Cli, Command, PluginCommand},
-    workspace_root, PluginManager,
+    automation::{self, Automations, Rule},
+    cli::{
+        AutomationCommand, Cli, Command, ConfigCommand, PluginCommand, SceneCommand, ScriptCommand,
+    },
+    config::Config,
+    control, logging,
+    scene::Scenes,
+    script::{self, Scripts},
+    services::log::{self, Follower, LogRecord},
+    settings, workspace_root, PluginManager,
 };
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    tracing_subscriber::fmt().with_env_filter("info").init();
     let cli = Cli::parse();
     let workspace = workspace_root()?;
-    let plugins_dir = cli.plugins_dir.clone().unwrap_or(workspace.join("plugins"));
+    let config = Config::load(&cli, &workspace)?;
+    logging::init(config.log_format, &config.log_level);
+    let discover = || -> Result<PluginManager> {
+        Ok(
+            PluginManager::discover_dirs(workspace.clone(), &config.plugin_dirs)?
+                .with_data_dir(config.data_dir.clone()),
+        )
+    };
 
     match cli.command {
-        Command::Run => {
+        Command::Run { .. } => {
             if cli.safe_mode {
                 warn!("safe mode enabled - not loading plugins");
-                tokio::signal::ctrl_c().await?;
+                shutdown_signal().await?;
                 return Ok(());
             }
-            let mut manager = PluginManager::discover(workspace.clone(), plugins_dir)?;
-            manager.start_all().await?;
+            let manager = discover()?;
+            for warning in config.warnings(manager.plugins.keys().map(String::as_str)) {
+                warn!("{warning}");
+            }
+            let disabled = manager
+                .plugins
+                .keys()
+                .filter(|id| !config.enabled(id))
+                .cloned()
+                .collect::<Vec<_>>();
+            let mut manager = manager
+                .with_home(config.home)
+                .with_restart_policy(config.restart.clone())
+                .with_log_files(config.log_files)
+                .with_disabled(disabled)
+                .with_settings(config.settings());
+            let automations =
+                Automations::start(manager.core_handle(), config.home, load_rules(&config));
+            manager.set_automations(automations.clone());
+            let scenes = match Scenes::load(manager.core_handle(), &config.data_dir) {
+                Ok(scenes) => {
+                    manager.set_scenes(scenes.clone());
+                    Some(scenes)
+                }
+                Err(e) => {
+                    error!("scenes not loaded: {e:#}");
+                    None
+                }
+            };
+            let started = manager.start_all().await;
+            for (id, reason) in &started.failed {
+                error!("plugin {id} failed to start: {reason}");
+            }
+            for (id, reason) in &started.skipped {
+                warn!("plugin {id} skipped: {reason}");
+            }
+            for id in &started.disabled {
+                info!("plugin {id} disabled");
+            }
+            let _scripts = Scripts::start(
+                manager.core_handle(),
+                config.home,
+                config.scripts_dir.clone(),
+                config.script_limits,
+            )
+            .await;
+            let _control = {
+                let path = control::socket_path(&config.data_dir);
+                let logs = manager.plugin_logs();
+                tokio::spawn(async move {
+                    if let Err(e) = control::serve(path, logs, scenes).await {
+                        error!("control socket stopped: {e:#}");
+                    }
+                })
+            };
             info!("plugins running - press Ctrl+C to exit");
-            tokio::signal::ctrl_c().await?;
+            let signal = loop {
+                tokio::select! {
+                    signal = shutdown_signal() => break signal?,
+                    _ = reload_signal() => {
+                        info!("received SIGHUP, reloading plugin settings and automations");
+                        reload_settings(&cli, &workspace, &mut manager).await;
+                        automations.load(load_rules(&config));
+                    }
+                }
+            };
+            info!("received {signal}, stopping plugins");
+            for report in manager.shutdown(config.shutdown_grace).await {
+                info!(
+                    "plugin {}: {:?} after {:.1}s",
+                    report.plugin_id,
+                    report.outcome,
+                    report.elapsed.as_secs_f32()
+                );
+            }
         }
         Command::Plugin {
             command: PluginCommand::List,
         } => {
-            let manager = PluginManager::discover(workspace.clone(), plugins_dir)?;
+            let mut manager = discover()?;
+            manager.load_status_report();
             for (manifest, status, path) in manager.list() {
                 println!(
-                    "{:<15} {:<20} {:<8} {:?} {}",
+                    "{:<15} {:<20} {:<8} {:<10} {}",
                     manifest.id,
                     manifest.name,
                     manifest.version,
-                    status,
+                    format!("{status:?}"),
                     path.display()
                 );
+                println!(
+                    "{:<15} permissions: {}",
+                    "",
+                    if manifest.permissions.is_empty() {
+                        "none".to_string()
+                    } else {
+                        manifest.permissions.join(", ")
+                    }
+                );
+                if let Some(state) = manager.state(&manifest.id) {
+                    if let Some(exit) = state.last_exit {
+                        println!("{:<15} last exit: {exit} ({} restarts)", "", state.restarts);
+                    }
+                }
+            }
+        }
+        Command::Plugin {
+            command: PluginCommand::Logs { id, lines, follow },
+        } => {
+            let manager = discover()?;
+            if !manager.plugins.contains_key(&id) {
+                anyhow::bail!("no plugin with id {id}");
+            }
+            // the running core also has what was logged without log files
+            let socket = control::socket_path(&config.data_dir);
+            let params = json!({ "plugin": id, "lines": lines });
+            let served = if follow {
+                control::follow(&socket, "log.follow", params, print_records).await
+            } else {
+                control::request(&socket, "log.tail", params)
+                    .await
+                    .map(print_records)
+            };
+            match served {
+                Err(e) if e.is::<control::NotRunning>() => {}
+                served => return served,
+            }
+            let dir = manager.log_dir();
+            // start following before reading, so no line falls in between
+            let mut follower = Follower::new(&dir, &id);
+            for record in log::read_tail(&dir, &id, lines)? {
+                println!("{record}");
+            }
+            if follow {
+                // runs until interrupted
+                loop {
+                    tokio::time::sleep(Duration::from_millis(500)).await;
+                    for record in follower.poll()? {
+                        println!("{record}");
+                    }
+                }
+            }
+        }
+        Command::Config {
+            command: ConfigCommand::Check,
+        } => {
+            // loading the config above already rejected invalid settings
+            let manager = discover()?;
+            match &config.file {
+                Some(file) => println!("config file:    {}", file.display()),
+                None => println!("config file:    none, using defaults"),
+            }
+            println!(
+                "log:            {} ({:?})",
+                config.log_level, config.log_format
+            );
+            println!("log files:      {}", config.log_files);
+            println!("data dir:       {}", config.data_dir.display());
+            for dir in &config.plugin_dirs {
+                println!("plugin dir:     {}", dir.display());
+            }
+            println!("automations:    {}", config.automations_dir.display());
+            println!(
+                "scripts:        {} (at most {} operations and {}ms per run)",
+                config.scripts_dir.display(),
+                config.script_limits.max_operations,
+                config.script_limits.timeout.as_millis()
+            );
+            println!("shutdown grace: {}s", config.shutdown_grace.as_secs());
+            println!(
+                "restart:        {} within {}s, backoff {}ms to {}s",
+                config.restart.max_restarts,
+                config.restart.window.as_secs(),
+                config.restart.initial_backoff.as_millis(),
+                config.restart.max_backoff.as_secs()
+            );
+            print!("home:           time zone {}", config.home.zone.name());
+            match config.home.location {
+                Some(loc) => println!(", at {}, {}", loc.latitude, loc.longitude),
+                None => println!(", no location"),
+            }
+            let mut ids: Vec<_> = manager.plugins.keys().collect();
+            ids.sort();
+            let mut errors = Vec::new();
+            for id in ids {
+                let state = if config.enabled(id) {
+                    "enabled"
+                } else {
+                    "disabled"
+                };
+                println!("plugin:         {id} ({state})");
+                let configured = config
+                    .plugins
+                    .get(id)
+                    .map(|p| p.settings.clone())
+                    .unwrap_or_default();
+                let checked = settings::load_schema(&manager.plugins[id].dir).and_then(|schema| {
+                    settings::resolve(schema.as_ref(), configured).map_err(anyhow::Error::msg)
+                });
+                if let Err(e) = checked {
+                    errors.push(format!("plugin {id}: {e:#}"));
+                }
+            }
+            for warning in config.warnings(manager.plugins.keys().map(String::as_str)) {
+                println!("warning: {warning}");
+            }
+            for error in &errors {
+                println!("error: {error}");
+            }
+            if !errors.is_empty() {
+                anyhow::bail!("the configuration has {} error(s)", errors.len());
+            }
+            println!("config ok");
+        }
+        Command::Automation {
+            command: AutomationCommand::Check,
+        } => {
+            let loaded = automation::load_dir(&config.automations_dir, &config.home);
+            println!("automations dir: {}", config.automations_dir.display());
+            for rule in &loaded.rules {
+                println!(
+                    "rule:            {} ({}, {} triggers, {} conditions, {} actions)",
+                    rule.id,
+                    if rule.enabled { "enabled" } else { "disabled" },
+                    rule.triggers.len(),
+                    rule.conditions.len(),
+                    rule.actions.len()
+                );
+            }
+            for warning in &loaded.warnings {
+                println!("warning: {warning}");
+            }
+            for error in &loaded.errors {
+                println!("error: {error}");
+            }
+            if !loaded.errors.is_empty() {
+                anyhow::bail!("the automations have {} error(s)", loaded.errors.len());
             }
+            println!("automations ok");
         }
+        Command::Script {
+            command: ScriptCommand::Check,
+        } => {
+            println!("scripts dir: {}", config.scripts_dir.display());
+            let files = match script::script_files(&config.scripts_dir) {
+                Ok(files) => files,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+                Err(e) => anyhow::bail!("reading {}: {e}", config.scripts_dir.display()),
+            };
+            let mut errors = 0;
+            for (name, file) in &files {
+                match script::check(file) {
+                    Ok(()) => println!("script:      {name} ok"),
+                    Err(e) => {
+                        println!("error: {}: {e}", file.display());
+                        errors += 1;
+                    }
+                }
+            }
+            if errors > 0 {
+                anyhow::bail!("{errors} script(s) do not compile");
+            }
+            println!("scripts ok");
+        }
+        Command::Scene { command } => {
+            let socket = control::socket_path(&config.data_dir);
+            let (method, params) = match &command {
+                SceneCommand::List => ("scene.list", json!({})),
+                SceneCommand::Activate { id } => ("scene.activate", json!({ "id": id })),
+                SceneCommand::Undo => ("scene.undo", json!({})),
+                SceneCommand::Capture { id, entities, name } => (
+                    "scene.capture",
+                    json!({ "id": id, "name": name, "entity_ids": entities }),
+                ),
+                SceneCommand::Delete { id } => ("scene.delete", json!({ "id": id })),
+            };
+            let result = control::request(&socket, method, params).await?;
+            match command {
+                SceneCommand::List => {
+                    for scene in result["scenes"].as_array().into_iter().flatten() {
+                        let entities = scene["entities"].as_object().map_or(0, |e| e.len());
+                        println!(
+                            "{:<20} {:<24} {entities} entities",
+                            scene["id"].as_str().unwrap_or_default(),
+                            scene["name"].as_str().unwrap_or("-"),
+                        );
+                    }
+                }
+                SceneCommand::Activate { .. } | SceneCommand::Undo => print_activation(&result)?,
+                SceneCommand::Capture { id, .. } => {
+                    let entities = &result["scene"]["entities"];
+                    println!("scene {id} saved");
+                    for (entity_id, target) in entities.as_object().into_iter().flatten() {
+                        println!("  {entity_id:<30} {target}");
+                    }
+                }
+                SceneCommand::Delete { id } => println!("scene {id} deleted"),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Print the `records` of a `log.tail` or `log.follow` message.
+fn print_records(message: Value) {
+    let records = serde_json::from_value::<Vec<LogRecord>>(message["records"].clone());
+    for record in records.into_iter().flatten() {
+        println!("{record}");
+    }
+}
+
+/// Print the per-entity results of activating a scene, failing if any
+/// entity did not reach its target.
+fn print_activation(result: &Value) -> Result<()> {
+    let results = result["results"].as_array().cloned().unwrap_or_default();
+    for entity in &results {
+        let commands = entity["commands"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(Value::as_str)
+            .collect::<Vec<_>>()
+            .join(", ");
+        let outcome = match entity["error"]["message"].as_str() {
+            Some(message) => format!("failed: {message}"),
+            None if commands.is_empty() => "unchanged".to_string(),
+            None => format!("ok ({commands})"),
+        };
+        println!(
+            "{:<30} {outcome}",
+            entity["entity_id"].as_str().unwrap_or_default()
+        );
+    }
+    let failed = results.iter().filter(|r| r["ok"] != true).count();
+    if failed > 0 {
+        anyhow::bail!(
+            "scene {}: {failed} of {} entities failed",
+            result["scene"].as_str().unwrap_or_default(),
+            results.len()
+        );
     }
     Ok(())
 }
+
+/// Read the automation rules, logging what is wrong with them. Rules with
+/// errors are left out.
+fn load_rules(config: &Config) -> Vec<Rule> {
+    let loaded = automation::load_dir(&config.automations_dir, &config.home);
+    for warning in &loaded.warnings {
+        warn!("{warning}");
+    }
+    for error in &loaded.errors {
+        error!("automation rule skipped: {error}");
+    }
+    info!("{} automation rules loaded", loaded.rules.len());
+    loaded.rules
+}
+
+/// Apply the plugin settings of a freshly read config to the running
+/// plugins. Other settings take effect on the next start.
+async fn reload_settings(cli: &Cli, workspace: &Path, manager: &mut PluginManager) {
+    let config = match Config::load(cli, workspace) {
+        Ok(config) => config,
+        Err(e) => {
+            error!("keeping the current settings: {e:#}");
+            return;
+        }
+    };
+    let mut ids: Vec<String> = manager.plugins.keys().cloned().collect();
+    ids.sort();
+    for id in ids {
+        let settings = config
+            .plugins
+            .get(&id)
+            .map(|p| p.settings.clone())
+            .unwrap_or_default();
+        match manager.update_settings(&id, settings).await {
+            Ok(true) => info!("plugin {id} settings changed"),
+            Ok(false) => {}
+            Err(e) => error!("plugin {id} keeps its settings: {e:#}"),
+        }
+    }
+}
+
+/// Wait for SIGHUP, the conventional request to reload the configuration.
+/// Never completes on platforms without it.
+async fn reload_signal() -> Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        signal(SignalKind::hangup())?.recv().await;
+        Ok(())
+    }
+    #[cfg(not(unix))]
+    {
+        std::future::pending().await
+    }
+}
+
+/// Wait for SIGINT or SIGTERM and return the name of the received signal.
+async fn shutdown_signal() -> Result<&'static str> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut term = signal(SignalKind::terminate())?;
+        let name = tokio::select! {
+            res = tokio::signal::ctrl_c() => res.map(|_| "SIGINT")?,
+            _ = term.recv() => "SIGTERM",
+        };
+        Ok(name)
+    }
+    #[cfg(not(unix))]
+    {
+        tokio::signal::ctrl_c().await?;
+        Ok("Ctrl+C")
+    }
+}
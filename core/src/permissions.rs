@@ -0,0 +1,141 @@
+use crate::services::timer::TICK_TOPIC;
+use anyhow::Result;
+use serde::Serialize;
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// File in the data directory that records refused calls.
+pub const AUDIT_FILE: &str = "audit.log";
+
+/// Permission a plugin must list in its manifest to use each core service,
+/// keyed by method namespace.
+pub const METHOD_PERMISSIONS: &[(&str, &str)] = &[
+    ("log", "log"),
+    ("event", "event"),
+    ("timer", "timer"),
+    ("storage", "storage"),
+    ("device", "device"),
+    ("state", "state"),
+    ("automation", "automation"),
+    ("scene", "scene"),
+];
+
+/// The permission needed to call a core-served `method`, if any. Handshake
+/// methods and requests forwarded to other plugins need none.
+pub fn required_permission(method: &str) -> Option<&'static str> {
+    let namespace = method.split('.').next()?;
+    METHOD_PERMISSIONS
+        .iter()
+        .find(|(ns, _)| *ns == namespace)
+        .map(|(_, perm)| *perm)
+}
+
+/// Permission needed to publish events.
+pub const PUBLISH_PERMISSION: &str = "event";
+
+/// The permission needed to (un)subscribe to `topics`. Ticks only ever reach
+/// the plugin that set the timer, so following them needs just `timer`.
+pub fn subscribe_permission(topics: &[String]) -> &'static str {
+    if !topics.is_empty() && topics.iter().all(|t| t == TICK_TOPIC) {
+        "timer"
+    } else {
+        "event"
+    }
+}
+
+/// One refused call.
+#[derive(Debug, Serialize)]
+pub struct AuditEntry<'a> {
+    pub ts: u64,
+    pub plugin: &'a str,
+    /// The refused method, or `publish <topic>` for events.
+    pub action: &'a str,
+    pub permission: &'a str,
+}
+
+/// Append-only JSON lines log of refused calls.
+#[derive(Debug, Clone)]
+pub struct AuditLog {
+    path: PathBuf,
+}
+
+impl AuditLog {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Record that `plugin` was refused `action` for lacking `permission`.
+    pub fn denied(&self, plugin: &str, action: &str, permission: &str) -> Result<()> {
+        let entry = AuditEntry {
+            ts: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            plugin,
+            action,
+            permission,
+        };
+        let mut line = serde_json::to_vec(&entry)?;
+        line.push(b'\n');
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let mut f = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        f.write_all(&line)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_methods_to_permissions() {
+        assert_eq!(required_permission("storage.put"), Some("storage"));
+        assert_eq!(required_permission("timer.set_interval"), Some("timer"));
+        assert_eq!(required_permission("event.subscribe"), Some("event"));
+        assert_eq!(required_permission("log.write"), Some("log"));
+        assert_eq!(required_permission("plugin.init"), None);
+        assert_eq!(required_permission("sample.ping"), None);
+        assert_eq!(required_permission("storagex.put"), None);
+    }
+
+    #[test]
+    fn following_ticks_needs_only_timer() {
+        let topics = |t: &[&str]| t.iter().map(|t| t.to_string()).collect::<Vec<_>>();
+        assert_eq!(subscribe_permission(&topics(&[TICK_TOPIC])), "timer");
+        assert_eq!(
+            subscribe_permission(&topics(&[TICK_TOPIC, "lamp.on"])),
+            "event"
+        );
+        assert_eq!(subscribe_permission(&topics(&["timer.*"])), "event");
+        assert_eq!(subscribe_permission(&[]), "event");
+    }
+
+    #[test]
+    fn audit_log_appends_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = AuditLog::new(dir.path().join(AUDIT_FILE));
+        log.denied("a", "storage.put", "storage").unwrap();
+        log.denied("b", "timer.set_interval", "timer").unwrap();
+        let text = std::fs::read_to_string(log.path()).unwrap();
+        let lines: Vec<serde_json::Value> = text
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["plugin"], "a");
+        assert_eq!(lines[1]["permission"], "timer");
+    }
+}
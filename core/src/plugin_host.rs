@@ -1,24 +1,60 @@
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    future::Future,
     path::{Path, PathBuf},
     process::Stdio,
     sync::Arc,
+    time::Instant,
 };
 
 use anyhow::{Context, Result};
+use chrono::Utc;
 use parking_lot::Mutex;
-use plugin_api::{Envelope, Kind};
-use serde::Deserialize;
-use serde_json::{json, Value};
+use plugin_api::{
+    api_compatible,
+    device::{DeviceCommand, EntityState, COMMAND},
+    features, Envelope, Kind, Metadata, RpcError, API_VERSION, CANCEL_METHOD, CONFIG_CHANGED,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
 use tokio::{
-    io::{BufReader, BufWriter},
-    process::{Child, Command},
-    sync::oneshot,
+    io::{AsyncBufReadExt, BufReader, BufWriter},
+    process::{Child, ChildStderr, ChildStdout, Command},
+    sync::{oneshot, watch},
+    task::JoinHandle,
+    time::Duration,
 };
-use tracing::error;
+use tracing::{error, info, warn};
 use uuid::Uuid;
 
-use crate::ipc::{read_envelope, write_envelope};
+use crate::{
+    automation::Automations,
+    deps,
+    events::{self, Event, EventBus},
+    ipc::{read_envelope, write_envelope},
+    permissions::{self, AuditLog},
+    router::Router,
+    scene::Scenes,
+    services::{
+        self,
+        device::{self, Registry},
+        log::{LogRecord, PluginLogs, LOG_DIR},
+        storage::{self, Storage},
+        timer::{self, Home, Timers},
+    },
+    settings,
+};
+
+/// How long a request to a plugin may take unless the caller sets its own
+/// timeout. Also applies to requests forwarded between plugins, and to each
+/// step of the handshake with a starting plugin.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long to wait for a plugin to exit after its stdout closed.
+const EXIT_WAIT: Duration = Duration::from_millis(500);
+
+/// File in the data directory where a running core reports plugin status.
+pub const STATUS_FILE: &str = "status.json";
 
 /// Manifest information parsed from `plugin.toml`.
 #[derive(Debug, Deserialize, Clone)]
@@ -30,38 +66,203 @@ pub struct PluginManifest {
     pub exec: String,
     #[serde(default)]
     pub permissions: Vec<String>,
+    /// Plugins and core services this plugin depends on.
+    #[serde(default)]
+    pub needs: Vec<String>,
 }
 
 /// Status of a plugin managed by the host.
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum PluginStatus {
     Discovered,
     Running,
+    /// The process exited unexpectedly; waiting out the backoff delay before
+    /// a restart.
+    Crashed,
+    /// Starting again after a crash.
+    Restarting,
+    /// Stopped on request.
+    Stopped,
+    /// Gave up after crashing too often, or could not be started.
+    Failed,
+    /// Not started because a dependency is unavailable.
+    Skipped,
+    /// Not started because it is disabled in the configuration.
+    Disabled,
+}
+
+/// How crashed plugins are restarted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RestartPolicy {
+    /// Delay before the first restart; doubled for every further crash.
+    pub initial_backoff: Duration,
+    /// Upper bound for the restart delay.
+    pub max_backoff: Duration,
+    /// Crashes tolerated within `window` before the plugin is marked failed.
+    pub max_restarts: usize,
+    pub window: Duration,
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        Self {
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+            max_restarts: 5,
+            window: Duration::from_secs(300),
+        }
+    }
+}
+
+impl RestartPolicy {
+    /// Delay before restarting after the `n`th recent crash (1-based).
+    pub fn backoff(&self, n: usize) -> Duration {
+        let factor = 1u32
+            .checked_shl(n.saturating_sub(1) as u32)
+            .unwrap_or(u32::MAX);
+        self.initial_backoff
+            .saturating_mul(factor)
+            .min(self.max_backoff)
+    }
+}
+
+/// Recent crash times of a plugin, used to detect crash loops.
+#[derive(Debug, Default)]
+struct CrashHistory(VecDeque<Instant>);
+
+impl CrashHistory {
+    /// Record a crash at `now` and return the number of crashes within `window`.
+    fn record(&mut self, now: Instant, window: Duration) -> usize {
+        self.0.push_back(now);
+        while self
+            .0
+            .front()
+            .is_some_and(|t| now.duration_since(*t) > window)
+        {
+            self.0.pop_front();
+        }
+        self.0.len()
+    }
+}
+
+/// Runtime state of a plugin, as reported in the status file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PluginState {
+    pub status: PluginStatus,
+    /// Number of times the plugin was restarted after crashing.
+    #[serde(default)]
+    pub restarts: u32,
+    /// Description of the last unexpected exit.
+    #[serde(default)]
+    pub last_exit: Option<String>,
+    /// Protocol features negotiated in the last handshake.
+    #[serde(default)]
+    pub features: Vec<String>,
+    /// Metadata sent by the plugin in `plugin.init`.
+    #[serde(skip)]
+    pub metadata: Option<Metadata>,
+}
+
+impl Default for PluginState {
+    fn default() -> Self {
+        Self {
+            status: PluginStatus::Discovered,
+            restarts: 0,
+            last_exit: None,
+            features: Vec::new(),
+            metadata: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct StatusReport {
+    pid: u32,
+    plugins: BTreeMap<String, PluginState>,
 }
 
 /// Runtime handle to a plugin process.
 pub struct PluginHandle {
     pub manifest: PluginManifest,
     pub dir: PathBuf,
-    pub status: PluginStatus,
-    pub child: Option<Child>,
-    writer: Option<Arc<tokio::sync::Mutex<BufWriter<tokio::process::ChildStdin>>>>,
     pending: ArcPending,
-    subscriptions: HashSet<String>,
+    storage: Option<Arc<Storage>>,
+    /// Settings passed in `plugin.init`, as last checked against the schema.
+    settings: Arc<Mutex<Value>>,
+    supervisor: Option<Supervisor>,
 }
 
 type ArcPending = std::sync::Arc<Mutex<HashMap<String, oneshot::Sender<Envelope>>>>;
+type Writer = timer::Writer;
+
+/// Control handle for the task supervising a plugin process. Sending a grace
+/// period on `stop` asks the supervisor to shut the plugin down.
+struct Supervisor {
+    stop: watch::Sender<Option<Duration>>,
+    task: JoinHandle<StopOutcome>,
+}
+
+/// How a plugin ended when it was asked to stop.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopOutcome {
+    /// Acknowledged `plugin.stop` and exited within the grace period.
+    Stopped,
+    /// Exited within the grace period without acknowledging `plugin.stop`.
+    Exited,
+    /// Still running after the grace period and was killed.
+    Killed,
+    /// Was not running when the stop was requested.
+    NotRunning,
+}
+
+/// Outcome of [`PluginManager::start_all`].
+#[derive(Debug, Default)]
+pub struct StartReport {
+    /// Plugins started, in start order.
+    pub started: Vec<String>,
+    /// Plugins that could not be started, with the reason.
+    pub failed: Vec<(String, String)>,
+    /// Plugins not started because a dependency is unavailable.
+    pub skipped: Vec<(String, String)>,
+    /// Plugins not started because they are disabled.
+    pub disabled: Vec<String>,
+}
+
+/// Why [`PluginManager::call`] failed.
+#[derive(Debug, Clone, thiserror::Error)]
+pub enum CallError {
+    #[error("plugin {0} not found")]
+    NotFound(String),
+    #[error("plugin {0} is not running")]
+    NotRunning(String),
+    #[error("no response within {0:?}")]
+    Timeout(Duration),
+    /// The core refused the request before sending it, e.g. a command the
+    /// entity does not take.
+    #[error("{0}")]
+    Invalid(String),
+    /// The plugin answered with an error.
+    #[error("{} (code {})", .0.message, .0.code)]
+    Rpc(RpcError),
+}
+
+/// Result of stopping one plugin during shutdown.
+#[derive(Debug, Clone)]
+pub struct ShutdownReport {
+    pub plugin_id: String,
+    pub outcome: StopOutcome,
+    pub elapsed: Duration,
+}
 
 impl PluginHandle {
     fn new(manifest: PluginManifest, dir: PathBuf) -> Self {
         Self {
             manifest,
             dir,
-            status: PluginStatus::Discovered,
-            child: None,
-            writer: None,
             pending: std::sync::Arc::new(Mutex::new(HashMap::new())),
-            subscriptions: HashSet::new(),
+            storage: None,
+            settings: Arc::new(Mutex::new(json!({}))),
+            supervisor: None,
         }
     }
 
@@ -111,303 +312,2120 @@ impl PluginHandle {
     }
 }
 
+/// Request channel to a running plugin, used to forward requests to it.
+#[derive(Clone)]
+struct Link {
+    writer: Writer,
+    pending: ArcPending,
+    /// Whether the plugin negotiated `$/cancel` notifications.
+    cancel: bool,
+}
+
+impl Link {
+    /// Send `env` to the plugin under a fresh id and wait for its response.
+    /// Gives up after `timeout`, or once `cancelled` completes; the plugin is
+    /// then sent `$/cancel` if it supports it.
+    async fn forward(
+        &self,
+        mut env: Envelope,
+        timeout: Duration,
+        cancelled: impl Future<Output = ()>,
+    ) -> Result<Value, RpcError> {
+        let id = Uuid::new_v4().to_string();
+        env.id = Some(id.clone());
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().insert(id.clone(), tx);
+        let sent = {
+            let mut w = self.writer.lock().await;
+            write_envelope(&mut *w, &env).await
+        };
+        if sent.is_err() {
+            self.pending.lock().remove(&id);
+            return Err(RpcError::new(
+                RpcError::TARGET_UNAVAILABLE,
+                "target plugin is not running",
+            ));
+        }
+        let received = tokio::select! {
+            received = rx => received,
+            _ = tokio::time::sleep(timeout) => {
+                self.abandon(&id).await;
+                return Err(RpcError::new(
+                    RpcError::TIMEOUT,
+                    format!("no response within {timeout:?}"),
+                ));
+            }
+            _ = cancelled => {
+                self.abandon(&id).await;
+                return Err(RpcError::new(RpcError::CANCELLED, "request cancelled"));
+            }
+        };
+        match received {
+            Ok(resp) => match resp.error {
+                Some(err) => Err(err),
+                None => Ok(resp.result.unwrap_or(Value::Null)),
+            },
+            Err(_) => Err(RpcError::new(
+                RpcError::TARGET_UNAVAILABLE,
+                "target plugin exited before responding",
+            )),
+        }
+    }
+
+    /// Stop waiting for request `id`; a late response is dropped.
+    async fn abandon(&self, id: &str) {
+        self.pending.lock().remove(id);
+        if self.cancel {
+            let env = Envelope::notification(CANCEL_METHOD, json!({ "id": id }));
+            let mut w = self.writer.lock().await;
+            let _ = write_envelope(&mut *w, &env).await;
+        }
+    }
+}
+
+/// State shared between the manager and the tasks serving each plugin.
+#[derive(Clone, Default)]
+struct Hub {
+    bus: Arc<Mutex<EventBus>>,
+    router: Arc<Mutex<Router>>,
+    links: Arc<Mutex<HashMap<String, Link>>>,
+    states: Arc<Mutex<BTreeMap<String, PluginState>>>,
+    timers: Timers,
+    registry: Arc<Mutex<Registry>>,
+    /// The automation engine, once started; serves `automation.*`.
+    automations: Arc<Mutex<Option<Automations>>>,
+    /// The scenes, once loaded; serve `scene.*`.
+    scenes: Arc<Mutex<Option<Scenes>>>,
+    logs: Arc<PluginLogs>,
+    status_file: Option<PathBuf>,
+    audit: Option<AuditLog>,
+}
+
+impl Hub {
+    /// Update the state of a plugin and rewrite the status file.
+    fn update(&self, plugin_id: &str, f: impl FnOnce(&mut PluginState)) {
+        let mut states = self.states.lock();
+        f(states.entry(plugin_id.to_string()).or_default());
+        if let Some(path) = &self.status_file {
+            let report = StatusReport {
+                pid: std::process::id(),
+                plugins: states.clone(),
+            };
+            if let Err(e) = write_status(path, &report) {
+                warn!("failed to write {}: {e}", path.display());
+            }
+        }
+    }
+
+    fn set_status(&self, plugin_id: &str, status: PluginStatus) {
+        self.update(plugin_id, |s| s.status = status);
+    }
+}
+
+/// Access to the plugins and core services for subsystems running inside
+/// the core, such as automations. Cheap to clone.
+#[derive(Clone)]
+pub struct CoreHandle {
+    hub: Hub,
+    timeout: Duration,
+}
+
+impl CoreHandle {
+    pub fn bus(&self) -> &Arc<Mutex<EventBus>> {
+        &self.hub.bus
+    }
+
+    pub fn timers(&self) -> &Timers {
+        &self.hub.timers
+    }
+
+    /// Publish an event from the core.
+    pub fn publish(&self, topic: &str, payload: Option<Value>) {
+        self.hub.bus.lock().publish(Event {
+            topic: topic.to_string(),
+            source: None,
+            payload,
+        });
+    }
+
+    /// The current state of an entity, if registered and reported.
+    pub fn state(&self, entity_id: &str) -> Option<EntityState> {
+        let registry = self.hub.registry.lock();
+        registry.state(entity_id).ok().flatten().cloned()
+    }
+
+    #[cfg(test)]
+    pub(crate) fn registry(&self) -> &Arc<Mutex<Registry>> {
+        &self.hub.registry
+    }
+
+    /// The capabilities of a registered entity.
+    pub fn capabilities(&self, entity_id: &str) -> Option<Vec<String>> {
+        let registry = self.hub.registry.lock();
+        registry
+            .capabilities(entity_id)
+            .ok()
+            .map(<[String]>::to_vec)
+    }
+
+    /// Send `method` to the plugin serving its namespace, like a request
+    /// from a plugin, waiting up to `timeout` or the default timeout.
+    pub async fn call(
+        &self,
+        method: &str,
+        params: Value,
+        timeout: Option<Duration>,
+    ) -> Result<Value, RpcError> {
+        let owner = self.hub.router.lock().owner(method).map(str::to_string);
+        let owner = owner.ok_or_else(|| {
+            RpcError::new(
+                RpcError::METHOD_NOT_FOUND,
+                format!("unknown method {method}"),
+            )
+        })?;
+        self.send(&owner, method, params, timeout.unwrap_or(self.timeout))
+            .await
+    }
+
+    /// Send a device command to the plugin owning the entity, after checking
+    /// it against the entity's capabilities.
+    pub async fn command(&self, command: &DeviceCommand) -> Result<Value, RpcError> {
+        let owner = self.hub.registry.lock().command_owner(command)?.to_string();
+        self.send(&owner, COMMAND, json!(command), self.timeout)
+            .await
+    }
+
+    async fn send(
+        &self,
+        plugin_id: &str,
+        method: &str,
+        params: Value,
+        timeout: Duration,
+    ) -> Result<Value, RpcError> {
+        let link = self.hub.links.lock().get(plugin_id).cloned();
+        let link = link.ok_or_else(|| {
+            RpcError::new(
+                RpcError::TARGET_UNAVAILABLE,
+                format!("plugin {plugin_id} serving {method} is not running"),
+            )
+        })?;
+        let env = Envelope::request(Uuid::new_v4().to_string(), method, params);
+        link.forward(env, timeout, std::future::pending()).await
+    }
+}
+
+fn write_status(path: &Path, report: &StatusReport) -> Result<()> {
+    let tmp = path.with_extension("json.tmp");
+    std::fs::write(&tmp, serde_json::to_vec_pretty(report)?)?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Whether the process that wrote a status report is still alive.
+fn process_alive(pid: u32) -> bool {
+    if cfg!(target_os = "linux") {
+        Path::new("/proc").join(pid.to_string()).exists()
+    } else {
+        true
+    }
+}
+
 /// Manager responsible for discovering and running plugins.
 pub struct PluginManager {
     workspace_root: PathBuf,
+    data_dir: PathBuf,
+    policy: RestartPolicy,
+    timeout: Duration,
+    log_files: bool,
+    disabled: HashSet<String>,
+    /// Settings from the config, by plugin id, before defaults are applied.
+    settings: HashMap<String, Map<String, Value>>,
+    hub: Hub,
+    /// Ids of started plugins in start order.
+    started: Vec<String>,
     pub plugins: HashMap<String, PluginHandle>,
 }
 
 impl PluginManager {
     /// Discover plugin manifests under a directory.
     pub fn discover(workspace_root: PathBuf, plugins_dir: PathBuf) -> Result<Self> {
-        let mut plugins = HashMap::new();
-        if plugins_dir.exists() {
-            for entry in std::fs::read_dir(&plugins_dir)? {
+        Self::discover_dirs(workspace_root, &[plugins_dir])
+    }
+
+    /// Discover plugin manifests under several directories. When two
+    /// directories hold a plugin with the same id, the first one wins.
+    pub fn discover_dirs(workspace_root: PathBuf, plugins_dirs: &[PathBuf]) -> Result<Self> {
+        let mut plugins: HashMap<String, PluginHandle> = HashMap::new();
+        for plugins_dir in plugins_dirs {
+            if !plugins_dir.exists() {
+                continue;
+            }
+            for entry in std::fs::read_dir(plugins_dir)? {
                 let entry = entry?;
                 if entry.file_type()?.is_dir() {
                     let dir = entry.path();
                     let manifest_path = dir.join("plugin.toml");
                     if manifest_path.exists() {
                         let text = std::fs::read_to_string(&manifest_path)?;
-                        let manifest: PluginManifest = toml::from_str(&text)?;
+                        let manifest: PluginManifest = toml::from_str(&text)
+                            .with_context(|| format!("invalid {}", manifest_path.display()))?;
+                        if let Some(existing) = plugins.get(&manifest.id) {
+                            warn!(
+                                "ignoring plugin {} in {}, already found in {}",
+                                manifest.id,
+                                dir.display(),
+                                existing.dir.display()
+                            );
+                            continue;
+                        }
                         let handle = PluginHandle::new(manifest.clone(), dir.clone());
                         plugins.insert(manifest.id.clone(), handle);
                     }
                 }
             }
         }
+        let hub = Hub::default();
+        for id in plugins.keys() {
+            hub.states.lock().insert(id.clone(), PluginState::default());
+        }
         Ok(Self {
             workspace_root,
+            data_dir: storage::default_data_dir(),
+            policy: RestartPolicy::default(),
+            timeout: DEFAULT_TIMEOUT,
+            log_files: true,
+            disabled: HashSet::new(),
+            settings: HashMap::new(),
+            hub,
+            started: Vec::new(),
             plugins,
         })
     }
 
+    /// Use `dir` instead of the platform data directory for plugin storage.
+    pub fn with_data_dir(mut self, dir: PathBuf) -> Self {
+        self.data_dir = dir;
+        self
+    }
+
+    /// Set the home time zone and location timers are computed for.
+    pub fn with_home(mut self, home: Home) -> Self {
+        self.hub.timers = Timers::new(home);
+        self
+    }
+
+    /// Use a custom timeout instead of [`DEFAULT_TIMEOUT`] for requests to
+    /// plugins, including requests plugins send each other.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Use a custom policy for restarting crashed plugins.
+    pub fn with_restart_policy(mut self, policy: RestartPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Whether to keep plugin logs in files under the data directory, in
+    /// addition to memory. On by default.
+    pub fn with_log_files(mut self, enabled: bool) -> Self {
+        self.log_files = enabled;
+        self
+    }
+
+    /// Do not start the plugins with these ids. Plugins needing them are
+    /// skipped.
+    pub fn with_disabled(mut self, ids: impl IntoIterator<Item = String>) -> Self {
+        self.disabled = ids.into_iter().collect();
+        self
+    }
+
+    /// Settings for plugins, by id, passed to each in its `plugin.init`
+    /// response once checked against its settings schema.
+    pub fn with_settings(
+        mut self,
+        settings: impl IntoIterator<Item = (String, Map<String, Value>)>,
+    ) -> Self {
+        self.settings = settings.into_iter().collect();
+        self
+    }
+
+    /// A handle for subsystems inside the core to reach plugins and core
+    /// services. Take it after configuring the manager.
+    pub fn core_handle(&self) -> CoreHandle {
+        CoreHandle {
+            hub: self.hub.clone(),
+            timeout: self.timeout,
+        }
+    }
+
+    /// Serve `automation.*` requests from `automations`.
+    pub fn set_automations(&self, automations: Automations) {
+        *self.hub.automations.lock() = Some(automations);
+    }
+
+    /// Serve `scene.*` requests from `scenes`.
+    pub fn set_scenes(&self, scenes: Scenes) {
+        *self.hub.scenes.lock() = Some(scenes);
+    }
+
+    /// Directory holding the plugin log files.
+    pub fn log_dir(&self) -> PathBuf {
+        self.data_dir.join(LOG_DIR)
+    }
+
+    /// The last `n` lines a plugin logged, oldest first.
+    pub fn logs(&self, plugin_id: &str, n: usize) -> Vec<LogRecord> {
+        self.hub.logs.tail(plugin_id, n)
+    }
+
+    /// Receive every line logged by any plugin from now on.
+    pub fn follow_logs(&self) -> tokio::sync::broadcast::Receiver<LogRecord> {
+        self.hub.logs.follow()
+    }
+
+    /// The log buffers of all plugins, for serving them elsewhere.
+    pub fn plugin_logs(&self) -> Arc<PluginLogs> {
+        self.hub.logs.clone()
+    }
+
     /// List current plugins and their status.
     pub fn list(&self) -> Vec<(&PluginManifest, PluginStatus, &PathBuf)> {
+        let states = self.hub.states.lock();
         self.plugins
             .values()
-            .map(|p| (&p.manifest, p.status.clone(), &p.dir))
+            .map(|p| {
+                let status = states
+                    .get(&p.manifest.id)
+                    .map(|s| s.status.clone())
+                    .unwrap_or(PluginStatus::Discovered);
+                (&p.manifest, status, &p.dir)
+            })
             .collect()
     }
 
-    /// Start all discovered plugins.
-    pub async fn start_all(&mut self) -> Result<()> {
-        let keys: Vec<String> = self.plugins.keys().cloned().collect();
-        for id in keys {
-            let handle = self.plugins.get_mut(&id).unwrap();
-            PluginManager::start_plugin(&self.workspace_root, handle).await?;
+    /// Current runtime state of a plugin.
+    pub fn state(&self, plugin_id: &str) -> Option<PluginState> {
+        self.hub.states.lock().get(plugin_id).cloned()
+    }
+
+    /// Take over the plugin states reported by a core running on the same
+    /// data directory, so `list` reflects the live system. Returns `false`
+    /// if no running core was found.
+    pub fn load_status_report(&mut self) -> bool {
+        let path = self.data_dir.join(STATUS_FILE);
+        let report: StatusReport = match std::fs::read(&path)
+            .ok()
+            .and_then(|b| serde_json::from_slice(&b).ok())
+        {
+            Some(report) => report,
+            None => return false,
+        };
+        if !process_alive(report.pid) {
+            return false;
         }
+        let mut states = self.hub.states.lock();
+        for (id, state) in report.plugins {
+            if let Some(s) = states.get_mut(&id) {
+                *s = state;
+            }
+        }
+        true
+    }
+
+    /// Start all discovered plugins in dependency order. A plugin that fails
+    /// to start does not stop the others, but the plugins needing it are
+    /// skipped.
+    pub async fn start_all(&mut self) -> StartReport {
+        self.hub.status_file = Some(self.data_dir.join(STATUS_FILE));
+        self.hub.audit = Some(AuditLog::new(self.data_dir.join(permissions::AUDIT_FILE)));
+        if self.log_files {
+            self.hub.logs.set_dir(Some(self.log_dir()));
+        }
+        let needs = self
+            .plugins
+            .iter()
+            .map(|(id, h)| (id.clone(), h.manifest.needs.clone()))
+            .collect();
+        let plan = deps::plan(&needs);
+        let mut report = StartReport::default();
+        for (id, reason) in &plan.blocked {
+            if !plan.order.contains(id) {
+                self.skip(id, reason.clone(), &mut report);
+            }
+        }
+        for id in plan.order {
+            if self.disabled.contains(&id) {
+                self.hub.update(&id, |s| s.status = PluginStatus::Disabled);
+                report.disabled.push(id);
+                continue;
+            }
+            if let Some(reason) = plan.blocked.get(&id) {
+                self.fail(&id, reason.clone(), &mut report);
+                continue;
+            }
+            let unavailable = needs[&id]
+                .iter()
+                .find(|n| self.plugins.contains_key(*n) && !self.started.contains(n));
+            if let Some(dep) = unavailable {
+                self.skip(
+                    &id,
+                    format!("needs {dep}, which is not running"),
+                    &mut report,
+                );
+                continue;
+            }
+            match self.start(&id).await {
+                Ok(()) => report.started.push(id),
+                Err(e) => report.failed.push((id, format!("{e:#}"))),
+            }
+        }
+        report
+    }
+
+    fn fail(&mut self, plugin_id: &str, reason: String, report: &mut StartReport) {
+        self.hub.update(plugin_id, |s| {
+            s.status = PluginStatus::Failed;
+            s.last_exit = Some(reason.clone());
+        });
+        report.failed.push((plugin_id.to_string(), reason));
+    }
+
+    fn skip(&mut self, plugin_id: &str, reason: String, report: &mut StartReport) {
+        self.hub.update(plugin_id, |s| {
+            s.status = PluginStatus::Skipped;
+            s.last_exit = Some(reason.clone());
+        });
+        report.skipped.push((plugin_id.to_string(), reason));
+    }
+
+    /// Start a plugin and supervise its process until it is stopped.
+    async fn start(&mut self, plugin_id: &str) -> Result<()> {
+        let handle = self
+            .plugins
+            .get_mut(plugin_id)
+            .context("plugin not found")?;
+        if handle.supervisor.is_some() {
+            return Ok(());
+        }
+        let configured = self.settings.get(plugin_id).cloned().unwrap_or_default();
+        let resolved = settings::load_schema(&handle.dir).and_then(|schema| {
+            settings::resolve(schema.as_ref(), configured)
+                .map_err(|e| anyhow::anyhow!("invalid settings: {e}"))
+        });
+        match resolved {
+            Ok(resolved) => *handle.settings.lock() = resolved,
+            Err(e) => {
+                self.hub.update(plugin_id, |s| {
+                    s.status = PluginStatus::Failed;
+                    s.last_exit = Some(format!("failed to start: {e:#}"));
+                });
+                return Err(e);
+            }
+        }
+        let storage = match &handle.storage {
+            Some(storage) => storage.clone(),
+            None => {
+                let storage = Arc::new(Storage::open(&self.data_dir, &handle.manifest.id).await?);
+                handle.storage = Some(storage.clone());
+                storage
+            }
+        };
+        let spec = Arc::new(PluginSpec {
+            manifest: handle.manifest.clone(),
+            dir: handle.dir.clone(),
+            exec: handle.exec_path(&self.workspace_root),
+            pending: handle.pending.clone(),
+            storage,
+            data_dir: std::path::absolute(storage::plugin_dir(&self.data_dir, plugin_id))?,
+            settings: handle.settings.clone(),
+            timeout: self.timeout,
+        });
+        let process = match launch(&spec, &self.hub).await {
+            Ok(process) => process,
+            Err(e) => {
+                self.hub.update(plugin_id, |s| {
+                    s.status = PluginStatus::Failed;
+                    s.last_exit = Some(format!("failed to start: {e}"));
+                });
+                return Err(e);
+            }
+        };
+        let (stop, stop_rx) = watch::channel(None);
+        let task = tokio::spawn(supervise(
+            spec,
+            self.hub.clone(),
+            self.policy.clone(),
+            process,
+            stop_rx,
+        ));
+        handle.supervisor = Some(Supervisor { stop, task });
+        self.started.push(plugin_id.to_string());
         Ok(())
     }
 
-    async fn start_plugin(workspace_root: &Path, handle: &mut PluginHandle) -> Result<()> {
-        let exec = handle.exec_path(workspace_root);
-        let mut cmd = Command::new(exec);
-        cmd.arg("--stdio").current_dir(&handle.dir);
-        cmd.stdin(Stdio::piped()).stdout(Stdio::piped());
-        let mut child = cmd.spawn().context("spawning plugin")?;
-        let stdin = child.stdin.take().unwrap();
-        let stdout = child.stdout.take().unwrap();
-        let writer = Arc::new(tokio::sync::Mutex::new(BufWriter::new(stdin)));
-        let mut reader = BufReader::new(stdout);
-
-        // send core.hello event
-        let env = Envelope {
-            id: None,
-            kind: Kind::Event,
+    /// Stop a plugin: send `plugin.stop`, wait up to `grace` for it to
+    /// answer and exit, then kill it.
+    pub async fn stop(&mut self, plugin_id: &str, grace: Duration) -> StopOutcome {
+        let sup = self
+            .plugins
+            .get_mut(plugin_id)
+            .and_then(|h| h.supervisor.take());
+        self.started.retain(|id| id != plugin_id);
+        match sup {
+            Some(sup) => {
+                let _ = sup.stop.send(Some(grace));
+                sup.task.await.unwrap_or(StopOutcome::Killed)
+            }
+            None => StopOutcome::NotRunning,
+        }
+    }
+
+    /// Stop all running plugins in reverse start order, so plugins are
+    /// stopped before the plugins they depend on.
+    pub async fn shutdown(&mut self, grace: Duration) -> Vec<ShutdownReport> {
+        let mut reports = Vec::new();
+        for plugin_id in self.started.clone().into_iter().rev() {
+            let started = Instant::now();
+            let outcome = self.stop(&plugin_id, grace).await;
+            reports.push(ShutdownReport {
+                plugin_id,
+                outcome,
+                elapsed: started.elapsed(),
+            });
+        }
+        reports
+    }
+
+    /// Replace the settings of a plugin after checking them against its
+    /// schema. A running plugin is sent [`CONFIG_CHANGED`] with the new
+    /// settings; later restarts use them too. Returns `false` if the settings
+    /// did not change.
+    pub async fn update_settings(
+        &mut self,
+        plugin_id: &str,
+        settings: Map<String, Value>,
+    ) -> Result<bool> {
+        let handle = self.plugins.get(plugin_id).context("plugin not found")?;
+        let schema = settings::load_schema(&handle.dir)?;
+        let resolved = settings::resolve(schema.as_ref(), settings.clone())
+            .map_err(|e| anyhow::anyhow!("invalid settings: {e}"))?;
+        self.settings.insert(plugin_id.to_string(), settings);
+        {
+            let mut current = handle.settings.lock();
+            if *current == resolved {
+                return Ok(false);
+            }
+            *current = resolved.clone();
+        }
+        let writer = self
+            .hub
+            .links
+            .lock()
+            .get(plugin_id)
+            .map(|l| l.writer.clone());
+        if let Some(writer) = writer {
+            let env = Envelope::event(CONFIG_CHANGED, Some(json!({ "settings": resolved })));
+            write_envelope(&mut *writer.lock().await, &env).await?;
+        }
+        Ok(true)
+    }
+
+    /// Publish an event from the core to all subscribed plugins.
+    pub fn publish(&self, topic: &str, payload: Value) -> usize {
+        self.hub.bus.lock().publish(Event {
+            topic: topic.to_string(),
+            source: None,
+            payload: Some(payload),
+        })
+    }
+
+    /// Send a request to a plugin and wait for the response, for at most
+    /// the manager's default timeout.
+    pub async fn call(
+        &self,
+        plugin_id: &str,
+        method: &str,
+        params: Value,
+    ) -> Result<Value, CallError> {
+        self.call_with_timeout(plugin_id, method, params, self.timeout)
+            .await
+    }
+
+    /// Send a request to a plugin and wait up to `timeout` for the response.
+    /// On timeout the request is abandoned and the plugin sent `$/cancel`.
+    pub async fn call_with_timeout(
+        &self,
+        plugin_id: &str,
+        method: &str,
+        params: Value,
+        timeout: Duration,
+    ) -> Result<Value, CallError> {
+        if !self.plugins.contains_key(plugin_id) {
+            return Err(CallError::NotFound(plugin_id.to_string()));
+        }
+        let link = self
+            .hub
+            .links
+            .lock()
+            .get(plugin_id)
+            .cloned()
+            .ok_or_else(|| CallError::NotRunning(plugin_id.to_string()))?;
+        let env = Envelope::request(Uuid::new_v4().to_string(), method, params);
+        link.forward(env, timeout, std::future::pending())
+            .await
+            .map_err(|e| match e.code {
+                RpcError::TIMEOUT => CallError::Timeout(timeout),
+                RpcError::TARGET_UNAVAILABLE => CallError::NotRunning(plugin_id.to_string()),
+                _ => CallError::Rpc(e),
+            })
+    }
+
+    /// Send a device command to the plugin owning the entity, after checking
+    /// it against the entity's capabilities, and return the plugin's answer.
+    /// The entity's state changes only once the owner reports it.
+    pub async fn command(&self, command: &DeviceCommand) -> Result<Value, CallError> {
+        let owner = self
+            .hub
+            .registry
+            .lock()
+            .command_owner(command)
+            .map_err(|e| CallError::Invalid(e.message))?
+            .to_string();
+        self.call(&owner, COMMAND, json!(command)).await
+    }
+}
+
+/// Everything needed to (re)start a plugin process.
+struct PluginSpec {
+    manifest: PluginManifest,
+    dir: PathBuf,
+    exec: PathBuf,
+    pending: ArcPending,
+    storage: Arc<Storage>,
+    /// Directory for the plugin's own files, passed in `plugin.init`.
+    data_dir: PathBuf,
+    settings: Arc<Mutex<Value>>,
+    /// Timeout for requests the plugin sends to other plugins.
+    timeout: Duration,
+}
+
+/// A plugin process that completed the handshake.
+struct Process {
+    child: Child,
+    reader: JoinHandle<()>,
+}
+
+/// What the core agreed to in response to `plugin.init`.
+#[derive(Debug)]
+struct Init {
+    metadata: Metadata,
+    /// Features supported by both sides.
+    features: Vec<String>,
+}
+
+/// Validate the `plugin.init` params against the plugin's manifest and pick
+/// the protocol features to use.
+fn negotiate(manifest: &PluginManifest, params: Option<&Value>) -> Result<Init, RpcError> {
+    let metadata: Metadata = params
+        .and_then(|p| p.get("metadata"))
+        .cloned()
+        .ok_or_else(|| RpcError::new(RpcError::INVALID_PARAMS, "missing metadata"))
+        .and_then(|m| {
+            serde_json::from_value(m).map_err(|e| {
+                RpcError::new(RpcError::INVALID_PARAMS, format!("invalid metadata: {e}"))
+            })
+        })?;
+    if metadata.id != manifest.id {
+        return Err(RpcError::new(
+            RpcError::INVALID_PARAMS,
+            format!(
+                "metadata id {:?} does not match manifest id {:?}",
+                metadata.id, manifest.id
+            ),
+        ));
+    }
+    if let Some(version) = &metadata.api_version {
+        if !api_compatible(version) {
+            return Err(RpcError::new(
+                RpcError::INCOMPATIBLE_VERSION,
+                format!("API version {version} is not supported, the core speaks {API_VERSION}"),
+            ));
+        }
+    }
+    let features: Vec<String> = metadata
+        .features
+        .iter()
+        .filter(|f| features::ALL.contains(&f.as_str()))
+        .cloned()
+        .collect();
+    if !metadata.provides.is_empty() && !features.iter().any(|f| f == features::RPC) {
+        return Err(RpcError::new(
+            RpcError::INCOMPATIBLE_VERSION,
+            format!(
+                "serving {:?} needs the {} feature",
+                metadata.provides,
+                features::RPC
+            ),
+        ));
+    }
+    Ok(Init { metadata, features })
+}
+
+/// Spawn the plugin process, run the handshake and start serving it.
+async fn launch(spec: &PluginSpec, hub: &Hub) -> Result<Process> {
+    let plugin_id = spec.manifest.id.clone();
+    if !api_compatible(&spec.manifest.api_version) {
+        anyhow::bail!(
+            "plugin {plugin_id} targets API version {} but the core supports {API_VERSION}",
+            spec.manifest.api_version
+        );
+    }
+    let mut cmd = Command::new(&spec.exec);
+    cmd.arg("--stdio").current_dir(&spec.dir);
+    cmd.stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    // a process that fails the handshake is killed when `child` is dropped
+    cmd.kill_on_drop(true);
+    let mut child = cmd.spawn().context("spawning plugin")?;
+    let stdin = child.stdin.take().unwrap();
+    let stdout = child.stdout.take().unwrap();
+    tokio::spawn(capture_stderr(
+        child.stderr.take().unwrap(),
+        plugin_id.clone(),
+        hub.logs.clone(),
+    ));
+    let writer = Arc::new(tokio::sync::Mutex::new(BufWriter::new(stdin)));
+    let mut reader = BufReader::new(stdout);
+
+    let features = match handshake(spec, hub, &writer, &mut reader).await {
+        Ok(features) => features,
+        Err(e) => {
+            // the namespaces may have been claimed before the handshake failed
+            hub.router.lock().release(&plugin_id);
+            let _ = child.kill().await;
+            return Err(e);
+        }
+    };
+
+    hub.links.lock().insert(
+        plugin_id.clone(),
+        Link {
+            writer: writer.clone(),
+            pending: spec.pending.clone(),
+            cancel: features.iter().any(|f| f == features::CANCEL),
+        },
+    );
+
+    // forward bus events the plugin subscribed to
+    let mut events = hub.bus.lock().register(&plugin_id);
+    let event_writer = writer.clone();
+    tokio::spawn(async move {
+        while let Some(event) = events.recv().await {
+            let env = Envelope::event(event.topic, event.payload);
+            let mut w = event_writer.lock().await;
+            if write_envelope(&mut *w, &env).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    // spawn reader task for further messages
+    let conn = Conn {
+        plugin_id,
+        writer,
+        permissions: Arc::new(spec.manifest.permissions.iter().cloned().collect()),
+        features: Arc::new(features.into_iter().collect()),
+        storage: spec.storage.clone(),
+        hub: hub.clone(),
+        timeout: spec.timeout,
+        inflight: Arc::default(),
+    };
+    let reader = tokio::spawn(conn.read_loop(reader, spec.pending.clone()));
+    Ok(Process { child, reader })
+}
+
+/// Greet the plugin and answer its `plugin.init` and `plugin.start`
+/// requests, returning the negotiated features.
+async fn handshake(
+    spec: &PluginSpec,
+    hub: &Hub,
+    writer: &Writer,
+    reader: &mut BufReader<ChildStdout>,
+) -> Result<Vec<String>> {
+    let plugin_id = spec.manifest.id.clone();
+
+    // send core.hello event
+    let env = Envelope {
+        id: None,
+        kind: Kind::Event,
+        method: None,
+        params: None,
+        result: None,
+        error: None,
+        topic: Some("core.hello".into()),
+        payload: Some(json!({
+            "api_version": API_VERSION,
+            "services": services::SERVICES,
+            "features": features::ALL,
+        })),
+    };
+    {
+        let mut w = writer.lock().await;
+        write_envelope(&mut *w, &env).await?;
+    }
+
+    // wait for plugin.init request
+    let env = read_handshake(reader, "plugin.init", spec.timeout).await?;
+    let features = if env.kind == Kind::Request && env.method.as_deref() == Some("plugin.init") {
+        let accepted = negotiate(&spec.manifest, env.params.as_ref()).and_then(|init| {
+            // claim the namespaces the plugin serves before acknowledging
+            hub.router
+                .lock()
+                .claim(&plugin_id, &init.metadata.provides)
+                .map_err(|e| RpcError::new(RpcError::INVALID_PARAMS, e))?;
+            Ok(init)
+        });
+        let resp = Envelope::response(
+            env.id.clone(),
+            accepted
+                .as_ref()
+                .map(|init| {
+                    json!({
+                        "ok": true,
+                        "api_version": API_VERSION,
+                        "features": init.features,
+                        "settings": *spec.settings.lock(),
+                        "data_dir": spec.data_dir,
+                        "permissions": spec.manifest.permissions,
+                    })
+                })
+                .map_err(Clone::clone),
+        );
+        {
+            let mut w = writer.lock().await;
+            write_envelope(&mut *w, &resp).await?;
+        }
+        let init = match accepted {
+            Ok(init) => init,
+            Err(e) => anyhow::bail!("plugin {plugin_id} rejected: {}", e.message),
+        };
+        // the core starts plugins in the order given by the manifests, so a
+        // plugin dependency only listed in the metadata is not waited for
+        for need in &init.metadata.needs {
+            if !services::SERVICES.contains(&need.as_str()) && !spec.manifest.needs.contains(need) {
+                warn!("plugin {plugin_id} needs {need} but its manifest does not declare it");
+            }
+        }
+        hub.update(&plugin_id, |s| {
+            s.metadata = Some(init.metadata);
+            s.features = init.features.clone();
+        });
+        init.features
+    } else {
+        anyhow::bail!("expected plugin.init request");
+    };
+
+    // expect plugin.start
+    let env = read_handshake(reader, "plugin.start", spec.timeout).await?;
+    if env.kind == Kind::Request && env.method.as_deref() == Some("plugin.start") {
+        let resp = Envelope {
+            id: env.id.clone(),
+            kind: Kind::Response,
             method: None,
             params: None,
-            result: None,
+            result: Some(json!({"ok":true})),
             error: None,
-            topic: Some("core.hello".into()),
-            payload: Some(json!({"api_version":"1","services":["log","event","timer","storage"]})),
+            topic: None,
+            payload: None,
         };
         {
             let mut w = writer.lock().await;
-            write_envelope(&mut *w, &env).await?;
-        }
-
-        // wait for plugin.init request
-        let env = read_envelope(&mut reader).await?;
-        if env.kind == Kind::Request && env.method.as_deref() == Some("plugin.init") {
-            // acknowledge
-            let resp = Envelope {
-                id: env.id.clone(),
-                kind: Kind::Response,
+            write_envelope(&mut *w, &resp).await?;
+            let ready = Envelope {
+                id: None,
+                kind: Kind::Event,
                 method: None,
                 params: None,
-                result: Some(json!({"ok":true})),
+                result: None,
                 error: None,
-                topic: None,
+                topic: Some("system.ready".into()),
                 payload: None,
             };
-            {
-                let mut w = writer.lock().await;
-                write_envelope(&mut *w, &resp).await?;
-            }
-        } else {
-            anyhow::bail!("expected plugin.init request");
+            write_envelope(&mut *w, &ready).await?;
         }
+        hub.set_status(&plugin_id, PluginStatus::Running);
+    } else {
+        anyhow::bail!("expected plugin.start request");
+    }
+    Ok(features)
+}
 
-        // expect plugin.start
-        let env = read_envelope(&mut reader).await?;
-        if env.kind == Kind::Request && env.method.as_deref() == Some("plugin.start") {
-            let resp = Envelope {
-                id: env.id.clone(),
-                kind: Kind::Response,
-                method: None,
-                params: None,
-                result: Some(json!({"ok":true})),
-                error: None,
-                topic: None,
-                payload: None,
-            };
-            {
-                let mut w = writer.lock().await;
-                write_envelope(&mut *w, &resp).await?;
-                let ready = Envelope {
-                    id: None,
-                    kind: Kind::Event,
-                    method: None,
-                    params: None,
-                    result: None,
-                    error: None,
-                    topic: Some("system.ready".into()),
-                    payload: None,
-                };
-                write_envelope(&mut *w, &ready).await?;
+/// Read the next handshake message, giving up after `timeout` so a plugin
+/// that never answers does not hold up the plugins started after it.
+async fn read_handshake(
+    reader: &mut BufReader<ChildStdout>,
+    expected: &str,
+    timeout: Duration,
+) -> Result<Envelope> {
+    tokio::time::timeout(timeout, read_envelope(reader))
+        .await
+        .map_err(|_| anyhow::anyhow!("no {expected} request within {timeout:?}"))?
+}
+
+/// Record what a plugin writes to stderr in its log, line by line.
+async fn capture_stderr(stderr: ChildStderr, plugin_id: String, logs: Arc<PluginLogs>) {
+    let mut reader = BufReader::new(stderr);
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        match reader.read_until(b'\n', &mut line).await {
+            Ok(0) | Err(_) => break,
+            Ok(_) => logs.stderr(&plugin_id, &String::from_utf8_lossy(&line)),
+        }
+    }
+}
+
+/// Watch a plugin process and restart it with exponential backoff when it
+/// exits unexpectedly, until it is stopped or crashes too often.
+async fn supervise(
+    spec: Arc<PluginSpec>,
+    hub: Hub,
+    policy: RestartPolicy,
+    mut process: Process,
+    mut stop: watch::Receiver<Option<Duration>>,
+) -> StopOutcome {
+    let plugin_id = spec.manifest.id.as_str();
+    let mut crashes = CrashHistory::default();
+    loop {
+        let reason = tokio::select! {
+            status = process.child.wait() => {
+                // let the reader clean up before a restart claims anew
+                let _ = tokio::time::timeout(EXIT_WAIT, &mut process.reader).await;
+                match status {
+                    Ok(status) => format!("exited with {status}"),
+                    Err(e) => format!("wait failed: {e}"),
+                }
             }
-            handle.status = PluginStatus::Running;
-        } else {
-            anyhow::bail!("expected plugin.start request");
+            _ = &mut process.reader => {
+                // stdout usually closes just before the process exits; the
+                // process is unusable without its connection either way
+                match tokio::time::timeout(EXIT_WAIT, process.child.wait()).await {
+                    Ok(Ok(status)) => format!("exited with {status}"),
+                    _ => {
+                        let _ = process.child.kill().await;
+                        "connection to plugin lost".to_string()
+                    }
+                }
+            }
+            _ = stop.changed() => {
+                let grace = stop.borrow().unwrap_or_default();
+                let outcome = stop_process(&hub, plugin_id, &mut process, grace).await;
+                hub.set_status(plugin_id, PluginStatus::Stopped);
+                return outcome;
+            }
+        };
+        let mut reason = reason;
+        process = loop {
+            let recent = crashes.record(Instant::now(), policy.window);
+            warn!("plugin {plugin_id} crashed: {reason}");
+            if recent > policy.max_restarts {
+                error!("plugin {plugin_id} crashed {recent} times, giving up");
+                hub.update(plugin_id, |s| {
+                    s.status = PluginStatus::Failed;
+                    s.last_exit = Some(reason);
+                });
+                return StopOutcome::NotRunning;
+            }
+            hub.update(plugin_id, |s| {
+                s.status = PluginStatus::Crashed;
+                s.last_exit = Some(reason.clone());
+            });
+            let delay = policy.backoff(recent);
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => {}
+                _ = stop.changed() => {
+                    hub.set_status(plugin_id, PluginStatus::Stopped);
+                    return StopOutcome::NotRunning;
+                }
+            }
+            hub.set_status(plugin_id, PluginStatus::Restarting);
+            match launch(&spec, &hub).await {
+                Ok(process) => {
+                    info!("plugin {plugin_id} restarted after {delay:?}");
+                    hub.update(plugin_id, |s| s.restarts += 1);
+                    break process;
+                }
+                Err(e) => reason = format!("restart failed: {e}"),
+            }
+        };
+    }
+}
+
+/// Ask a plugin to stop and make sure its process is gone within `grace`.
+async fn stop_process(
+    hub: &Hub,
+    plugin_id: &str,
+    process: &mut Process,
+    grace: Duration,
+) -> StopOutcome {
+    let deadline = Instant::now() + grace;
+    let link = hub.links.lock().get(plugin_id).cloned();
+    let acknowledged = match link {
+        Some(link) => {
+            let env = Envelope::request(Uuid::new_v4().to_string(), "plugin.stop", json!({}));
+            link.forward(env, grace, std::future::pending())
+                .await
+                .is_ok()
+        }
+        None => false,
+    };
+    let remaining = deadline.saturating_duration_since(Instant::now());
+    match tokio::time::timeout(remaining, process.child.wait()).await {
+        Ok(Ok(_)) if acknowledged => StopOutcome::Stopped,
+        Ok(Ok(_)) => StopOutcome::Exited,
+        _ => {
+            warn!("plugin {plugin_id} did not exit within {grace:?}, killing it");
+            let _ = process.child.kill().await;
+            StopOutcome::Killed
         }
+    }
+}
 
-        let pending = handle.pending.clone();
-        let subscriptions = std::sync::Arc::new(Mutex::new(HashSet::new()));
-        handle.subscriptions = HashSet::new();
-        let writer_clone = writer.clone();
-        let plugin_id = handle.manifest.id.clone();
+/// Per-plugin context used to serve the requests a plugin sends to the core.
+#[derive(Clone)]
+struct Conn {
+    plugin_id: String,
+    writer: Writer,
+    /// Permissions granted by the plugin's manifest.
+    permissions: Arc<HashSet<String>>,
+    /// Protocol features negotiated in the handshake.
+    features: Arc<HashSet<String>>,
+    storage: Arc<Storage>,
+    hub: Hub,
+    /// Timeout for requests forwarded to other plugins.
+    timeout: Duration,
+    /// Forwarded requests still waiting for a response, by the plugin's
+    /// request id, used to cancel them on `$/cancel`.
+    inflight: Arc<Mutex<HashMap<String, oneshot::Sender<()>>>>,
+}
 
-        // spawn reader task for further messages
-        tokio::spawn(async move {
-            let mut reader = reader;
-            let writer = writer_clone;
-            loop {
-                match read_envelope(&mut reader).await {
-                    Ok(env) => {
-                        match env.kind {
-                            Kind::Request => {
-                                if let Some(method) = env.method.as_deref() {
-                                    if method == "log.write" {
-                                        if let Some(params) = env.params {
-                                            if let (Some(level), Some(message)) =
-                                                (params.get("level"), params.get("message"))
-                                            {
-                                                if let (Some(level), Some(message)) =
-                                                    (level.as_str(), message.as_str())
-                                                {
-                                                    crate::services::log::write(level, message);
-                                                }
-                                            }
-                                        }
-                                        let resp = Envelope {
-                                            id: env.id,
-                                            kind: Kind::Response,
-                                            method: None,
-                                            params: None,
-                                            result: Some(json!({"ok":true})),
-                                            error: None,
-                                            topic: None,
-                                            payload: None,
-                                        };
-                                        let mut w = writer.lock().await;
-                                        let _ = write_envelope(&mut *w, &resp).await;
-                                    } else if method == "event.subscribe" {
-                                        if let Some(params) = env.params {
-                                            if let Some(arr) =
-                                                params.get("topics").and_then(|t| t.as_array())
-                                            {
-                                                let mut subs = subscriptions.lock();
-                                                for topic in arr {
-                                                    if let Some(t) = topic.as_str() {
-                                                        subs.insert(t.to_string());
-                                                    }
-                                                }
-                                            }
-                                        }
-                                        let resp = Envelope {
-                                            id: env.id,
-                                            kind: Kind::Response,
-                                            method: None,
-                                            params: None,
-                                            result: Some(json!({"ok":true})),
-                                            error: None,
-                                            topic: None,
-                                            payload: None,
-                                        };
-                                        let mut w = writer.lock().await;
-                                        let _ = write_envelope(&mut *w, &resp).await;
-                                    } else if method == "timer.set_interval" {
-                                        if let Some(params) = env.params {
-                                            if let (Some(id_val), Some(ms_val)) =
-                                                (params.get("id"), params.get("millis"))
-                                            {
-                                                if let (Some(id), Some(ms)) =
-                                                    (id_val.as_str(), ms_val.as_u64())
-                                                {
-                                                    let writer_inner = writer.clone();
-                                                    crate::services::timer::spawn_timer(
-                                                        writer_inner,
-                                                        crate::services::timer::TimerParams {
-                                                            id: id.to_string(),
-                                                            millis: ms,
-                                                        },
-                                                    );
-                                                }
-                                            }
-                                        }
-                                        let resp = Envelope {
-                                            id: env.id,
-                                            kind: Kind::Response,
-                                            method: None,
-                                            params: None,
-                                            result: Some(json!({"ok":true})),
-                                            error: None,
-                                            topic: None,
-                                            payload: None,
-                                        };
-                                        let mut w = writer.lock().await;
-                                        let _ = write_envelope(&mut *w, &resp).await;
-                                    } else {
-                                        // unknown method
-                                        let resp = Envelope {
-                                            id: env.id,
-                                            kind: Kind::Response,
-                                            method: None,
-                                            params: None,
-                                            result: None,
-                                            error: Some(plugin_api::RpcError {
-                                                code: -32601,
-                                                message: format!("unknown method {}", method),
-                                            }),
-                                            topic: None,
-                                            payload: None,
-                                        };
-                                        let mut w = writer.lock().await;
-                                        let _ = write_envelope(&mut *w, &resp).await;
-                                    }
-                                }
-                            }
-                            Kind::Response => {
-                                if let Some(id) = env.id.clone() {
-                                    if let Some(tx) = pending.lock().remove(&id) {
-                                        let _ = tx.send(env);
-                                    }
-                                }
-                            }
-                            Kind::Event => {
-                                // ignore events from plugin
+impl Conn {
+    async fn send(&self, env: &Envelope) {
+        let mut w = self.writer.lock().await;
+        let _ = write_envelope(&mut *w, env).await;
+    }
+
+    async fn read_loop(self, mut reader: BufReader<ChildStdout>, pending: ArcPending) {
+        let plugin_id = &self.plugin_id;
+        loop {
+            match read_envelope(&mut reader).await {
+                Ok(env) => match env.kind {
+                    Kind::Request => self.handle_request(env).await,
+                    Kind::Response => {
+                        if let Some(id) = env.id.clone() {
+                            if let Some(tx) = pending.lock().remove(&id) {
+                                let _ = tx.send(env);
                             }
                         }
                     }
-                    Err(err) => {
-                        error!("error reading from plugin {plugin_id}: {err}");
-                        break;
-                    }
+                    Kind::Event => match env.topic {
+                        Some(topic)
+                            if !self.permitted(
+                                &format!("publish {topic}"),
+                                permissions::PUBLISH_PERMISSION,
+                            ) =>
+                        {
+                            // refused and audited by `permitted`
+                        }
+                        Some(topic) if events::valid_topic(&topic) => {
+                            self.hub.bus.lock().publish(Event {
+                                topic,
+                                source: Some(plugin_id.clone()),
+                                payload: env.payload,
+                            });
+                        }
+                        topic => {
+                            warn!("plugin {plugin_id} sent event with invalid topic {topic:?}")
+                        }
+                    },
+                },
+                Err(err) => {
+                    error!("error reading from plugin {plugin_id}: {err}");
+                    break;
+                }
+            }
+        }
+        self.hub.bus.lock().remove(plugin_id);
+        self.hub.router.lock().release(plugin_id);
+        self.hub.links.lock().remove(plugin_id);
+        self.hub.timers.clear(plugin_id);
+        let changes = self
+            .hub
+            .registry
+            .lock()
+            .owner_gone(plugin_id, Utc::now().timestamp_millis());
+        device::publish(&self.hub.bus, changes);
+        // fail requests still waiting on this plugin
+        for (id, tx) in pending.lock().drain() {
+            let _ = tx.send(Envelope::response(
+                Some(id),
+                Err(RpcError::new(
+                    RpcError::TARGET_UNAVAILABLE,
+                    format!("plugin {plugin_id} exited"),
+                )),
+            ));
+        }
+    }
+
+    /// Check a permission, recording a refusal in the audit trail.
+    fn permitted(&self, what: &str, permission: &str) -> bool {
+        if self.permissions.contains(permission) {
+            return true;
+        }
+        warn!(
+            "plugin {} denied {what}: missing permission {permission}",
+            self.plugin_id
+        );
+        if let Some(audit) = &self.hub.audit {
+            if let Err(e) = audit.denied(&self.plugin_id, what, permission) {
+                warn!("failed to write {}: {e}", audit.path().display());
+            }
+        }
+        false
+    }
+
+    async fn handle_request(&self, env: Envelope) {
+        let Some(method) = env.method.clone() else {
+            return;
+        };
+        if method == CANCEL_METHOD {
+            let id = env.params.as_ref().and_then(|p| p.get("id"));
+            if let Some(cancel) = id
+                .and_then(Value::as_str)
+                .and_then(|id| self.inflight.lock().remove(id))
+            {
+                let _ = cancel.send(());
+            }
+            return;
+        }
+        let permission = match method.as_str() {
+            "event.subscribe" | "event.unsubscribe" => {
+                Some(permissions::subscribe_permission(&topics(&env.params)))
+            }
+            m => permissions::required_permission(m),
+        };
+        if let Some(permission) = permission {
+            if !self.permitted(&method, permission) {
+                let err = RpcError::new(
+                    RpcError::PERMISSION_DENIED,
+                    format!("permission {permission} required for {method}"),
+                );
+                self.send(&Envelope::response(env.id, Err(err))).await;
+                return;
+            }
+        }
+        let result = match method.as_str() {
+            "log.write" => services::log::handle(&self.hub.logs, &self.plugin_id, env.params),
+            "event.subscribe" | "event.unsubscribe" => self.subscribe(&method, &env.params),
+            m if m.starts_with("timer.") => {
+                let target = timer::Target {
+                    plugin_id: self.plugin_id.clone(),
+                    writer: Some(self.writer.clone()),
+                    bus: self.hub.bus.clone(),
+                };
+                timer::handle(&self.hub.timers, target, m, env.params)
+            }
+            m if m.starts_with("storage.") => storage::handle(&self.storage, m, env.params).await,
+            COMMAND => match device::route_command(&self.hub.registry, env.params) {
+                Ok((owner, command)) => {
+                    let env = Envelope {
+                        params: Some(command),
+                        ..env
+                    };
+                    return self.forward(&owner, env).await;
+                }
+                Err(e) => Err(e),
+            },
+            m if m.starts_with("automation.") => {
+                let automations = self.hub.automations.lock().clone();
+                match automations {
+                    Some(automations) => automations.handle(m, env.params),
+                    None => Err(RpcError::new(
+                        RpcError::METHOD_NOT_FOUND,
+                        "automations are not running",
+                    )),
+                }
+            }
+            m if m.starts_with("scene.") => {
+                let scenes = self.hub.scenes.lock().clone();
+                let Some(scenes) = scenes else {
+                    let err = RpcError::new(RpcError::METHOD_NOT_FOUND, "scenes are not loaded");
+                    return self.send(&Envelope::response(env.id, Err(err))).await;
+                };
+                // activating sends commands, possibly to this very plugin,
+                // so answer from a task rather than blocking its reader
+                let conn = self.clone();
+                let m = m.to_string();
+                tokio::spawn(async move {
+                    let result = scenes.handle(&m, env.params).await;
+                    conn.send(&Envelope::response(env.id, result)).await;
+                });
+                return;
+            }
+            m if m.starts_with("device.") || m.starts_with("state.") => device::handle(
+                &self.hub.registry,
+                &self.hub.bus,
+                &self.plugin_id,
+                m,
+                env.params,
+            ),
+            m => {
+                // without `rpc` the plugin can only call the core
+                let owner = if self.features.contains(features::RPC) {
+                    self.hub.router.lock().owner(m).map(str::to_string)
+                } else {
+                    None
+                };
+                match owner {
+                    Some(owner) => return self.forward(&owner, env).await,
+                    None => Err(RpcError::new(
+                        RpcError::METHOD_NOT_FOUND,
+                        format!("unknown method {}", m),
+                    )),
                 }
             }
+        };
+        self.send(&Envelope::response(env.id, result)).await;
+    }
+
+    /// Forward a request to plugin `owner` and relay its response under the
+    /// caller's request id, without blocking this plugin's reader.
+    async fn forward(&self, owner: &str, env: Envelope) {
+        let link = self.hub.links.lock().get(owner).cloned();
+        let Some(link) = link else {
+            let method = env.method.as_deref().unwrap_or_default();
+            let err = RpcError::new(
+                RpcError::TARGET_UNAVAILABLE,
+                format!("plugin {owner} serving {method} is not running"),
+            );
+            self.send(&Envelope::response(env.id, Err(err))).await;
+            return;
+        };
+        let conn = self.clone();
+        let id = env.id.clone();
+        let (cancel, cancelled) = oneshot::channel();
+        if let Some(id) = &id {
+            self.inflight.lock().insert(id.clone(), cancel);
+        }
+        tokio::spawn(async move {
+            let cancelled = async {
+                // a dropped sender means no cancel can come
+                if cancelled.await.is_err() {
+                    std::future::pending::<()>().await;
+                }
+            };
+            let result = link.forward(env, conn.timeout, cancelled).await;
+            if let Some(id) = &id {
+                conn.inflight.lock().remove(id);
+            }
+            conn.send(&Envelope::response(id, result)).await;
         });
+    }
 
-        handle.writer = Some(writer);
-        handle.child = Some(child);
-        Ok(())
+    fn subscribe(&self, method: &str, params: &Option<Value>) -> Result<Value, RpcError> {
+        let topics = topics(params);
+        if let Some(bad) = topics.iter().find(|t| !events::valid_pattern(t)) {
+            return Err(RpcError::new(
+                RpcError::INVALID_PARAMS,
+                format!("invalid topic pattern {bad}"),
+            ));
+        }
+        if !self.features.contains(features::EVENT_WILDCARDS) {
+            // a valid pattern that is not a valid topic has wildcards
+            if let Some(pattern) = topics.iter().find(|t| !events::valid_topic(t)) {
+                return Err(RpcError::new(
+                    RpcError::INCOMPATIBLE_VERSION,
+                    format!(
+                        "pattern {pattern} needs the {} feature",
+                        features::EVENT_WILDCARDS
+                    ),
+                ));
+            }
+        }
+        let mut bus = self.hub.bus.lock();
+        for topic in &topics {
+            if method == "event.subscribe" {
+                bus.subscribe(&self.plugin_id, topic);
+            } else {
+                bus.unsubscribe(&self.plugin_id, topic);
+            }
+        }
+        let topics = bus.subscriptions(&self.plugin_id);
+        Ok(json!({"ok":true,"topics":topics}))
     }
+}
 
-    /// Send a request to a plugin and wait for the response.
-    pub async fn call(&self, plugin_id: &str, method: &str, params: Value) -> Result<Value> {
-        let handle = self.plugins.get(plugin_id).context("plugin not found")?;
-        let writer = handle.writer.as_ref().context("plugin not running")?;
-        let id = Uuid::new_v4().to_string();
-        let env = Envelope {
-            id: Some(id.clone()),
-            kind: Kind::Request,
-            method: Some(method.to_string()),
-            params: Some(params),
-            result: None,
-            error: None,
-            topic: None,
-            payload: None,
+/// The `topics` of an `event.subscribe` or `event.unsubscribe` request.
+fn topics(params: &Option<Value>) -> Vec<String> {
+    params
+        .as_ref()
+        .and_then(|p| p.get("topics"))
+        .and_then(|t| t.as_array())
+        .map(|arr| {
+            arr.iter()
+                .filter_map(|t| t.as_str())
+                .map(str::to_string)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest(id: &str) -> PluginManifest {
+        PluginManifest {
+            name: id.into(),
+            id: id.into(),
+            version: "0.1.0".into(),
+            api_version: "1".into(),
+            exec: id.into(),
+            permissions: vec![],
+            needs: vec![],
+        }
+    }
+
+    #[test]
+    fn negotiates_common_features() {
+        let params = json!({"metadata": {
+            "id": "p", "name": "P", "version": "0.1.0", "api_version": "1.3",
+            "features": ["rpc", "from.the.future"],
+        }});
+        let init = negotiate(&manifest("p"), Some(&params)).unwrap();
+        assert_eq!(init.features, vec!["rpc"]);
+        assert_eq!(init.metadata.id, "p");
+    }
+
+    #[test]
+    fn rejects_mismatched_or_incompatible_plugins() {
+        let params = json!({"metadata": {"id": "other", "name": "P", "version": "0.1.0"}});
+        let err = negotiate(&manifest("p"), Some(&params)).unwrap_err();
+        assert_eq!(err.code, RpcError::INVALID_PARAMS);
+        assert!(err.message.contains("does not match"), "{}", err.message);
+
+        let params = json!({"metadata": {
+            "id": "p", "name": "P", "version": "0.1.0", "api_version": "2",
+        }});
+        let err = negotiate(&manifest("p"), Some(&params)).unwrap_err();
+        assert_eq!(err.code, RpcError::INCOMPATIBLE_VERSION);
+
+        assert!(negotiate(&manifest("p"), None).is_err());
+
+        let params = json!({"metadata": {
+            "id": "p", "name": "P", "version": "0.1.0", "provides": ["p.*"],
+        }});
+        let err = negotiate(&manifest("p"), Some(&params)).unwrap_err();
+        assert_eq!(err.code, RpcError::INCOMPATIBLE_VERSION);
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_limit() {
+        let policy = RestartPolicy {
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(10),
+            ..Default::default()
         };
-        let (tx, rx) = oneshot::channel();
-        handle.pending.lock().insert(id.clone(), tx);
-        {
-            let mut w = writer.lock().await;
-            write_envelope(&mut *w, &env).await?;
+        let delays: Vec<u64> = (1..=6).map(|n| policy.backoff(n).as_secs()).collect();
+        assert_eq!(delays, vec![1, 2, 4, 8, 10, 10]);
+        assert_eq!(policy.backoff(200), Duration::from_secs(10));
+    }
+
+    #[test]
+    fn crash_history_forgets_old_crashes() {
+        let window = Duration::from_secs(60);
+        let start = Instant::now();
+        let mut history = CrashHistory::default();
+        assert_eq!(history.record(start, window), 1);
+        assert_eq!(history.record(start + Duration::from_secs(30), window), 2);
+        assert_eq!(history.record(start + Duration::from_secs(61), window), 2);
+        assert_eq!(history.record(start + Duration::from_secs(200), window), 1);
+    }
+
+    /// A plugin run as a shell script speaking the protocol, for tests that
+    /// need a live process.
+    #[derive(Default)]
+    struct Script<'a> {
+        id: &'a str,
+        needs: &'a [&'a str],
+        permissions: &'a [&'a str],
+        features: &'a [&'a str],
+        provides: &'a [&'a str],
+        /// Shell run once the handshake is done.
+        start: &'a str,
+        /// Shell run for every line received after the handshake, with the
+        /// line in `$line` and its id, if any, in `$id`. Every line is also
+        /// appended to `received.log` in the plugin directory.
+        on_line: &'a str,
+        /// Leave `plugin.stop` unanswered and keep running.
+        ignore_stop: bool,
+    }
+
+    impl Script<'_> {
+        /// Write the plugin below `plugins_dir` and return its directory.
+        fn write(&self, plugins_dir: &Path) -> PathBuf {
+            let metadata = json!({
+                "id": self.id, "name": self.id, "version": "0.1.0",
+                "features": self.features, "provides": self.provides,
+            });
+            let init = Envelope::request("init", "plugin.init", json!({ "metadata": metadata }));
+            let start = Envelope::request("start", "plugin.start", json!({}));
+            let stop = if self.ignore_stop {
+                String::new()
+            } else {
+                format!(r#"case "$line" in *'"plugin.stop"'*) {REPLY}; exit 0;; esac"#)
+            };
+            let script = format!(
+                "#!/bin/sh\n\
+                 read -r hello\n\
+                 echo '{}'\n\
+                 read -r init\n\
+                 echo '{}'\n\
+                 read -r start\n\
+                 read -r ready\n\
+                 {}\n\
+                 while read -r line; do\n\
+                 printf '%s\\n' \"$line\" >> received.log\n\
+                 id=$(printf '%s' \"$line\" | sed -n 's/^{{\"id\":\"\\([^\"]*\\)\".*/\\1/p')\n\
+                 {}\n\
+                 {}\n\
+                 done\n",
+                serde_json::to_string(&init).unwrap(),
+                serde_json::to_string(&start).unwrap(),
+                self.start,
+                stop,
+                self.on_line,
+            );
+            write_script_plugin(plugins_dir, self.id, self.needs, self.permissions, &script)
+        }
+    }
+
+    fn write_script_plugin(
+        plugins_dir: &Path,
+        id: &str,
+        needs: &[&str],
+        permissions: &[&str],
+        script: &str,
+    ) -> PathBuf {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = plugins_dir.join(id);
+        std::fs::create_dir_all(&dir).unwrap();
+        let manifest = format!(
+            "id = \"{id}\"\nname = \"{id}\"\nversion = \"0.1.0\"\napi_version = \"1\"\n\
+             exec = \"./run.sh\"\nneeds = {needs:?}\npermissions = {permissions:?}\n"
+        );
+        std::fs::write(dir.join("plugin.toml"), manifest).unwrap();
+        let exec = dir.join("run.sh");
+        std::fs::write(&exec, script).unwrap();
+        std::fs::set_permissions(&exec, std::fs::Permissions::from_mode(0o755)).unwrap();
+        dir
+    }
+
+    /// Answer a request with `{"ok": true}`, for `Script::on_line`.
+    const REPLY: &str = r#"printf '{"id":"%s","kind":"response","result":{"ok":true}}\n' "$id""#;
+
+    fn script_manager(root: &Path) -> PluginManager {
+        PluginManager::discover(root.into(), root.join("plugins"))
+            .unwrap()
+            .with_data_dir(root.join("data"))
+            .with_log_files(false)
+    }
+
+    /// Wait until a line of `path` contains `needle` and return that line.
+    async fn wait_for_line(path: &Path, needle: &str) -> String {
+        for _ in 0..250 {
+            let text = std::fs::read_to_string(path).unwrap_or_default();
+            if let Some(line) = text.lines().find(|l| l.contains(needle)) {
+                return line.to_string();
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("no line containing {needle} in {}", path.display());
+    }
+
+    /// Wait until the state of `plugin_id` satisfies `f`.
+    async fn wait_for_state(
+        manager: &PluginManager,
+        plugin_id: &str,
+        f: impl Fn(&PluginState) -> bool,
+    ) -> PluginState {
+        for _ in 0..250 {
+            let state = manager.state(plugin_id).unwrap();
+            if f(&state) {
+                return state;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("{plugin_id} stuck in {:?}", manager.state(plugin_id));
+    }
+
+    #[tokio::test]
+    async fn crashed_plugin_releases_and_reclaims_its_namespaces() {
+        let root = tempfile::tempdir().unwrap();
+        Script {
+            id: "lamp",
+            features: &[features::RPC],
+            provides: &["lamp.*"],
+            on_line: r#"case "$line" in *'"lamp.crash"'*) exit 1;; esac"#,
+            ..Default::default()
+        }
+        .write(&root.path().join("plugins"));
+        let mut manager = script_manager(root.path()).with_restart_policy(RestartPolicy {
+            initial_backoff: Duration::from_millis(300),
+            ..Default::default()
+        });
+        let core = manager.core_handle();
+        assert!(manager.start_all().await.failed.is_empty());
+        assert_eq!(manager.hub.router.lock().owner("lamp.on"), Some("lamp"));
+
+        let _ = core.call("lamp.crash", json!({}), None).await;
+        wait_for_state(&manager, "lamp", |s| s.status != PluginStatus::Running).await;
+        assert_eq!(manager.hub.router.lock().owner("lamp.on"), None);
+        let err = core.call("lamp.on", json!({}), None).await.unwrap_err();
+        assert_eq!(err.code, RpcError::METHOD_NOT_FOUND);
+
+        wait_for_state(&manager, "lamp", |s| s.restarts == 1).await;
+        assert_eq!(manager.hub.router.lock().owner("lamp.on"), Some("lamp"));
+        manager.shutdown(Duration::from_secs(1)).await;
+    }
+
+    #[tokio::test]
+    async fn failed_handshake_releases_claimed_namespaces() {
+        let root = tempfile::tempdir().unwrap();
+        let init = Envelope::request(
+            "init",
+            "plugin.init",
+            json!({"metadata": {
+                "id": "lamp", "name": "lamp", "version": "0.1.0",
+                "features": ["rpc"], "provides": ["lamp.*"],
+            }}),
+        );
+        // exits after plugin.init instead of sending plugin.start
+        let script = format!(
+            "#!/bin/sh\nread -r hello\necho '{}'\nread -r init\n",
+            serde_json::to_string(&init).unwrap()
+        );
+        write_script_plugin(&root.path().join("plugins"), "lamp", &[], &[], &script);
+        let mut manager = script_manager(root.path());
+
+        let report = manager.start_all().await;
+        assert_eq!(report.failed.len(), 1, "{report:?}");
+        assert_eq!(manager.state("lamp").unwrap().status, PluginStatus::Failed);
+        assert_eq!(manager.hub.router.lock().owner("lamp.on"), None);
+    }
+
+    #[tokio::test]
+    async fn crashed_plugin_is_restarted() {
+        let root = tempfile::tempdir().unwrap();
+        let on_line = format!(
+            r#"case "$line" in *'"lamp.crash"'*) exit 3;; *'"lamp.ping"'*) {REPLY};; esac"#
+        );
+        Script {
+            id: "lamp",
+            features: &[features::RPC],
+            provides: &["lamp.*"],
+            on_line: &on_line,
+            ..Default::default()
+        }
+        .write(&root.path().join("plugins"));
+        let mut manager = script_manager(root.path()).with_restart_policy(RestartPolicy {
+            initial_backoff: Duration::from_millis(300),
+            ..Default::default()
+        });
+        let core = manager.core_handle();
+        assert!(manager.start_all().await.failed.is_empty());
+
+        let _ = core.call("lamp.crash", json!({}), None).await;
+        let state = wait_for_state(&manager, "lamp", |s| s.status == PluginStatus::Crashed).await;
+        assert!(
+            state
+                .last_exit
+                .as_deref()
+                .unwrap()
+                .contains("exit status: 3"),
+            "{state:?}"
+        );
+        // `plugin list` in another process reads the status file
+        let report: StatusReport = serde_json::from_slice(
+            &std::fs::read(root.path().join("data").join(STATUS_FILE)).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(report.plugins["lamp"].status, PluginStatus::Crashed);
+
+        wait_for_state(&manager, "lamp", |s| {
+            s.status == PluginStatus::Running && s.restarts == 1
+        })
+        .await;
+        assert_eq!(
+            core.call("lamp.ping", json!({}), None).await.unwrap(),
+            json!({"ok": true})
+        );
+        manager.shutdown(Duration::from_secs(1)).await;
+    }
+
+    #[tokio::test]
+    async fn requests_to_a_crashed_plugin_fail() {
+        let root = tempfile::tempdir().unwrap();
+        Script {
+            id: "lamp",
+            features: &[features::RPC],
+            provides: &["lamp.*"],
+            // dies while the request is pending
+            on_line: r#"case "$line" in *'"lamp.crash"'*) exit 1;; esac"#,
+            ..Default::default()
+        }
+        .write(&root.path().join("plugins"));
+        let mut manager = script_manager(root.path());
+        let core = manager.core_handle();
+        assert!(manager.start_all().await.failed.is_empty());
+
+        let err = core.call("lamp.crash", json!({}), None).await.unwrap_err();
+        assert_eq!(err.code, RpcError::TARGET_UNAVAILABLE, "{err:?}");
+        assert!(manager.plugins["lamp"].pending.lock().is_empty());
+        manager.shutdown(Duration::from_secs(1)).await;
+    }
+
+    #[tokio::test]
+    async fn shutdown_stops_plugins_in_reverse_start_order() {
+        let root = tempfile::tempdir().unwrap();
+        let plugins = root.path().join("plugins");
+        for (id, needs) in [
+            ("lamp", &[][..]),
+            ("scenes", &["lamp"][..]),
+            ("panel", &["scenes"][..]),
+        ] {
+            Script {
+                id,
+                needs,
+                ..Default::default()
+            }
+            .write(&plugins);
+        }
+        let mut manager = script_manager(root.path());
+        let report = manager.start_all().await;
+        assert_eq!(report.started, vec!["lamp", "scenes", "panel"]);
+
+        let reports = manager.shutdown(Duration::from_secs(1)).await;
+        let order: Vec<_> = reports.iter().map(|r| r.plugin_id.as_str()).collect();
+        assert_eq!(order, vec!["panel", "scenes", "lamp"]);
+    }
+
+    #[tokio::test]
+    async fn plugin_acknowledging_stop_is_stopped() {
+        let root = tempfile::tempdir().unwrap();
+        Script {
+            id: "lamp",
+            ..Default::default()
+        }
+        .write(&root.path().join("plugins"));
+        let mut manager = script_manager(root.path());
+        assert!(manager.start_all().await.failed.is_empty());
+
+        let reports = manager.shutdown(Duration::from_secs(5)).await;
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].outcome, StopOutcome::Stopped);
+        assert!(reports[0].elapsed < Duration::from_secs(5));
+        assert_eq!(manager.state("lamp").unwrap().status, PluginStatus::Stopped);
+        assert_eq!(
+            manager.stop("lamp", Duration::from_secs(1)).await,
+            StopOutcome::NotRunning
+        );
+    }
+
+    #[tokio::test]
+    async fn plugin_ignoring_stop_is_killed_after_grace() {
+        let root = tempfile::tempdir().unwrap();
+        Script {
+            id: "lamp",
+            ignore_stop: true,
+            ..Default::default()
         }
-        let resp = rx.await?;
-        if let Some(err) = resp.error {
-            anyhow::bail!(err.message);
+        .write(&root.path().join("plugins"));
+        let mut manager = script_manager(root.path());
+        assert!(manager.start_all().await.failed.is_empty());
+
+        let grace = Duration::from_millis(300);
+        let reports = manager.shutdown(grace).await;
+        assert_eq!(reports[0].outcome, StopOutcome::Killed);
+        assert!(reports[0].elapsed >= grace, "{:?}", reports[0]);
+        assert_eq!(manager.state("lamp").unwrap().status, PluginStatus::Stopped);
+    }
+
+    /// The response to request `id` the script plugin in `dir` received.
+    async fn response(dir: &Path, id: &str) -> Envelope {
+        let line = wait_for_line(&dir.join("received.log"), &format!(r#""id":"{id}""#)).await;
+        serde_json::from_str(&line).unwrap()
+    }
+
+    fn audit_lines(root: &Path) -> Vec<Value> {
+        std::fs::read_to_string(root.join("data").join(permissions::AUDIT_FILE))
+            .unwrap()
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn storage_without_permission_is_denied_and_audited() {
+        let root = tempfile::tempdir().unwrap();
+        let dir = Script {
+            id: "lamp",
+            permissions: &["log", "event"],
+            start: r#"echo '{"id":"s1","kind":"request","method":"storage.get","params":{"key":"k"}}'"#,
+            ..Default::default()
+        }
+        .write(&root.path().join("plugins"));
+        let mut manager = script_manager(root.path());
+        assert!(manager.start_all().await.failed.is_empty());
+
+        let resp = response(&dir, "s1").await;
+        assert_eq!(resp.error.unwrap().code, RpcError::PERMISSION_DENIED);
+        let audit = audit_lines(root.path());
+        assert_eq!(audit.len(), 1);
+        assert_eq!(audit[0]["plugin"], "lamp");
+        assert_eq!(audit[0]["action"], "storage.get");
+        assert_eq!(audit[0]["permission"], "storage");
+        manager.shutdown(Duration::from_secs(1)).await;
+    }
+
+    #[tokio::test]
+    async fn publishing_without_permission_is_denied_and_audited() {
+        let root = tempfile::tempdir().unwrap();
+        // the event is handled before the request, which is answered
+        let start = r#"echo '{"kind":"event","topic":"lamp.on"}'
+echo '{"id":"e1","kind":"request","method":"event.publish","params":{"topic":"lamp.on"}}'"#;
+        let dir = Script {
+            id: "lamp",
+            permissions: &["storage"],
+            start,
+            ..Default::default()
         }
-        Ok(resp.result.unwrap_or(Value::Null))
+        .write(&root.path().join("plugins"));
+        let mut manager = script_manager(root.path());
+        assert!(manager.start_all().await.failed.is_empty());
+
+        let resp = response(&dir, "e1").await;
+        assert_eq!(resp.error.unwrap().code, RpcError::PERMISSION_DENIED);
+        let audit = audit_lines(root.path());
+        let actions: Vec<_> = audit
+            .iter()
+            .map(|l| l["action"].as_str().unwrap())
+            .collect();
+        assert_eq!(actions, vec!["publish lamp.on", "event.publish"]);
+        assert!(audit.iter().all(|l| l["permission"] == "event"));
+        manager.shutdown(Duration::from_secs(1)).await;
+    }
+
+    /// Answers `<ns>.ping` and leaves everything else unanswered.
+    fn ping_only() -> String {
+        format!(r#"case "$line" in *'.ping"'*) {REPLY};; esac"#)
+    }
+
+    #[tokio::test]
+    async fn optional_features_are_used_only_if_negotiated() {
+        let root = tempfile::tempdir().unwrap();
+        let plugins = root.path().join("plugins");
+        let on_line = ping_only();
+        Script {
+            id: "lamp",
+            features: &[features::RPC],
+            provides: &["lamp.*"],
+            on_line: &on_line,
+            ..Default::default()
+        }
+        .write(&plugins);
+        let start = r#"echo '{"id":"ping","kind":"request","method":"lamp.ping","params":{}}'
+echo '{"id":"wild","kind":"request","method":"event.subscribe","params":{"topics":["lamp.*"]}}'
+echo '{"id":"exact","kind":"request","method":"event.subscribe","params":{"topics":["lamp.on"]}}'"#;
+        let panel = Script {
+            id: "panel",
+            needs: &["lamp"],
+            permissions: &["event"],
+            start,
+            ..Default::default()
+        }
+        .write(&plugins);
+        let mut manager = script_manager(root.path());
+        assert!(manager.start_all().await.failed.is_empty());
+
+        let ping = response(&panel, "ping").await;
+        assert_eq!(ping.error.unwrap().code, RpcError::METHOD_NOT_FOUND);
+        let wild = response(&panel, "wild").await;
+        assert_eq!(wild.error.unwrap().code, RpcError::INCOMPATIBLE_VERSION);
+        let exact = response(&panel, "exact").await;
+        assert_eq!(exact.result.unwrap()["topics"], json!(["lamp.on"]));
+        manager.shutdown(Duration::from_secs(1)).await;
+    }
+
+    #[tokio::test]
+    async fn timer_permission_is_enough_to_receive_ticks() {
+        let root = tempfile::tempdir().unwrap();
+        let start = r#"echo '{"id":"sub","kind":"request","method":"event.subscribe","params":{"topics":["timer.tick"]}}'
+echo '{"id":"other","kind":"request","method":"event.subscribe","params":{"topics":["lamp.on"]}}'
+echo '{"id":"set","kind":"request","method":"timer.set_timeout","params":{"id":"t","millis":50}}'"#;
+        let dir = Script {
+            id: "chat",
+            permissions: &["storage", "timer", "log"],
+            start,
+            ..Default::default()
+        }
+        .write(&root.path().join("plugins"));
+        let mut manager = script_manager(root.path());
+        assert!(manager.start_all().await.failed.is_empty());
+
+        let sub = response(&dir, "sub").await;
+        assert_eq!(sub.result.unwrap()["topics"], json!([timer::TICK_TOPIC]));
+        let other = response(&dir, "other").await;
+        assert_eq!(other.error.unwrap().code, RpcError::PERMISSION_DENIED);
+        assert!(response(&dir, "set").await.error.is_none());
+        let tick = wait_for_line(&dir.join("received.log"), r#""topic":"timer.tick""#).await;
+        let tick: Envelope = serde_json::from_str(&tick).unwrap();
+        assert_eq!(tick.payload.unwrap()["id"], "t");
+        manager.shutdown(Duration::from_secs(1)).await;
+    }
+
+    #[tokio::test]
+    async fn plugin_stuck_in_handshake_is_killed() {
+        let root = tempfile::tempdir().unwrap();
+        let plugins = root.path().join("plugins");
+        // never sends plugin.init
+        let script = "#!/bin/sh\necho $$ > pid\nwhile read -r line; do :; done\n";
+        let dir = write_script_plugin(&plugins, "mute", &[], &[], script);
+        Script {
+            id: "lamp",
+            ..Default::default()
+        }
+        .write(&plugins);
+        let mut manager = script_manager(root.path()).with_timeout(Duration::from_millis(300));
+
+        let report = manager.start_all().await;
+        assert_eq!(report.started, vec!["lamp"]);
+        assert_eq!(report.failed[0].0, "mute");
+        assert!(
+            report.failed[0].1.contains("no plugin.init request within"),
+            "{report:?}"
+        );
+        assert_eq!(manager.state("mute").unwrap().status, PluginStatus::Failed);
+        let pid: u32 = std::fs::read_to_string(dir.join("pid"))
+            .unwrap()
+            .trim()
+            .parse()
+            .unwrap();
+        assert!(!process_alive(pid));
+        manager.shutdown(Duration::from_secs(1)).await;
+    }
+
+    #[tokio::test]
+    async fn slow_target_times_out() {
+        let root = tempfile::tempdir().unwrap();
+        Script {
+            id: "slow",
+            features: &[features::RPC],
+            provides: &["slow.*"],
+            ..Default::default()
+        }
+        .write(&root.path().join("plugins"));
+        let mut manager = script_manager(root.path());
+        assert!(manager.start_all().await.failed.is_empty());
+
+        let timeout = Duration::from_millis(200);
+        let err = manager
+            .call_with_timeout("slow", "slow.wait", json!({}), timeout)
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(err, CallError::Timeout(t) if t == timeout),
+            "{err:?}"
+        );
+        assert!(manager.plugins["slow"].pending.lock().is_empty());
+        let err = manager
+            .core_handle()
+            .call("slow.wait", json!({}), Some(timeout))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code, RpcError::TIMEOUT);
+        assert!(manager.plugins["slow"].pending.lock().is_empty());
+        manager.shutdown(Duration::from_secs(1)).await;
+    }
+
+    #[tokio::test]
+    async fn timed_out_requests_are_cancelled_if_negotiated() {
+        let root = tempfile::tempdir().unwrap();
+        let plugins = root.path().join("plugins");
+        let on_line = ping_only();
+        let keen = Script {
+            id: "keen",
+            features: &[features::CANCEL],
+            on_line: &on_line,
+            ..Default::default()
+        }
+        .write(&plugins);
+        let plain = Script {
+            id: "plain",
+            on_line: &on_line,
+            ..Default::default()
+        }
+        .write(&plugins);
+        let mut manager = script_manager(root.path());
+        assert!(manager.start_all().await.failed.is_empty());
+
+        for id in ["keen", "plain"] {
+            let timeout = Duration::from_millis(100);
+            let wait = format!("{id}.wait");
+            let err = manager
+                .call_with_timeout(id, &wait, json!({}), timeout)
+                .await;
+            assert!(matches!(err, Err(CallError::Timeout(_))), "{err:?}");
+            // the plugin reads its input in order, so once the ping is
+            // answered, a `$/cancel` sent before it has been received
+            let ping = format!("{id}.ping");
+            manager.call(id, &ping, json!({})).await.unwrap();
+        }
+
+        let received = |dir: &Path| -> Vec<Envelope> {
+            std::fs::read_to_string(dir.join("received.log"))
+                .unwrap()
+                .lines()
+                .map(|l| serde_json::from_str(l).unwrap())
+                .collect()
+        };
+        let keen = received(&keen);
+        let wait = keen
+            .iter()
+            .find(|e| e.method.as_deref() == Some("keen.wait"))
+            .unwrap();
+        let cancel = keen
+            .iter()
+            .find(|e| e.method.as_deref() == Some(CANCEL_METHOD))
+            .unwrap();
+        assert_eq!(cancel.id, None);
+        assert_eq!(cancel.params, Some(json!({"id": wait.id})));
+        assert!(received(&plain)
+            .iter()
+            .all(|e| e.method.as_deref() != Some(CANCEL_METHOD)));
+        manager.shutdown(Duration::from_secs(1)).await;
+    }
+
+    #[tokio::test]
+    async fn caller_can_cancel_a_forwarded_request() {
+        let root = tempfile::tempdir().unwrap();
+        let plugins = root.path().join("plugins");
+        let slow = Script {
+            id: "slow",
+            features: &[features::RPC, features::CANCEL],
+            provides: &["slow.*"],
+            ..Default::default()
+        }
+        .write(&plugins);
+        let start = r#"echo '{"id":"c1","kind":"request","method":"slow.wait","params":{}}'
+echo '{"kind":"request","method":"$/cancel","params":{"id":"c1"}}'"#;
+        let panel = Script {
+            id: "panel",
+            needs: &["slow"],
+            features: &[features::RPC, features::CANCEL],
+            start,
+            ..Default::default()
+        }
+        .write(&plugins);
+        let mut manager = script_manager(root.path());
+        assert_eq!(manager.start_all().await.started, vec!["slow", "panel"]);
+
+        let resp = response(&panel, "c1").await;
+        assert_eq!(resp.error.unwrap().code, RpcError::CANCELLED);
+        // the target is told to stop working on the forwarded request
+        let line = wait_for_line(&slow.join("received.log"), CANCEL_METHOD).await;
+        let cancel: Envelope = serde_json::from_str(&line).unwrap();
+        let forwarded = wait_for_line(&slow.join("received.log"), "slow.wait").await;
+        let forwarded: Envelope = serde_json::from_str(&forwarded).unwrap();
+        assert_ne!(forwarded.id.as_deref(), Some("c1"));
+        assert_eq!(cancel.params, Some(json!({"id": forwarded.id})));
+        assert!(manager.plugins["slow"].pending.lock().is_empty());
+        manager.shutdown(Duration::from_secs(1)).await;
+    }
+
+    fn write_manifest(dir: &Path, id: &str, needs: &[&str]) {
+        let dir = dir.join(id);
+        std::fs::create_dir_all(&dir).unwrap();
+        let manifest = format!(
+            "id = \"{id}\"\nname = \"{id}\"\nversion = \"0.1.0\"\n\
+             api_version = \"1\"\nexec = \"{id}\"\nneeds = {needs:?}\n"
+        );
+        std::fs::write(dir.join("plugin.toml"), manifest).unwrap();
+    }
+
+    #[tokio::test]
+    async fn disabled_plugins_are_not_started() {
+        let root = tempfile::tempdir().unwrap();
+        let (first, second) = (root.path().join("a"), root.path().join("b"));
+        write_manifest(&first, "lamp", &[]);
+        write_manifest(&second, "lamp", &[]);
+        write_manifest(&second, "scenes", &["lamp"]);
+        let mut manager =
+            PluginManager::discover_dirs(root.path().into(), &[first.clone(), second])
+                .unwrap()
+                .with_data_dir(root.path().join("data"))
+                .with_log_files(false)
+                .with_disabled(["lamp".to_string()]);
+        assert_eq!(manager.plugins["lamp"].dir, first.join("lamp"));
+
+        let report = manager.start_all().await;
+        assert_eq!(report.disabled, vec!["lamp"]);
+        assert_eq!(report.skipped.len(), 1);
+        assert_eq!(report.skipped[0].0, "scenes");
+        assert!(report.started.is_empty());
+        assert_eq!(
+            manager.state("lamp").unwrap().status,
+            PluginStatus::Disabled
+        );
+    }
+
+    #[tokio::test]
+    async fn checks_settings_against_the_schema() {
+        let root = tempfile::tempdir().unwrap();
+        write_manifest(root.path(), "lamp", &[]);
+        let schema = json!({
+            "type": "object",
+            "additionalProperties": false,
+            "properties": {"level": {"type": "integer", "default": 1}},
+        });
+        std::fs::write(
+            root.path().join("lamp").join(settings::SCHEMA_FILE),
+            schema.to_string(),
+        )
+        .unwrap();
+        let colour = json!({"colour": "red"}).as_object().unwrap().clone();
+        let mut manager = PluginManager::discover(root.path().into(), root.path().into())
+            .unwrap()
+            .with_data_dir(root.path().join("data"))
+            .with_log_files(false)
+            .with_settings([("lamp".to_string(), colour.clone())]);
+
+        let report = manager.start_all().await;
+        assert!(
+            report.failed[0]
+                .1
+                .contains("settings.colour: unknown setting"),
+            "{report:?}"
+        );
+        assert!(manager.update_settings("lamp", colour).await.is_err());
+        assert!(manager.update_settings("lamp", Map::new()).await.unwrap());
+        assert_eq!(
+            *manager.plugins["lamp"].settings.lock(),
+            json!({"level": 1})
+        );
+        assert!(!manager.update_settings("lamp", Map::new()).await.unwrap());
     }
 }
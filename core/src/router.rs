@@ -0,0 +1,124 @@
+use std::collections::BTreeMap;
+
+/// Namespaces served by the core itself, which plugins may not claim.
+pub const RESERVED_NAMESPACES: &[&str] = &[
+    "core",
+    "plugin",
+    "system",
+    "config",
+    "log",
+    "event",
+    "timer",
+    "storage",
+    "device",
+    "state",
+    "automation",
+    "scene",
+];
+
+/// Table of method namespaces served by plugins, used to route requests one
+/// plugin sends to another.
+#[derive(Debug, Default)]
+pub struct Router {
+    namespaces: BTreeMap<String, String>,
+}
+
+/// Normalise a namespace declaration such as `chat.*` or `chat` to `chat`.
+pub fn parse_namespace(decl: &str) -> Result<String, String> {
+    let ns = decl.strip_suffix(".*").unwrap_or(decl);
+    if ns.is_empty()
+        || ns
+            .split('.')
+            .any(|seg| seg.is_empty() || seg.contains(['*', '#']))
+    {
+        return Err(format!("invalid namespace {decl:?}"));
+    }
+    let root = ns.split('.').next().unwrap_or(ns);
+    if RESERVED_NAMESPACES.contains(&root) {
+        return Err(format!("namespace {decl:?} is reserved by the core"));
+    }
+    Ok(ns.to_string())
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replace the namespaces served by `plugin_id`. Fails without changing
+    /// anything if a declaration is invalid or already claimed by another
+    /// plugin.
+    pub fn claim(&mut self, plugin_id: &str, decls: &[String]) -> Result<Vec<String>, String> {
+        let mut claimed = Vec::new();
+        for decl in decls {
+            let ns = parse_namespace(decl)?;
+            if let Some(owner) = self.namespaces.get(&ns) {
+                if owner != plugin_id {
+                    return Err(format!("namespace {ns} is already served by {owner}"));
+                }
+            }
+            claimed.push(ns);
+        }
+        self.namespaces.retain(|_, owner| owner != plugin_id);
+        for ns in &claimed {
+            self.namespaces.insert(ns.clone(), plugin_id.to_string());
+        }
+        Ok(claimed)
+    }
+
+    /// Drop all namespaces served by `plugin_id`.
+    pub fn release(&mut self, plugin_id: &str) {
+        self.namespaces.retain(|_, owner| owner != plugin_id);
+    }
+
+    /// The plugin serving `method`, picking the most specific namespace.
+    pub fn owner(&self, method: &str) -> Option<&str> {
+        self.namespaces
+            .iter()
+            .filter(|(ns, _)| {
+                method
+                    .strip_prefix(ns.as_str())
+                    .is_some_and(|rest| rest.starts_with('.'))
+            })
+            .max_by_key(|(ns, _)| ns.len())
+            .map(|(_, owner)| owner.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decls(v: &[&str]) -> Vec<String> {
+        v.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn routes_to_most_specific_namespace() {
+        let mut r = Router::new();
+        r.claim("chat", &decls(&["chat.*"])).unwrap();
+        r.claim("rooms", &decls(&["chat.rooms"])).unwrap();
+        assert_eq!(r.owner("chat.send"), Some("chat"));
+        assert_eq!(r.owner("chat.rooms.list"), Some("rooms"));
+        assert_eq!(r.owner("chatter.send"), None);
+        assert_eq!(r.owner("chat"), None);
+    }
+
+    #[test]
+    fn rejects_conflicts_and_reserved_namespaces() {
+        let mut r = Router::new();
+        r.claim("a", &decls(&["sample.*"])).unwrap();
+        assert!(r.claim("b", &decls(&["other", "sample"])).is_err());
+        assert_eq!(r.owner("other.x"), None);
+        assert!(r.claim("b", &decls(&["storage.*"])).is_err());
+        assert!(r.claim("b", &decls(&["x.*.y"])).is_err());
+
+        // a plugin may re-declare its own namespaces, e.g. after a restart
+        assert_eq!(
+            r.claim("a", &decls(&["sample.*", "demo"])).unwrap().len(),
+            2
+        );
+        r.release("a");
+        assert_eq!(r.owner("sample.ping"), None);
+    }
+}
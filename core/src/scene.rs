@@ -0,0 +1,735 @@
+//! Scenes: target states for groups of entities, set in one go.
+//!
+//! Scenes are kept in `scenes.json` in the data directory. Activating a
+//! scene sends each entity the device commands that bring it to its target
+//! state, all entities at once, and remembers the states they had before so
+//! the last activation can be undone.
+
+use anyhow::{Context, Result};
+use parking_lot::Mutex;
+use plugin_api::{
+    device::{self, capabilities, Command, DeviceCommand, Domain, EntityState},
+    RpcError,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio::task::JoinSet;
+use tracing::info;
+
+use crate::{plugin_host::CoreHandle, services::parse};
+
+/// File in the data directory holding the scenes.
+pub const SCENES_FILE: &str = "scenes.json";
+
+/// Published after a scene was activated or undone, with `{id, ok}`.
+pub const ACTIVATED: &str = "scene.activated";
+
+/// Attributes scenes capture and restore, by domain. They are named like the
+/// capabilities needed to set them.
+fn scene_attributes(domain: Domain) -> &'static [&'static str] {
+    use capabilities::*;
+    match domain {
+        Domain::Light => &[BRIGHTNESS, COLOR_TEMP, COLOR],
+        Domain::Cover => &[POSITION],
+        Domain::Climate => &[TARGET_TEMPERATURE],
+        _ => &[],
+    }
+}
+
+/// The state a scene sets an entity to.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Target {
+    pub state: Value,
+    /// `brightness`, `color_temp` and `color` of lights, `position` of
+    /// covers and `target_temperature` of climate entities.
+    #[serde(default, skip_serializing_if = "Map::is_empty")]
+    pub attributes: Map<String, Value>,
+}
+
+impl Target {
+    /// The part of a reported state a scene can restore.
+    pub fn from_state(domain: Domain, state: &EntityState) -> Self {
+        let keep = scene_attributes(domain);
+        Self {
+            state: state.state.clone(),
+            attributes: state
+                .attributes
+                .iter()
+                .filter(|(k, _)| keep.contains(&k.as_str()))
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect(),
+        }
+    }
+
+    fn attribute<T: for<'de> Deserialize<'de>>(&self, name: &str) -> Result<Option<T>, String> {
+        self.attributes
+            .get(name)
+            .map(|v| serde_json::from_value(v.clone()))
+            .transpose()
+            .map_err(|e| format!("invalid {name}: {e}"))
+    }
+
+    /// The commands that bring an entity with `capabilities` from `current`
+    /// to this target, in order. Empty if it is there already. Attributes
+    /// the entity has no capability for are left alone.
+    pub fn commands(
+        &self,
+        domain: Domain,
+        capabilities: &[String],
+        current: Option<&EntityState>,
+    ) -> Result<Vec<Command>, String> {
+        use capabilities::*;
+        domain.check_state(&self.state)?;
+        if self.state == device::UNAVAILABLE {
+            return Err(format!("cannot set an entity {}", device::UNAVAILABLE));
+        }
+        let can = |c: &str| capabilities.iter().any(|have| have == c);
+        let state = self.state.as_str().unwrap_or_default();
+        let now = current.map(|c| &c.state);
+        let now_attr = |name: &str| current.and_then(|c| c.attributes.get(name));
+        // an attribute needs setting if the entity takes it, the scene sets
+        // it and the entity has a different value
+        let differs = |name: &str| {
+            can(name)
+                && self
+                    .attributes
+                    .get(name)
+                    .is_some_and(|v| now_attr(name) != Some(v))
+        };
+        let mut commands = Vec::new();
+        match domain {
+            Domain::Light | Domain::Switch if state == "off" => {
+                if now != Some(&self.state) {
+                    commands.push(Command::TurnOff);
+                }
+            }
+            Domain::Light | Domain::Switch => {
+                let dim = [BRIGHTNESS, COLOR_TEMP].iter().any(|a| differs(a));
+                if now != Some(&self.state) || dim {
+                    commands.push(Command::TurnOn {
+                        brightness: can(BRIGHTNESS)
+                            .then(|| self.attribute(BRIGHTNESS))
+                            .transpose()?
+                            .flatten(),
+                        color_temp: can(COLOR_TEMP)
+                            .then(|| self.attribute(COLOR_TEMP))
+                            .transpose()?
+                            .flatten(),
+                    });
+                }
+                if differs(COLOR) {
+                    if let Some(color) = self.attribute(COLOR)? {
+                        commands.push(Command::SetColor { color });
+                    }
+                }
+            }
+            Domain::Cover if can(POSITION) && self.attributes.contains_key(POSITION) => {
+                if differs(POSITION) {
+                    if let Some(position) = self.attribute(POSITION)? {
+                        commands.push(Command::SetPosition { position });
+                    }
+                }
+            }
+            Domain::Cover => match state {
+                "open" | "opening" if !matches!(now_str(now), Some("open" | "opening")) => {
+                    commands.push(Command::Open)
+                }
+                "closed" | "closing" if !matches!(now_str(now), Some("closed" | "closing")) => {
+                    commands.push(Command::Close)
+                }
+                _ => {}
+            },
+            Domain::Climate => {
+                if now != Some(&self.state) {
+                    commands.push(Command::SetHvacMode {
+                        hvac_mode: state.to_string(),
+                    });
+                }
+                if differs(TARGET_TEMPERATURE) {
+                    if let Some(temperature) = self.attribute(TARGET_TEMPERATURE)? {
+                        commands.push(Command::SetTargetTemperature { temperature });
+                    }
+                }
+            }
+            Domain::Sensor | Domain::BinarySensor => {
+                return Err(format!("{domain} entities cannot be set"));
+            }
+        }
+        for command in &commands {
+            command.check(domain, capabilities)?;
+        }
+        Ok(commands)
+    }
+}
+
+fn now_str(state: Option<&Value>) -> Option<&str> {
+    state.and_then(Value::as_str)
+}
+
+/// A named set of target states.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Scene {
+    pub id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Target states by entity id.
+    pub entities: BTreeMap<String, Target>,
+}
+
+impl Scene {
+    /// Check ids and that every target can be set, assuming entities have
+    /// all the capabilities of their domain.
+    pub fn check(&self) -> Result<(), String> {
+        if self.id.is_empty()
+            || !self
+                .id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        {
+            return Err(format!(
+                "invalid scene id {:?}, ids use letters, digits, _ and -",
+                self.id
+            ));
+        }
+        if self.entities.is_empty() {
+            return Err("a scene needs at least one entity".into());
+        }
+        for (entity_id, target) in &self.entities {
+            let (domain, _) = device::parse_entity_id(entity_id)?;
+            let all: Vec<String> = domain
+                .capabilities()
+                .iter()
+                .map(|c| c.to_string())
+                .collect();
+            target
+                .commands(domain, &all, None)
+                .map_err(|e| format!("{entity_id}: {e}"))?;
+        }
+        Ok(())
+    }
+}
+
+/// What activating a scene did to one entity.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct EntityResult {
+    pub entity_id: String,
+    pub ok: bool,
+    /// Names of the commands sent, in order; empty if the entity already
+    /// had its target state.
+    pub commands: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<RpcError>,
+}
+
+/// What activating a scene did.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Activation {
+    pub scene: String,
+    /// Whether every entity reached its target.
+    pub ok: bool,
+    pub results: Vec<EntityResult>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct StoreFile {
+    #[serde(default)]
+    scenes: BTreeMap<String, Scene>,
+}
+
+struct Inner {
+    core: CoreHandle,
+    file: PathBuf,
+    scenes: Mutex<BTreeMap<String, Scene>>,
+    /// The states the entities of the last activated scene had before.
+    previous: Mutex<Option<Scene>>,
+}
+
+/// The scenes, with the core to activate them through. Cheap to clone.
+#[derive(Clone)]
+pub struct Scenes(Arc<Inner>);
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct IdParams {
+    id: String,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CaptureParams {
+    id: String,
+    #[serde(default)]
+    name: Option<String>,
+    entity_ids: Vec<String>,
+}
+
+impl Scenes {
+    /// Read the scenes kept in `data_dir`.
+    pub fn load(core: CoreHandle, data_dir: &Path) -> Result<Self> {
+        let file = data_dir.join(SCENES_FILE);
+        let stored: StoreFile = match std::fs::read(&file) {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .with_context(|| format!("invalid scenes file {}", file.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => StoreFile::default(),
+            Err(e) => return Err(e).with_context(|| format!("reading {}", file.display())),
+        };
+        Ok(Self(Arc::new(Inner {
+            core,
+            file,
+            scenes: Mutex::new(stored.scenes),
+            previous: Mutex::new(None),
+        })))
+    }
+
+    fn store(&self, scenes: &BTreeMap<String, Scene>) -> Result<(), RpcError> {
+        let write = || -> Result<()> {
+            if let Some(dir) = self.0.file.parent() {
+                std::fs::create_dir_all(dir)?;
+            }
+            let tmp = self.0.file.with_extension("json.tmp");
+            let stored = json!({ "scenes": scenes });
+            std::fs::write(&tmp, serde_json::to_vec_pretty(&stored)?)?;
+            std::fs::rename(&tmp, &self.0.file)?;
+            Ok(())
+        };
+        write().map_err(|e| {
+            RpcError::new(
+                RpcError::INTERNAL_ERROR,
+                format!("writing {}: {e:#}", self.0.file.display()),
+            )
+        })
+    }
+
+    pub fn list(&self) -> Vec<Scene> {
+        self.0.scenes.lock().values().cloned().collect()
+    }
+
+    /// Add or replace a scene.
+    pub fn save(&self, scene: Scene) -> Result<(), RpcError> {
+        scene.check().map_err(invalid)?;
+        let mut scenes = self.0.scenes.lock();
+        let mut updated = scenes.clone();
+        updated.insert(scene.id.clone(), scene);
+        self.store(&updated)?;
+        *scenes = updated;
+        Ok(())
+    }
+
+    /// Save the current states of `entity_ids` as scene `id`.
+    pub fn capture(
+        &self,
+        id: &str,
+        name: Option<String>,
+        entity_ids: &[String],
+    ) -> Result<Scene, RpcError> {
+        let mut entities = BTreeMap::new();
+        for entity_id in entity_ids {
+            let (domain, _) = device::parse_entity_id(entity_id).map_err(invalid)?;
+            let state = self
+                .0
+                .core
+                .state(entity_id)
+                .ok_or_else(|| invalid(format!("{entity_id} has no state to capture")))?;
+            if state.state == device::UNAVAILABLE {
+                return Err(invalid(format!("{entity_id} is unavailable")));
+            }
+            entities.insert(entity_id.clone(), Target::from_state(domain, &state));
+        }
+        let scene = Scene {
+            id: id.to_string(),
+            name,
+            entities,
+        };
+        self.save(scene.clone())?;
+        Ok(scene)
+    }
+
+    pub fn delete(&self, id: &str) -> Result<(), RpcError> {
+        let mut scenes = self.0.scenes.lock();
+        if !scenes.contains_key(id) {
+            return Err(unknown(id));
+        }
+        let mut updated = scenes.clone();
+        updated.remove(id);
+        self.store(&updated)?;
+        *scenes = updated;
+        Ok(())
+    }
+
+    /// Bring the entities of scene `id` to their targets, remembering their
+    /// current states for [`Scenes::undo`].
+    pub async fn activate(&self, id: &str) -> Result<Activation, RpcError> {
+        let scene = self.0.scenes.lock().get(id).cloned();
+        let scene = scene.ok_or_else(|| unknown(id))?;
+        let mut before = BTreeMap::new();
+        for entity_id in scene.entities.keys() {
+            let Ok((domain, _)) = device::parse_entity_id(entity_id) else {
+                continue;
+            };
+            if let Some(state) = self.0.core.state(entity_id) {
+                if state.state != device::UNAVAILABLE {
+                    before.insert(entity_id.clone(), Target::from_state(domain, &state));
+                }
+            }
+        }
+        *self.0.previous.lock() = Some(Scene {
+            id: format!("undo-{id}"),
+            name: None,
+            entities: before,
+        });
+        Ok(self.apply(scene).await)
+    }
+
+    /// Restore the states the entities of the last activated scene had
+    /// before it.
+    pub async fn undo(&self) -> Result<Activation, RpcError> {
+        let previous = self.0.previous.lock().take();
+        let previous =
+            previous.ok_or_else(|| invalid("no scene activation to undo".to_string()))?;
+        Ok(self.apply(previous).await)
+    }
+
+    async fn apply(&self, scene: Scene) -> Activation {
+        let mut tasks = JoinSet::new();
+        for (entity_id, target) in scene.entities {
+            let core = self.0.core.clone();
+            tasks.spawn(async move { set_entity(&core, entity_id, target).await });
+        }
+        let mut results = Vec::new();
+        while let Some(result) = tasks.join_next().await {
+            if let Ok(result) = result {
+                results.push(result);
+            }
+        }
+        results.sort_by(|a, b| a.entity_id.cmp(&b.entity_id));
+        let ok = results.iter().all(|r| r.ok);
+        info!(
+            "scene {} set {} of {} entities",
+            scene.id,
+            results.iter().filter(|r| r.ok).count(),
+            results.len()
+        );
+        self.0
+            .core
+            .publish(ACTIVATED, Some(json!({ "id": scene.id, "ok": ok })));
+        Activation {
+            scene: scene.id,
+            ok,
+            results,
+        }
+    }
+
+    /// Serve a `scene.*` request.
+    pub async fn handle(&self, method: &str, params: Option<Value>) -> Result<Value, RpcError> {
+        match method {
+            "scene.list" => Ok(json!({ "scenes": self.list() })),
+            "scene.save" => {
+                let scene: Scene = parse(params)?;
+                self.save(scene)?;
+                Ok(json!({ "ok": true }))
+            }
+            "scene.capture" => {
+                let p: CaptureParams = parse(params)?;
+                let scene = self.capture(&p.id, p.name, &p.entity_ids)?;
+                Ok(json!({ "scene": scene }))
+            }
+            "scene.delete" => {
+                let p: IdParams = parse(params)?;
+                self.delete(&p.id)?;
+                Ok(json!({ "ok": true }))
+            }
+            "scene.activate" => {
+                let p: IdParams = parse(params)?;
+                Ok(json!(self.activate(&p.id).await?))
+            }
+            "scene.undo" => Ok(json!(self.undo().await?)),
+            _ => Err(RpcError::new(
+                RpcError::METHOD_NOT_FOUND,
+                format!("unknown method {}", method),
+            )),
+        }
+    }
+}
+
+/// Send one entity the commands it needs, in order, stopping at the first
+/// failure.
+async fn set_entity(core: &CoreHandle, entity_id: String, target: Target) -> EntityResult {
+    let mut result = EntityResult {
+        entity_id,
+        ok: false,
+        commands: Vec::new(),
+        error: None,
+    };
+    let planned = device::parse_entity_id(&result.entity_id).and_then(|(domain, _)| {
+        let capabilities = core
+            .capabilities(&result.entity_id)
+            .ok_or_else(|| format!("unknown entity {}", result.entity_id))?;
+        let current = core.state(&result.entity_id);
+        target.commands(domain, &capabilities, current.as_ref())
+    });
+    let commands = match planned {
+        Ok(commands) => commands,
+        Err(e) => {
+            result.error = Some(invalid(e));
+            return result;
+        }
+    };
+    for command in commands {
+        result.commands.push(command.name().to_string());
+        let command = DeviceCommand {
+            entity_id: result.entity_id.clone(),
+            command,
+        };
+        if let Err(e) = core.command(&command).await {
+            result.error = Some(e);
+            return result;
+        }
+    }
+    result.ok = true;
+    result
+}
+
+fn invalid(message: String) -> RpcError {
+    RpcError::new(RpcError::INVALID_PARAMS, message)
+}
+
+fn unknown(id: &str) -> RpcError {
+    invalid(format!("unknown scene {id}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PluginManager;
+    use plugin_api::device::{Device, Entity};
+
+    fn state(entity_id: &str, state: &str, attributes: Value) -> EntityState {
+        EntityState {
+            entity_id: entity_id.into(),
+            state: json!(state),
+            attributes: attributes.as_object().cloned().unwrap_or_default(),
+            last_changed_ms: 0,
+            last_updated_ms: 0,
+        }
+    }
+
+    fn target(value: Value) -> Target {
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn caps(names: &[&str]) -> Vec<String> {
+        names.iter().map(|c| c.to_string()).collect()
+    }
+
+    #[test]
+    fn derives_commands_from_the_current_state() {
+        use capabilities::*;
+        let dimmable = caps(&[BRIGHTNESS, COLOR]);
+        let dim =
+            target(json!({"state": "on", "attributes": {"brightness": 40, "color_temp": 300}}));
+        let commands = dim.commands(Domain::Light, &dimmable, None).unwrap();
+        // color_temp is dropped, the lamp cannot take it
+        assert_eq!(
+            commands,
+            vec![Command::TurnOn {
+                brightness: Some(40),
+                color_temp: None
+            }]
+        );
+        let now = state("light.desk", "on", json!({"brightness": 40}));
+        assert!(dim
+            .commands(Domain::Light, &dimmable, Some(&now))
+            .unwrap()
+            .is_empty());
+        let now = state("light.desk", "on", json!({"brightness": 200}));
+        assert_eq!(
+            dim.commands(Domain::Light, &dimmable, Some(&now))
+                .unwrap()
+                .len(),
+            1
+        );
+
+        let red = target(json!({"state": "on", "attributes": {"color": [255, 0, 0]}}));
+        let names: Vec<_> = red
+            .commands(Domain::Light, &dimmable, None)
+            .unwrap()
+            .iter()
+            .map(Command::name)
+            .collect();
+        assert_eq!(names, ["turn_on", "set_color"]);
+
+        let off = target(json!({"state": "off"}));
+        assert_eq!(
+            off.commands(Domain::Switch, &[], None).unwrap(),
+            vec![Command::TurnOff]
+        );
+
+        let half = target(json!({"state": "open", "attributes": {"position": 50}}));
+        assert_eq!(
+            half.commands(Domain::Cover, &caps(&[POSITION]), None)
+                .unwrap(),
+            vec![Command::SetPosition { position: 50 }]
+        );
+        assert_eq!(
+            half.commands(Domain::Cover, &[], None).unwrap(),
+            vec![Command::Open]
+        );
+
+        let heat = target(json!({"state": "heat", "attributes": {"target_temperature": 21.5}}));
+        let now = state("climate.hall", "heat", json!({"target_temperature": 19.0}));
+        assert_eq!(
+            heat.commands(
+                Domain::Climate,
+                &caps(&[HVAC_MODE, TARGET_TEMPERATURE]),
+                Some(&now)
+            )
+            .unwrap(),
+            vec![Command::SetTargetTemperature { temperature: 21.5 }]
+        );
+        let err = heat.commands(Domain::Climate, &[], None).unwrap_err();
+        assert!(err.contains("needs capability hvac_mode"), "{err}");
+
+        let err = target(json!({"state": "dim"}))
+            .commands(Domain::Light, &[], None)
+            .unwrap_err();
+        assert!(err.contains("invalid light state"), "{err}");
+        assert!(target(json!({"state": 3}))
+            .commands(Domain::Sensor, &[], None)
+            .is_err());
+    }
+
+    fn setup(dir: &Path) -> (Scenes, CoreHandle) {
+        let manager = PluginManager::discover(dir.into(), dir.into()).unwrap();
+        let core = manager.core_handle();
+        {
+            let mut registry = core.registry().lock();
+            let device = Device {
+                id: "hub".into(),
+                name: "Hub".into(),
+                manufacturer: None,
+                model: None,
+            };
+            let entities = ["light.sofa", "switch.tv", "sensor.temp"].map(|id| Entity {
+                entity_id: id.into(),
+                name: None,
+                capabilities: if id == "light.sofa" {
+                    caps(&[capabilities::BRIGHTNESS])
+                } else {
+                    vec![]
+                },
+            });
+            registry.register("hue", device, entities.to_vec()).unwrap();
+            let sofa = json!({"brightness": 255});
+            registry
+                .set_state(
+                    "hue",
+                    "light.sofa",
+                    json!("on"),
+                    sofa.as_object().cloned(),
+                    1,
+                )
+                .unwrap();
+            registry
+                .set_state("hue", "switch.tv", json!("on"), None, 1)
+                .unwrap();
+        }
+        (Scenes::load(core.clone(), dir).unwrap(), core)
+    }
+
+    #[tokio::test]
+    async fn captures_and_keeps_scenes() {
+        let dir = tempfile::tempdir().unwrap();
+        let (scenes, core) = setup(dir.path());
+        let ids = ["light.sofa".to_string(), "switch.tv".to_string()];
+        let scene = scenes.capture("evening", None, &ids).unwrap();
+        assert_eq!(
+            scene.entities["light.sofa"],
+            target(json!({"state": "on", "attributes": {"brightness": 255}}))
+        );
+
+        let err = scenes.capture("bad id", None, &ids).unwrap_err();
+        assert_eq!(err.code, RpcError::INVALID_PARAMS);
+        let err = scenes
+            .capture("x", None, &["light.missing".to_string()])
+            .unwrap_err();
+        assert!(err.message.contains("no state"), "{}", err.message);
+        let err = scenes
+            .handle(
+                "scene.save",
+                Some(json!({"id": "x", "entities": {"sensor.temp": {"state": 3}}})),
+            )
+            .await
+            .unwrap_err();
+        assert!(err.message.contains("cannot be set"), "{}", err.message);
+
+        // scenes survive a restart
+        let reloaded = Scenes::load(core, dir.path()).unwrap();
+        assert_eq!(reloaded.list(), vec![scene]);
+        reloaded.delete("evening").unwrap();
+        assert!(reloaded.delete("evening").is_err());
+        assert!(Scenes::load(reloaded.0.core.clone(), dir.path())
+            .unwrap()
+            .list()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn activates_per_entity_and_undoes() {
+        let dir = tempfile::tempdir().unwrap();
+        let (scenes, core) = setup(dir.path());
+        let events = {
+            let mut bus = core.bus().lock();
+            let rx = bus.register("test");
+            bus.subscribe("test", ACTIVATED);
+            rx
+        };
+        let movie = json!({"id": "movie", "entities": {
+            "light.sofa": {"state": "on", "attributes": {"brightness": 30}},
+            "switch.tv": {"state": "on"},
+            "light.gone": {"state": "off"},
+        }});
+        scenes.handle("scene.save", Some(movie)).await.unwrap();
+
+        // the owner is not running, so only the tv, already on, succeeds
+        let activation = scenes.activate("movie").await.unwrap();
+        assert!(!activation.ok);
+        let results: BTreeMap<_, _> = activation
+            .results
+            .iter()
+            .map(|r| (r.entity_id.as_str(), r))
+            .collect();
+        assert!(results["switch.tv"].ok);
+        assert!(results["switch.tv"].commands.is_empty());
+        assert_eq!(results["light.sofa"].commands, ["turn_on"]);
+        let err = results["light.sofa"].error.as_ref().unwrap();
+        assert_eq!(err.code, RpcError::TARGET_UNAVAILABLE);
+        let err = results["light.gone"].error.as_ref().unwrap();
+        assert!(err.message.contains("unknown entity"), "{}", err.message);
+        let mut events = events;
+        let event = events.recv().await.unwrap();
+        assert_eq!(event.payload, Some(json!({"id": "movie", "ok": false})));
+
+        // undo restores the states from before, once
+        let undo = scenes.handle("scene.undo", None).await.unwrap();
+        assert_eq!(undo["scene"], "undo-movie");
+        let restored: Vec<_> = undo["results"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|r| r["entity_id"].clone())
+            .collect();
+        assert_eq!(restored, [json!("light.sofa"), json!("switch.tv")]);
+        let err = scenes.undo().await.unwrap_err();
+        assert_eq!(err.code, RpcError::INVALID_PARAMS);
+
+        let err = scenes.activate("missing").await.unwrap_err();
+        assert!(err.message.contains("unknown scene"), "{}", err.message);
+    }
+}
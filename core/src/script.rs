@@ -0,0 +1,743 @@
+//! Scripts: house logic written in [Rhai](https://rhai.rs) and run inside
+//! the core.
+//!
+//! Every `*.rhai` file in the scripts directory is one script, named after
+//! the file. Its top level runs when the script is loaded and registers
+//! event handlers and timers; those then run as their events and ticks
+//! arrive, one at a time per script. Scripts reach the same event bus, state
+//! registry, plugins and timer service plugins do, and nothing else: they
+//! cannot read files, import modules or `eval` code. Changed files are
+//! reloaded while the core runs.
+
+use chrono::Utc;
+use parking_lot::Mutex;
+use plugin_api::{device::DeviceCommand, RpcError};
+use rhai::{
+    module_resolvers::DummyModuleResolver,
+    serde::{from_dynamic, to_dynamic},
+    Dynamic, Engine, EvalAltResult, FnPtr, AST,
+};
+use serde_json::{json, Value};
+use std::{
+    collections::{BTreeMap, HashMap},
+    future::Future,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+use tokio::{runtime::Handle, sync::mpsc::UnboundedReceiver, task::JoinHandle};
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    automation::rule::parse_duration,
+    events::{self, topic_matches, Event},
+    plugin_host::CoreHandle,
+    services::timer::{Home, Schedule, Target, TICK_TOPIC},
+};
+
+/// File extension of scripts.
+pub const EXTENSION: &str = "rhai";
+
+/// How often the scripts directory is checked for changed files.
+pub const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Bounds on each run of a script: its top level, or one event handler or
+/// timer callback. A run exceeding them is stopped with an error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// Rhai operations, roughly one per expression evaluated.
+    pub max_operations: u64,
+    /// Wall-clock time, including time spent waiting on calls.
+    pub timeout: Duration,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_operations: 1_000_000,
+            timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+type ScriptResult<T> = Result<T, Box<EvalAltResult>>;
+
+/// The source of the events script `name` publishes.
+pub fn subscriber_id(name: &str) -> String {
+    format!("$script/{name}")
+}
+
+/// The scripts in `dir` by name, in name order.
+pub fn script_files(dir: &Path) -> std::io::Result<Vec<(String, PathBuf)>> {
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|e| e.to_str()) != Some(EXTENSION) {
+            continue;
+        }
+        if let Some(name) = path.file_stem().and_then(|s| s.to_str()) {
+            files.push((name.to_string(), path.clone()));
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Compile a script without running it, reporting syntax errors.
+pub fn check(path: &Path) -> Result<(), String> {
+    let text = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+    sandbox(Limits::default())
+        .compile(&text)
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+/// An engine with the sandbox and limits applied but none of the core
+/// functions.
+fn sandbox(limits: Limits) -> Engine {
+    let mut engine = Engine::new();
+    engine
+        .set_module_resolver(DummyModuleResolver::new())
+        .set_max_operations(limits.max_operations)
+        .set_max_call_levels(64)
+        .set_max_expr_depths(64, 32)
+        .set_max_string_size(1 << 20)
+        .set_max_array_size(100_000)
+        .set_max_map_size(100_000)
+        .disable_symbol("eval");
+    engine
+}
+
+/// What the core functions of one script work on.
+struct Ctx {
+    name: String,
+    /// [`subscriber_id`] of the script.
+    id: String,
+    /// The id this version of the script has on the event bus and as the
+    /// owner of its timers, so a new version can start next to the old one.
+    owner: String,
+    core: CoreHandle,
+    home: Home,
+    runtime: Handle,
+    timeout: Duration,
+    /// Event handlers by topic pattern, in registration order.
+    handlers: Mutex<Vec<(String, FnPtr)>>,
+    /// Timer callbacks by timer id, and whether the timer fires once.
+    timers: Mutex<HashMap<String, (FnPtr, bool)>>,
+    next_timer: AtomicU64,
+    /// When the current run has to end.
+    deadline: Mutex<Instant>,
+}
+
+impl Ctx {
+    /// Wait for a call to a plugin or the core from a blocking run, for no
+    /// longer than the run has left.
+    fn wait<T>(&self, call: impl Future<Output = Result<T, RpcError>>) -> ScriptResult<T> {
+        let left = self
+            .deadline
+            .lock()
+            .saturating_duration_since(Instant::now());
+        match self.runtime.block_on(tokio::time::timeout(left, call)) {
+            Ok(Ok(value)) => Ok(value),
+            Ok(Err(e)) => Err(format!("{} ({})", e.message, e.code).into()),
+            Err(_) => Err("time limit exceeded".into()),
+        }
+    }
+
+    fn publish(&self, topic: &str, payload: Option<Dynamic>) -> ScriptResult<()> {
+        if !events::valid_topic(topic) {
+            return Err(format!("invalid topic {topic:?}").into());
+        }
+        let payload = payload.map(|p| from_dynamic::<Value>(&p)).transpose()?;
+        self.core.bus().lock().publish(Event {
+            topic: topic.to_string(),
+            source: Some(self.id.clone()),
+            payload,
+        });
+        Ok(())
+    }
+
+    fn request(&self, method: &str, params: Dynamic) -> ScriptResult<Dynamic> {
+        let params = if params.is_unit() {
+            json!({})
+        } else {
+            from_dynamic(&params)?
+        };
+        let result = self.wait(self.core.call(method, params, None))?;
+        to_dynamic(result)
+    }
+
+    fn command(&self, entity_id: &str, command: &str, fields: rhai::Map) -> ScriptResult<Dynamic> {
+        let mut params: Value = from_dynamic(&Dynamic::from_map(fields))?;
+        params["entity_id"] = json!(entity_id);
+        params["command"] = json!(command);
+        let command: DeviceCommand =
+            serde_json::from_value(params).map_err(|e| format!("invalid command: {e}"))?;
+        let result = self.wait(self.core.command(&command))?;
+        to_dynamic(result)
+    }
+
+    fn timer(
+        &self,
+        schedule: Result<Schedule, String>,
+        callback: FnPtr,
+        once: bool,
+    ) -> ScriptResult<String> {
+        let schedule = schedule?;
+        let id = format!("t{}", self.next_timer.fetch_add(1, Ordering::Relaxed) + 1);
+        self.timers.lock().insert(id.clone(), (callback, once));
+        let target = Target {
+            plugin_id: self.owner.clone(),
+            writer: None,
+            bus: self.core.bus().clone(),
+        };
+        self.core.timers().set(target, id.clone(), schedule);
+        Ok(id)
+    }
+}
+
+fn duration(s: &str) -> Result<Duration, String> {
+    parse_duration(s)
+        .filter(|d| !d.is_zero())
+        .ok_or_else(|| format!("invalid duration {s:?}, expected e.g. 30s, 5m or 1h30m"))
+}
+
+/// Register the functions scripts use to reach the core.
+fn register(engine: &mut Engine, ctx: &Arc<Ctx>) {
+    let c = ctx.clone();
+    engine.register_fn(
+        "on",
+        move |pattern: &str, handler: FnPtr| -> ScriptResult<()> {
+            if !events::valid_pattern(pattern) {
+                return Err(format!("invalid topic pattern {pattern:?}").into());
+            }
+            c.core.bus().lock().subscribe(&c.owner, pattern);
+            c.handlers.lock().push((pattern.to_string(), handler));
+            Ok(())
+        },
+    );
+    let c = ctx.clone();
+    engine.register_fn("state", move |entity_id: &str| -> ScriptResult<Dynamic> {
+        match c.core.state(entity_id) {
+            Some(state) => to_dynamic(state.state),
+            None => Ok(Dynamic::UNIT),
+        }
+    });
+    let c = ctx.clone();
+    engine.register_fn(
+        "attributes",
+        move |entity_id: &str| -> ScriptResult<Dynamic> {
+            match c.core.state(entity_id) {
+                Some(state) => to_dynamic(state.attributes),
+                None => Ok(Dynamic::UNIT),
+            }
+        },
+    );
+    let c = ctx.clone();
+    engine.register_fn("publish", move |topic: &str| c.publish(topic, None));
+    let c = ctx.clone();
+    engine.register_fn("publish", move |topic: &str, payload: Dynamic| {
+        c.publish(topic, Some(payload))
+    });
+    let c = ctx.clone();
+    engine.register_fn("request", move |method: &str| {
+        c.request(method, Dynamic::UNIT)
+    });
+    let c = ctx.clone();
+    engine.register_fn("request", move |method: &str, params: Dynamic| {
+        c.request(method, params)
+    });
+    let c = ctx.clone();
+    engine.register_fn("command", move |entity_id: &str, command: &str| {
+        c.command(entity_id, command, rhai::Map::new())
+    });
+    let c = ctx.clone();
+    engine.register_fn(
+        "command",
+        move |entity_id: &str, command: &str, fields: rhai::Map| {
+            c.command(entity_id, command, fields)
+        },
+    );
+    let c = ctx.clone();
+    engine.register_fn("after", move |delay: &str, callback: FnPtr| {
+        c.timer(duration(delay).map(Schedule::Timeout), callback, true)
+    });
+    let c = ctx.clone();
+    engine.register_fn("every", move |period: &str, callback: FnPtr| {
+        c.timer(duration(period).map(Schedule::Interval), callback, false)
+    });
+    let c = ctx.clone();
+    engine.register_fn("cron", move |expr: &str, callback: FnPtr| {
+        c.timer(Schedule::cron(expr, c.home.zone), callback, false)
+    });
+    let c = ctx.clone();
+    engine.register_fn("solar", move |spec: &str, callback: FnPtr| {
+        let schedule = match c.home.location {
+            Some(location) => Schedule::solar(spec, location, c.home.zone),
+            None => Err("solar timers need the home latitude and longitude".into()),
+        };
+        c.timer(schedule, callback, false)
+    });
+    let c = ctx.clone();
+    engine.register_fn("cancel", move |timer: &str| {
+        c.timers.lock().remove(timer);
+        c.core.timers().cancel(&c.owner, timer)
+    });
+    engine.register_fn("now_ms", || Utc::now().timestamp_millis());
+
+    let name = ctx.name.clone();
+    engine.on_print(move |text| info!("script {name}: {text}"));
+    let name = ctx.name.clone();
+    engine.on_debug(move |text, _, pos| debug!("script {name}: {text} ({pos})"));
+    let c = ctx.clone();
+    engine.on_progress(move |ops| {
+        // reading the clock on every operation would slow scripts down
+        (ops.is_multiple_of(1024) && Instant::now() > *c.deadline.lock())
+            .then(|| Dynamic::from("time limit exceeded"))
+    });
+}
+
+/// A compiled script with its engine.
+struct Script {
+    ctx: Arc<Ctx>,
+    engine: Engine,
+    ast: AST,
+}
+
+impl Script {
+    fn compile(
+        name: &str,
+        version: u64,
+        text: &str,
+        core: CoreHandle,
+        home: Home,
+        limits: Limits,
+    ) -> Result<Self, String> {
+        let ctx = Arc::new(Ctx {
+            name: name.to_string(),
+            id: subscriber_id(name),
+            owner: format!("{}#{version}", subscriber_id(name)),
+            core,
+            home,
+            runtime: Handle::current(),
+            timeout: limits.timeout,
+            handlers: Mutex::new(Vec::new()),
+            timers: Mutex::new(HashMap::new()),
+            next_timer: AtomicU64::new(0),
+            deadline: Mutex::new(Instant::now()),
+        });
+        let mut engine = sandbox(limits);
+        register(&mut engine, &ctx);
+        let ast = engine.compile(text).map_err(|e| e.to_string())?;
+        Ok(Self { ctx, engine, ast })
+    }
+
+    /// Run `f` within the limits. Blocks; call from a blocking task.
+    fn run(&self, f: impl FnOnce(&Self) -> ScriptResult<()>) -> Result<(), String> {
+        *self.ctx.deadline.lock() = Instant::now() + self.ctx.timeout;
+        f(self).map_err(|e| match *e {
+            EvalAltResult::ErrorTerminated(reason, pos) => format!("{reason} ({pos})"),
+            e => e.to_string(),
+        })
+    }
+
+    /// Drop the script's subscriptions and timers.
+    fn stop(&self) {
+        self.ctx.core.bus().lock().remove(&self.ctx.owner);
+        self.ctx.core.timers().clear(&self.ctx.owner);
+    }
+}
+
+/// Deliver events and ticks to the handlers and timer callbacks of a
+/// script, one run at a time.
+async fn serve(script: Arc<Script>, mut events: UnboundedReceiver<Event>) {
+    let ctx = script.ctx.clone();
+    while let Some(event) = events.recv().await {
+        let mut runs: Vec<(String, FnPtr, Vec<Dynamic>)> = Vec::new();
+        if event.topic == TICK_TOPIC && event.source.is_none() {
+            let Some(id) = event.payload.as_ref().and_then(|p| p.get("id")?.as_str()) else {
+                continue;
+            };
+            let mut timers = ctx.timers.lock();
+            let callback = match timers.get(id) {
+                Some((callback, true)) => {
+                    let callback = callback.clone();
+                    timers.remove(id);
+                    callback
+                }
+                Some((callback, false)) => callback.clone(),
+                None => continue,
+            };
+            runs.push((format!("timer {id}"), callback, Vec::new()));
+        } else {
+            let arg = json!({"topic": event.topic, "payload": event.payload});
+            let Ok(arg) = to_dynamic(arg) else {
+                continue;
+            };
+            for (pattern, handler) in ctx.handlers.lock().iter() {
+                if topic_matches(pattern, &event.topic) {
+                    runs.push((
+                        format!("handler for {pattern}"),
+                        handler.clone(),
+                        vec![arg.clone()],
+                    ));
+                }
+            }
+        }
+        for (what, callback, args) in runs {
+            let s = script.clone();
+            let result = tokio::task::spawn_blocking(move || {
+                s.run(|s| {
+                    callback
+                        .call::<Dynamic>(&s.engine, &s.ast, args)
+                        .map(|_| ())
+                })
+            })
+            .await;
+            match result {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => warn!("script {}: {what} failed: {e}", ctx.name),
+                Err(e) => warn!("script {}: {what} failed: {e}", ctx.name),
+            }
+        }
+    }
+}
+
+struct Loaded {
+    file: PathBuf,
+    modified: Option<SystemTime>,
+    /// The running script, if loading succeeded.
+    running: Option<(Arc<Script>, JoinHandle<()>)>,
+}
+
+impl Loaded {
+    fn stop(self) {
+        if let Some((script, task)) = self.running {
+            task.abort();
+            script.stop();
+        }
+    }
+}
+
+struct Inner {
+    core: CoreHandle,
+    home: Home,
+    dir: PathBuf,
+    limits: Limits,
+    scripts: tokio::sync::Mutex<BTreeMap<String, Loaded>>,
+    /// Numbers the loaded versions of scripts.
+    versions: AtomicU64,
+}
+
+/// The scripts of the scripts directory, kept in step with the files.
+/// Cheap to clone.
+#[derive(Clone)]
+pub struct Scripts(Arc<Inner>);
+
+impl Scripts {
+    /// Load the scripts in `dir` and keep checking it for changes.
+    pub async fn start(core: CoreHandle, home: Home, dir: PathBuf, limits: Limits) -> Self {
+        if !dir.is_dir() {
+            info!("scripts directory {} does not exist", dir.display());
+        }
+        let scripts = Self(Arc::new(Inner {
+            core,
+            home,
+            dir,
+            limits,
+            scripts: tokio::sync::Mutex::new(BTreeMap::new()),
+            versions: AtomicU64::new(0),
+        }));
+        scripts.rescan().await;
+        let watcher = scripts.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(POLL_INTERVAL).await;
+                watcher.rescan().await;
+            }
+        });
+        scripts
+    }
+
+    /// Names of the scripts currently running.
+    pub async fn running(&self) -> Vec<String> {
+        let scripts = self.0.scripts.lock().await;
+        scripts
+            .iter()
+            .filter(|(_, l)| l.running.is_some())
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
+    /// Load new and changed scripts and stop removed ones. A script that no
+    /// longer compiles, or whose top level fails, keeps running in its
+    /// previous version.
+    pub async fn rescan(&self) {
+        let files = match script_files(&self.0.dir) {
+            Ok(files) => files,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => {
+                warn!("reading {}: {e}", self.0.dir.display());
+                return;
+            }
+        };
+        let mut scripts = self.0.scripts.lock().await;
+        let gone: Vec<String> = scripts
+            .keys()
+            .filter(|name| !files.iter().any(|(n, _)| n == *name))
+            .cloned()
+            .collect();
+        for name in gone {
+            if let Some(loaded) = scripts.remove(&name) {
+                loaded.stop();
+                info!("script {name} removed");
+            }
+        }
+        for (name, file) in files {
+            let modified = std::fs::metadata(&file).and_then(|m| m.modified()).ok();
+            if let Some(loaded) = scripts.get(&name) {
+                if loaded.file == file && loaded.modified == modified {
+                    continue;
+                }
+            }
+            let compiled = std::fs::read_to_string(&file)
+                .map_err(|e| e.to_string())
+                .and_then(|text| {
+                    Script::compile(
+                        &name,
+                        self.0.versions.fetch_add(1, Ordering::Relaxed),
+                        &text,
+                        self.0.core.clone(),
+                        self.0.home,
+                        self.0.limits,
+                    )
+                });
+            let running = match compiled {
+                Ok(script) => launch(Arc::new(script)).await,
+                Err(e) => {
+                    error!("script {name}: {e}");
+                    None
+                }
+            };
+            if running.is_none() {
+                // remember the file so it is not retried until it changes
+                let loaded = scripts.entry(name).or_insert(Loaded {
+                    file: file.clone(),
+                    modified,
+                    running: None,
+                });
+                loaded.file = file;
+                loaded.modified = modified;
+                continue;
+            }
+            info!("script {name} loaded from {}", file.display());
+            let new = Loaded {
+                file,
+                modified,
+                running,
+            };
+            if let Some(old) = scripts.insert(name, new) {
+                old.stop();
+            }
+        }
+    }
+}
+
+/// Run the top level of a script and start serving its events.
+async fn launch(script: Arc<Script>) -> Option<(Arc<Script>, JoinHandle<()>)> {
+    let ctx = script.ctx.clone();
+    let events = ctx.core.bus().lock().register(&ctx.owner);
+    let s = script.clone();
+    let result = tokio::task::spawn_blocking(move || s.run(|s| s.engine.run_ast(&s.ast))).await;
+    let failed = match result {
+        Ok(Ok(())) => None,
+        Ok(Err(e)) => Some(e),
+        Err(e) => Some(e.to_string()),
+    };
+    if let Some(e) = failed {
+        error!("script {} failed: {e}", ctx.name);
+        script.stop();
+        return None;
+    }
+    let task = tokio::spawn(serve(script.clone(), events));
+    Some((script, task))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PluginManager;
+
+    struct Fixture {
+        dir: tempfile::TempDir,
+        core: CoreHandle,
+        seen: UnboundedReceiver<Event>,
+    }
+
+    impl Fixture {
+        fn new() -> Self {
+            let dir = tempfile::tempdir().unwrap();
+            std::fs::create_dir(dir.path().join("scripts")).unwrap();
+            let manager = PluginManager::discover(dir.path().into(), dir.path().into()).unwrap();
+            let core = manager.core_handle();
+            let seen = {
+                let mut bus = core.bus().lock();
+                let rx = bus.register("test");
+                bus.subscribe("test", "seen.#");
+                rx
+            };
+            Self { dir, core, seen }
+        }
+
+        fn write(&self, name: &str, text: &str) {
+            std::fs::write(self.dir.path().join("scripts").join(name), text).unwrap();
+        }
+
+        async fn start(&self, limits: Limits) -> Scripts {
+            let dir = self.dir.path().join("scripts");
+            Scripts::start(self.core.clone(), Home::default(), dir, limits).await
+        }
+
+        async fn next(&mut self) -> Option<Event> {
+            let event = tokio::time::timeout(Duration::from_millis(500), self.seen.recv()).await;
+            event.ok().flatten()
+        }
+    }
+
+    #[tokio::test]
+    async fn runs_handlers_and_timers() {
+        let mut f = Fixture::new();
+        f.write(
+            "door.rhai",
+            r#"
+            let count = 0;
+            on("door.#", |event| {
+                count += 1;
+                publish("seen.door", #{ count: count, by: event.payload.by });
+            });
+            after("20ms", || publish("seen.once"));
+            "#,
+        );
+        let scripts = f.start(Limits::default()).await;
+        assert_eq!(scripts.running().await, ["door"]);
+
+        assert_eq!(f.next().await.unwrap().topic, "seen.once");
+        f.core.publish("door.front", Some(json!({"by": "key"})));
+        f.core.publish("door.back", Some(json!({"by": "code"})));
+        let first = f.next().await.unwrap();
+        assert_eq!(first.payload, Some(json!({"count": 1, "by": "key"})));
+        assert_eq!(first.source.as_deref(), Some("$script/door"));
+        let second = f.next().await.unwrap();
+        assert_eq!(second.payload, Some(json!({"count": 2, "by": "code"})));
+        assert!(f.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn reports_failed_calls_to_the_script() {
+        let mut f = Fixture::new();
+        f.write(
+            "ask.rhai",
+            r#"
+            on("ask", |event| {
+                try {
+                    request("nobody.home", #{ q: 1 });
+                } catch (err) {
+                    publish("seen.error", #{ error: `${err}`, state: state("light.desk") });
+                }
+            });
+            "#,
+        );
+        let _scripts = f.start(Limits::default()).await;
+        f.core.publish("ask", None);
+        let event = f.next().await.unwrap();
+        assert_eq!(
+            event.payload,
+            Some(json!({"error": "unknown method nobody.home (-32601)", "state": null}))
+        );
+    }
+
+    #[tokio::test]
+    async fn stops_runs_over_the_limits() {
+        let mut f = Fixture::new();
+        f.write(
+            "busy.rhai",
+            r#"
+            on("spin", |event| { loop {} });
+            on("ping", |event| publish("seen.pong"));
+            "#,
+        );
+        let limits = Limits {
+            max_operations: 10_000,
+            timeout: Duration::from_secs(5),
+        };
+        let _scripts = f.start(limits).await;
+        f.core.publish("spin", None);
+        f.core.publish("ping", None);
+        assert_eq!(f.next().await.unwrap().topic, "seen.pong");
+
+        let mut f = Fixture::new();
+        f.write(
+            "busy.rhai",
+            r#"on("spin", |event| { loop {} }); publish("seen.up");"#,
+        );
+        let limits = Limits {
+            max_operations: u64::MAX,
+            timeout: Duration::from_millis(50),
+        };
+        let _scripts = f.start(limits).await;
+        assert_eq!(f.next().await.unwrap().topic, "seen.up");
+        let started = Instant::now();
+        f.core.publish("spin", None);
+        f.core.publish("spin", None);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        // runaway top levels fail the script
+        f.write("hang.rhai", "loop {}");
+        let scripts = f.start(limits).await;
+        assert_eq!(scripts.running().await, ["busy"]);
+    }
+
+    #[tokio::test]
+    async fn reloads_changed_files() {
+        let mut f = Fixture::new();
+        f.write("a.rhai", r#"on("go", |e| publish("seen.v1"));"#);
+        let scripts = f.start(Limits::default()).await;
+        f.core.publish("go", None);
+        assert_eq!(f.next().await.unwrap().topic, "seen.v1");
+
+        f.write("a.rhai", r#"on("go", |e| publish("seen.v2"));"#);
+        scripts.rescan().await;
+        f.core.publish("go", None);
+        assert_eq!(f.next().await.unwrap().topic, "seen.v2");
+        assert!(f.next().await.is_none());
+
+        // a broken edit leaves the running version alone
+        f.write("a.rhai", r#"on("go", |e| publish("seen.v3")"#);
+        scripts.rescan().await;
+        f.core.publish("go", None);
+        assert_eq!(f.next().await.unwrap().topic, "seen.v2");
+        assert!(f.next().await.is_none());
+        assert!(check(&f.dir.path().join("scripts/a.rhai")).is_err());
+
+        // so does one whose top level fails after subscribing
+        f.write(
+            "a.rhai",
+            r#"on("go", |e| publish("seen.v4")); throw "not today";"#,
+        );
+        scripts.rescan().await;
+        assert_eq!(scripts.running().await, ["a"]);
+        f.core.publish("go", None);
+        assert_eq!(f.next().await.unwrap().topic, "seen.v2");
+        assert!(f.next().await.is_none());
+
+        std::fs::remove_file(f.dir.path().join("scripts/a.rhai")).unwrap();
+        scripts.rescan().await;
+        assert!(scripts.running().await.is_empty());
+        f.core.publish("go", None);
+        assert!(f.next().await.is_none());
+    }
+}
@@ -0,0 +1,583 @@
+//! Registry of the devices and entities plugins control, and of the
+//! entities' current state.
+//!
+//! Plugins register devices with `device.register` and report entity state
+//! with `state.set`. Every change is published as a `state.changed` event,
+//! so integrations and automations work from the same model.
+
+use crate::events::{Event, EventBus};
+use chrono::Utc;
+use parking_lot::Mutex;
+use plugin_api::{
+    device::{
+        self, Device, DeviceCommand, Domain, Entity, EntityState, StateChanged, STATE_CHANGED,
+    },
+    RpcError,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::collections::{BTreeMap, BTreeSet};
+
+use super::parse;
+
+/// A registered device, as listed by `device.list`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DeviceInfo {
+    #[serde(flatten)]
+    pub device: Device,
+    /// Id of the plugin that registered the device.
+    pub owner: String,
+    pub entities: Vec<Entity>,
+}
+
+struct EntityEntry {
+    entity: Entity,
+    domain: Domain,
+    device_id: String,
+    owner: String,
+    state: Option<EntityState>,
+}
+
+/// Devices, entities and entity states by id.
+#[derive(Default)]
+pub struct Registry {
+    devices: BTreeMap<String, (Device, String)>,
+    entities: BTreeMap<String, EntityEntry>,
+}
+
+impl Registry {
+    /// Register `device` with its `entities` for plugin `owner`. A device the
+    /// same plugin registered before is replaced; its entities that are no
+    /// longer listed are removed, the others keep their state.
+    pub fn register(
+        &mut self,
+        owner: &str,
+        device: Device,
+        entities: Vec<Entity>,
+    ) -> Result<Vec<StateChanged>, RpcError> {
+        if device.id.is_empty() {
+            return Err(invalid("device id must not be empty".into()));
+        }
+        if let Some((_, other)) = self.devices.get(&device.id) {
+            if other != owner {
+                return Err(invalid(format!(
+                    "device {} is registered by plugin {other}",
+                    device.id
+                )));
+            }
+        }
+        let mut seen = BTreeSet::new();
+        let mut domains = Vec::with_capacity(entities.len());
+        for entity in &entities {
+            let (domain, _) = device::parse_entity_id(&entity.entity_id).map_err(invalid)?;
+            if !seen.insert(entity.entity_id.as_str()) {
+                return Err(invalid(format!("entity {} listed twice", entity.entity_id)));
+            }
+            if let Some(other) = self.entities.get(&entity.entity_id) {
+                if other.device_id != device.id {
+                    return Err(invalid(format!(
+                        "entity {} belongs to device {}",
+                        entity.entity_id, other.device_id
+                    )));
+                }
+            }
+            if let Some(cap) = entity
+                .capabilities
+                .iter()
+                .find(|c| !domain.capabilities().contains(&c.as_str()))
+            {
+                return Err(invalid(format!(
+                    "{domain} entity {} cannot have capability {cap}",
+                    entity.entity_id
+                )));
+            }
+            domains.push(domain);
+        }
+
+        let dropped: Vec<String> = self
+            .entities
+            .iter()
+            .filter(|(id, e)| e.device_id == device.id && !seen.contains(id.as_str()))
+            .map(|(id, _)| id.clone())
+            .collect();
+        let changes = dropped
+            .iter()
+            .filter_map(|id| self.remove_entity(id))
+            .collect();
+        for (entity, domain) in entities.into_iter().zip(domains) {
+            let state = self
+                .entities
+                .remove(&entity.entity_id)
+                .and_then(|e| e.state);
+            self.entities.insert(
+                entity.entity_id.clone(),
+                EntityEntry {
+                    entity,
+                    domain,
+                    device_id: device.id.clone(),
+                    owner: owner.to_string(),
+                    state,
+                },
+            );
+        }
+        self.devices
+            .insert(device.id.clone(), (device, owner.to_string()));
+        Ok(changes)
+    }
+
+    /// Remove a device of `owner` together with its entities.
+    pub fn unregister(
+        &mut self,
+        owner: &str,
+        device_id: &str,
+    ) -> Result<Vec<StateChanged>, RpcError> {
+        match self.devices.get(device_id) {
+            None => return Err(invalid(format!("unknown device {device_id}"))),
+            Some((_, other)) if other != owner => {
+                return Err(denied(format!(
+                    "device {device_id} is registered by plugin {other}"
+                )))
+            }
+            Some(_) => {}
+        }
+        self.devices.remove(device_id);
+        let ids: Vec<String> = self
+            .entities
+            .iter()
+            .filter(|(_, e)| e.device_id == device_id)
+            .map(|(id, _)| id.clone())
+            .collect();
+        Ok(ids.iter().filter_map(|id| self.remove_entity(id)).collect())
+    }
+
+    fn remove_entity(&mut self, entity_id: &str) -> Option<StateChanged> {
+        let old = self.entities.remove(entity_id)?.state?;
+        Some(StateChanged {
+            entity_id: entity_id.to_string(),
+            old_state: Some(old),
+            new_state: None,
+        })
+    }
+
+    /// Report the state of an entity `owner` registered. `attributes`
+    /// replace the current ones if given. Returns the change, or `None` if
+    /// neither the state nor the attributes changed.
+    pub fn set_state(
+        &mut self,
+        owner: &str,
+        entity_id: &str,
+        state: Value,
+        attributes: Option<Map<String, Value>>,
+        now_ms: i64,
+    ) -> Result<Option<StateChanged>, RpcError> {
+        let entry = self
+            .entities
+            .get_mut(entity_id)
+            .ok_or_else(|| invalid(format!("unknown entity {entity_id}")))?;
+        if entry.owner != owner {
+            return Err(denied(format!(
+                "entity {entity_id} is registered by plugin {}",
+                entry.owner
+            )));
+        }
+        entry.domain.check_state(&state).map_err(invalid)?;
+        Ok(update(entry, state, attributes, now_ms))
+    }
+
+    /// Mark the reported entities of `owner` unavailable, after it stopped.
+    /// The registrations stay, so the plugin can pick them up on restart.
+    pub fn owner_gone(&mut self, owner: &str, now_ms: i64) -> Vec<StateChanged> {
+        self.entities
+            .values_mut()
+            .filter(|e| e.owner == owner && e.state.is_some())
+            .filter_map(|e| update(e, json!(device::UNAVAILABLE), None, now_ms))
+            .collect()
+    }
+
+    /// Check `command` against its entity's domain and capabilities,
+    /// returning the id of the plugin that carries it out.
+    pub fn command_owner(&self, command: &DeviceCommand) -> Result<&str, RpcError> {
+        let entry = self
+            .entities
+            .get(&command.entity_id)
+            .ok_or_else(|| invalid(format!("unknown entity {}", command.entity_id)))?;
+        command
+            .command
+            .check(entry.domain, &entry.entity.capabilities)
+            .map_err(|e| invalid(format!("{}: {e}", command.entity_id)))?;
+        Ok(&entry.owner)
+    }
+
+    /// The current state of an entity, `None` if it was never reported.
+    pub fn state(&self, entity_id: &str) -> Result<Option<&EntityState>, RpcError> {
+        self.entities
+            .get(entity_id)
+            .map(|e| e.state.as_ref())
+            .ok_or_else(|| invalid(format!("unknown entity {entity_id}")))
+    }
+
+    /// The capabilities an entity declared.
+    pub fn capabilities(&self, entity_id: &str) -> Result<&[String], RpcError> {
+        self.entities
+            .get(entity_id)
+            .map(|e| e.entity.capabilities.as_slice())
+            .ok_or_else(|| invalid(format!("unknown entity {entity_id}")))
+    }
+
+    /// The reported states, optionally only of one domain or device.
+    pub fn states(&self, domain: Option<Domain>, device_id: Option<&str>) -> Vec<&EntityState> {
+        self.entities
+            .values()
+            .filter(|e| domain.is_none_or(|d| e.domain == d))
+            .filter(|e| device_id.is_none_or(|d| e.device_id == d))
+            .filter_map(|e| e.state.as_ref())
+            .collect()
+    }
+
+    pub fn devices(&self) -> Vec<DeviceInfo> {
+        self.devices
+            .values()
+            .map(|(device, owner)| DeviceInfo {
+                device: device.clone(),
+                owner: owner.clone(),
+                entities: self
+                    .entities
+                    .values()
+                    .filter(|e| e.device_id == device.id)
+                    .map(|e| e.entity.clone())
+                    .collect(),
+            })
+            .collect()
+    }
+}
+
+/// Apply a reported state to an entity, returning the change if any.
+fn update(
+    entry: &mut EntityEntry,
+    state: Value,
+    attributes: Option<Map<String, Value>>,
+    now_ms: i64,
+) -> Option<StateChanged> {
+    let old = entry.state.clone();
+    let attributes = attributes
+        .or_else(|| old.as_ref().map(|s| s.attributes.clone()))
+        .unwrap_or_default();
+    let last_changed_ms = match &old {
+        Some(old) if old.state == state => {
+            if old.attributes == attributes {
+                return None;
+            }
+            old.last_changed_ms
+        }
+        _ => now_ms,
+    };
+    let new = EntityState {
+        entity_id: entry.entity.entity_id.clone(),
+        state,
+        attributes,
+        last_changed_ms,
+        last_updated_ms: now_ms,
+    };
+    entry.state = Some(new.clone());
+    Some(StateChanged {
+        entity_id: new.entity_id.clone(),
+        old_state: old,
+        new_state: Some(new),
+    })
+}
+
+/// Publish `changes` as `state.changed` events from the core.
+pub fn publish(bus: &Mutex<EventBus>, changes: Vec<StateChanged>) {
+    let mut bus = bus.lock();
+    for change in changes {
+        bus.publish(Event {
+            topic: STATE_CHANGED.to_string(),
+            source: None,
+            payload: serde_json::to_value(change).ok(),
+        });
+    }
+}
+
+#[derive(Deserialize)]
+struct RegisterParams {
+    device: Device,
+    #[serde(default)]
+    entities: Vec<Entity>,
+}
+
+#[derive(Deserialize)]
+struct IdParams {
+    id: String,
+}
+
+#[derive(Deserialize)]
+struct SetParams {
+    entity_id: String,
+    state: Value,
+    #[serde(default)]
+    attributes: Option<Map<String, Value>>,
+}
+
+#[derive(Deserialize)]
+struct GetParams {
+    entity_id: String,
+}
+
+#[derive(Deserialize)]
+struct ListParams {
+    #[serde(default)]
+    domain: Option<Domain>,
+    #[serde(default)]
+    device_id: Option<String>,
+}
+
+fn invalid(message: String) -> RpcError {
+    RpcError::new(RpcError::INVALID_PARAMS, message)
+}
+
+fn denied(message: String) -> RpcError {
+    RpcError::new(RpcError::PERMISSION_DENIED, message)
+}
+
+/// Check the params of a `device.command` request, returning the plugin to
+/// send the command to and the params to send it with.
+pub fn route_command(
+    registry: &Mutex<Registry>,
+    params: Option<Value>,
+) -> Result<(String, Value), RpcError> {
+    let command: DeviceCommand = parse(params)?;
+    let owner = registry.lock().command_owner(&command)?.to_string();
+    Ok((owner, json!(command)))
+}
+
+/// Handle a `device.*` or `state.*` request from `plugin_id`.
+pub fn handle(
+    registry: &Mutex<Registry>,
+    bus: &Mutex<EventBus>,
+    plugin_id: &str,
+    method: &str,
+    params: Option<Value>,
+) -> Result<Value, RpcError> {
+    let now_ms = Utc::now().timestamp_millis();
+    match method {
+        "device.register" => {
+            let p: RegisterParams = parse(params)?;
+            let entities: Vec<String> = p.entities.iter().map(|e| e.entity_id.clone()).collect();
+            let changes = registry.lock().register(plugin_id, p.device, p.entities)?;
+            publish(bus, changes);
+            Ok(json!({"ok": true, "entities": entities}))
+        }
+        "device.unregister" => {
+            let p: IdParams = parse(params)?;
+            let changes = registry.lock().unregister(plugin_id, &p.id)?;
+            publish(bus, changes);
+            Ok(json!({"ok": true}))
+        }
+        "device.list" => Ok(json!({"devices": registry.lock().devices()})),
+        "state.set" => {
+            let p: SetParams = parse(params)?;
+            let change = registry.lock().set_state(
+                plugin_id,
+                &p.entity_id,
+                p.state,
+                p.attributes,
+                now_ms,
+            )?;
+            let changed = change.is_some();
+            publish(bus, change.into_iter().collect());
+            Ok(json!({"ok": true, "changed": changed}))
+        }
+        "state.get" => {
+            let p: GetParams = parse(params)?;
+            Ok(json!({"state": registry.lock().state(&p.entity_id)?}))
+        }
+        "state.list" => {
+            let p: ListParams = parse(params)?;
+            let registry = registry.lock();
+            Ok(json!({"states": registry.states(p.domain, p.device_id.as_deref())}))
+        }
+        _ => Err(RpcError::new(
+            RpcError::METHOD_NOT_FOUND,
+            format!("unknown method {}", method),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use plugin_api::device::capabilities;
+
+    fn lamp() -> (Device, Vec<Entity>) {
+        let device = Device {
+            id: "lamp1".into(),
+            name: "Desk lamp".into(),
+            manufacturer: None,
+            model: None,
+        };
+        let entities = vec![
+            Entity {
+                entity_id: "light.desk".into(),
+                name: None,
+                capabilities: vec![capabilities::BRIGHTNESS.into()],
+            },
+            Entity {
+                entity_id: "sensor.desk_power".into(),
+                name: None,
+                capabilities: vec![],
+            },
+        ];
+        (device, entities)
+    }
+
+    fn attrs(value: Value) -> Option<Map<String, Value>> {
+        value.as_object().cloned()
+    }
+
+    #[test]
+    fn registers_devices_per_owner() {
+        let mut r = Registry::default();
+        let (device, entities) = lamp();
+        r.register("hue", device.clone(), entities.clone()).unwrap();
+        let err = r.register("other", device.clone(), vec![]).unwrap_err();
+        assert_eq!(err.code, RpcError::INVALID_PARAMS);
+
+        let mut bad = entities.clone();
+        bad[1].capabilities = vec![capabilities::POSITION.into()];
+        let err = r.register("hue", device.clone(), bad).unwrap_err();
+        assert!(err.message.contains("cannot have capability position"));
+
+        r.set_state("hue", "sensor.desk_power", json!(4.5), None, 1)
+            .unwrap();
+        // re-registering without the sensor removes it
+        let changes = r
+            .register("hue", device.clone(), entities[..1].to_vec())
+            .unwrap();
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].new_state, None);
+        assert!(r.state("sensor.desk_power").is_err());
+        assert_eq!(r.devices()[0].entities, entities[..1].to_vec());
+
+        let err = r.unregister("other", "lamp1").unwrap_err();
+        assert_eq!(err.code, RpcError::PERMISSION_DENIED);
+        r.unregister("hue", "lamp1").unwrap();
+        assert!(r.devices().is_empty());
+    }
+
+    #[test]
+    fn tracks_state_changes() {
+        let mut r = Registry::default();
+        let (device, entities) = lamp();
+        r.register("hue", device, entities).unwrap();
+        assert_eq!(r.state("light.desk").unwrap(), None);
+
+        let change = r
+            .set_state(
+                "hue",
+                "light.desk",
+                json!("on"),
+                attrs(json!({"brightness": 80})),
+                10,
+            )
+            .unwrap()
+            .unwrap();
+        assert_eq!(change.old_state, None);
+        // same state and attributes again is no change
+        let again = r.set_state("hue", "light.desk", json!("on"), None, 20);
+        assert_eq!(again.unwrap(), None);
+
+        let change = r
+            .set_state(
+                "hue",
+                "light.desk",
+                json!("on"),
+                attrs(json!({"brightness": 90})),
+                30,
+            )
+            .unwrap()
+            .unwrap();
+        let new = change.new_state.unwrap();
+        assert_eq!((new.last_changed_ms, new.last_updated_ms), (10, 30));
+
+        let err = r.set_state("hue", "light.desk", json!("dim"), None, 40);
+        assert_eq!(err.unwrap_err().code, RpcError::INVALID_PARAMS);
+        let err = r.set_state("other", "light.desk", json!("off"), None, 40);
+        assert_eq!(err.unwrap_err().code, RpcError::PERMISSION_DENIED);
+
+        let changes = r.owner_gone("hue", 50);
+        assert_eq!(changes.len(), 1);
+        let state = r.state("light.desk").unwrap().unwrap();
+        assert_eq!(state.state, json!(device::UNAVAILABLE));
+        assert_eq!(state.attributes["brightness"], json!(90));
+        assert_eq!(r.states(Some(Domain::Light), None).len(), 1);
+        assert!(r.states(Some(Domain::Sensor), None).is_empty());
+    }
+
+    #[test]
+    fn routes_commands_to_the_owner() {
+        let mut r = Registry::default();
+        let (device, entities) = lamp();
+        r.register("hue", device, entities).unwrap();
+        let command = |value: Value| serde_json::from_value::<DeviceCommand>(value).unwrap();
+
+        let dim = command(
+            json!({"entity_id": "light.desk", "command": "set_brightness", "brightness": 10}),
+        );
+        assert_eq!(r.command_owner(&dim).unwrap(), "hue");
+        let warm = command(
+            json!({"entity_id": "light.desk", "command": "set_color_temp", "color_temp": 400}),
+        );
+        let err = r.command_owner(&warm).unwrap_err();
+        assert_eq!(err.code, RpcError::INVALID_PARAMS);
+        assert_eq!(
+            err.message,
+            "light.desk: set_color_temp needs capability color_temp"
+        );
+        let off = command(json!({"entity_id": "sensor.desk_power", "command": "turn_off"}));
+        assert!(r.command_owner(&off).is_err());
+        let off = command(json!({"entity_id": "light.gone", "command": "turn_off"}));
+        assert!(r.command_owner(&off).is_err());
+    }
+
+    #[tokio::test]
+    async fn publishes_state_changed() {
+        let registry = Mutex::new(Registry::default());
+        let bus = Mutex::new(EventBus::default());
+        let mut rx = bus.lock().register("watcher");
+        bus.lock().subscribe("watcher", STATE_CHANGED);
+        let (device, entities) = lamp();
+        let r = handle(
+            &registry,
+            &bus,
+            "hue",
+            "device.register",
+            Some(json!({"device": device, "entities": entities})),
+        )
+        .unwrap();
+        assert_eq!(r["entities"], json!(["light.desk", "sensor.desk_power"]));
+
+        let set = json!({"entity_id": "light.desk", "state": "off"});
+        let r = handle(&registry, &bus, "hue", "state.set", Some(set.clone())).unwrap();
+        assert_eq!(r, json!({"ok": true, "changed": true}));
+        let r = handle(&registry, &bus, "hue", "state.set", Some(set)).unwrap();
+        assert_eq!(r["changed"], json!(false));
+
+        let event = rx.recv().await.unwrap();
+        assert_eq!(event.topic, STATE_CHANGED);
+        let change: StateChanged = serde_json::from_value(event.payload.unwrap()).unwrap();
+        assert_eq!(change.new_state.unwrap().state, json!("off"));
+        assert!(rx.try_recv().is_err());
+
+        let r = handle(
+            &registry,
+            &bus,
+            "ui",
+            "state.list",
+            Some(json!({"domain": "light"})),
+        )
+        .unwrap();
+        assert_eq!(r["states"][0]["entity_id"], json!("light.desk"));
+        let r = handle(&registry, &bus, "ui", "device.list", None).unwrap();
+        assert_eq!(r["devices"][0]["owner"], json!("hue"));
+    }
+}
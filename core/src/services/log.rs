@@ -1,13 +1,524 @@
-use tracing::{debug, error, info, trace, warn};
-
-/// Write a log message coming from a plugin.
-pub fn write(level: &str, message: &str) {
-    match level.to_ascii_uppercase().as_str() {
-        "ERROR" => error!("{}", message),
-        "WARN" => warn!("{}", message),
-        "INFO" => info!("{}", message),
-        "DEBUG" => debug!("{}", message),
-        "TRACE" => trace!("{}", message),
-        _ => info!("{}", message),
+//! Logs from plugins, written with `log.write` or to their stderr. Each
+//! plugin's recent lines are kept in memory, and optionally appended to
+//! rotating files under the data directory, where `homecore plugin logs`
+//! reads them. All lines are also emitted to the core's log with `plugin` as
+//! target.
+
+use anyhow::Result;
+use chrono::{DateTime, Local, Utc};
+use parking_lot::Mutex;
+use plugin_api::RpcError;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    fs::{File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+use tokio::sync::broadcast;
+use tracing::{debug, error, field::display, info, trace, warn};
+
+use super::parse;
+
+/// Directory under the data directory holding plugin log files.
+pub const LOG_DIR: &str = "logs";
+/// Lines kept in memory per plugin.
+pub const DEFAULT_CAPACITY: usize = 1000;
+/// Size at which a plugin's log file is rotated.
+pub const MAX_FILE_BYTES: u64 = 1024 * 1024;
+/// Rotated files kept per plugin besides the current one.
+pub const KEEP_FILES: usize = 3;
+
+/// Levels a log line can have, most severe first.
+const LEVELS: &[&str] = &["ERROR", "WARN", "INFO", "DEBUG", "TRACE"];
+
+/// Where a log line came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Source {
+    /// A `log.write` request.
+    Rpc,
+    /// A line the plugin wrote to stderr.
+    Stderr,
+}
+
+/// One line logged by a plugin.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LogRecord {
+    /// Milliseconds since the Unix epoch.
+    pub ts_ms: i64,
+    pub plugin: String,
+    pub level: String,
+    pub source: Source,
+    pub message: String,
+    /// Structured context attached by the plugin.
+    #[serde(default, skip_serializing_if = "Map::is_empty")]
+    pub fields: Map<String, Value>,
+    /// Id tying together lines that belong to one operation, e.g. a request.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub correlation_id: Option<String>,
+}
+
+impl fmt::Display for LogRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ts = DateTime::<Utc>::from_timestamp_millis(self.ts_ms)
+            .unwrap_or_default()
+            .with_timezone(&Local);
+        let source = match self.source {
+            Source::Rpc => "log",
+            Source::Stderr => "stderr",
+        };
+        write!(
+            f,
+            "{} {:<5} [{source}] {}",
+            ts.format("%Y-%m-%d %H:%M:%S%.3f"),
+            self.level,
+            self.message
+        )?;
+        if let Some(id) = &self.correlation_id {
+            write!(f, " correlation_id={id}")?;
+        }
+        for (key, value) in &self.fields {
+            write!(f, " {key}={value}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Log lines of all plugins.
+pub struct PluginLogs {
+    capacity: usize,
+    buffers: Mutex<HashMap<String, VecDeque<LogRecord>>>,
+    files: Mutex<Option<LogFiles>>,
+    live: broadcast::Sender<LogRecord>,
+}
+
+impl Default for PluginLogs {
+    fn default() -> Self {
+        Self::new(DEFAULT_CAPACITY)
+    }
+}
+
+impl PluginLogs {
+    /// Keep the last `capacity` lines of each plugin in memory.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            buffers: Mutex::default(),
+            files: Mutex::default(),
+            live: broadcast::channel(256).0,
+        }
+    }
+
+    /// Also append lines to rotating files in `dir`, or stop doing so.
+    pub fn set_dir(&self, dir: Option<PathBuf>) {
+        *self.files.lock() = dir.map(LogFiles::new);
+    }
+
+    /// Record a `log.write` from a plugin. Unknown levels count as `INFO`.
+    pub fn write(&self, plugin: &str, level: &str, message: &str) {
+        self.write_with(plugin, level, message, Map::new(), None);
+    }
+
+    /// Record a `log.write` with structured fields and a correlation id.
+    pub fn write_with(
+        &self,
+        plugin: &str,
+        level: &str,
+        message: &str,
+        fields: Map<String, Value>,
+        correlation_id: Option<String>,
+    ) {
+        let level = LEVELS
+            .iter()
+            .find(|l| l.eq_ignore_ascii_case(level))
+            .unwrap_or(&"INFO");
+        self.record(LogRecord {
+            ts_ms: Utc::now().timestamp_millis(),
+            plugin: plugin.to_string(),
+            level: level.to_string(),
+            source: Source::Rpc,
+            message: message.to_string(),
+            fields,
+            correlation_id,
+        });
+    }
+
+    /// Record a line a plugin wrote to stderr. The level is taken from the
+    /// line if it looks like the output of a common logger, and is `INFO`
+    /// otherwise.
+    pub fn stderr(&self, plugin: &str, line: &str) {
+        let line = strip_ansi(line.trim_end());
+        if line.is_empty() {
+            return;
+        }
+        let level = detect_level(&line).unwrap_or("INFO");
+        self.record(LogRecord {
+            ts_ms: Utc::now().timestamp_millis(),
+            plugin: plugin.to_string(),
+            level: level.to_string(),
+            source: Source::Stderr,
+            message: line,
+            fields: Map::new(),
+            correlation_id: None,
+        });
+    }
+
+    fn record(&self, record: LogRecord) {
+        emit(&record);
+        let plugin = record.plugin.as_str();
+        if let Some(files) = self.files.lock().as_mut() {
+            if let Err(e) = files.append(&record) {
+                warn!("failed to write log file of plugin {plugin}: {e}");
+            }
+        }
+        // sent under the lock, so `tail_and_follow` sees each line once
+        let mut buffers = self.buffers.lock();
+        let buffer = buffers.entry(plugin.to_string()).or_default();
+        if buffer.len() == self.capacity {
+            buffer.pop_front();
+        }
+        buffer.push_back(record.clone());
+        let _ = self.live.send(record);
+    }
+
+    /// The last `n` lines of a plugin, oldest first.
+    pub fn tail(&self, plugin: &str, n: usize) -> Vec<LogRecord> {
+        let buffers = self.buffers.lock();
+        let Some(buffer) = buffers.get(plugin) else {
+            return Vec::new();
+        };
+        buffer
+            .iter()
+            .skip(buffer.len().saturating_sub(n))
+            .cloned()
+            .collect()
+    }
+
+    /// Receive every line logged from now on, of all plugins.
+    pub fn follow(&self) -> broadcast::Receiver<LogRecord> {
+        self.live.subscribe()
+    }
+
+    /// The last `n` lines of a plugin, and every line of any plugin logged
+    /// after them.
+    pub fn tail_and_follow(
+        &self,
+        plugin: &str,
+        n: usize,
+    ) -> (Vec<LogRecord>, broadcast::Receiver<LogRecord>) {
+        let buffers = self.buffers.lock();
+        let tail = buffers.get(plugin).map_or_else(Vec::new, |buffer| {
+            buffer
+                .iter()
+                .skip(buffer.len().saturating_sub(n))
+                .cloned()
+                .collect()
+        });
+        (tail, self.live.subscribe())
+    }
+}
+
+/// Emit a plugin's log line to the core's log, with the plugin's fields
+/// passed on as one JSON object.
+fn emit(r: &LogRecord) {
+    let plugin = r.plugin.as_str();
+    let correlation_id = r.correlation_id.as_deref();
+    let fields = (!r.fields.is_empty()).then(|| display(Value::Object(r.fields.clone())));
+    let message = &r.message;
+    match r.level.as_str() {
+        "ERROR" => error!(target: "plugin", plugin, correlation_id, fields, "{message}"),
+        "WARN" => warn!(target: "plugin", plugin, correlation_id, fields, "{message}"),
+        "DEBUG" => debug!(target: "plugin", plugin, correlation_id, fields, "{message}"),
+        "TRACE" => trace!(target: "plugin", plugin, correlation_id, fields, "{message}"),
+        _ => info!(target: "plugin", plugin, correlation_id, fields, "{message}"),
+    }
+}
+
+#[derive(Deserialize)]
+struct WriteParams {
+    #[serde(default = "default_level")]
+    level: String,
+    message: String,
+    #[serde(default)]
+    fields: Map<String, Value>,
+    #[serde(default)]
+    correlation_id: Option<String>,
+}
+
+fn default_level() -> String {
+    "INFO".into()
+}
+
+/// Serve `log.write` for `plugin`.
+pub fn handle(logs: &PluginLogs, plugin: &str, params: Option<Value>) -> Result<Value, RpcError> {
+    let p: WriteParams = parse(params)?;
+    logs.write_with(plugin, &p.level, &p.message, p.fields, p.correlation_id);
+    Ok(json!({"ok": true}))
+}
+
+/// The current log file of `plugin` in `dir`.
+pub fn log_file(dir: &Path, plugin: &str) -> PathBuf {
+    dir.join(format!("{plugin}.log"))
+}
+
+/// The `n`th rotated log file of `plugin`, 1 being the most recent.
+fn rotated_file(dir: &Path, plugin: &str, n: usize) -> PathBuf {
+    dir.join(format!("{plugin}.log.{n}"))
+}
+
+/// Open log files with their current sizes, by plugin.
+struct LogFiles {
+    dir: PathBuf,
+    open: HashMap<String, (File, u64)>,
+}
+
+impl LogFiles {
+    fn new(dir: PathBuf) -> Self {
+        Self {
+            dir,
+            open: HashMap::new(),
+        }
+    }
+
+    fn append(&mut self, record: &LogRecord) -> Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        let plugin = record.plugin.as_str();
+        if let Some((_, size)) = self.open.get(plugin) {
+            if *size > 0 && size + line.len() as u64 > MAX_FILE_BYTES {
+                self.open.remove(plugin);
+                self.rotate(plugin)?;
+            }
+        }
+        if !self.open.contains_key(plugin) {
+            std::fs::create_dir_all(&self.dir)?;
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(log_file(&self.dir, plugin))?;
+            let size = file.metadata()?.len();
+            self.open.insert(plugin.to_string(), (file, size));
+        }
+        let (file, size) = self.open.get_mut(plugin).unwrap();
+        file.write_all(&line)?;
+        *size += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&self, plugin: &str) -> Result<()> {
+        for n in (1..KEEP_FILES).rev() {
+            let from = rotated_file(&self.dir, plugin, n);
+            if from.exists() {
+                std::fs::rename(from, rotated_file(&self.dir, plugin, n + 1))?;
+            }
+        }
+        std::fs::rename(
+            log_file(&self.dir, plugin),
+            rotated_file(&self.dir, plugin, 1),
+        )?;
+        Ok(())
+    }
+}
+
+/// The last `n` lines of `plugin` from the log files in `dir`, oldest
+/// first.
+pub fn read_tail(dir: &Path, plugin: &str, n: usize) -> Result<Vec<LogRecord>> {
+    let mut files: Vec<PathBuf> = (1..=KEEP_FILES)
+        .rev()
+        .map(|i| rotated_file(dir, plugin, i))
+        .collect();
+    files.push(log_file(dir, plugin));
+    let mut records = VecDeque::new();
+    for path in files {
+        let text = match std::fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+        for line in text.lines() {
+            if let Ok(record) = serde_json::from_str(line) {
+                if records.len() == n {
+                    records.pop_front();
+                }
+                records.push_back(record);
+            }
+        }
+    }
+    Ok(records.into())
+}
+
+/// Reads lines appended to a plugin's log file, starting at its current
+/// end. A file that shrank is taken to be rotated and read from the start.
+pub struct Follower {
+    path: PathBuf,
+    pos: u64,
+    partial: Vec<u8>,
+}
+
+impl Follower {
+    pub fn new(dir: &Path, plugin: &str) -> Self {
+        let path = log_file(dir, plugin);
+        let pos = std::fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+        Self {
+            path,
+            pos,
+            partial: Vec::new(),
+        }
+    }
+
+    /// Lines appended since the last call.
+    pub fn poll(&mut self) -> Result<Vec<LogRecord>> {
+        let mut file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        if file.metadata()?.len() < self.pos {
+            self.pos = 0;
+            self.partial.clear();
+        }
+        file.seek(SeekFrom::Start(self.pos))?;
+        let read = file.read_to_end(&mut self.partial)?;
+        self.pos += read as u64;
+        // keep an incomplete last line for the next call
+        let complete = self
+            .partial
+            .iter()
+            .rposition(|b| *b == b'\n')
+            .map_or(0, |i| i + 1);
+        let lines: Vec<u8> = self.partial.drain(..complete).collect();
+        Ok(lines
+            .split(|b| *b == b'\n')
+            .filter_map(|line| serde_json::from_slice(line).ok())
+            .collect())
+    }
+}
+
+/// Remove ANSI escape sequences, as written by loggers printing colors.
+fn strip_ansi(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c == '\u{1b}' {
+            // CSI sequences end with a letter
+            if chars.next() == Some('[') {
+                for c in chars.by_ref() {
+                    if c.is_ascii_alphabetic() {
+                        break;
+                    }
+                }
+            }
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+/// Find a level such as `INFO`, `[warn]` or `ERROR:` among the first words
+/// of a line.
+fn detect_level(line: &str) -> Option<&'static str> {
+    line.split_whitespace().take(4).find_map(|word| {
+        let word = word.trim_matches(|c: char| !c.is_ascii_alphabetic());
+        let word = match word.to_ascii_uppercase().as_str() {
+            "WARNING" => "WARN",
+            "ERR" => "ERROR",
+            _ => word,
+        };
+        LEVELS
+            .iter()
+            .find(|l| l.eq_ignore_ascii_case(word))
+            .copied()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_the_last_lines_per_plugin() {
+        let logs = PluginLogs::new(2);
+        logs.write("a", "info", "one");
+        logs.write("a", "bogus", "two");
+        logs.write("a", "ERROR", "three");
+        logs.stderr(
+            "b",
+            "\u{1b}[2m2024-01-01T00:00:00Z\u{1b}[0m \u{1b}[33m WARN\u{1b}[0m b: careful",
+        );
+        logs.stderr("b", "thread 'main' panicked");
+
+        let a = logs.tail("a", 10);
+        let levels: Vec<_> = a
+            .iter()
+            .map(|r| (r.level.as_str(), r.message.as_str()))
+            .collect();
+        assert_eq!(levels, [("INFO", "two"), ("ERROR", "three")]);
+        let b = logs.tail("b", 10);
+        assert_eq!(b[0].level, "WARN");
+        assert_eq!(b[0].message, "2024-01-01T00:00:00Z  WARN b: careful");
+        assert_eq!(b[0].source, Source::Stderr);
+        assert_eq!(b[1].level, "INFO");
+        assert!(logs.tail("c", 10).is_empty());
+    }
+
+    #[test]
+    fn serves_log_write_with_fields() {
+        let logs = PluginLogs::default();
+        let params = json!({
+            "level": "warn",
+            "message": "slow",
+            "fields": {"room_id": 3},
+            "correlation_id": "req-1",
+        });
+        handle(&logs, "chat", Some(params)).unwrap();
+        handle(&logs, "chat", Some(json!({"message": "plain"}))).unwrap();
+        let err = handle(&logs, "chat", Some(json!({"message": "x", "fields": 1}))).unwrap_err();
+        assert_eq!(err.code, RpcError::INVALID_PARAMS);
+
+        let [slow, plain] = <[LogRecord; 2]>::try_from(logs.tail("chat", 10)).unwrap();
+        assert_eq!(slow.level, "WARN");
+        assert_eq!(slow.fields["room_id"], 3);
+        assert_eq!(slow.correlation_id.as_deref(), Some("req-1"));
+        assert!(slow
+            .to_string()
+            .ends_with("slow correlation_id=req-1 room_id=3"));
+        assert_eq!(plain.level, "INFO");
+        let line = serde_json::to_value(&plain).unwrap();
+        assert!(line.get("fields").is_none() && line.get("correlation_id").is_none());
+    }
+
+    #[test]
+    fn rotates_files_and_reads_them_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let logs = PluginLogs::default();
+        logs.set_dir(Some(dir.path().to_path_buf()));
+        let message = "x".repeat(1000);
+        for _ in 0..5 {
+            logs.write("p", "info", &message);
+        }
+        let mut follower = Follower::new(dir.path(), "p");
+        while !rotated_file(dir.path(), "p", 1).exists() {
+            logs.write("p", "info", &message);
+        }
+        // the new file is shorter than the follower's position
+        logs.write("p", "warn", "last");
+        let seen = follower.poll().unwrap();
+        assert_eq!(seen.len(), 2);
+        assert_eq!(seen[1].message, "last");
+        assert!(follower.poll().unwrap().is_empty());
+
+        for _ in 0..4 * MAX_FILE_BYTES / 1000 {
+            logs.write("p", "info", &message);
+        }
+        assert!(rotated_file(dir.path(), "p", KEEP_FILES).exists());
+        assert!(!rotated_file(dir.path(), "p", KEEP_FILES + 1).exists());
+        logs.write("p", "warn", "very last");
+        let tail = read_tail(dir.path(), "p", 3).unwrap();
+        assert_eq!(tail.len(), 3);
+        assert_eq!(tail[2].message, "very last");
     }
 }
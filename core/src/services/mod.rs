@@ -1,3 +1,27 @@
+use plugin_api::RpcError;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+pub mod device;
 pub mod log;
+pub mod solar;
 pub mod storage;
 pub mod timer;
+
+/// Services the core offers to plugins, as announced in `core.hello`.
+pub const SERVICES: &[&str] = &[
+    "log",
+    "event",
+    "timer",
+    "storage",
+    "device",
+    "state",
+    "automation",
+    "scene",
+];
+
+/// Deserialize request params, treating missing params as an empty object.
+pub(crate) fn parse<T: for<'de> Deserialize<'de>>(params: Option<Value>) -> Result<T, RpcError> {
+    serde_json::from_value(params.unwrap_or_else(|| json!({})))
+        .map_err(|e| RpcError::new(RpcError::INVALID_PARAMS, format!("invalid params: {e}")))
+}
@@ -0,0 +1,268 @@
+//! Offline sunrise, sunset and twilight times, using the sunrise equation.
+//! Times are accurate to about a minute, which is plenty for schedules.
+
+use chrono::{DateTime, Days, NaiveDate, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+use super::timer::Zone;
+
+/// Julian date of 2000-01-01 12:00 UTC.
+const J2000: f64 = 2_451_545.0;
+/// Julian date of the Unix epoch.
+const UNIX_EPOCH_JD: f64 = 2_440_587.5;
+/// Axial tilt of the earth, in degrees.
+const OBLIQUITY: f64 = 23.4397;
+
+/// A point on earth, in degrees; north and east are positive.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Location {
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+impl Location {
+    pub fn new(latitude: f64, longitude: f64) -> Result<Self, String> {
+        if !(-90.0..=90.0).contains(&latitude) {
+            return Err(format!("latitude {latitude} is not between -90 and 90"));
+        }
+        if !(-180.0..=180.0).contains(&longitude) {
+            return Err(format!("longitude {longitude} is not between -180 and 180"));
+        }
+        Ok(Self {
+            latitude,
+            longitude,
+        })
+    }
+}
+
+/// A daily solar event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SolarEvent {
+    /// Start of civil twilight, when the sun is 6° below the horizon.
+    Dawn,
+    Sunrise,
+    /// The sun's highest point.
+    Noon,
+    Sunset,
+    /// End of civil twilight.
+    Dusk,
+}
+
+impl SolarEvent {
+    fn parse(name: &str) -> Option<Self> {
+        Some(match name {
+            "dawn" => SolarEvent::Dawn,
+            "sunrise" => SolarEvent::Sunrise,
+            "noon" => SolarEvent::Noon,
+            "sunset" => SolarEvent::Sunset,
+            "dusk" => SolarEvent::Dusk,
+            _ => return None,
+        })
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            SolarEvent::Dawn => "dawn",
+            SolarEvent::Sunrise => "sunrise",
+            SolarEvent::Noon => "noon",
+            SolarEvent::Sunset => "sunset",
+            SolarEvent::Dusk => "dusk",
+        }
+    }
+
+    /// When the event happens on the day `n` days after 2000-01-01 at
+    /// `loc`, or `None` if the sun does not rise or set that day.
+    fn time(self, n: i64, loc: Location) -> Option<DateTime<Utc>> {
+        // mean solar time, and the sun's mean anomaly and ecliptic longitude
+        let j = n as f64 - loc.longitude / 360.0;
+        let m = (357.5291 + 0.985_600_28 * j).rem_euclid(360.0);
+        let mr = m.to_radians();
+        let c = 1.9148 * mr.sin() + 0.02 * (2.0 * mr).sin() + 0.0003 * (3.0 * mr).sin();
+        let lambda = (m + c + 180.0 + 102.9372).rem_euclid(360.0).to_radians();
+        let transit = J2000 + j + 0.0053 * mr.sin() - 0.0069 * (2.0 * lambda).sin();
+
+        let altitude: f64 = match self {
+            SolarEvent::Noon => return julian_to_utc(transit),
+            SolarEvent::Sunrise | SolarEvent::Sunset => -0.833,
+            SolarEvent::Dawn | SolarEvent::Dusk => -6.0,
+        };
+        let declination = (lambda.sin() * OBLIQUITY.to_radians().sin()).asin();
+        let phi = loc.latitude.to_radians();
+        let cos_hour_angle = (altitude.to_radians().sin() - phi.sin() * declination.sin())
+            / (phi.cos() * declination.cos());
+        if !(-1.0..=1.0).contains(&cos_hour_angle) {
+            return None;
+        }
+        let hour_angle = cos_hour_angle.acos().to_degrees() / 360.0;
+        match self {
+            SolarEvent::Dawn | SolarEvent::Sunrise => julian_to_utc(transit - hour_angle),
+            _ => julian_to_utc(transit + hour_angle),
+        }
+    }
+}
+
+fn julian_to_utc(jd: f64) -> Option<DateTime<Utc>> {
+    let millis = ((jd - UNIX_EPOCH_JD) * 86_400_000.0).round() as i64;
+    Utc.timestamp_millis_opt(millis).single()
+}
+
+/// A solar event with an offset, written like `sunset`, `sunset-30m` or
+/// `sunrise+1h15m`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SolarSpec {
+    pub event: SolarEvent,
+    pub offset: chrono::Duration,
+}
+
+impl SolarSpec {
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let invalid = || format!("invalid solar time {spec:?}");
+        let (name, offset) = match spec.find(['+', '-']) {
+            Some(i) => {
+                let sign = if spec[i..].starts_with('-') { -1 } else { 1 };
+                (
+                    &spec[..i],
+                    sign * parse_offset(&spec[i + 1..]).ok_or_else(invalid)?,
+                )
+            }
+            None => (spec, 0),
+        };
+        let event = SolarEvent::parse(name.trim()).ok_or_else(invalid)?;
+        Ok(Self {
+            event,
+            offset: chrono::Duration::seconds(offset),
+        })
+    }
+
+    /// The first time after `now` this spec matches at `loc`, with calendar
+    /// days counted in `zone`. Looks at most a year ahead, so near the poles
+    /// it may be `None`.
+    pub fn next_after(
+        &self,
+        now: DateTime<Utc>,
+        loc: Location,
+        zone: Zone,
+    ) -> Option<DateTime<Utc>> {
+        let today = zone.date(now);
+        let epoch = NaiveDate::from_ymd_opt(2000, 1, 1)?;
+        // start a day early: a negative offset can pull tomorrow's event
+        // into today, and a positive one yesterday's
+        let first = today.checked_sub_days(Days::new(1))?;
+        (0..=367)
+            .filter_map(|i| first.checked_add_days(Days::new(i)))
+            .filter_map(|day| {
+                let n = (day - epoch).num_days();
+                self.event.time(n, loc).map(|t| t + self.offset)
+            })
+            .find(|t| *t > now)
+    }
+}
+
+impl fmt::Display for SolarSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.event.name())?;
+        let secs = self.offset.num_seconds();
+        if secs != 0 {
+            let sign = if secs < 0 { '-' } else { '+' };
+            let secs = secs.abs();
+            write!(f, "{sign}")?;
+            let (h, m, s) = (secs / 3600, secs / 60 % 60, secs % 60);
+            if h > 0 {
+                write!(f, "{h}h")?;
+            }
+            if m > 0 {
+                write!(f, "{m}m")?;
+            }
+            if s > 0 {
+                write!(f, "{s}s")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Parse an offset such as `30m`, `1h15m` or `90s` into seconds.
+fn parse_offset(s: &str) -> Option<i64> {
+    let mut total = 0i64;
+    let mut digits = String::new();
+    for ch in s.trim().chars() {
+        if ch.is_ascii_digit() {
+            digits.push(ch);
+            continue;
+        }
+        let unit = match ch {
+            'h' => 3600,
+            'm' => 60,
+            's' => 1,
+            _ => return None,
+        };
+        total = total.checked_add(digits.parse::<i64>().ok()?.checked_mul(unit)?)?;
+        digits.clear();
+    }
+    // a trailing bare number is not allowed: the unit must be explicit
+    (digits.is_empty() && total > 0).then_some(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    fn assert_close(actual: DateTime<Utc>, expected: &str) {
+        let diff = (actual - at(expected)).num_seconds().abs();
+        assert!(
+            diff <= 120,
+            "{actual} is not within 2 minutes of {expected}"
+        );
+    }
+
+    #[test]
+    fn computes_berlin_midsummer() {
+        let berlin = Location::new(52.52, 13.405).unwrap();
+        let zone = Zone::parse("Europe/Berlin").unwrap();
+        let now = at("2024-06-21T00:00:00+02:00");
+        let next = |spec: &str| {
+            SolarSpec::parse(spec)
+                .unwrap()
+                .next_after(now, berlin, zone)
+                .unwrap()
+        };
+        assert_close(next("sunrise"), "2024-06-21T04:43:00+02:00");
+        assert_close(next("sunset"), "2024-06-21T21:33:00+02:00");
+        assert_close(next("dusk"), "2024-06-21T22:24:00+02:00");
+        assert_close(next("sunset-30m"), "2024-06-21T21:03:00+02:00");
+    }
+
+    #[test]
+    fn skips_days_without_sunset() {
+        let tromso = Location::new(69.65, 18.96).unwrap();
+        let zone = Zone::parse("Europe/Oslo").unwrap();
+        let spec = SolarSpec::parse("sunset").unwrap();
+        let next = spec
+            .next_after(at("2024-06-21T12:00:00Z"), tromso, zone)
+            .unwrap();
+        // midnight sun lasts until late July
+        assert!(next > at("2024-07-20T00:00:00Z"), "{next}");
+    }
+
+    #[test]
+    fn parses_and_formats_specs() {
+        let spec = SolarSpec::parse("sunrise+1h15m").unwrap();
+        assert_eq!(spec.event, SolarEvent::Sunrise);
+        assert_eq!(spec.offset, chrono::Duration::minutes(75));
+        assert_eq!(spec.to_string(), "sunrise+1h15m");
+        assert_eq!(
+            SolarSpec::parse("sunset-30m").unwrap().to_string(),
+            "sunset-30m"
+        );
+        assert_eq!(SolarSpec::parse("dusk").unwrap().to_string(), "dusk");
+        for bad in ["moonrise", "sunset-30", "sunset+", "sunset-5x", "sunset-0m"] {
+            assert!(SolarSpec::parse(bad).is_err(), "{bad}");
+        }
+        assert!(Location::new(91.0, 0.0).is_err());
+    }
+}
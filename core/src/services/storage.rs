@@ -1,27 +1,60 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 use directories::ProjectDirs;
-use serde_json::Value;
-use std::{collections::HashMap, path::PathBuf};
-use tokio::{fs, sync::Mutex};
+use plugin_api::RpcError;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+use tokio::{fs, io::AsyncWriteExt, sync::Mutex};
+
+use super::parse;
+
+/// Default directory under which per-plugin data is stored.
+pub fn default_data_dir() -> PathBuf {
+    let proj = ProjectDirs::from("org", "homecore", "homecore").unwrap();
+    proj.data_dir().to_path_buf()
+}
+
+/// Directory below `data_dir` for the files of `plugin_id`, including its
+/// storage.
+pub fn plugin_dir(data_dir: &Path, plugin_id: &str) -> PathBuf {
+    data_dir.join("plugins").join(plugin_id)
+}
 
 /// Simple JSON based key-value storage for plugins.
 pub struct Storage {
     file: PathBuf,
-    data: Mutex<HashMap<String, Value>>,
+    data: Mutex<BTreeMap<String, Value>>,
 }
 
 impl Storage {
-    /// Create storage for a specific plugin id.
+    /// Create storage for a specific plugin id in the default data directory.
     pub async fn new(plugin_id: &str) -> Result<Self> {
-        let proj = ProjectDirs::from("org", "homecore", "homecore").unwrap();
-        let dir = proj.data_dir().join("plugins").join(plugin_id);
+        Self::open(&default_data_dir(), plugin_id).await
+    }
+
+    /// Open the storage of `plugin_id` below `data_dir`.
+    pub async fn open(data_dir: &Path, plugin_id: &str) -> Result<Self> {
+        if plugin_id.is_empty()
+            || plugin_id == "."
+            || plugin_id == ".."
+            || plugin_id.contains(['/', '\\'])
+        {
+            anyhow::bail!("invalid plugin id {plugin_id:?}");
+        }
+        let dir = plugin_dir(data_dir, plugin_id);
         fs::create_dir_all(&dir).await?;
         let file = dir.join("data.json");
-        let data = if let Ok(bytes) = fs::read(&file).await {
-            serde_json::from_slice(&bytes).unwrap_or_default()
-        } else {
-            HashMap::new()
+        let mut data: BTreeMap<String, Value> = match fs::read(&file).await {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .with_context(|| format!("corrupt storage file {}", file.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e.into()),
         };
+        // nulls stand for absent keys, so drop any found in the file
+        data.retain(|_, v| !v.is_null());
         Ok(Self {
             file,
             data: Mutex::new(data),
@@ -33,12 +66,259 @@ impl Storage {
         self.data.lock().await.get(key).cloned()
     }
 
-    /// Store a value under a key.
+    /// Store a value under a key. Storing `null` removes the key, as `null`
+    /// stands for an absent key throughout the storage API.
     pub async fn put(&self, key: String, value: Value) -> Result<()> {
         let mut data = self.data.lock().await;
-        data.insert(key, value);
-        let bytes = serde_json::to_vec(&*data)?;
-        fs::write(&self.file, bytes).await?;
+        if value.is_null() {
+            if data.contains_key(&key) {
+                self.commit(&mut data, key, None).await?;
+            }
+            return Ok(());
+        }
+        self.commit(&mut data, key, Some(value)).await?;
         Ok(())
     }
+
+    /// Remove a key, returning whether it existed.
+    pub async fn delete(&self, key: &str) -> Result<bool> {
+        let mut data = self.data.lock().await;
+        if !data.contains_key(key) {
+            return Ok(false);
+        }
+        self.commit(&mut data, key.to_string(), None).await?;
+        Ok(true)
+    }
+
+    /// List keys starting with `prefix` in lexicographic order.
+    pub async fn list(&self, prefix: &str) -> Vec<String> {
+        self.data
+            .lock()
+            .await
+            .range(prefix.to_string()..)
+            .take_while(|(k, _)| k.starts_with(prefix))
+            .map(|(k, _)| k.clone())
+            .collect()
+    }
+
+    /// Replace the value of `key` with `new` only if it currently equals
+    /// `expected`. `None` or `null` stands for an absent key on either side,
+    /// so a swap from `None` creates the key and a swap to `None` deletes it.
+    /// Returns the value found, which equals `expected` exactly when the swap
+    /// happened.
+    pub async fn compare_and_swap(
+        &self,
+        key: &str,
+        expected: Option<&Value>,
+        new: Option<Value>,
+    ) -> Result<Option<Value>> {
+        let expected = expected.filter(|v| !v.is_null());
+        let new = new.filter(|v| !v.is_null());
+        let mut data = self.data.lock().await;
+        let current = data.get(key).cloned();
+        if current.as_ref() == expected && current != new {
+            self.commit(&mut data, key.to_string(), new).await?;
+        }
+        Ok(current)
+    }
+
+    /// Apply a single change and persist it. The in-memory map is only left
+    /// modified if the file was written successfully.
+    async fn commit(
+        &self,
+        data: &mut BTreeMap<String, Value>,
+        key: String,
+        value: Option<Value>,
+    ) -> Result<()> {
+        let previous = match value {
+            Some(v) => data.insert(key.clone(), v),
+            None => data.remove(&key),
+        };
+        if let Err(e) = self.persist(data).await {
+            match previous {
+                Some(v) => data.insert(key, v),
+                None => data.remove(&key),
+            };
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Write the map to a temporary file and atomically rename it over the
+    /// data file, so a crash never leaves a truncated file behind.
+    async fn persist(&self, data: &BTreeMap<String, Value>) -> Result<()> {
+        let bytes = serde_json::to_vec(data)?;
+        let tmp = self.file.with_extension("json.tmp");
+        let mut f = fs::File::create(&tmp).await?;
+        f.write_all(&bytes).await?;
+        f.sync_all().await?;
+        drop(f);
+        fs::rename(&tmp, &self.file).await?;
+        Ok(())
+    }
+}
+
+#[derive(Deserialize)]
+struct KeyParams {
+    key: String,
+}
+
+#[derive(Deserialize)]
+struct PutParams {
+    key: String,
+    value: Value,
+}
+
+#[derive(Deserialize)]
+struct ListParams {
+    #[serde(default)]
+    prefix: String,
+}
+
+#[derive(Deserialize)]
+struct CasParams {
+    key: String,
+    #[serde(default)]
+    expected: Option<Value>,
+    #[serde(default)]
+    value: Option<Value>,
+}
+
+fn internal(e: anyhow::Error) -> RpcError {
+    RpcError::new(RpcError::INTERNAL_ERROR, format!("storage error: {e}"))
+}
+
+/// Handle a `storage.*` request on behalf of the plugin owning `storage`.
+pub async fn handle(
+    storage: &Storage,
+    method: &str,
+    params: Option<Value>,
+) -> Result<Value, RpcError> {
+    match method {
+        "storage.get" => {
+            let p: KeyParams = parse(params)?;
+            Ok(json!({ "value": storage.get(&p.key).await }))
+        }
+        "storage.put" => {
+            let p: PutParams = parse(params)?;
+            storage.put(p.key, p.value).await.map_err(internal)?;
+            Ok(json!({"ok":true}))
+        }
+        "storage.delete" => {
+            let p: KeyParams = parse(params)?;
+            let deleted = storage.delete(&p.key).await.map_err(internal)?;
+            Ok(json!({ "deleted": deleted }))
+        }
+        "storage.list" => {
+            let p: ListParams = parse(params)?;
+            Ok(json!({ "keys": storage.list(&p.prefix).await }))
+        }
+        "storage.compare_and_swap" => {
+            // a JSON null in `expected` or `value` means "absent"
+            let p: CasParams = parse(params)?;
+            let expected = p.expected.filter(|v| !v.is_null());
+            let value = p.value.filter(|v| !v.is_null());
+            let current = storage
+                .compare_and_swap(&p.key, expected.as_ref(), value)
+                .await
+                .map_err(internal)?;
+            Ok(json!({ "swapped": current == expected, "current": current }))
+        }
+        _ => Err(RpcError::new(
+            RpcError::METHOD_NOT_FOUND,
+            format!("unknown method {}", method),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn persists_across_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let s = Storage::open(dir.path(), "p").await.unwrap();
+        s.put("a".into(), json!(1)).await.unwrap();
+        s.put("b".into(), json!({"x": true})).await.unwrap();
+        assert!(s.delete("a").await.unwrap());
+        assert!(!s.delete("a").await.unwrap());
+        drop(s);
+
+        let s = Storage::open(dir.path(), "p").await.unwrap();
+        assert_eq!(s.get("a").await, None);
+        assert_eq!(s.get("b").await, Some(json!({"x": true})));
+        assert!(!dir.path().join("plugins/p/data.json.tmp").exists());
+    }
+
+    #[tokio::test]
+    async fn scoped_per_plugin() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = Storage::open(dir.path(), "a").await.unwrap();
+        let b = Storage::open(dir.path(), "b").await.unwrap();
+        a.put("k".into(), json!("a")).await.unwrap();
+        assert_eq!(b.get("k").await, None);
+        assert!(Storage::open(dir.path(), "../a").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn list_by_prefix() {
+        let dir = tempfile::tempdir().unwrap();
+        let s = Storage::open(dir.path(), "p").await.unwrap();
+        for k in ["room/2", "room/1", "rooms", "user/1"] {
+            s.put(k.into(), json!(true)).await.unwrap();
+        }
+        assert_eq!(s.list("room/").await, vec!["room/1", "room/2"]);
+        assert_eq!(s.list("").await.len(), 4);
+        assert!(s.list("zzz").await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn compare_and_swap_over_ipc() {
+        let dir = tempfile::tempdir().unwrap();
+        let s = Storage::open(dir.path(), "p").await.unwrap();
+        let cas = |expected: Value, value: Value| {
+            let s = &s;
+            async move {
+                handle(
+                    s,
+                    "storage.compare_and_swap",
+                    Some(json!({"key":"n","expected":expected,"value":value})),
+                )
+                .await
+                .unwrap()
+            }
+        };
+
+        let r = cas(json!(null), json!(1)).await;
+        assert_eq!(r, json!({"swapped": true, "current": null}));
+        let r = cas(json!(null), json!(2)).await;
+        assert_eq!(r, json!({"swapped": false, "current": 1}));
+        let r = cas(json!(1), json!(2)).await;
+        assert_eq!(r["swapped"], json!(true));
+        let r = cas(json!(2), json!(null)).await;
+        assert_eq!(r["swapped"], json!(true));
+        assert_eq!(
+            handle(&s, "storage.get", Some(json!({"key":"n"})))
+                .await
+                .unwrap(),
+            json!({"value": null})
+        );
+
+        // putting null removes the key, so it agrees with get and CAS
+        for (key, value) in [("n", json!(3)), ("n", json!(null)), ("m", json!(null))] {
+            handle(&s, "storage.put", Some(json!({"key": key, "value": value})))
+                .await
+                .unwrap();
+        }
+        let list = handle(&s, "storage.list", None).await.unwrap();
+        assert_eq!(list, json!({"keys": []}));
+        let r = cas(json!(null), json!(4)).await;
+        assert_eq!(r, json!({"swapped": true, "current": null}));
+
+        let err = handle(&s, "storage.put", Some(json!({"value": 1})))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code, RpcError::INVALID_PARAMS);
+    }
 }
@@ -1,43 +1,667 @@
-use crate::ipc::write_envelope;
-use plugin_api::Envelope;
-use serde::Deserialize;
-use serde_json::json;
-use std::sync::Arc;
-use tokio::sync::Mutex;
-use tokio::time::{self, Duration};
-
-#[derive(Debug, Deserialize)]
-pub struct TimerParams {
+use crate::{
+    events::{Event, EventBus},
+    ipc::write_envelope,
+};
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, Utc};
+use croner::Cron;
+use parking_lot::Mutex;
+use plugin_api::{Envelope, RpcError};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+use tokio::{
+    io::BufWriter,
+    process::ChildStdin,
+    task::JoinHandle,
+    time::{self, Duration, Instant, MissedTickBehavior},
+};
+use uuid::Uuid;
+
+use super::{
+    parse,
+    solar::{Location, SolarSpec},
+};
+
+/// Topic of the events timers send.
+pub const TICK_TOPIC: &str = "timer.tick";
+
+/// Longest a wall-clock timer sleeps before looking at the clock again, so
+/// clock changes such as the first NTP sync after boot are picked up.
+const CLOCK_CHECK: Duration = Duration::from_secs(60);
+
+pub type Writer = Arc<tokio::sync::Mutex<BufWriter<ChildStdin>>>;
+
+/// Time zone wall-clock schedules are evaluated in.
+#[derive(Debug, Clone, Copy, Default)]
+pub enum Zone {
+    /// The system's local zone.
+    #[default]
+    Local,
+    Named(chrono_tz::Tz),
+}
+
+impl Zone {
+    /// Look up an IANA zone name such as `Europe/Berlin`, or `local`.
+    pub fn parse(name: &str) -> Result<Self, String> {
+        if name == "local" {
+            return Ok(Zone::Local);
+        }
+        name.parse()
+            .map(Zone::Named)
+            .map_err(|_| format!("unknown time zone {name:?}"))
+    }
+
+    pub fn name(&self) -> &str {
+        match self {
+            Zone::Local => "local",
+            Zone::Named(tz) => tz.name(),
+        }
+    }
+
+    /// The wall-clock time at `t` in this zone.
+    pub fn local(&self, t: DateTime<Utc>) -> NaiveDateTime {
+        match self {
+            Zone::Local => t.with_timezone(&Local).naive_local(),
+            Zone::Named(tz) => t.with_timezone(tz).naive_local(),
+        }
+    }
+
+    /// The calendar date at `t` in this zone.
+    pub fn date(&self, t: DateTime<Utc>) -> NaiveDate {
+        match self {
+            Zone::Local => t.with_timezone(&Local).date_naive(),
+            Zone::Named(tz) => t.with_timezone(tz).date_naive(),
+        }
+    }
+}
+
+/// Where the home is: the default time zone for wall-clock timers, and the
+/// location solar timers are computed for.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Home {
+    pub zone: Zone,
+    pub location: Option<Location>,
+}
+
+/// When a timer fires.
+#[derive(Debug, Clone)]
+pub enum Schedule {
+    /// Every `period`, starting one period from now.
+    Interval(Duration),
+    /// Once, after the delay.
+    Timeout(Duration),
+    /// At the times matching a five-field cron expression.
+    Cron {
+        expr: String,
+        cron: Box<Cron>,
+        zone: Zone,
+    },
+    /// Daily at a solar event such as `sunset-30m`.
+    Solar {
+        spec: SolarSpec,
+        location: Location,
+        zone: Zone,
+    },
+}
+
+impl Schedule {
+    /// Parse a standard cron expression (`minute hour day month weekday`).
+    pub fn cron(expr: &str, zone: Zone) -> Result<Self, String> {
+        let cron = Cron::new(expr)
+            .parse()
+            .map_err(|e| format!("invalid cron expression {expr:?}: {e}"))?;
+        let schedule = Schedule::Cron {
+            expr: expr.to_string(),
+            cron: Box::new(cron),
+            zone,
+        };
+        if schedule.next_after(Utc::now()).is_none() {
+            return Err(format!("cron expression {expr:?} never fires"));
+        }
+        Ok(schedule)
+    }
+
+    /// A daily solar schedule, for days counted in `zone`.
+    pub fn solar(spec: &str, location: Location, zone: Zone) -> Result<Self, String> {
+        let spec = SolarSpec::parse(spec)?;
+        let schedule = Schedule::Solar {
+            spec,
+            location,
+            zone,
+        };
+        if schedule.next_after(Utc::now()).is_none() {
+            return Err(format!("{spec} does not occur at the home location"));
+        }
+        Ok(schedule)
+    }
+
+    /// The first wall-clock time after `now` this schedule fires at. Only
+    /// meaningful for wall-clock schedules.
+    fn next_after(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        match self {
+            Schedule::Cron { cron, zone, .. } => match zone {
+                Zone::Local => cron
+                    .find_next_occurrence(&now.with_timezone(&Local), false)
+                    .ok()
+                    .map(|t| t.with_timezone(&Utc)),
+                Zone::Named(tz) => cron
+                    .find_next_occurrence(&now.with_timezone(tz), false)
+                    .ok()
+                    .map(|t| t.with_timezone(&Utc)),
+            },
+            Schedule::Solar {
+                spec,
+                location,
+                zone,
+            } => spec.next_after(now, *location, *zone),
+            Schedule::Interval(d) | Schedule::Timeout(d) => {
+                chrono::Duration::from_std(*d).ok().map(|d| now + d)
+            }
+        }
+    }
+}
+
+/// Where the ticks of one plugin's timers go.
+#[derive(Clone)]
+pub struct Target {
+    pub plugin_id: String,
+    /// The plugin's stdin, or `None` for subscribers inside the core, which
+    /// get their ticks through the event bus.
+    pub writer: Option<Writer>,
+    pub bus: Arc<Mutex<EventBus>>,
+}
+
+impl Target {
+    /// Send a tick if the plugin subscribed to them. Returns `false` once the
+    /// plugin can no longer be written to.
+    ///
+    /// The write waits while the plugin's stdin is full, and ticks falling
+    /// due meanwhile are dropped, so a slow plugin gets one tick per timer
+    /// rather than a backlog.
+    async fn tick(&self, id: &str) -> bool {
+        let payload = json!({"id": id, "now_ms": Utc::now().timestamp_millis()});
+        let Some(writer) = &self.writer else {
+            let event = Event {
+                topic: TICK_TOPIC.to_string(),
+                source: None,
+                payload: Some(payload),
+            };
+            return self.bus.lock().send_to(&self.plugin_id, event);
+        };
+        if !self.bus.lock().is_subscribed(&self.plugin_id, TICK_TOPIC) {
+            return true;
+        }
+        let env = Envelope::event(TICK_TOPIC, Some(payload));
+        let mut w = writer.lock().await;
+        write_envelope(&mut *w, &env).await.is_ok()
+    }
+}
+
+/// A timer as reported by `timer.list`.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct TimerInfo {
     pub id: String,
-    pub millis: u64,
+    pub kind: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub millis: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cron: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub at: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tz: Option<String>,
+    /// When the timer fires next, in milliseconds since the epoch.
+    pub next_ms: Option<i64>,
+}
+
+struct Entry {
+    seq: u64,
+    schedule: Schedule,
+    next: Arc<Mutex<Option<DateTime<Utc>>>>,
+    task: JoinHandle<()>,
+}
+
+impl Drop for Entry {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Timers of all plugins, keyed by owning plugin and timer id. Dropping an
+/// entry stops its timer.
+#[derive(Clone, Default)]
+pub struct Timers {
+    home: Home,
+    entries: Arc<Mutex<BTreeMap<(String, String), Entry>>>,
+    seq: Arc<AtomicU64>,
 }
 
-/// Spawn a repeating timer that sends `timer.tick` events using the provided writer.
-pub fn spawn_timer(
-    writer: Arc<Mutex<tokio::io::BufWriter<tokio::process::ChildStdin>>>,
-    params: TimerParams,
+impl Timers {
+    pub fn new(home: Home) -> Self {
+        Self {
+            home,
+            ..Self::default()
+        }
+    }
+
+    /// Start a timer for `target`, replacing one with the same id.
+    pub fn set(&self, target: Target, id: String, schedule: Schedule) {
+        let key = (target.plugin_id.clone(), id);
+        let seq = self.seq.fetch_add(1, Ordering::Relaxed);
+        let next = Arc::new(Mutex::new(schedule.next_after(Utc::now())));
+        // hold the lock so a timeout cannot fire and remove itself before
+        // it is inserted
+        let mut entries = self.entries.lock();
+        let task = tokio::spawn(run(
+            self.clone(),
+            key.clone(),
+            seq,
+            target,
+            schedule.clone(),
+            next.clone(),
+        ));
+        entries.insert(
+            key,
+            Entry {
+                seq,
+                schedule,
+                next,
+                task,
+            },
+        );
+    }
+
+    /// Stop a timer. Returns whether it existed.
+    pub fn cancel(&self, plugin_id: &str, id: &str) -> bool {
+        let key = (plugin_id.to_string(), id.to_string());
+        self.entries.lock().remove(&key).is_some()
+    }
+
+    /// Stop all timers of a plugin.
+    pub fn clear(&self, plugin_id: &str) {
+        self.entries
+            .lock()
+            .retain(|(owner, _), _| owner != plugin_id);
+    }
+
+    pub fn list(&self, plugin_id: &str) -> Vec<TimerInfo> {
+        self.entries
+            .lock()
+            .iter()
+            .filter(|((owner, _), _)| owner == plugin_id)
+            .map(|((_, id), entry)| {
+                let info = TimerInfo {
+                    id: id.clone(),
+                    next_ms: entry.next.lock().map(|t| t.timestamp_millis()),
+                    ..Default::default()
+                };
+                match &entry.schedule {
+                    Schedule::Interval(d) => TimerInfo {
+                        kind: "interval",
+                        millis: Some(d.as_millis() as u64),
+                        ..info
+                    },
+                    Schedule::Timeout(d) => TimerInfo {
+                        kind: "timeout",
+                        millis: Some(d.as_millis() as u64),
+                        ..info
+                    },
+                    Schedule::Cron { expr, zone, .. } => TimerInfo {
+                        kind: "cron",
+                        cron: Some(expr.clone()),
+                        tz: Some(zone.name().to_string()),
+                        ..info
+                    },
+                    Schedule::Solar { spec, zone, .. } => TimerInfo {
+                        kind: "solar",
+                        at: Some(spec.to_string()),
+                        tz: Some(zone.name().to_string()),
+                        ..info
+                    },
+                }
+            })
+            .collect()
+    }
+
+    /// The zone named by a request, defaulting to the home zone.
+    fn zone(&self, name: Option<&str>) -> Result<Zone, RpcError> {
+        match name {
+            Some(name) => Zone::parse(name).map_err(invalid),
+            None => Ok(self.home.zone),
+        }
+    }
+
+    /// Forget a finished timer, unless it has been replaced meanwhile.
+    fn finished(&self, key: &(String, String), seq: u64) {
+        let mut entries = self.entries.lock();
+        if entries.get(key).is_some_and(|e| e.seq == seq) {
+            entries.remove(key);
+        }
+    }
+}
+
+async fn run(
+    timers: Timers,
+    key: (String, String),
+    seq: u64,
+    target: Target,
+    schedule: Schedule,
+    next: Arc<Mutex<Option<DateTime<Utc>>>>,
 ) {
-    let TimerParams { id, millis } = params;
-    tokio::spawn(async move {
-        let mut interval = time::interval(Duration::from_millis(millis));
-        loop {
-            interval.tick().await;
-            let now = std::time::SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH)
-                .unwrap()
-                .as_millis();
-            let env = Envelope {
-                id: None,
-                kind: plugin_api::Kind::Event,
-                method: None,
-                params: None,
-                result: None,
-                error: None,
-                topic: Some("timer.tick".into()),
-                payload: Some(json!({"id":id,"now_ms":now})),
+    let id = key.1.as_str();
+    match &schedule {
+        Schedule::Interval(period) => {
+            let mut interval = time::interval_at(Instant::now() + *period, *period);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+            loop {
+                interval.tick().await;
+                *next.lock() = schedule.next_after(Utc::now());
+                if !target.tick(id).await {
+                    break;
+                }
+            }
+        }
+        Schedule::Timeout(delay) => {
+            time::sleep(*delay).await;
+            target.tick(id).await;
+        }
+        Schedule::Cron { .. } | Schedule::Solar { .. } => {
+            while let Some(at) = schedule.next_after(Utc::now()) {
+                *next.lock() = Some(at);
+                sleep_until(at).await;
+                // occurrences missed while the tick was being written are
+                // skipped by computing the next one from the current time
+                if !target.tick(id).await {
+                    break;
+                }
+            }
+        }
+    }
+    timers.finished(&key, seq);
+}
+
+/// Sleep until a wall-clock time.
+async fn sleep_until(at: DateTime<Utc>) {
+    loop {
+        let Ok(left) = (at - Utc::now()).to_std() else {
+            return;
+        };
+        if left.is_zero() {
+            return;
+        }
+        time::sleep(left.min(CLOCK_CHECK)).await;
+    }
+}
+
+#[derive(Deserialize)]
+struct DelayParams {
+    #[serde(default)]
+    id: Option<String>,
+    millis: u64,
+}
+
+#[derive(Deserialize)]
+struct CronParams {
+    #[serde(default)]
+    id: Option<String>,
+    cron: String,
+    #[serde(default)]
+    tz: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct SolarParams {
+    #[serde(default)]
+    id: Option<String>,
+    at: String,
+}
+
+#[derive(Deserialize)]
+struct IdParams {
+    id: String,
+}
+
+fn invalid(message: String) -> RpcError {
+    RpcError::new(RpcError::INVALID_PARAMS, message)
+}
+
+/// Handle a `timer.*` request on behalf of the plugin `target` belongs to.
+pub fn handle(
+    timers: &Timers,
+    target: Target,
+    method: &str,
+    params: Option<Value>,
+) -> Result<Value, RpcError> {
+    let (id, schedule) = match method {
+        "timer.set_interval" | "timer.set_timeout" => {
+            let p: DelayParams = parse(params)?;
+            if p.millis == 0 {
+                return Err(invalid("millis must be positive".into()));
+            }
+            let d = Duration::from_millis(p.millis);
+            let schedule = if method == "timer.set_interval" {
+                Schedule::Interval(d)
+            } else {
+                Schedule::Timeout(d)
+            };
+            (p.id, schedule)
+        }
+        "timer.set_cron" => {
+            let p: CronParams = parse(params)?;
+            let zone = timers.zone(p.tz.as_deref())?;
+            (p.id, Schedule::cron(&p.cron, zone).map_err(invalid)?)
+        }
+        "timer.set_solar" => {
+            let p: SolarParams = parse(params)?;
+            let Some(location) = timers.home.location else {
+                return Err(RpcError::new(
+                    RpcError::INTERNAL_ERROR,
+                    "the core has no home location configured",
+                ));
             };
-            let mut w = writer.lock().await;
-            let _ = write_envelope(&mut *w, &env).await;
+            let zone = timers.zone(None)?;
+            (
+                p.id,
+                Schedule::solar(&p.at, location, zone).map_err(invalid)?,
+            )
+        }
+        "timer.cancel" => {
+            let p: IdParams = parse(params)?;
+            let cancelled = timers.cancel(&target.plugin_id, &p.id);
+            return Ok(json!({ "cancelled": cancelled }));
         }
-    });
+        "timer.list" => return Ok(json!({ "timers": timers.list(&target.plugin_id) })),
+        _ => {
+            return Err(RpcError::new(
+                RpcError::METHOD_NOT_FOUND,
+                format!("unknown method {}", method),
+            ))
+        }
+    };
+    let id = id.unwrap_or_else(|| Uuid::new_v4().to_string());
+    timers.set(target, id.clone(), schedule);
+    Ok(json!({"ok": true, "id": id}))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use tokio::sync::mpsc::UnboundedReceiver;
+
+    #[test]
+    fn cron_runs_in_the_given_zone() {
+        let zone = Zone::parse("Europe/Berlin").unwrap();
+        let schedule = Schedule::cron("30 7 * * 1-5", zone).unwrap();
+        // Friday 2024-03-29 12:00 UTC; next weekday 07:30 in Berlin is
+        // Monday 07:30 CEST, after the switch to summer time
+        let now = Utc.with_ymd_and_hms(2024, 3, 29, 12, 0, 0).unwrap();
+        let next = schedule.next_after(now).unwrap();
+        assert_eq!(next, Utc.with_ymd_and_hms(2024, 4, 1, 5, 30, 0).unwrap());
+    }
+
+    #[test]
+    fn rejects_bad_schedules() {
+        assert!(Zone::parse("Mars/Olympus").is_err());
+        assert!(Schedule::cron("61 * * * *", Zone::Local).is_err());
+        assert!(Schedule::cron("0 0 31 2 *", Zone::Local).is_err());
+    }
+
+    /// A target inside the core for `plugin_id`, and where its ticks arrive.
+    fn core_target(plugin_id: &str) -> (Target, UnboundedReceiver<Event>) {
+        let bus = Arc::new(Mutex::new(EventBus::new()));
+        let rx = bus.lock().register(plugin_id);
+        let target = Target {
+            plugin_id: plugin_id.into(),
+            writer: None,
+            bus,
+        };
+        (target, rx)
+    }
+
+    /// The ids of the ticks received so far.
+    fn ticks(rx: &mut UnboundedReceiver<Event>) -> Vec<String> {
+        let mut ids = Vec::new();
+        while let Ok(event) = rx.try_recv() {
+            ids.push(event.payload.unwrap()["id"].as_str().unwrap().to_string());
+        }
+        ids
+    }
+
+    fn set(timers: &Timers, target: &Target, method: &str, params: Value) {
+        handle(timers, target.clone(), method, Some(params)).unwrap();
+    }
+
+    // With the clock paused, sleeping advances it straight to the next timer,
+    // so the tests check for ticks halfway between due times.
+
+    #[tokio::test(start_paused = true)]
+    async fn timeout_fires_once_and_is_forgotten() {
+        let timers = Timers::default();
+        let (target, mut rx) = core_target("p");
+        set(
+            &timers,
+            &target,
+            "timer.set_timeout",
+            json!({"id": "t", "millis": 100}),
+        );
+        assert_eq!(timers.list("p")[0].kind, "timeout");
+
+        time::sleep(Duration::from_millis(50)).await;
+        assert!(ticks(&mut rx).is_empty());
+        time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(ticks(&mut rx), vec!["t"]);
+        assert!(timers.list("p").is_empty());
+
+        time::sleep(Duration::from_secs(1)).await;
+        assert!(ticks(&mut rx).is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cancel_stops_a_running_timer() {
+        let timers = Timers::default();
+        let (target, mut rx) = core_target("p");
+        set(
+            &timers,
+            &target,
+            "timer.set_interval",
+            json!({"id": "i", "millis": 100}),
+        );
+        time::sleep(Duration::from_millis(150)).await;
+        assert_eq!(ticks(&mut rx), vec!["i"]);
+
+        let r = handle(
+            &timers,
+            target.clone(),
+            "timer.cancel",
+            Some(json!({"id": "i"})),
+        );
+        assert_eq!(r.unwrap(), json!({"cancelled": true}));
+        assert!(timers.list("p").is_empty());
+        time::sleep(Duration::from_secs(1)).await;
+        assert!(ticks(&mut rx).is_empty());
+        assert!(!timers.cancel("p", "i"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn clear_removes_only_the_plugins_timers() {
+        let timers = Timers::default();
+        let (gone, mut gone_rx) = core_target("gone");
+        let (kept, mut kept_rx) = core_target("kept");
+        for id in ["a", "b"] {
+            set(
+                &timers,
+                &gone,
+                "timer.set_interval",
+                json!({"id": id, "millis": 100}),
+            );
+        }
+        set(
+            &timers,
+            &kept,
+            "timer.set_interval",
+            json!({"id": "a", "millis": 100}),
+        );
+
+        timers.clear("gone");
+        assert!(timers.list("gone").is_empty());
+        assert_eq!(timers.list("kept").len(), 1);
+        time::sleep(Duration::from_millis(150)).await;
+        assert!(ticks(&mut gone_rx).is_empty());
+        assert_eq!(ticks(&mut kept_rx), vec!["a"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn missed_ticks_are_coalesced() {
+        let timers = Timers::default();
+        let (target, mut rx) = core_target("p");
+        set(
+            &timers,
+            &target,
+            "timer.set_interval",
+            json!({"id": "i", "millis": 100}),
+        );
+        time::sleep(Duration::from_millis(50)).await;
+
+        // three due times pass at once, as when the core was starved
+        time::advance(Duration::from_millis(300)).await;
+        time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(ticks(&mut rx).len(), 1);
+        // and the timer keeps its original schedule
+        time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(ticks(&mut rx).len(), 1);
+    }
+
+    #[tokio::test]
+    async fn ticks_only_reach_subscribed_plugins() {
+        use tokio::io::{AsyncBufReadExt, BufReader};
+
+        // `cat` echoes what the target writes to the plugin's stdin
+        let mut child = tokio::process::Command::new("cat")
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .unwrap();
+        let writer = Arc::new(tokio::sync::Mutex::new(BufWriter::new(
+            child.stdin.take().unwrap(),
+        )));
+        let mut echoed = BufReader::new(child.stdout.take().unwrap()).lines();
+        let (mut target, _rx) = core_target("p");
+        target.writer = Some(writer);
+
+        assert!(target.tick("unsubscribed").await);
+        target.bus.lock().subscribe("p", TICK_TOPIC);
+        assert!(target.tick("subscribed").await);
+        let line = echoed.next_line().await.unwrap().unwrap();
+        let env: Envelope = serde_json::from_str(&line).unwrap();
+        assert_eq!(env.topic.as_deref(), Some(TICK_TOPIC));
+        assert_eq!(env.payload.unwrap()["id"], "subscribed");
+    }
 }
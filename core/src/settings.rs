@@ -0,0 +1,266 @@
+//! Plugin settings: the `[plugins.<id>.settings]` table of the core config,
+//! checked against the JSON schema a plugin ships next to its manifest.
+//!
+//! Only the parts of JSON Schema that matter for configuration are
+//! supported: `type`, `enum`, `const`, numeric and length bounds,
+//! `properties`, `required`, `additionalProperties` and `items`. Other
+//! keywords are ignored. Missing properties with a `default` are filled in
+//! before validating.
+
+use anyhow::{Context, Result};
+use serde_json::{Map, Value};
+use std::path::Path;
+
+/// Schema file in the plugin directory, next to `plugin.toml`.
+pub const SCHEMA_FILE: &str = "settings.schema.json";
+
+/// Read the settings schema of the plugin in `plugin_dir`, if it has one.
+pub fn load_schema(plugin_dir: &Path) -> Result<Option<Value>> {
+    let path = plugin_dir.join(SCHEMA_FILE);
+    match std::fs::read(&path) {
+        Ok(bytes) => serde_json::from_slice(&bytes)
+            .map(Some)
+            .with_context(|| format!("invalid {}", path.display())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("reading {}", path.display())),
+    }
+}
+
+/// Fill in defaults and validate `settings` against `schema`. Without a
+/// schema the settings are passed on as they are.
+pub fn resolve(schema: Option<&Value>, settings: Map<String, Value>) -> Result<Value, String> {
+    let mut settings = Value::Object(settings);
+    if let Some(schema) = schema {
+        fill_defaults(schema, &mut settings);
+        let mut errors = Vec::new();
+        validate(schema, &settings, "settings", &mut errors);
+        if !errors.is_empty() {
+            return Err(errors.join("; "));
+        }
+    }
+    Ok(settings)
+}
+
+fn fill_defaults(schema: &Value, value: &mut Value) {
+    let (Some(props), Value::Object(map)) = (schema.get("properties"), value) else {
+        return;
+    };
+    let Some(props) = props.as_object() else {
+        return;
+    };
+    for (name, prop) in props {
+        if !map.contains_key(name) {
+            match prop.get("default") {
+                Some(default) => {
+                    map.insert(name.clone(), default.clone());
+                }
+                None => continue,
+            }
+        }
+        if let Some(child) = map.get_mut(name) {
+            fill_defaults(prop, child);
+        }
+    }
+}
+
+/// Check `value` against `schema`, adding one message per violation.
+fn validate(schema: &Value, value: &Value, path: &str, errors: &mut Vec<String>) {
+    let schema = match schema {
+        Value::Bool(true) => return,
+        Value::Bool(false) => {
+            errors.push(format!("{path}: not allowed"));
+            return;
+        }
+        Value::Object(schema) => schema,
+        _ => return,
+    };
+    if let Some(ty) = schema.get("type") {
+        let allowed: Vec<&str> = match ty {
+            Value::String(t) => vec![t.as_str()],
+            Value::Array(ts) => ts.iter().filter_map(Value::as_str).collect(),
+            _ => Vec::new(),
+        };
+        if !allowed.iter().any(|t| has_type(value, t)) {
+            errors.push(format!(
+                "{path}: expected {}, got {}",
+                allowed.join(" or "),
+                type_name(value)
+            ));
+            // the other keywords assume the right type
+            return;
+        }
+    }
+    if let Some(Value::Array(options)) = schema.get("enum") {
+        if !options.contains(value) {
+            let options: Vec<String> = options.iter().map(Value::to_string).collect();
+            errors.push(format!("{path}: must be one of {}", options.join(", ")));
+        }
+    }
+    if let Some(expected) = schema.get("const") {
+        if value != expected {
+            errors.push(format!("{path}: must be {expected}"));
+        }
+    }
+    if let Some(n) = value.as_f64() {
+        let bound = |key| schema.get(key).and_then(Value::as_f64);
+        if let Some(min) = bound("minimum").filter(|min| n < *min) {
+            errors.push(format!("{path}: must be at least {min}"));
+        }
+        if let Some(max) = bound("maximum").filter(|max| n > *max) {
+            errors.push(format!("{path}: must be at most {max}"));
+        }
+        if let Some(min) = bound("exclusiveMinimum").filter(|min| n <= *min) {
+            errors.push(format!("{path}: must be greater than {min}"));
+        }
+        if let Some(max) = bound("exclusiveMaximum").filter(|max| n >= *max) {
+            errors.push(format!("{path}: must be less than {max}"));
+        }
+    }
+    let len = match value {
+        Value::String(s) => Some(s.chars().count()),
+        Value::Array(items) => Some(items.len()),
+        _ => None,
+    };
+    if let Some(len) = len {
+        let (min_key, max_key, unit) = if value.is_string() {
+            ("minLength", "maxLength", "characters")
+        } else {
+            ("minItems", "maxItems", "items")
+        };
+        let bound = |key| schema.get(key).and_then(Value::as_u64);
+        if let Some(min) = bound(min_key).filter(|min| (len as u64) < *min) {
+            errors.push(format!("{path}: must have at least {min} {unit}"));
+        }
+        if let Some(max) = bound(max_key).filter(|max| (len as u64) > *max) {
+            errors.push(format!("{path}: must have at most {max} {unit}"));
+        }
+    }
+    match value {
+        Value::Object(map) => {
+            let props = schema.get("properties").and_then(Value::as_object);
+            if let Some(Value::Array(required)) = schema.get("required") {
+                for name in required.iter().filter_map(Value::as_str) {
+                    if !map.contains_key(name) {
+                        errors.push(format!("{path}.{name}: required"));
+                    }
+                }
+            }
+            for (name, child) in map {
+                let child_path = format!("{path}.{name}");
+                match props.and_then(|p| p.get(name)) {
+                    Some(prop) => validate(prop, child, &child_path, errors),
+                    None => {
+                        if let Some(extra) = schema.get("additionalProperties") {
+                            if extra == &Value::Bool(false) {
+                                errors.push(format!("{child_path}: unknown setting"));
+                            } else {
+                                validate(extra, child, &child_path, errors);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        Value::Array(items) => {
+            if let Some(item_schema) = schema.get("items") {
+                for (i, item) in items.iter().enumerate() {
+                    validate(item_schema, item, &format!("{path}[{i}]"), errors);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+fn has_type(value: &Value, ty: &str) -> bool {
+    match ty {
+        "null" => value.is_null(),
+        "boolean" => value.is_boolean(),
+        "integer" => value.is_i64() || value.is_u64(),
+        "number" => value.is_number(),
+        "string" => value.is_string(),
+        "array" => value.is_array(),
+        "object" => value.is_object(),
+        _ => false,
+    }
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(n) if n.is_f64() => "number",
+        Value::Number(_) => "integer",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "additionalProperties": false,
+            "required": ["host"],
+            "properties": {
+                "host": {"type": "string", "minLength": 1},
+                "port": {"type": "integer", "minimum": 1024, "maximum": 65535, "default": 8787},
+                "mode": {"enum": ["fast", "safe"]},
+                "rooms": {"type": "array", "items": {"type": "string"}},
+                "limits": {
+                    "type": "object",
+                    "properties": {"upload_mb": {"type": "number", "default": 5}},
+                },
+            },
+        })
+    }
+
+    fn settings(value: Value) -> Map<String, Value> {
+        value.as_object().unwrap().clone()
+    }
+
+    #[test]
+    fn fills_defaults_and_accepts_valid_settings() {
+        let resolved = resolve(
+            Some(&schema()),
+            settings(json!({"host": "lan", "limits": {}, "rooms": ["a"]})),
+        )
+        .unwrap();
+        assert_eq!(
+            resolved,
+            json!({"host": "lan", "port": 8787, "limits": {"upload_mb": 5}, "rooms": ["a"]})
+        );
+        let raw = settings(json!({"anything": [1, 2]}));
+        assert_eq!(resolve(None, raw.clone()).unwrap(), Value::Object(raw));
+    }
+
+    #[test]
+    fn reports_every_violation_with_its_path() {
+        let err = resolve(
+            Some(&schema()),
+            settings(json!({"port": 80, "mode": "slow", "rooms": ["a", 2], "colour": "red"})),
+        )
+        .unwrap_err();
+        for expected in [
+            "settings.host: required",
+            "settings.port: must be at least 1024",
+            "settings.mode: must be one of \"fast\", \"safe\"",
+            "settings.rooms[1]: expected string, got integer",
+            "settings.colour: unknown setting",
+        ] {
+            assert!(err.contains(expected), "{expected:?} missing in {err}");
+        }
+        let err = resolve(Some(&schema()), settings(json!({"host": "", "port": 1e4})));
+        let err = err.unwrap_err();
+        assert!(err.contains("settings.host: must have at least 1"), "{err}");
+        assert!(
+            err.contains("settings.port: expected integer, got number"),
+            "{err}"
+        );
+    }
+}
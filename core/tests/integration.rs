@@ -1,59 +1,49 @@
-use std::{
-    io,
-    sync::{Arc, Mutex},
-    time::Duration,
-};
+use std::time::Duration;
 
-use homecore::{workspace_root, PluginManager};
+use homecore::{plugin_host::StopOutcome, workspace_root, PluginManager};
 use serde_json::json;
 
-struct LogWriter(Arc<Mutex<Vec<u8>>>);
-impl io::Write for LogWriter {
-    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        self.0.lock().unwrap().extend_from_slice(buf);
-        Ok(buf.len())
-    }
-    fn flush(&mut self) -> io::Result<()> {
-        Ok(())
-    }
-}
-struct MakeLogWriter(Arc<Mutex<Vec<u8>>>);
-impl<'a> tracing_subscriber::fmt::MakeWriter<'a> for MakeLogWriter {
-    type Writer = LogWriter;
-    fn make_writer(&'a self) -> Self::Writer {
-        LogWriter(self.0.clone())
-    }
-}
-
 #[tokio::test]
-#[ignore]
 async fn sample_plugin_runs() {
-    let buf = Arc::new(Mutex::new(Vec::new()));
-    let make = MakeLogWriter(buf.clone());
-    let subscriber = tracing_subscriber::fmt().with_writer(make).finish();
     let workspace = workspace_root().unwrap();
     // ensure plugin binary is built
-    std::process::Command::new("cargo")
+    let cargo = std::env::var("CARGO").unwrap_or_else(|_| "cargo".into());
+    let status = std::process::Command::new(cargo)
         .args(["build", "-p", "sample_plugin"])
+        .current_dir(&workspace)
         .status()
         .expect("build sample_plugin");
+    assert!(status.success());
+    let data = tempfile::tempdir().unwrap();
     let plugins_dir = workspace.join("plugins");
-    tracing::subscriber::with_default(subscriber, || async move {
-        let mut manager = PluginManager::discover(workspace.clone(), plugins_dir).unwrap();
-        manager.start_all().await.unwrap();
-        let resp = manager
-            .call("sample_plugin", "sample.ping", json!({"text":"hi"}))
-            .await
-            .unwrap();
-        assert_eq!(resp.get("text").and_then(|v| v.as_str()), Some("hi"));
-        tokio::time::sleep(Duration::from_millis(1100)).await;
-        for handle in manager.plugins.values_mut() {
-            if let Some(child) = handle.child.as_mut() {
-                let _ = child.kill().await;
-            }
+    let mut manager = PluginManager::discover(workspace.clone(), plugins_dir)
+        .unwrap()
+        .with_data_dir(data.path().into())
+        .with_disabled(["family_chat".to_string()]);
+    let started = manager.start_all().await;
+    assert!(started.failed.is_empty(), "{:?}", started.failed);
+    assert_eq!(started.started, vec!["sample_plugin"]);
+    let resp = manager
+        .call("sample_plugin", "sample.ping", json!({"text":"hi"}))
+        .await
+        .unwrap();
+    assert_eq!(resp.get("text").and_then(|v| v.as_str()), Some("hi"));
+
+    // the plugin logs every tick of its one-second interval timer
+    let mut ticked = false;
+    for _ in 0..50 {
+        ticked = manager
+            .logs("sample_plugin", 100)
+            .iter()
+            .any(|r| r.message == "tick from sample_plugin");
+        if ticked {
+            break;
         }
-    })
-    .await;
-    let logs = String::from_utf8(buf.lock().unwrap().clone()).unwrap();
-    assert!(logs.contains("tick from sample_plugin"), "logs: {logs}");
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert!(ticked, "logs: {:?}", manager.logs("sample_plugin", 100));
+
+    let reports = manager.shutdown(Duration::from_secs(2)).await;
+    assert_eq!(reports.len(), 1);
+    assert_eq!(reports[0].outcome, StopOutcome::Stopped);
 }
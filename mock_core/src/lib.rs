@@ -0,0 +1,1035 @@
+//! A stand-in for the core, for testing plugins over their stdio protocol.
+//!
+//! [`MockCore`] talks to a plugin over in-memory pipes or to a spawned plugin
+//! process. It sends `core.hello`, answers `plugin.init` and `plugin.start`,
+//! serves the `log`, `event`, `timer`, `storage`, `device` and `state`
+//! services from memory and records every envelope in both directions. Tests
+//! drive the plugin by injecting events, timer ticks and requests, and
+//! inspect what the plugin did through the recorded calls.
+//!
+//! ```no_run
+//! # async fn test(plugin: plugin_api::sdk::Plugin) -> anyhow::Result<()> {
+//! use mock_core::MockCore;
+//! use serde_json::json;
+//!
+//! let (core, io) = MockCore::pipe();
+//! tokio::spawn(plugin.run(io.reader, io.writer));
+//! core.started().await?;
+//! core.tick("beat").await?;
+//! core.wait_for_log("tick").await?;
+//! assert_eq!(core.request("hello.echo", json!(1)).await?, json!(1));
+//! core.stop().await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use anyhow::{bail, Context as _};
+use plugin_api::{
+    device::{self, EntityState},
+    features, topic_matches, Envelope, InitResult, Kind, Metadata, RpcError, API_VERSION,
+    CANCEL_METHOD, CONFIG_CHANGED,
+};
+use serde_json::{json, Value};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    ffi::OsStr,
+    path::PathBuf,
+    pin::Pin,
+    process::{ExitStatus, Stdio},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    io::{
+        duplex, split, AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader,
+        DuplexStream, ReadHalf, WriteHalf,
+    },
+    process::{Child, Command},
+    sync::{oneshot, Notify},
+    task::JoinHandle,
+};
+use uuid::Uuid;
+
+/// How long waits and requests may take before failing the test.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Services announced in `core.hello`, as in the real core.
+const SERVICES: &[&str] = &["log", "event", "timer", "storage", "device", "state"];
+
+type Writer = Arc<tokio::sync::Mutex<Pin<Box<dyn AsyncWrite + Send>>>>;
+
+/// The plugin's end of an in-memory connection made by [`MockCore::pipe`].
+pub struct PluginIo {
+    pub reader: ReadHalf<DuplexStream>,
+    pub writer: WriteHalf<DuplexStream>,
+}
+
+/// Which way a recorded envelope travelled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    ToPlugin,
+    FromPlugin,
+}
+
+/// An envelope exchanged with the plugin, in the order it was sent.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Recorded {
+    pub direction: Direction,
+    pub envelope: Envelope,
+}
+
+/// A `log.write` call.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogLine {
+    pub level: String,
+    pub message: String,
+    /// Structured fields, an empty object if none were given.
+    pub fields: Value,
+    pub correlation_id: Option<String>,
+}
+
+/// A timer the plugin set and has not cancelled.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MockTimer {
+    /// `interval`, `timeout`, `cron` or `solar`.
+    pub kind: String,
+    /// The params of the request that set it.
+    pub params: Value,
+}
+
+#[derive(Default)]
+struct State {
+    recorded: Vec<Recorded>,
+    metadata: Option<Metadata>,
+    features: Vec<String>,
+    /// What `plugin.init` is answered with besides the features.
+    init: InitResult,
+    started: bool,
+    closed: bool,
+    subscriptions: BTreeSet<String>,
+    timers: BTreeMap<String, MockTimer>,
+    storage: BTreeMap<String, Value>,
+    /// `device.register` params by device id.
+    devices: BTreeMap<String, Value>,
+    entity_states: BTreeMap<String, EntityState>,
+    stubs: HashMap<String, Result<Value, RpcError>>,
+    /// Responses awaited by [`MockCore::request`], by request id.
+    pending: HashMap<String, oneshot::Sender<Envelope>>,
+    /// How many calls of each method the `wait_for_*` helpers have returned.
+    seen: HashMap<String, usize>,
+    now_ms: i64,
+}
+
+impl State {
+    fn calls<'a>(&'a self, method: &'a str) -> impl Iterator<Item = &'a Envelope> {
+        self.recorded
+            .iter()
+            .filter(|r| r.direction == Direction::FromPlugin)
+            .map(|r| &r.envelope)
+            .filter(move |e| e.kind == Kind::Request && e.method.as_deref() == Some(method))
+    }
+}
+
+/// The core's side of a connection to one plugin.
+pub struct MockCore {
+    writer: Writer,
+    state: Arc<Mutex<State>>,
+    changed: Arc<Notify>,
+    reader: JoinHandle<()>,
+    child: Option<Child>,
+    timeout: Duration,
+}
+
+impl MockCore {
+    /// Connect over in-memory pipes. Run the plugin on the returned ends,
+    /// e.g. with `Plugin::run`.
+    pub fn pipe() -> (Self, PluginIo) {
+        let (core_end, plugin_end) = duplex(1 << 16);
+        let (core_reader, core_writer) = split(core_end);
+        let (reader, writer) = split(plugin_end);
+        (
+            Self::connect(core_reader, core_writer),
+            PluginIo { reader, writer },
+        )
+    }
+
+    /// Spawn the plugin executable at `program` with `--stdio`, as the core
+    /// does. The plugin's stderr is passed through.
+    pub fn spawn(program: impl AsRef<OsStr>) -> anyhow::Result<Self> {
+        let mut cmd = Command::new(program);
+        cmd.arg("--stdio");
+        Self::spawn_command(cmd)
+    }
+
+    /// Spawn a plugin with a custom command, e.g. to add arguments or
+    /// environment variables.
+    pub fn spawn_command(mut cmd: Command) -> anyhow::Result<Self> {
+        cmd.stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .kill_on_drop(true);
+        let mut child = cmd.spawn().context("spawning plugin")?;
+        let stdin = child.stdin.take().unwrap();
+        let stdout = child.stdout.take().unwrap();
+        let mut core = Self::connect(stdout, stdin);
+        core.child = Some(child);
+        Ok(core)
+    }
+
+    /// Act as the core on an existing connection. Sends `core.hello` right
+    /// away.
+    pub fn connect<R, W>(reader: R, writer: W) -> Self
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+        W: AsyncWrite + Send + 'static,
+    {
+        let writer: Writer = Arc::new(tokio::sync::Mutex::new(Box::pin(writer)));
+        let state = Arc::new(Mutex::new(State::default()));
+        let changed = Arc::new(Notify::new());
+        let hello = Envelope::event(
+            "core.hello",
+            Some(json!({
+                "api_version": API_VERSION,
+                "services": SERVICES,
+                "features": features::ALL,
+            })),
+        );
+        let conn = Conn {
+            writer: writer.clone(),
+            state: state.clone(),
+            changed: changed.clone(),
+        };
+        let reader = tokio::spawn(async move {
+            if conn.send(hello).await.is_ok() {
+                conn.serve(reader).await;
+            }
+            conn.state.lock().unwrap().closed = true;
+            conn.changed.notify_waiters();
+        });
+        Self {
+            writer,
+            state,
+            changed,
+            reader,
+            child: None,
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+
+    /// Use `timeout` instead of [`DEFAULT_TIMEOUT`] for waits and requests.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Pass `settings` to the plugin in the `plugin.init` response. Call
+    /// this right after creating the core, before the plugin sends
+    /// `plugin.init`.
+    pub fn with_settings(self, settings: Value) -> Self {
+        let settings = match settings {
+            Value::Object(map) => map,
+            _ => panic!("settings must be a JSON object"),
+        };
+        self.state.lock().unwrap().init.settings = settings;
+        self
+    }
+
+    /// Pass `dir` as the plugin's data directory in the `plugin.init`
+    /// response. Call this before the plugin sends `plugin.init`.
+    pub fn with_data_dir(self, dir: impl Into<PathBuf>) -> Self {
+        self.state.lock().unwrap().init.data_dir = Some(dir.into());
+        self
+    }
+
+    /// Report `permissions` as granted in the `plugin.init` response. Call
+    /// this before the plugin sends `plugin.init`.
+    pub fn with_permissions(self, permissions: &[&str]) -> Self {
+        self.state.lock().unwrap().init.permissions =
+            permissions.iter().map(|p| p.to_string()).collect();
+        self
+    }
+
+    /// Replace the plugin's settings and send it [`CONFIG_CHANGED`], as the
+    /// core does when its config is reloaded.
+    pub async fn change_settings(&self, settings: Value) -> anyhow::Result<()> {
+        let Value::Object(map) = &settings else {
+            bail!("settings must be a JSON object");
+        };
+        self.state.lock().unwrap().init.settings = map.clone();
+        self.conn()
+            .send(Envelope::event(
+                CONFIG_CHANGED,
+                Some(json!({ "settings": settings })),
+            ))
+            .await
+    }
+
+    /// Answer requests for `method` with `result`, e.g. to stand in for
+    /// another plugin. Stubs take precedence over the built-in services.
+    pub fn stub(&self, method: &str, result: Result<Value, RpcError>) {
+        self.state
+            .lock()
+            .unwrap()
+            .stubs
+            .insert(method.to_string(), result);
+    }
+
+    /// Wait until the plugin has completed the handshake. Returns the
+    /// metadata it sent with `plugin.init`.
+    pub async fn started(&self) -> anyhow::Result<Metadata> {
+        self.wait("the plugin to start", |s| {
+            s.started.then(|| s.metadata.clone()).flatten()
+        })
+        .await
+    }
+
+    /// Features agreed with the plugin.
+    pub fn features(&self) -> Vec<String> {
+        self.state.lock().unwrap().features.clone()
+    }
+
+    /// Send a request to the plugin and wait for its response.
+    pub async fn request(&self, method: &str, params: Value) -> Result<Value, RpcError> {
+        let id = Uuid::new_v4().to_string();
+        let (tx, rx) = oneshot::channel();
+        self.state.lock().unwrap().pending.insert(id.clone(), tx);
+        let conn = self.conn();
+        if let Err(e) = conn
+            .send(Envelope::request(id.clone(), method, params))
+            .await
+        {
+            return Err(RpcError::new(RpcError::TARGET_UNAVAILABLE, e.to_string()));
+        }
+        match tokio::time::timeout(self.timeout, rx).await {
+            Ok(Ok(resp)) => match resp.error {
+                Some(err) => Err(err),
+                None => Ok(resp.result.unwrap_or(Value::Null)),
+            },
+            Ok(Err(_)) => Err(RpcError::new(
+                RpcError::TARGET_UNAVAILABLE,
+                "plugin closed the connection",
+            )),
+            Err(_) => {
+                self.state.lock().unwrap().pending.remove(&id);
+                Err(RpcError::new(
+                    RpcError::TIMEOUT,
+                    format!("no response to {method} within {:?}", self.timeout),
+                ))
+            }
+        }
+    }
+
+    /// Publish an event to the plugin if it is subscribed to `topic`.
+    /// Returns whether it was delivered.
+    pub async fn emit(&self, topic: &str, payload: Value) -> anyhow::Result<bool> {
+        let subscribed = {
+            let s = self.state.lock().unwrap();
+            s.subscriptions.iter().any(|p| topic_matches(p, topic))
+        };
+        if subscribed {
+            self.conn()
+                .send(Envelope::event(topic, Some(payload)))
+                .await?;
+        }
+        Ok(subscribed)
+    }
+
+    /// Fire the plugin's timer `id` once, as if it were due. Fails if the
+    /// plugin has no such timer or is not subscribed to `timer.tick`. A
+    /// timeout is removed after firing, like in the core.
+    pub async fn tick(&self, id: &str) -> anyhow::Result<()> {
+        let now_ms = {
+            let mut s = self.state.lock().unwrap();
+            let Some(timer) = s.timers.get(id) else {
+                bail!("the plugin has no timer {id:?}");
+            };
+            if timer.kind == "timeout" {
+                s.timers.remove(id);
+            }
+            s.now_ms
+        };
+        let payload = json!({ "id": id, "now_ms": now_ms });
+        if !self.emit("timer.tick", payload).await? {
+            bail!("the plugin is not subscribed to timer.tick");
+        }
+        Ok(())
+    }
+
+    /// Set the clock reported in `now_ms` of timer ticks.
+    pub fn set_now_ms(&self, now_ms: i64) {
+        self.state.lock().unwrap().now_ms = now_ms;
+    }
+
+    /// Send `plugin.stop`, wait for the acknowledgement and for the plugin
+    /// to close the connection. Returns the exit status of a spawned plugin.
+    pub async fn stop(mut self) -> anyhow::Result<Option<ExitStatus>> {
+        self.request("plugin.stop", json!({}))
+            .await
+            .context("plugin.stop failed")?;
+        self.closed().await?;
+        match self.child.take() {
+            Some(mut child) => {
+                let status = tokio::time::timeout(self.timeout, child.wait())
+                    .await
+                    .context("the plugin did not exit")??;
+                Ok(Some(status))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Wait until the plugin closes the connection.
+    pub async fn closed(&self) -> anyhow::Result<()> {
+        self.wait("the plugin to close the connection", |s| {
+            s.closed.then_some(())
+        })
+        .await
+    }
+
+    /// Every envelope exchanged so far.
+    pub fn recorded(&self) -> Vec<Recorded> {
+        self.state.lock().unwrap().recorded.clone()
+    }
+
+    /// Params of every request for `method` the plugin sent so far.
+    pub fn calls(&self, method: &str) -> Vec<Value> {
+        let s = self.state.lock().unwrap();
+        s.calls(method)
+            .map(|e| e.params.clone().unwrap_or(Value::Null))
+            .collect()
+    }
+
+    /// Wait for the next request for `method` not yet returned by this
+    /// function, and return its params.
+    pub async fn wait_for_call(&self, method: &str) -> anyhow::Result<Value> {
+        self.wait(&format!("a call to {method}"), |s| {
+            let seen = s.seen.get(method).copied().unwrap_or(0);
+            let params = s
+                .calls(method)
+                .nth(seen)
+                .map(|e| e.params.clone().unwrap_or(Value::Null))?;
+            s.seen.insert(method.to_string(), seen + 1);
+            Some(params)
+        })
+        .await
+    }
+
+    /// Messages logged with `log.write` so far.
+    pub fn logs(&self) -> Vec<LogLine> {
+        self.calls("log.write").iter().map(log_line).collect()
+    }
+
+    /// Wait for a `log.write` whose message contains `text`.
+    pub async fn wait_for_log(&self, text: &str) -> anyhow::Result<LogLine> {
+        self.wait(&format!("a log message containing {text:?}"), |s| {
+            s.calls("log.write")
+                .filter_map(|e| e.params.as_ref())
+                .map(log_line)
+                .find(|l| l.message.contains(text))
+        })
+        .await
+    }
+
+    /// Events the plugin published so far, as topic and payload.
+    pub fn published(&self) -> Vec<(String, Value)> {
+        let s = self.state.lock().unwrap();
+        s.recorded
+            .iter()
+            .filter(|r| r.direction == Direction::FromPlugin && r.envelope.kind == Kind::Event)
+            .map(|r| {
+                (
+                    r.envelope.topic.clone().unwrap_or_default(),
+                    r.envelope.payload.clone().unwrap_or(Value::Null),
+                )
+            })
+            .collect()
+    }
+
+    /// Wait for the plugin to publish an event on `topic` and return its
+    /// payload.
+    pub async fn wait_for_event(&self, topic: &str) -> anyhow::Result<Value> {
+        self.wait(&format!("an event on {topic}"), |s| {
+            s.recorded
+                .iter()
+                .filter(|r| r.direction == Direction::FromPlugin)
+                .map(|r| &r.envelope)
+                .find(|e| e.kind == Kind::Event && e.topic.as_deref() == Some(topic))
+                .map(|e| e.payload.clone().unwrap_or(Value::Null))
+        })
+        .await
+    }
+
+    /// Topic patterns the plugin is subscribed to.
+    pub fn subscriptions(&self) -> Vec<String> {
+        let s = self.state.lock().unwrap();
+        s.subscriptions.iter().cloned().collect()
+    }
+
+    /// Timers the plugin has set and not cancelled, by id.
+    pub fn timers(&self) -> BTreeMap<String, MockTimer> {
+        self.state.lock().unwrap().timers.clone()
+    }
+
+    /// The value the plugin stored under `key`.
+    pub fn stored(&self, key: &str) -> Option<Value> {
+        self.state.lock().unwrap().storage.get(key).cloned()
+    }
+
+    /// Seed the plugin's storage.
+    pub fn store(&self, key: &str, value: Value) {
+        self.state
+            .lock()
+            .unwrap()
+            .storage
+            .insert(key.to_string(), value);
+    }
+
+    /// Devices the plugin registered, as the params of `device.register`,
+    /// by device id.
+    pub fn devices(&self) -> BTreeMap<String, Value> {
+        self.state.lock().unwrap().devices.clone()
+    }
+
+    /// The state of an entity, as reported by the plugin or seeded with
+    /// [`set_entity_state`](Self::set_entity_state).
+    pub fn entity_state(&self, entity_id: &str) -> Option<EntityState> {
+        self.state
+            .lock()
+            .unwrap()
+            .entity_states
+            .get(entity_id)
+            .cloned()
+    }
+
+    /// Seed the state of an entity, e.g. one owned by another plugin.
+    pub fn set_entity_state(&self, entity_id: &str, state: Value, attributes: Value) {
+        let mut s = self.state.lock().unwrap();
+        let now_ms = s.now_ms;
+        s.entity_states.insert(
+            entity_id.to_string(),
+            EntityState {
+                entity_id: entity_id.to_string(),
+                state,
+                attributes: attributes.as_object().cloned().unwrap_or_default(),
+                last_changed_ms: now_ms,
+                last_updated_ms: now_ms,
+            },
+        );
+    }
+
+    /// Panic unless the plugin called `method` at least once.
+    #[track_caller]
+    pub fn assert_called(&self, method: &str) {
+        if self.calls(method).is_empty() {
+            panic!(
+                "expected a call to {method}, the plugin sent {:?}",
+                self.methods()
+            );
+        }
+    }
+
+    /// Panic if the plugin called `method`.
+    #[track_caller]
+    pub fn assert_not_called(&self, method: &str) {
+        let calls = self.calls(method);
+        if !calls.is_empty() {
+            panic!("expected no call to {method}, got {calls:?}");
+        }
+    }
+
+    /// Methods of all requests the plugin sent, in order.
+    fn methods(&self) -> Vec<String> {
+        let s = self.state.lock().unwrap();
+        s.recorded
+            .iter()
+            .filter(|r| r.direction == Direction::FromPlugin && r.envelope.kind == Kind::Request)
+            .filter_map(|r| r.envelope.method.clone())
+            .collect()
+    }
+
+    fn conn(&self) -> Conn {
+        Conn {
+            writer: self.writer.clone(),
+            state: self.state.clone(),
+            changed: self.changed.clone(),
+        }
+    }
+
+    /// Wait until `check` returns a value, re-running it whenever the plugin
+    /// sends something.
+    async fn wait<T>(
+        &self,
+        what: &str,
+        mut check: impl FnMut(&mut State) -> Option<T>,
+    ) -> anyhow::Result<T> {
+        let deadline = tokio::time::Instant::now() + self.timeout;
+        loop {
+            // register interest before checking, so no change is missed
+            let changed = self.changed.notified();
+            {
+                let mut s = self.state.lock().unwrap();
+                if let Some(value) = check(&mut s) {
+                    return Ok(value);
+                }
+                if s.closed {
+                    bail!("the plugin closed the connection while waiting for {what}");
+                }
+            }
+            if tokio::time::timeout_at(deadline, changed).await.is_err() {
+                bail!("timed out waiting for {what}");
+            }
+        }
+    }
+}
+
+impl Drop for MockCore {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+fn log_line(params: &Value) -> LogLine {
+    let field = |name| {
+        params
+            .get(name)
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_string()
+    };
+    LogLine {
+        level: field("level"),
+        message: field("message"),
+        fields: params.get("fields").cloned().unwrap_or_else(|| json!({})),
+        correlation_id: params
+            .get("correlation_id")
+            .and_then(Value::as_str)
+            .map(str::to_string),
+    }
+}
+
+/// Shared by the reader task and [`MockCore`] to send and record envelopes.
+#[derive(Clone)]
+struct Conn {
+    writer: Writer,
+    state: Arc<Mutex<State>>,
+    changed: Arc<Notify>,
+}
+
+impl Conn {
+    async fn send(&self, env: Envelope) -> anyhow::Result<()> {
+        let mut line = serde_json::to_vec(&env)?;
+        line.push(b'\n');
+        let mut w = self.writer.lock().await;
+        // record under the writer lock to keep the recorded order
+        self.record(Direction::ToPlugin, env);
+        w.write_all(&line).await?;
+        w.flush().await?;
+        Ok(())
+    }
+
+    fn record(&self, direction: Direction, envelope: Envelope) {
+        self.state.lock().unwrap().recorded.push(Recorded {
+            direction,
+            envelope,
+        });
+        self.changed.notify_waiters();
+    }
+
+    async fn serve<R: AsyncRead + Unpin>(&self, reader: R) {
+        let mut lines = BufReader::new(reader).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let Ok(env) = serde_json::from_str::<Envelope>(&line) else {
+                // not part of the protocol; the core would drop it too
+                continue;
+            };
+            self.record(Direction::FromPlugin, env.clone());
+            match env.kind {
+                Kind::Response => {
+                    let tx = env
+                        .id
+                        .as_ref()
+                        .and_then(|id| self.state.lock().unwrap().pending.remove(id));
+                    if let Some(tx) = tx {
+                        let _ = tx.send(env);
+                    }
+                }
+                Kind::Event => {}
+                Kind::Request => {
+                    let method = env.method.clone().unwrap_or_default();
+                    let result = self.handle(&method, env.params.unwrap_or(Value::Null));
+                    if env.id.is_some() {
+                        let _ = self.send(Envelope::response(env.id, result)).await;
+                    }
+                    if method == "plugin.start" {
+                        let _ = self.send(Envelope::event("system.ready", None)).await;
+                        self.state.lock().unwrap().started = true;
+                        self.changed.notify_waiters();
+                    }
+                }
+            }
+        }
+    }
+
+    /// Answer a request from the plugin.
+    fn handle(&self, method: &str, params: Value) -> Result<Value, RpcError> {
+        let mut s = self.state.lock().unwrap();
+        if let Some(result) = s.stubs.get(method) {
+            return result.clone();
+        }
+        let invalid = |what: &str| {
+            RpcError::new(
+                RpcError::INVALID_PARAMS,
+                format!("invalid params for {method}: {what}"),
+            )
+        };
+        let str_param = |name: &str| params.get(name).and_then(Value::as_str);
+        match method {
+            "plugin.init" => {
+                let metadata: Metadata = serde_json::from_value(params["metadata"].clone())
+                    .map_err(|e| invalid(&e.to_string()))?;
+                let agreed: Vec<String> = metadata
+                    .features
+                    .iter()
+                    .filter(|f| features::ALL.contains(&f.as_str()))
+                    .cloned()
+                    .collect();
+                s.features = agreed.clone();
+                s.metadata = Some(metadata);
+                Ok(json!({
+                    "ok": true,
+                    "api_version": API_VERSION,
+                    "features": agreed,
+                    "settings": s.init.settings,
+                    "data_dir": s.init.data_dir,
+                    "permissions": s.init.permissions,
+                }))
+            }
+            "plugin.start" => Ok(json!({"ok": true})),
+            CANCEL_METHOD => Ok(Value::Null),
+            "log.write" => Ok(json!({"ok": true})),
+            "event.subscribe" | "event.unsubscribe" => {
+                let topics: Vec<String> = serde_json::from_value(params["topics"].clone())
+                    .map_err(|_| invalid("topics must be a list of strings"))?;
+                if !s.features.iter().any(|f| f == features::EVENT_WILDCARDS) {
+                    if let Some(pattern) = topics.iter().find(|t| t.contains(['*', '#'])) {
+                        return Err(RpcError::new(
+                            RpcError::INCOMPATIBLE_VERSION,
+                            format!(
+                                "pattern {pattern} needs the {} feature",
+                                features::EVENT_WILDCARDS
+                            ),
+                        ));
+                    }
+                }
+                for topic in topics {
+                    if method == "event.subscribe" {
+                        s.subscriptions.insert(topic);
+                    } else {
+                        s.subscriptions.remove(&topic);
+                    }
+                }
+                Ok(json!({"ok": true, "topics": s.subscriptions}))
+            }
+            "timer.set_interval" | "timer.set_timeout" | "timer.set_cron" | "timer.set_solar" => {
+                let id = str_param("id")
+                    .map(str::to_string)
+                    .unwrap_or_else(|| Uuid::new_v4().to_string());
+                let kind = method.trim_start_matches("timer.set_").to_string();
+                s.timers.insert(id.clone(), MockTimer { kind, params });
+                Ok(json!({"ok": true, "id": id}))
+            }
+            "timer.cancel" => {
+                let id = str_param("id").ok_or_else(|| invalid("missing id"))?;
+                Ok(json!({ "cancelled": s.timers.remove(id).is_some() }))
+            }
+            "timer.list" => {
+                let timers: Vec<Value> = s
+                    .timers
+                    .iter()
+                    .map(|(id, t)| {
+                        let mut info = t.params.clone();
+                        info["id"] = json!(id);
+                        info["kind"] = json!(t.kind);
+                        info["next_ms"] = Value::Null;
+                        info
+                    })
+                    .collect();
+                Ok(json!({ "timers": timers }))
+            }
+            "storage.get" => {
+                let key = str_param("key").ok_or_else(|| invalid("missing key"))?;
+                Ok(json!({ "value": s.storage.get(key) }))
+            }
+            "storage.put" => {
+                let key = str_param("key").ok_or_else(|| invalid("missing key"))?;
+                // like the core, a null value removes the key
+                match params.get("value").filter(|v| !v.is_null()) {
+                    Some(value) => s.storage.insert(key.to_string(), value.clone()),
+                    None => s.storage.remove(key),
+                };
+                Ok(json!({"ok": true}))
+            }
+            "storage.delete" => {
+                let key = str_param("key").ok_or_else(|| invalid("missing key"))?;
+                Ok(json!({ "deleted": s.storage.remove(key).is_some() }))
+            }
+            "storage.list" => {
+                let prefix = str_param("prefix").unwrap_or_default();
+                let keys: Vec<&String> =
+                    s.storage.keys().filter(|k| k.starts_with(prefix)).collect();
+                Ok(json!({ "keys": keys }))
+            }
+            "storage.compare_and_swap" => {
+                let key = str_param("key").ok_or_else(|| invalid("missing key"))?;
+                let expected = params.get("expected").filter(|v| !v.is_null());
+                let value = params.get("value").filter(|v| !v.is_null()).cloned();
+                let current = s.storage.get(key);
+                if current != expected {
+                    return Ok(json!({"swapped": false, "current": current}));
+                }
+                match &value {
+                    Some(v) => s.storage.insert(key.to_string(), v.clone()),
+                    None => s.storage.remove(key),
+                };
+                Ok(json!({"swapped": true, "current": value}))
+            }
+            "device.register" => {
+                let id = params["device"]["id"]
+                    .as_str()
+                    .ok_or_else(|| invalid("missing device.id"))?
+                    .to_string();
+                let entities: Vec<&str> = params["entities"]
+                    .as_array()
+                    .map(|a| a.iter().filter_map(|e| e["entity_id"].as_str()).collect())
+                    .unwrap_or_default();
+                for entity_id in &entities {
+                    device::parse_entity_id(entity_id).map_err(|e| invalid(&e))?;
+                }
+                let reply = json!({"ok": true, "entities": entities});
+                s.devices.insert(id, params);
+                Ok(reply)
+            }
+            "device.unregister" => {
+                let id = str_param("id").ok_or_else(|| invalid("missing id"))?;
+                match s.devices.remove(id) {
+                    Some(_) => Ok(json!({"ok": true})),
+                    None => Err(invalid(&format!("unknown device {id}"))),
+                }
+            }
+            "device.list" => {
+                let owner = s.metadata.as_ref().map(|m| m.id.clone());
+                let devices: Vec<Value> = s
+                    .devices
+                    .values()
+                    .map(|p| {
+                        let mut info = p["device"].clone();
+                        info["owner"] = json!(owner);
+                        info["entities"] = p.get("entities").cloned().unwrap_or(json!([]));
+                        info
+                    })
+                    .collect();
+                Ok(json!({ "devices": devices }))
+            }
+            "state.set" => {
+                let entity_id =
+                    str_param("entity_id").ok_or_else(|| invalid("missing entity_id"))?;
+                let (domain, _) = device::parse_entity_id(entity_id).map_err(|e| invalid(&e))?;
+                let state = params.get("state").cloned().unwrap_or(Value::Null);
+                domain.check_state(&state).map_err(|e| invalid(&e))?;
+                let now_ms = s.now_ms;
+                let old = s.entity_states.get(entity_id);
+                let attributes = match params.get("attributes").and_then(Value::as_object) {
+                    Some(attributes) => attributes.clone(),
+                    None => old.map(|o| o.attributes.clone()).unwrap_or_default(),
+                };
+                let changed = old.is_none_or(|o| o.state != state || o.attributes != attributes);
+                if changed {
+                    let last_changed_ms = match old {
+                        Some(o) if o.state == state => o.last_changed_ms,
+                        _ => now_ms,
+                    };
+                    let new = EntityState {
+                        entity_id: entity_id.to_string(),
+                        state,
+                        attributes,
+                        last_changed_ms,
+                        last_updated_ms: now_ms,
+                    };
+                    s.entity_states.insert(entity_id.to_string(), new);
+                }
+                Ok(json!({"ok": true, "changed": changed}))
+            }
+            device::COMMAND => {
+                serde_json::from_value::<device::DeviceCommand>(params)
+                    .map_err(|e| invalid(&e.to_string()))?;
+                Ok(json!({"ok": true}))
+            }
+            "state.get" => {
+                let entity_id =
+                    str_param("entity_id").ok_or_else(|| invalid("missing entity_id"))?;
+                Ok(json!({ "state": s.entity_states.get(entity_id) }))
+            }
+            "state.list" => {
+                let prefix = str_param("domain").map(|d| format!("{d}."));
+                let states: Vec<&EntityState> = s
+                    .entity_states
+                    .values()
+                    .filter(|st| prefix.as_ref().is_none_or(|p| st.entity_id.starts_with(p)))
+                    .collect();
+                Ok(json!({ "states": states }))
+            }
+            _ => Err(RpcError::new(
+                RpcError::METHOD_NOT_FOUND,
+                format!("unknown method {method}"),
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use plugin_api::sdk::{Event, Plugin, Tick};
+
+    fn plugin() -> Plugin {
+        Plugin::new(Metadata {
+            id: "counter".into(),
+            name: "Counter".into(),
+            version: "0.1.0".into(),
+            provides: vec!["counter.*".into()],
+            ..Default::default()
+        })
+        .method("counter.get", |ctx, _: Value| async move {
+            Ok(ctx.storage().get::<i64>("n").await?.unwrap_or(0))
+        })
+        .event("timer.tick", |ctx, _: Event<Tick>| async move {
+            let n = ctx.storage().get::<i64>("n").await?.unwrap_or(0) + 1;
+            ctx.storage().put("n", &n).await?;
+            ctx.events()
+                .publish("counter.changed", json!({ "n": n }))
+                .await?;
+            ctx.log()
+                .field("n", n)
+                .info(&format!("counted to {n}"))
+                .await?;
+            Ok(())
+        })
+        .on_start(|ctx| async move {
+            ctx.timer().set_interval("count", 60_000).await?;
+            Ok(())
+        })
+    }
+
+    #[tokio::test]
+    async fn drives_a_plugin_over_pipes() {
+        let (core, io) = MockCore::pipe();
+        let task = tokio::spawn(plugin().run(io.reader, io.writer));
+        let metadata = core.started().await.unwrap();
+        assert_eq!(metadata.id, "counter");
+        assert_eq!(core.features(), features::ALL);
+
+        core.wait_for_call("timer.set_interval").await.unwrap();
+        assert_eq!(core.timers()["count"].kind, "interval");
+        assert_eq!(core.subscriptions(), vec!["timer.tick".to_string()]);
+
+        core.store("n", json!(41));
+        core.tick("count").await.unwrap();
+        assert_eq!(
+            core.wait_for_event("counter.changed").await.unwrap(),
+            json!({"n": 42})
+        );
+        let log = core.wait_for_log("counted").await.unwrap();
+        assert_eq!(log.message, "counted to 42");
+        assert_eq!(log.fields, json!({"n": 42}));
+        assert_eq!(core.stored("n"), Some(json!(42)));
+        assert_eq!(core.request("counter.get", json!({})).await, Ok(json!(42)));
+
+        assert!(!core.emit("other.topic", json!({})).await.unwrap());
+        assert!(core.tick("missing").await.is_err());
+        core.assert_not_called("storage.delete");
+        let err = core.request("counter.nope", json!({})).await.unwrap_err();
+        assert_eq!(err.code, RpcError::METHOD_NOT_FOUND);
+
+        let recorded = core.recorded();
+        assert_eq!(recorded[0].direction, Direction::ToPlugin);
+        assert_eq!(recorded[0].envelope.topic.as_deref(), Some("core.hello"));
+
+        assert_eq!(core.stop().await.unwrap(), None);
+        task.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn times_out_waiting() {
+        let (core, io) = MockCore::pipe();
+        let core = core.with_timeout(Duration::from_millis(50));
+        tokio::spawn(plugin().run(io.reader, io.writer));
+        core.started().await.unwrap();
+        let err = core.wait_for_call("storage.delete").await.unwrap_err();
+        assert!(err.to_string().contains("timed out"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn passes_and_changes_settings() {
+        let (core, io) = MockCore::pipe();
+        let core = core
+            .with_settings(json!({"step": 2}))
+            .with_data_dir("/data/counter");
+        let plugin = plugin().method("counter.step", |ctx, _: Value| async move {
+            let settings: Value = ctx.settings()?;
+            Ok(json!({"step": settings["step"], "data_dir": ctx.data_dir()}))
+        });
+        tokio::spawn(plugin.run(io.reader, io.writer));
+        core.started().await.unwrap();
+        assert_eq!(
+            core.request("counter.step", json!({})).await,
+            Ok(json!({"step": 2, "data_dir": "/data/counter"}))
+        );
+        core.change_settings(json!({"step": 3})).await.unwrap();
+        let step = core.request("counter.step", json!({})).await.unwrap();
+        assert_eq!(step["step"], 3);
+    }
+
+    #[tokio::test]
+    async fn serves_the_device_registry() {
+        let (core, io) = MockCore::pipe();
+        let plugin = plugin().method("counter.light", |ctx, on: bool| async move {
+            let devices = ctx.devices();
+            let entity = device::Entity {
+                entity_id: "light.counter".into(),
+                name: None,
+                capabilities: vec![],
+            };
+            let lamp = device::Device {
+                id: "counter".into(),
+                name: "Counter lamp".into(),
+                manufacturer: None,
+                model: None,
+            };
+            devices.register(&lamp, &[entity]).await?;
+            let state = if on { "on" } else { "off" };
+            devices.set_state("light.counter", state, None).await?;
+            let fan_off = device::DeviceCommand {
+                entity_id: "switch.fan".into(),
+                command: device::Command::TurnOff,
+            };
+            devices.command(&fan_off).await?;
+            let door = devices.state("binary_sensor.door").await?;
+            Ok(door.map(|d| d.state))
+        });
+        tokio::spawn(plugin.run(io.reader, io.writer));
+        core.started().await.unwrap();
+        core.set_entity_state("binary_sensor.door", json!("on"), json!({}));
+
+        let door = core.request("counter.light", json!(true)).await.unwrap();
+        assert_eq!(door, json!("on"));
+        assert!(core.devices().contains_key("counter"));
+        let light = core.entity_state("light.counter").unwrap();
+        assert_eq!(light.state, json!("on"));
+        assert_eq!(
+            core.calls(device::COMMAND),
+            vec![json!({"entity_id": "switch.fan", "command": "turn_off"})]
+        );
+    }
+}
@@ -0,0 +1,390 @@
+//! Device and entity model shared by the core's registry and the plugins
+//! that register devices.
+//!
+//! A device is a piece of hardware or a service a plugin controls. It has
+//! one or more entities, each a single thing with a state, such as a light
+//! or a temperature sensor. Entity ids are `<domain>.<name>`, e.g.
+//! `light.kitchen`.
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::fmt;
+
+/// Event published by the core whenever an entity's state or attributes
+/// change. The payload is a [`StateChanged`].
+pub const STATE_CHANGED: &str = "state.changed";
+
+/// Method for commanding an entity, with a [`DeviceCommand`] as params.
+/// Plugins call it on the core, which checks the command and sends the same
+/// request on to the plugin owning the entity.
+pub const COMMAND: &str = "device.command";
+
+/// State of every entity whose owning plugin is not running, and which
+/// owners may report for devices they cannot reach.
+pub const UNAVAILABLE: &str = "unavailable";
+
+/// Optional features an entity can declare, and that commands may need.
+pub mod capabilities {
+    /// Lights: brightness from 0 to 255.
+    pub const BRIGHTNESS: &str = "brightness";
+    /// Lights: color temperature in mireds.
+    pub const COLOR_TEMP: &str = "color_temp";
+    /// Lights: RGB color.
+    pub const COLOR: &str = "color";
+    /// Covers: position from 0 (closed) to 100 (open).
+    pub const POSITION: &str = "position";
+    /// Covers: stopping while moving.
+    pub const STOP: &str = "stop";
+    /// Climate: target temperature in degrees Celsius.
+    pub const TARGET_TEMPERATURE: &str = "target_temperature";
+    /// Climate: switching between HVAC modes.
+    pub const HVAC_MODE: &str = "hvac_mode";
+}
+
+/// Kind of an entity, which fixes its valid states and capabilities.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Domain {
+    Light,
+    Switch,
+    Sensor,
+    BinarySensor,
+    Cover,
+    Climate,
+}
+
+impl Domain {
+    pub const ALL: &'static [Domain] = &[
+        Domain::Light,
+        Domain::Switch,
+        Domain::Sensor,
+        Domain::BinarySensor,
+        Domain::Cover,
+        Domain::Climate,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Domain::Light => "light",
+            Domain::Switch => "switch",
+            Domain::Sensor => "sensor",
+            Domain::BinarySensor => "binary_sensor",
+            Domain::Cover => "cover",
+            Domain::Climate => "climate",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|d| d.as_str() == name)
+    }
+
+    /// Capabilities entities of this domain may declare.
+    pub fn capabilities(self) -> &'static [&'static str] {
+        use capabilities::*;
+        match self {
+            Domain::Light => &[BRIGHTNESS, COLOR_TEMP, COLOR],
+            Domain::Cover => &[POSITION, STOP],
+            Domain::Climate => &[TARGET_TEMPERATURE, HVAC_MODE],
+            Domain::Switch | Domain::Sensor | Domain::BinarySensor => &[],
+        }
+    }
+
+    /// The states an entity of this domain can be in, or `None` if any
+    /// number, string or boolean is allowed. [`UNAVAILABLE`] is always
+    /// allowed.
+    pub fn states(self) -> Option<&'static [&'static str]> {
+        match self {
+            Domain::Light | Domain::Switch | Domain::BinarySensor => Some(&["on", "off"]),
+            Domain::Cover => Some(&["open", "closed", "opening", "closing"]),
+            Domain::Climate => Some(&["off", "heat", "cool", "auto"]),
+            Domain::Sensor => None,
+        }
+    }
+
+    /// Check that `state` is valid for this domain.
+    pub fn check_state(self, state: &Value) -> Result<(), String> {
+        if state == UNAVAILABLE {
+            return Ok(());
+        }
+        match self.states() {
+            Some(states) => match state.as_str() {
+                Some(s) if states.contains(&s) => Ok(()),
+                _ => Err(format!(
+                    "invalid {self} state {state}, expected one of {}",
+                    states.join(", ")
+                )),
+            },
+            None if state.is_number() || state.is_string() || state.is_boolean() => Ok(()),
+            None => Err(format!(
+                "invalid {self} state {state}, expected a number, string or boolean"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Domain {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Split an entity id into its domain and name, checking that the name only
+/// uses lowercase letters, digits and underscores.
+pub fn parse_entity_id(entity_id: &str) -> Result<(Domain, &str), String> {
+    let (domain, name) = entity_id
+        .split_once('.')
+        .ok_or_else(|| format!("invalid entity id {entity_id:?}, expected <domain>.<name>"))?;
+    let domain =
+        Domain::parse(domain).ok_or_else(|| format!("unknown domain {domain:?} in {entity_id}"))?;
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+    {
+        return Err(format!(
+            "invalid entity id {entity_id:?}, names use a-z, 0-9 and _"
+        ));
+    }
+    Ok((domain, name))
+}
+
+/// A device, as registered by the plugin controlling it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Device {
+    /// Unique among all devices in the core.
+    pub id: String,
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub manufacturer: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+}
+
+/// An entity of a device.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Entity {
+    /// `<domain>.<name>`, unique among all entities in the core.
+    pub entity_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Optional features out of [`Domain::capabilities`].
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+}
+
+/// Something an entity should do, by the `command` field. Which commands an
+/// entity takes depends on its domain and capabilities; see
+/// [`Command::check`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Command {
+    /// Lights and switches. Lights with the capability can be given the
+    /// brightness or color temperature to turn on with.
+    TurnOn {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        brightness: Option<u8>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        color_temp: Option<u16>,
+    },
+    TurnOff,
+    Toggle,
+    SetBrightness {
+        brightness: u8,
+    },
+    SetColorTemp {
+        color_temp: u16,
+    },
+    SetColor {
+        color: [u8; 3],
+    },
+    Open,
+    Close,
+    Stop,
+    /// Covers: move to `position`, from 0 (closed) to 100 (open).
+    SetPosition {
+        position: u8,
+    },
+    /// Climate: target temperature in degrees Celsius.
+    SetTargetTemperature {
+        temperature: f64,
+    },
+    SetHvacMode {
+        hvac_mode: String,
+    },
+}
+
+impl Command {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::TurnOn { .. } => "turn_on",
+            Command::TurnOff => "turn_off",
+            Command::Toggle => "toggle",
+            Command::SetBrightness { .. } => "set_brightness",
+            Command::SetColorTemp { .. } => "set_color_temp",
+            Command::SetColor { .. } => "set_color",
+            Command::Open => "open",
+            Command::Close => "close",
+            Command::Stop => "stop",
+            Command::SetPosition { .. } => "set_position",
+            Command::SetTargetTemperature { .. } => "set_target_temperature",
+            Command::SetHvacMode { .. } => "set_hvac_mode",
+        }
+    }
+
+    /// Check that an entity of `domain` with `capabilities` takes this
+    /// command, and that its values are in range.
+    pub fn check(&self, domain: Domain, capabilities: &[String]) -> Result<(), String> {
+        use capabilities::*;
+        use Domain::*;
+        let (domains, needed): (&[Domain], Vec<&str>) = match self {
+            Command::TurnOn {
+                brightness,
+                color_temp,
+            } => (
+                &[Light, Switch],
+                [
+                    brightness.map(|_| BRIGHTNESS),
+                    color_temp.map(|_| COLOR_TEMP),
+                ]
+                .into_iter()
+                .flatten()
+                .collect(),
+            ),
+            Command::TurnOff | Command::Toggle => (&[Light, Switch], vec![]),
+            Command::SetBrightness { .. } => (&[Light], vec![BRIGHTNESS]),
+            Command::SetColorTemp { .. } => (&[Light], vec![COLOR_TEMP]),
+            Command::SetColor { .. } => (&[Light], vec![COLOR]),
+            Command::Open | Command::Close => (&[Cover], vec![]),
+            Command::Stop => (&[Cover], vec![STOP]),
+            Command::SetPosition { position } => {
+                if *position > 100 {
+                    return Err(format!("position {position} is not between 0 and 100"));
+                }
+                (&[Cover], vec![POSITION])
+            }
+            Command::SetTargetTemperature { temperature } => {
+                if !temperature.is_finite() {
+                    return Err(format!("invalid temperature {temperature}"));
+                }
+                (&[Climate], vec![TARGET_TEMPERATURE])
+            }
+            Command::SetHvacMode { hvac_mode } => {
+                let modes = Climate.states().unwrap_or_default();
+                if !modes.contains(&hvac_mode.as_str()) {
+                    return Err(format!(
+                        "unknown hvac_mode {hvac_mode:?}, expected one of {}",
+                        modes.join(", ")
+                    ));
+                }
+                (&[Climate], vec![HVAC_MODE])
+            }
+        };
+        if !domains.contains(&domain) {
+            return Err(format!("{domain} entities do not take {}", self.name()));
+        }
+        match needed
+            .iter()
+            .find(|c| !capabilities.iter().any(|has| has == *c))
+        {
+            Some(cap) => Err(format!("{} needs capability {cap}", self.name())),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Params of [`COMMAND`]: a command for one entity.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeviceCommand {
+    pub entity_id: String,
+    #[serde(flatten)]
+    pub command: Command,
+}
+
+/// The reported state of an entity.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EntityState {
+    pub entity_id: String,
+    pub state: Value,
+    /// Details beside the state, e.g. `brightness` of a light or `unit` of
+    /// a sensor.
+    #[serde(default)]
+    pub attributes: Map<String, Value>,
+    /// When the state last changed, in milliseconds since the epoch.
+    pub last_changed_ms: i64,
+    /// When the state or attributes were last reported.
+    pub last_updated_ms: i64,
+}
+
+/// Payload of [`STATE_CHANGED`]. `old_state` is absent for the first report
+/// of an entity and `new_state` when the entity is removed.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StateChanged {
+    pub entity_id: String,
+    pub old_state: Option<EntityState>,
+    pub new_state: Option<EntityState>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn parses_entity_ids_and_checks_states() {
+        assert_eq!(
+            parse_entity_id("binary_sensor.front_door"),
+            Ok((Domain::BinarySensor, "front_door"))
+        );
+        assert!(parse_entity_id("light").is_err());
+        assert!(parse_entity_id("lamp.kitchen").is_err());
+        assert!(parse_entity_id("light.Kitchen").is_err());
+        assert!(parse_entity_id("light.").is_err());
+
+        assert!(Domain::Light.check_state(&json!("on")).is_ok());
+        assert!(Domain::Light.check_state(&json!(UNAVAILABLE)).is_ok());
+        assert!(Domain::Light.check_state(&json!(true)).is_err());
+        assert!(Domain::Cover.check_state(&json!("opening")).is_ok());
+        assert!(Domain::Sensor.check_state(&json!(21.5)).is_ok());
+        assert!(Domain::Sensor.check_state(&json!({"t": 1})).is_err());
+        assert_eq!(
+            serde_json::to_value(Domain::BinarySensor).unwrap(),
+            "binary_sensor"
+        );
+    }
+
+    #[test]
+    fn checks_commands_against_capabilities() {
+        let cmd: DeviceCommand = serde_json::from_value(
+            json!({"entity_id": "light.desk", "command": "turn_on", "brightness": 128}),
+        )
+        .unwrap();
+        assert_eq!(
+            cmd.command,
+            Command::TurnOn {
+                brightness: Some(128),
+                color_temp: None
+            }
+        );
+        let dimmable = vec![capabilities::BRIGHTNESS.to_string()];
+        assert!(cmd.command.check(Domain::Light, &dimmable).is_ok());
+        let err = cmd.command.check(Domain::Light, &[]).unwrap_err();
+        assert_eq!(err, "turn_on needs capability brightness");
+        let err = cmd.command.check(Domain::Sensor, &dimmable).unwrap_err();
+        assert_eq!(err, "sensor entities do not take turn_on");
+
+        let bad = json!({"entity_id": "light.desk", "command": "turn_on", "brightnes": 1});
+        assert!(serde_json::from_value::<DeviceCommand>(bad).is_err());
+        let bad = json!({"entity_id": "light.desk", "command": "dim"});
+        assert!(serde_json::from_value::<DeviceCommand>(bad).is_err());
+
+        let position = vec![capabilities::POSITION.to_string()];
+        let open = Command::SetPosition { position: 101 };
+        assert!(open.check(Domain::Cover, &position).is_err());
+        let mode = Command::SetHvacMode {
+            hvac_mode: "dry".into(),
+        };
+        assert!(mode
+            .check(Domain::Climate, &[capabilities::HVAC_MODE.into()])
+            .is_err());
+    }
+}
@@ -1,5 +1,65 @@
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use std::fmt;
+
+pub mod device;
+pub mod sdk;
+
+/// Version of the plugin protocol implemented by this crate. Core and plugin
+/// are compatible when the major versions match.
+pub const API_VERSION: &str = "1";
+
+/// Major component of an API version string such as `1` or `1.2`.
+pub fn api_major(version: &str) -> Option<u32> {
+    version.trim().split('.').next()?.parse().ok()
+}
+
+/// Whether a plugin built for `version` can talk to this protocol version.
+pub fn api_compatible(version: &str) -> bool {
+    api_major(version).is_some() && api_major(version) == api_major(API_VERSION)
+}
+
+/// Optional protocol features, negotiated during the handshake. The core
+/// lists the features it supports in `core.hello`, the plugin lists its own in
+/// its `plugin.init` metadata, and only features on both lists are used.
+pub mod features {
+    /// Requests forwarded between plugins by method namespace.
+    pub const RPC: &str = "rpc";
+    /// Event subscriptions using `*` and `#` wildcards.
+    pub const EVENT_WILDCARDS: &str = "event.wildcards";
+    /// `$/cancel` notifications for requests the sender gave up on.
+    pub const CANCEL: &str = "cancel";
+
+    /// All features known to this protocol version.
+    pub const ALL: &[&str] = &[RPC, EVENT_WILDCARDS, CANCEL];
+}
+
+/// Notification telling the receiver that the sender no longer waits for
+/// the request whose id is given in `params.id`. Sent only to peers that
+/// negotiated [`features::CANCEL`]; the receiver may stop working on it.
+pub const CANCEL_METHOD: &str = "$/cancel";
+
+/// Event the core sends a plugin, without a subscription, when the plugin's
+/// settings change. The payload is `{"settings": {...}}`.
+pub const CONFIG_CHANGED: &str = "config.changed";
+
+/// Whether the event `topic` matches the subscription `pattern`, where `*`
+/// stands for one dot-separated segment and `#` for any number of them.
+pub fn topic_matches(pattern: &str, topic: &str) -> bool {
+    fn go(pattern: &[&str], topic: &[&str]) -> bool {
+        match (pattern.split_first(), topic.split_first()) {
+            (None, None) => true,
+            (Some((&"#", rest)), _) => {
+                go(rest, topic) || (!topic.is_empty() && go(pattern, &topic[1..]))
+            }
+            (Some((p, prest)), Some((t, trest))) => (*p == "*" || p == t) && go(prest, trest),
+            _ => false,
+        }
+    }
+    let pattern: Vec<&str> = pattern.split('.').collect();
+    let topic: Vec<&str> = topic.split('.').collect();
+    go(&pattern, &topic)
+}
 
 /// Kind of envelope used in the JSON protocol.
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
@@ -17,6 +77,46 @@ pub struct RpcError {
     pub message: String,
 }
 
+impl RpcError {
+    /// No handler exists for the requested method.
+    pub const METHOD_NOT_FOUND: i32 = -32601;
+    /// The params of a request could not be decoded.
+    pub const INVALID_PARAMS: i32 = -32602;
+    /// The handler failed while processing a valid request.
+    pub const INTERNAL_ERROR: i32 = -32603;
+    /// The plugin serving the method is not running.
+    pub const TARGET_UNAVAILABLE: i32 = -32001;
+    /// No response arrived in time.
+    pub const TIMEOUT: i32 = -32002;
+    /// The plugin targets an API version the core does not support.
+    pub const INCOMPATIBLE_VERSION: i32 = -32004;
+    /// The plugin's manifest does not grant the permission the method needs.
+    pub const PERMISSION_DENIED: i32 = -32003;
+    /// The caller cancelled the request.
+    pub const CANCELLED: i32 = -32800;
+
+    pub fn new(code: i32, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.message, self.code)
+    }
+}
+
+impl std::error::Error for RpcError {}
+
+impl From<anyhow::Error> for RpcError {
+    fn from(e: anyhow::Error) -> Self {
+        Self::new(Self::INTERNAL_ERROR, format!("{e:#}"))
+    }
+}
+
 /// Top level envelope exchanged between core and plugins.
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct Envelope {
@@ -30,14 +130,108 @@ pub struct Envelope {
     pub payload: Option<Value>,
 }
 
+impl Envelope {
+    /// Build a request envelope.
+    pub fn request(id: impl Into<String>, method: impl Into<String>, params: Value) -> Self {
+        Self {
+            id: Some(id.into()),
+            kind: Kind::Request,
+            method: Some(method.into()),
+            params: Some(params),
+            result: None,
+            error: None,
+            topic: None,
+            payload: None,
+        }
+    }
+
+    /// Build a notification: a request without an id, which gets no
+    /// response.
+    pub fn notification(method: impl Into<String>, params: Value) -> Self {
+        Self {
+            id: None,
+            kind: Kind::Request,
+            method: Some(method.into()),
+            params: Some(params),
+            result: None,
+            error: None,
+            topic: None,
+            payload: None,
+        }
+    }
+
+    /// Build the response to the request with the given id.
+    pub fn response(id: Option<String>, result: Result<Value, RpcError>) -> Self {
+        let (result, error) = match result {
+            Ok(v) => (Some(v), None),
+            Err(e) => (None, Some(e)),
+        };
+        Self {
+            id,
+            kind: Kind::Response,
+            method: None,
+            params: None,
+            result,
+            error,
+            topic: None,
+            payload: None,
+        }
+    }
+
+    /// Build an event envelope.
+    pub fn event(topic: impl Into<String>, payload: Option<Value>) -> Self {
+        Self {
+            id: None,
+            kind: Kind::Event,
+            method: None,
+            params: None,
+            result: None,
+            error: None,
+            topic: Some(topic.into()),
+            payload,
+        }
+    }
+}
+
 /// Metadata a plugin provides during the init phase.
-#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Default)]
 pub struct Metadata {
     pub id: String,
     pub name: String,
     pub version: String,
+    /// Protocol version the plugin was built against.
+    #[serde(default)]
+    pub api_version: Option<String>,
+    /// Optional protocol features the plugin supports.
+    #[serde(default)]
+    pub features: Vec<String>,
     #[serde(default)]
     pub needs: Vec<String>,
+    /// Method namespaces served by the plugin, e.g. `sample.*`. Requests for
+    /// these methods sent by other plugins are forwarded by the core.
+    #[serde(default)]
+    pub provides: Vec<String>,
+}
+
+/// The core's answer to `plugin.init`.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+pub struct InitResult {
+    /// Protocol version spoken by the core.
+    #[serde(default)]
+    pub api_version: Option<String>,
+    /// Optional protocol features both sides support.
+    #[serde(default)]
+    pub features: Vec<String>,
+    /// The plugin's `[plugins.<id>.settings]` from the core config, checked
+    /// against the plugin's settings schema.
+    #[serde(default)]
+    pub settings: serde_json::Map<String, Value>,
+    /// Directory the plugin may keep its own files in.
+    #[serde(default)]
+    pub data_dir: Option<std::path::PathBuf>,
+    /// Permissions granted to the plugin.
+    #[serde(default)]
+    pub permissions: Vec<String>,
 }
 
 #[cfg(test)]
@@ -60,4 +254,32 @@ mod tests {
         let de: Envelope = serde_json::from_str(&s).unwrap();
         assert_eq!(env, de);
     }
+
+    #[test]
+    fn response_carries_result_or_error() {
+        let ok = Envelope::response(Some("1".into()), Ok(serde_json::json!(1)));
+        assert_eq!(ok.kind, Kind::Response);
+        assert_eq!(ok.result, Some(serde_json::json!(1)));
+        assert!(ok.error.is_none());
+        let err = Envelope::response(None, Err(RpcError::new(RpcError::TIMEOUT, "late")));
+        assert!(err.result.is_none());
+        assert_eq!(err.error.unwrap().code, RpcError::TIMEOUT);
+    }
+
+    #[test]
+    fn major_versions_must_match() {
+        assert_eq!(api_major("1.4"), Some(1));
+        assert!(api_compatible("1"));
+        assert!(api_compatible("1.7"));
+        assert!(!api_compatible("2"));
+        assert!(!api_compatible("one"));
+    }
+
+    #[test]
+    fn metadata_fields_default() {
+        let md: Metadata =
+            serde_json::from_str(r#"{"id":"x","name":"X","version":"0.1.0"}"#).unwrap();
+        assert_eq!(md.api_version, None);
+        assert!(md.features.is_empty() && md.needs.is_empty() && md.provides.is_empty());
+    }
 }
@@ -0,0 +1,1114 @@
+//! Async runtime for writing plugins: runs the handshake with the core,
+//! matches responses to requests, dispatches incoming requests and events to
+//! typed handlers, and offers clients for the core services.
+//!
+//! ```no_run
+//! use plugin_api::{sdk::{Event, Plugin}, Metadata};
+//! use serde_json::Value;
+//!
+//! # async fn run() -> anyhow::Result<()> {
+//! Plugin::new(Metadata {
+//!     id: "hello".into(),
+//!     name: "Hello".into(),
+//!     version: "0.1.0".into(),
+//!     provides: vec!["hello.*".into()],
+//!     ..Default::default()
+//! })
+//! .method("hello.echo", |_ctx, params: Value| async move { Ok(params) })
+//! .event("timer.tick", |ctx, _tick: Event<Value>| async move {
+//!     ctx.log().info("tick").await?;
+//!     Ok(())
+//! })
+//! .on_start(|ctx| async move {
+//!     ctx.timer().set_interval("hello", 1000).await?;
+//!     Ok(())
+//! })
+//! .run_stdio()
+//! .await
+//! # }
+//! ```
+
+use crate::{
+    device::{self, Device, DeviceCommand, Entity, EntityState},
+    features, Envelope, InitResult, Kind, Metadata, RpcError, API_VERSION, CANCEL_METHOD,
+    CONFIG_CHANGED,
+};
+use anyhow::{bail, Context as _};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{
+    collections::HashMap,
+    future::Future,
+    path::PathBuf,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, Lines},
+    sync::oneshot,
+    task::AbortHandle,
+};
+use tracing::warn;
+use uuid::Uuid;
+
+/// How long a request to the core may take unless a timeout is given.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Topics the core sends without a subscription.
+const DIRECT_TOPICS: &[&str] = &["core.hello", "system.ready", CONFIG_CHANGED];
+
+type BoxFuture<T> = Pin<Box<dyn Future<Output = T> + Send>>;
+type MethodHandler =
+    Arc<dyn Fn(Context, Value) -> BoxFuture<Result<Value, RpcError>> + Send + Sync>;
+type EventHandler =
+    Arc<dyn Fn(Context, String, Value) -> BoxFuture<anyhow::Result<()>> + Send + Sync>;
+type Hook = Box<dyn FnOnce(Context) -> BoxFuture<anyhow::Result<()>> + Send>;
+type Writer = tokio::sync::Mutex<Pin<Box<dyn AsyncWrite + Send>>>;
+
+/// An event delivered to an event handler.
+#[derive(Debug, Clone)]
+pub struct Event<P> {
+    pub topic: String,
+    pub payload: P,
+}
+
+/// A plugin under construction: its metadata and handlers.
+pub struct Plugin {
+    metadata: Metadata,
+    methods: HashMap<String, MethodHandler>,
+    events: Vec<(String, EventHandler)>,
+    on_start: Option<Hook>,
+    on_stop: Option<Hook>,
+}
+
+impl Plugin {
+    /// Start a plugin with the given metadata. `api_version` defaults to the
+    /// version of this crate, and every feature in [`features::ALL`] is
+    /// offered, since the SDK implements them all.
+    pub fn new(mut metadata: Metadata) -> Self {
+        metadata
+            .api_version
+            .get_or_insert_with(|| API_VERSION.to_string());
+        for feature in features::ALL {
+            if !metadata.features.iter().any(|f| f == feature) {
+                metadata.features.push(feature.to_string());
+            }
+        }
+        Self {
+            metadata,
+            methods: HashMap::new(),
+            events: Vec::new(),
+            on_start: None,
+            on_stop: None,
+        }
+    }
+
+    /// Serve requests for `method`. Params that do not decode into `P` are
+    /// answered with an invalid params error. Remember to list the method's
+    /// namespace in the metadata's `provides`.
+    pub fn method<P, R, F, Fut>(mut self, method: &str, handler: F) -> Self
+    where
+        P: DeserializeOwned + Send + 'static,
+        R: Serialize,
+        F: Fn(Context, P) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<R, RpcError>> + Send + 'static,
+    {
+        let handler = Arc::new(handler);
+        let method_handler: MethodHandler = Arc::new(move |ctx, params| {
+            let handler = handler.clone();
+            Box::pin(async move {
+                let params: P = serde_json::from_value(params).map_err(|e| {
+                    RpcError::new(RpcError::INVALID_PARAMS, format!("invalid params: {e}"))
+                })?;
+                let result = handler(ctx, params).await?;
+                serde_json::to_value(result)
+                    .map_err(|e| RpcError::new(RpcError::INTERNAL_ERROR, e.to_string()))
+            })
+        });
+        self.methods.insert(method.to_string(), method_handler);
+        self
+    }
+
+    /// Handle events matching `pattern`, which may use `*` and `#`
+    /// wildcards. The plugin subscribes to the pattern once started. Events
+    /// whose payload does not decode into `P` are logged and dropped.
+    pub fn event<P, F, Fut>(mut self, pattern: &str, handler: F) -> Self
+    where
+        P: DeserializeOwned + Send + 'static,
+        F: Fn(Context, Event<P>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = anyhow::Result<()>> + Send + 'static,
+    {
+        let handler = Arc::new(handler);
+        let event_handler: EventHandler = Arc::new(move |ctx, topic, payload| {
+            let handler = handler.clone();
+            Box::pin(async move {
+                let payload: P = serde_json::from_value(payload)
+                    .with_context(|| format!("invalid payload for {topic}"))?;
+                handler(ctx, Event { topic, payload }).await
+            })
+        });
+        self.events.push((pattern.to_string(), event_handler));
+        self
+    }
+
+    /// Run `hook` once the handshake is done and event subscriptions are in
+    /// place. Requests and events are already being served while it runs. An
+    /// error ends the plugin.
+    pub fn on_start<F, Fut>(mut self, hook: F) -> Self
+    where
+        F: FnOnce(Context) -> Fut + Send + 'static,
+        Fut: Future<Output = anyhow::Result<()>> + Send + 'static,
+    {
+        self.on_start = Some(Box::new(move |ctx| Box::pin(hook(ctx))));
+        self
+    }
+
+    /// Run `hook` when the core sends `plugin.stop`, before acknowledging it.
+    pub fn on_stop<F, Fut>(mut self, hook: F) -> Self
+    where
+        F: FnOnce(Context) -> Fut + Send + 'static,
+        Fut: Future<Output = anyhow::Result<()>> + Send + 'static,
+    {
+        self.on_stop = Some(Box::new(move |ctx| Box::pin(hook(ctx))));
+        self
+    }
+
+    /// Talk to the core over stdin and stdout until it stops the plugin.
+    pub async fn run_stdio(self) -> anyhow::Result<()> {
+        self.run(tokio::io::stdin(), tokio::io::stdout()).await
+    }
+
+    /// Talk to the core over the given streams until it stops the plugin or
+    /// closes the connection.
+    pub async fn run<R, W>(mut self, reader: R, writer: W) -> anyhow::Result<()>
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+        W: AsyncWrite + Send + 'static,
+    {
+        let mut lines = BufReader::new(reader).lines();
+        let client = Arc::new(Client {
+            writer: tokio::sync::Mutex::new(Box::pin(writer)),
+            pending: Mutex::default(),
+            running: Mutex::default(),
+            init: Mutex::default(),
+        });
+        let ctx = Context {
+            plugin_id: Arc::from(self.metadata.id.as_str()),
+            client: client.clone(),
+        };
+
+        let hello = next(&mut lines)
+            .await?
+            .context("core closed before hello")?;
+        if hello.kind != Kind::Event || hello.topic.as_deref() != Some("core.hello") {
+            bail!("expected core.hello, got {hello:?}");
+        }
+        let init = handshake(
+            &mut lines,
+            &client,
+            "plugin.init",
+            json!({"metadata": self.metadata}),
+        )
+        .await?;
+        *client.init.lock().unwrap() =
+            serde_json::from_value(init).context("invalid plugin.init response")?;
+        handshake(&mut lines, &client, "plugin.start", json!({})).await?;
+
+        let (stopped_tx, stopped) = oneshot::channel();
+        let dispatcher = Dispatcher {
+            ctx: ctx.clone(),
+            methods: std::mem::take(&mut self.methods),
+            events: std::mem::take(&mut self.events),
+            on_stop: Mutex::new(self.on_stop.take()),
+        };
+        let subscriptions: Vec<String> = dispatcher
+            .events
+            .iter()
+            .map(|(pattern, _)| pattern.clone())
+            .filter(|p| !DIRECT_TOPICS.contains(&p.as_str()))
+            .collect();
+        let reader = tokio::spawn(async move {
+            let result = dispatcher.serve(lines).await;
+            let _ = stopped_tx.send(());
+            result
+        });
+
+        if !subscriptions.is_empty() {
+            ctx.events().subscribe(&subscriptions).await?;
+        }
+        if let Some(hook) = self.on_start.take() {
+            // a stop or lost connection ends start-up early
+            tokio::select! {
+                result = hook(ctx.clone()) => result?,
+                _ = stopped => {}
+            }
+        }
+        reader.await?
+    }
+}
+
+/// Send a handshake request and wait for its response, which is the next
+/// message the core sends.
+async fn handshake<R: AsyncRead + Unpin>(
+    lines: &mut Lines<BufReader<R>>,
+    client: &Client,
+    method: &str,
+    params: Value,
+) -> anyhow::Result<Value> {
+    let id = Uuid::new_v4().to_string();
+    client
+        .send(&Envelope::request(id.clone(), method, params))
+        .await?;
+    loop {
+        let env = next(lines)
+            .await?
+            .with_context(|| format!("core closed during {method}"))?;
+        if env.kind == Kind::Response && env.id.as_deref() == Some(id.as_str()) {
+            return match env.error {
+                Some(e) => bail!("core refused {method}: {e}"),
+                None => Ok(env.result.unwrap_or(Value::Null)),
+            };
+        }
+    }
+}
+
+async fn next<R: AsyncRead + Unpin>(
+    lines: &mut Lines<BufReader<R>>,
+) -> anyhow::Result<Option<Envelope>> {
+    loop {
+        match lines.next_line().await? {
+            None => return Ok(None),
+            Some(line) if line.trim().is_empty() => continue,
+            Some(line) => return Ok(Some(serde_json::from_str(&line)?)),
+        }
+    }
+}
+
+/// Connection state shared by the dispatcher and all [`Context`]s.
+struct Client {
+    writer: Writer,
+    /// Requests sent to the core, by id.
+    pending: Mutex<HashMap<String, oneshot::Sender<Envelope>>>,
+    /// Requests from the core being handled, by id.
+    running: Mutex<HashMap<String, AbortHandle>>,
+    /// The core's answer to `plugin.init`, with settings kept up to date.
+    init: Mutex<InitResult>,
+}
+
+impl Client {
+    async fn send(&self, env: &Envelope) -> anyhow::Result<()> {
+        let mut line = serde_json::to_vec(env)?;
+        line.push(b'\n');
+        let mut w = self.writer.lock().await;
+        w.write_all(&line).await?;
+        w.flush().await?;
+        Ok(())
+    }
+
+    async fn request(
+        &self,
+        method: &str,
+        params: Value,
+        timeout: Duration,
+    ) -> Result<Value, RpcError> {
+        let id = Uuid::new_v4().to_string();
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().unwrap().insert(id.clone(), tx);
+        if let Err(e) = self
+            .send(&Envelope::request(id.clone(), method, params))
+            .await
+        {
+            self.pending.lock().unwrap().remove(&id);
+            return Err(RpcError::new(
+                RpcError::TARGET_UNAVAILABLE,
+                format!("connection to core lost: {e}"),
+            ));
+        }
+        match tokio::time::timeout(timeout, rx).await {
+            Ok(Ok(resp)) => match resp.error {
+                Some(err) => Err(err),
+                None => Ok(resp.result.unwrap_or(Value::Null)),
+            },
+            Ok(Err(_)) => Err(RpcError::new(
+                RpcError::TARGET_UNAVAILABLE,
+                "connection to core lost",
+            )),
+            Err(_) => {
+                self.pending.lock().unwrap().remove(&id);
+                if self.has_feature(features::CANCEL) {
+                    let cancel = Envelope::notification(CANCEL_METHOD, json!({ "id": id }));
+                    let _ = self.send(&cancel).await;
+                }
+                Err(RpcError::new(
+                    RpcError::TIMEOUT,
+                    format!("no response to {method} within {timeout:?}"),
+                ))
+            }
+        }
+    }
+
+    fn has_feature(&self, feature: &str) -> bool {
+        self.init
+            .lock()
+            .unwrap()
+            .features
+            .iter()
+            .any(|f| f == feature)
+    }
+}
+
+/// Serves messages from the core once the handshake is done.
+struct Dispatcher {
+    ctx: Context,
+    methods: HashMap<String, MethodHandler>,
+    events: Vec<(String, EventHandler)>,
+    on_stop: Mutex<Option<Hook>>,
+}
+
+impl Dispatcher {
+    async fn serve<R: AsyncRead + Unpin>(
+        &self,
+        mut lines: Lines<BufReader<R>>,
+    ) -> anyhow::Result<()> {
+        let client = &self.ctx.client;
+        while let Some(env) = next(&mut lines).await? {
+            match env.kind {
+                Kind::Response => {
+                    let tx = env
+                        .id
+                        .as_ref()
+                        .and_then(|id| client.pending.lock().unwrap().remove(id));
+                    if let Some(tx) = tx {
+                        let _ = tx.send(env);
+                    }
+                }
+                Kind::Event => self.event(env),
+                Kind::Request => match env.method.as_deref() {
+                    Some("plugin.stop") => {
+                        let hook = self.on_stop.lock().unwrap().take();
+                        if let Some(hook) = hook {
+                            if let Err(e) = hook(self.ctx.clone()).await {
+                                warn!("stop hook failed: {e:#}");
+                            }
+                        }
+                        client
+                            .send(&Envelope::response(env.id, Ok(json!({}))))
+                            .await?;
+                        break;
+                    }
+                    Some(CANCEL_METHOD) => {
+                        let id = env.params.as_ref().and_then(|p| p.get("id"));
+                        let task = id
+                            .and_then(Value::as_str)
+                            .and_then(|id| client.running.lock().unwrap().remove(id));
+                        if let Some(task) = task {
+                            task.abort();
+                        }
+                    }
+                    _ => self.request(env),
+                },
+            }
+        }
+        // fail requests still waiting for the core
+        client.pending.lock().unwrap().clear();
+        Ok(())
+    }
+
+    fn request(&self, env: Envelope) {
+        let method = env.method.unwrap_or_default();
+        let handler = self.methods.get(&method).cloned();
+        let ctx = self.ctx.clone();
+        let id = env.id;
+        let params = env.params.unwrap_or(Value::Null);
+        // hold the lock until the task is registered, so it cannot remove
+        // its entry before it exists
+        let mut running = self.ctx.client.running.lock().unwrap();
+        let task = tokio::spawn({
+            let id = id.clone();
+            async move {
+                let result = match handler {
+                    Some(handler) => handler(ctx.clone(), params).await,
+                    None => Err(RpcError::new(
+                        RpcError::METHOD_NOT_FOUND,
+                        format!("unknown method {method}"),
+                    )),
+                };
+                if let Some(id) = id {
+                    ctx.client.running.lock().unwrap().remove(&id);
+                    let _ = ctx.client.send(&Envelope::response(Some(id), result)).await;
+                }
+            }
+        });
+        if let Some(id) = id {
+            running.insert(id, task.abort_handle());
+        }
+    }
+
+    fn event(&self, env: Envelope) {
+        let Some(topic) = env.topic else {
+            return;
+        };
+        let payload = env.payload.unwrap_or(Value::Null);
+        if topic == CONFIG_CHANGED {
+            if let Some(Value::Object(settings)) = payload.get("settings") {
+                self.ctx.client.init.lock().unwrap().settings = settings.clone();
+            }
+        }
+        for (pattern, handler) in &self.events {
+            if crate::topic_matches(pattern, &topic) {
+                let fut = handler(self.ctx.clone(), topic.clone(), payload.clone());
+                let topic = topic.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = fut.await {
+                        warn!("handler for event {topic} failed: {e:#}");
+                    }
+                });
+            }
+        }
+    }
+}
+
+/// Handle to the connection with the core, passed to every handler.
+#[derive(Clone)]
+pub struct Context {
+    plugin_id: Arc<str>,
+    client: Arc<Client>,
+}
+
+impl Context {
+    pub fn plugin_id(&self) -> &str {
+        &self.plugin_id
+    }
+
+    /// Whether the core agreed to use an optional protocol feature.
+    pub fn has_feature(&self, feature: &str) -> bool {
+        self.client.has_feature(feature)
+    }
+
+    /// Send a request to the core, or through it to another plugin, and
+    /// wait up to [`DEFAULT_TIMEOUT`] for the response.
+    pub async fn request<P: Serialize, R: DeserializeOwned>(
+        &self,
+        method: &str,
+        params: P,
+    ) -> Result<R, RpcError> {
+        self.request_with_timeout(method, params, DEFAULT_TIMEOUT)
+            .await
+    }
+
+    /// Like [`Context::request`] with a custom timeout.
+    pub async fn request_with_timeout<P: Serialize, R: DeserializeOwned>(
+        &self,
+        method: &str,
+        params: P,
+        timeout: Duration,
+    ) -> Result<R, RpcError> {
+        let params = serde_json::to_value(params)
+            .map_err(|e| RpcError::new(RpcError::INVALID_PARAMS, e.to_string()))?;
+        let result = self.client.request(method, params, timeout).await?;
+        serde_json::from_value(result).map_err(|e| {
+            RpcError::new(
+                RpcError::INTERNAL_ERROR,
+                format!("unexpected response to {method}: {e}"),
+            )
+        })
+    }
+
+    /// The plugin's settings from the core config, decoded into `T`. They
+    /// are replaced when the core sends [`CONFIG_CHANGED`]; handle that event
+    /// to react to changes.
+    pub fn settings<T: DeserializeOwned>(&self) -> anyhow::Result<T> {
+        let settings = self.client.init.lock().unwrap().settings.clone();
+        serde_json::from_value(Value::Object(settings)).context("invalid settings")
+    }
+
+    /// Directory the core set aside for the plugin's files, if it named one.
+    pub fn data_dir(&self) -> Option<PathBuf> {
+        self.client.init.lock().unwrap().data_dir.clone()
+    }
+
+    /// Permissions the core granted the plugin.
+    pub fn permissions(&self) -> Vec<String> {
+        self.client.init.lock().unwrap().permissions.clone()
+    }
+
+    pub fn log(&self) -> Log<'_> {
+        Log {
+            ctx: self,
+            fields: serde_json::Map::new(),
+            correlation_id: None,
+        }
+    }
+
+    pub fn events(&self) -> Events<'_> {
+        Events(self)
+    }
+
+    pub fn timer(&self) -> Timer<'_> {
+        Timer(self)
+    }
+
+    pub fn storage(&self) -> Storage<'_> {
+        Storage(self)
+    }
+
+    pub fn devices(&self) -> Devices<'_> {
+        Devices(self)
+    }
+}
+
+/// Client for the `log` service. Lines can carry structured fields and a
+/// correlation id:
+///
+/// ```ignore
+/// ctx.log()
+///     .field("room_id", 7)
+///     .correlation_id(&request_id)
+///     .info("member joined")
+///     .await?;
+/// ```
+pub struct Log<'a> {
+    ctx: &'a Context,
+    fields: serde_json::Map<String, Value>,
+    correlation_id: Option<String>,
+}
+
+impl Log<'_> {
+    /// Attach a structured field to the line.
+    pub fn field(mut self, name: &str, value: impl Serialize) -> Self {
+        let value = serde_json::to_value(value).unwrap_or(Value::Null);
+        self.fields.insert(name.to_string(), value);
+        self
+    }
+
+    /// Tie the line to other lines of the same operation.
+    pub fn correlation_id(mut self, id: &str) -> Self {
+        self.correlation_id = Some(id.to_string());
+        self
+    }
+
+    pub async fn write(&self, level: &str, message: &str) -> Result<(), RpcError> {
+        let mut params = json!({"level": level, "message": message});
+        if !self.fields.is_empty() {
+            params["fields"] = Value::Object(self.fields.clone());
+        }
+        if let Some(id) = &self.correlation_id {
+            params["correlation_id"] = json!(id);
+        }
+        self.ctx.request::<_, Value>("log.write", params).await?;
+        Ok(())
+    }
+
+    pub async fn error(&self, message: &str) -> Result<(), RpcError> {
+        self.write("ERROR", message).await
+    }
+
+    pub async fn warn(&self, message: &str) -> Result<(), RpcError> {
+        self.write("WARN", message).await
+    }
+
+    pub async fn info(&self, message: &str) -> Result<(), RpcError> {
+        self.write("INFO", message).await
+    }
+
+    pub async fn debug(&self, message: &str) -> Result<(), RpcError> {
+        self.write("DEBUG", message).await
+    }
+}
+
+#[derive(Deserialize)]
+struct Topics {
+    topics: Vec<String>,
+}
+
+/// Client for the `event` service.
+pub struct Events<'a>(&'a Context);
+
+impl Events<'_> {
+    /// Subscribe to topic patterns. Returns all current subscriptions.
+    /// Events only reach handlers registered with [`Plugin::event`].
+    pub async fn subscribe(&self, patterns: &[String]) -> Result<Vec<String>, RpcError> {
+        let r: Topics = self
+            .0
+            .request("event.subscribe", json!({ "topics": patterns }))
+            .await?;
+        Ok(r.topics)
+    }
+
+    /// Drop subscriptions. Returns the remaining ones.
+    pub async fn unsubscribe(&self, patterns: &[String]) -> Result<Vec<String>, RpcError> {
+        let r: Topics = self
+            .0
+            .request("event.unsubscribe", json!({ "topics": patterns }))
+            .await?;
+        Ok(r.topics)
+    }
+
+    /// Publish an event to the plugins subscribed to its topic.
+    pub async fn publish<P: Serialize>(&self, topic: &str, payload: P) -> Result<(), RpcError> {
+        let payload = serde_json::to_value(payload)
+            .map_err(|e| RpcError::new(RpcError::INVALID_PARAMS, e.to_string()))?;
+        self.0
+            .client
+            .send(&Envelope::event(topic, Some(payload)))
+            .await
+            .map_err(|e| RpcError::new(RpcError::TARGET_UNAVAILABLE, e.to_string()))
+    }
+}
+
+/// Payload of `timer.tick` events.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Tick {
+    pub id: String,
+    pub now_ms: i64,
+}
+
+/// A timer as reported by `timer.list`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct TimerInfo {
+    pub id: String,
+    pub kind: String,
+    #[serde(default)]
+    pub millis: Option<u64>,
+    #[serde(default)]
+    pub cron: Option<String>,
+    #[serde(default)]
+    pub at: Option<String>,
+    #[serde(default)]
+    pub tz: Option<String>,
+    pub next_ms: Option<i64>,
+}
+
+#[derive(Deserialize)]
+struct TimerId {
+    id: String,
+}
+
+/// Client for the `timer` service. Ticks arrive as `timer.tick` events with
+/// a [`Tick`] payload; register a handler for them with [`Plugin::event`].
+pub struct Timer<'a>(&'a Context);
+
+impl Timer<'_> {
+    async fn set(&self, method: &str, params: Value) -> Result<String, RpcError> {
+        let r: TimerId = self.0.request(method, params).await?;
+        Ok(r.id)
+    }
+
+    pub async fn set_interval(&self, id: &str, millis: u64) -> Result<String, RpcError> {
+        self.set("timer.set_interval", json!({"id": id, "millis": millis}))
+            .await
+    }
+
+    pub async fn set_timeout(&self, id: &str, millis: u64) -> Result<String, RpcError> {
+        self.set("timer.set_timeout", json!({"id": id, "millis": millis}))
+            .await
+    }
+
+    /// Fire at the times matching a five-field cron expression, in the IANA
+    /// time zone `tz` or the core's home zone.
+    pub async fn set_cron(
+        &self,
+        id: &str,
+        cron: &str,
+        tz: Option<&str>,
+    ) -> Result<String, RpcError> {
+        self.set("timer.set_cron", json!({"id": id, "cron": cron, "tz": tz}))
+            .await
+    }
+
+    /// Fire daily at a solar event such as `sunset-30m`.
+    pub async fn set_solar(&self, id: &str, at: &str) -> Result<String, RpcError> {
+        self.set("timer.set_solar", json!({"id": id, "at": at}))
+            .await
+    }
+
+    pub async fn cancel(&self, id: &str) -> Result<bool, RpcError> {
+        #[derive(Deserialize)]
+        struct Cancelled {
+            cancelled: bool,
+        }
+        let r: Cancelled = self.0.request("timer.cancel", json!({ "id": id })).await?;
+        Ok(r.cancelled)
+    }
+
+    pub async fn list(&self) -> Result<Vec<TimerInfo>, RpcError> {
+        #[derive(Deserialize)]
+        struct Timers {
+            timers: Vec<TimerInfo>,
+        }
+        let r: Timers = self.0.request("timer.list", json!({})).await?;
+        Ok(r.timers)
+    }
+}
+
+/// Result of [`Storage::compare_and_swap`].
+#[derive(Debug, Clone, Deserialize)]
+pub struct Swap {
+    pub swapped: bool,
+    /// The value stored after the call.
+    pub current: Option<Value>,
+}
+
+/// Client for the plugin's key-value `storage` service.
+pub struct Storage<'a>(&'a Context);
+
+impl Storage<'_> {
+    /// The value stored under `key`, if any.
+    pub async fn get<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>, RpcError> {
+        #[derive(Deserialize)]
+        struct Get {
+            value: Option<Value>,
+        }
+        let r: Get = self.0.request("storage.get", json!({ "key": key })).await?;
+        r.value
+            .map(serde_json::from_value)
+            .transpose()
+            .map_err(|e| RpcError::new(RpcError::INTERNAL_ERROR, format!("stored {key}: {e}")))
+    }
+
+    pub async fn put<T: Serialize>(&self, key: &str, value: &T) -> Result<(), RpcError> {
+        self.0
+            .request::<_, Value>("storage.put", json!({"key": key, "value": value}))
+            .await?;
+        Ok(())
+    }
+
+    /// Remove `key`. Returns whether it existed.
+    pub async fn delete(&self, key: &str) -> Result<bool, RpcError> {
+        #[derive(Deserialize)]
+        struct Deleted {
+            deleted: bool,
+        }
+        let r: Deleted = self
+            .0
+            .request("storage.delete", json!({ "key": key }))
+            .await?;
+        Ok(r.deleted)
+    }
+
+    /// Keys starting with `prefix`.
+    pub async fn list(&self, prefix: &str) -> Result<Vec<String>, RpcError> {
+        #[derive(Deserialize)]
+        struct Keys {
+            keys: Vec<String>,
+        }
+        let r: Keys = self
+            .0
+            .request("storage.list", json!({ "prefix": prefix }))
+            .await?;
+        Ok(r.keys)
+    }
+
+    /// Set `key` to `value` only if it currently holds `expected`. `None`
+    /// stands for an absent key in `expected` and deletes it in `value`.
+    pub async fn compare_and_swap(
+        &self,
+        key: &str,
+        expected: Option<&Value>,
+        value: Option<&Value>,
+    ) -> Result<Swap, RpcError> {
+        self.0
+            .request(
+                "storage.compare_and_swap",
+                json!({"key": key, "expected": expected, "value": value}),
+            )
+            .await
+    }
+}
+
+/// Client for the device registry: the `device` and `state` services.
+/// Changes arrive as `state.changed` events with a
+/// [`StateChanged`](crate::device::StateChanged) payload.
+pub struct Devices<'a>(&'a Context);
+
+impl Devices<'_> {
+    /// Register a device with its entities, replacing an earlier
+    /// registration of the same device.
+    pub async fn register(&self, device: &Device, entities: &[Entity]) -> Result<(), RpcError> {
+        self.0
+            .request::<_, Value>(
+                "device.register",
+                json!({"device": device, "entities": entities}),
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Report the state of one of the plugin's entities. `attributes`
+    /// replace the current ones unless `None`. Returns whether anything
+    /// changed.
+    pub async fn set_state(
+        &self,
+        entity_id: &str,
+        state: impl Serialize,
+        attributes: Option<Value>,
+    ) -> Result<bool, RpcError> {
+        #[derive(Deserialize)]
+        struct Changed {
+            changed: bool,
+        }
+        let r: Changed = self
+            .0
+            .request(
+                "state.set",
+                json!({"entity_id": entity_id, "state": state, "attributes": attributes}),
+            )
+            .await?;
+        Ok(r.changed)
+    }
+
+    /// The state of an entity, `None` if it has not been reported yet.
+    pub async fn state(&self, entity_id: &str) -> Result<Option<EntityState>, RpcError> {
+        #[derive(Deserialize)]
+        struct Get {
+            state: Option<EntityState>,
+        }
+        let r: Get = self
+            .0
+            .request("state.get", json!({ "entity_id": entity_id }))
+            .await?;
+        Ok(r.state)
+    }
+
+    /// Command an entity, e.g. turn on a light. The core checks the command
+    /// against the entity and passes it to the plugin owning the entity;
+    /// the owner's answer is returned. The new state follows as a
+    /// `state.changed` event once the owner reports it.
+    pub async fn command(&self, command: &DeviceCommand) -> Result<Value, RpcError> {
+        self.0.request(device::COMMAND, command).await
+    }
+
+    /// The states of all entities, or of one domain such as `light`.
+    pub async fn states(&self, domain: Option<&str>) -> Result<Vec<EntityState>, RpcError> {
+        #[derive(Deserialize)]
+        struct States {
+            states: Vec<EntityState>,
+        }
+        let r: States = self
+            .0
+            .request("state.list", json!({ "domain": domain }))
+            .await?;
+        Ok(r.states)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{duplex, split, AsyncBufReadExt, BufReader, DuplexStream, ReadHalf, WriteHalf};
+
+    /// The core's end of an in-memory connection.
+    struct FakeCore {
+        lines: Lines<BufReader<ReadHalf<DuplexStream>>>,
+        writer: WriteHalf<DuplexStream>,
+    }
+
+    impl FakeCore {
+        async fn send(&mut self, env: Envelope) {
+            let mut line = serde_json::to_vec(&env).unwrap();
+            line.push(b'\n');
+            self.writer.write_all(&line).await.unwrap();
+        }
+
+        async fn recv(&mut self) -> Envelope {
+            let line = self.lines.next_line().await.unwrap().unwrap();
+            serde_json::from_str(&line).unwrap()
+        }
+
+        /// Answer the next request, which must be for `method`.
+        async fn answer(&mut self, method: &str, result: Value) -> Envelope {
+            let req = self.recv().await;
+            assert_eq!(req.method.as_deref(), Some(method), "{req:?}");
+            self.send(Envelope::response(req.id.clone(), Ok(result)))
+                .await;
+            req
+        }
+    }
+
+    fn start(plugin: Plugin) -> (FakeCore, tokio::task::JoinHandle<anyhow::Result<()>>) {
+        let (core_end, plugin_end) = duplex(1 << 16);
+        let (pr, pw) = split(plugin_end);
+        let (cr, cw) = split(core_end);
+        let task = tokio::spawn(plugin.run(pr, pw));
+        let core = FakeCore {
+            lines: BufReader::new(cr).lines(),
+            writer: cw,
+        };
+        (core, task)
+    }
+
+    fn metadata() -> Metadata {
+        Metadata {
+            id: "t".into(),
+            name: "T".into(),
+            version: "0.1.0".into(),
+            provides: vec!["t.*".into()],
+            ..Default::default()
+        }
+    }
+
+    async fn handshake(core: &mut FakeCore) -> Envelope {
+        core.send(Envelope::event(
+            "core.hello",
+            Some(json!({"api_version": "1"})),
+        ))
+        .await;
+        let init = core
+            .answer("plugin.init", json!({"ok": true, "features": ["cancel"]}))
+            .await;
+        core.answer("plugin.start", json!({"ok": true})).await;
+        init
+    }
+
+    #[tokio::test]
+    async fn serves_methods_and_events() {
+        let (ticks_tx, mut ticks) = tokio::sync::mpsc::unbounded_channel();
+        let plugin = Plugin::new(metadata())
+            .method("t.add", |_ctx, (a, b): (i64, i64)| async move { Ok(a + b) })
+            .event("timer.tick", move |_ctx, tick: Event<Tick>| {
+                let ticks_tx = ticks_tx.clone();
+                async move {
+                    ticks_tx.send(tick.payload.id)?;
+                    Ok(())
+                }
+            })
+            .on_start(|ctx| async move {
+                ctx.timer().set_interval("beat", 10).await?;
+                Ok(())
+            });
+        let (mut core, task) = start(plugin);
+
+        let init = handshake(&mut core).await;
+        let md: Metadata =
+            serde_json::from_value(init.params.unwrap()["metadata"].clone()).unwrap();
+        assert_eq!(md.api_version.as_deref(), Some(API_VERSION));
+        assert_eq!(md.features, features::ALL);
+
+        let sub = core
+            .answer(
+                "event.subscribe",
+                json!({"ok": true, "topics": ["timer.tick"]}),
+            )
+            .await;
+        assert_eq!(sub.params.unwrap()["topics"], json!(["timer.tick"]));
+        let set = core
+            .answer("timer.set_interval", json!({"ok": true, "id": "beat"}))
+            .await;
+        assert_eq!(set.params.unwrap()["millis"], 10);
+
+        core.send(Envelope::event(
+            "timer.tick",
+            Some(json!({"id": "beat", "now_ms": 1})),
+        ))
+        .await;
+        assert_eq!(ticks.recv().await.unwrap(), "beat");
+
+        core.send(Envelope::request("1", "t.add", json!([2, 3])))
+            .await;
+        let resp = core.recv().await;
+        assert_eq!(resp.id.as_deref(), Some("1"));
+        assert_eq!(resp.result, Some(json!(5)));
+
+        core.send(Envelope::request("2", "t.add", json!("x"))).await;
+        assert_eq!(
+            core.recv().await.error.unwrap().code,
+            RpcError::INVALID_PARAMS
+        );
+        core.send(Envelope::request("3", "t.nope", json!({}))).await;
+        assert_eq!(
+            core.recv().await.error.unwrap().code,
+            RpcError::METHOD_NOT_FOUND
+        );
+
+        core.send(Envelope::request("4", "plugin.stop", json!({})))
+            .await;
+        assert_eq!(core.recv().await.id.as_deref(), Some("4"));
+        task.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn times_out_and_cancels_requests() {
+        let plugin = Plugin::new(metadata())
+            .method("t.slow", |_ctx, _: Value| async move {
+                tokio::time::sleep(Duration::from_secs(3600)).await;
+                Ok(())
+            })
+            .on_start(|ctx| async move {
+                let err = ctx
+                    .request_with_timeout::<_, Value>("x.y", json!({}), Duration::from_millis(20))
+                    .await
+                    .unwrap_err();
+                assert_eq!(err.code, RpcError::TIMEOUT);
+                Ok(())
+            });
+        let (mut core, task) = start(plugin);
+        handshake(&mut core).await;
+
+        let req = core.recv().await;
+        assert_eq!(req.method.as_deref(), Some("x.y"));
+        let cancel = core.recv().await;
+        assert_eq!(cancel.method.as_deref(), Some(CANCEL_METHOD));
+        assert_eq!(cancel.params.unwrap()["id"], json!(req.id.unwrap()));
+
+        core.send(Envelope::request("9", "t.slow", json!({}))).await;
+        core.send(Envelope::notification(CANCEL_METHOD, json!({"id": "9"})))
+            .await;
+        core.send(Envelope::request("10", "plugin.stop", json!({})))
+            .await;
+        // the cancelled request is never answered
+        assert_eq!(core.recv().await.id.as_deref(), Some("10"));
+        task.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn keeps_settings_from_init_up_to_date() {
+        #[derive(Deserialize)]
+        struct Settings {
+            greeting: String,
+        }
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let changed_tx = tx.clone();
+        let plugin = Plugin::new(metadata())
+            .event(CONFIG_CHANGED, move |ctx, _: Event<Value>| {
+                let tx = changed_tx.clone();
+                async move {
+                    tx.send(ctx.settings::<Settings>()?.greeting)?;
+                    Ok(())
+                }
+            })
+            .on_start(move |ctx| async move {
+                assert_eq!(ctx.data_dir(), Some(PathBuf::from("/data/t")));
+                assert_eq!(ctx.permissions(), vec!["storage"]);
+                tx.send(ctx.settings::<Settings>()?.greeting)?;
+                Ok(())
+            });
+        let (mut core, task) = start(plugin);
+        core.send(Envelope::event("core.hello", None)).await;
+        core.answer(
+            "plugin.init",
+            json!({
+                "ok": true,
+                "settings": {"greeting": "hello"},
+                "data_dir": "/data/t",
+                "permissions": ["storage"],
+            }),
+        )
+        .await;
+        core.answer("plugin.start", json!({"ok": true})).await;
+        assert_eq!(rx.recv().await.unwrap(), "hello");
+
+        // delivered without subscribing
+        core.send(Envelope::event(
+            CONFIG_CHANGED,
+            Some(json!({"settings": {"greeting": "hi"}})),
+        ))
+        .await;
+        assert_eq!(rx.recv().await.unwrap(), "hi");
+
+        core.send(Envelope::request("1", "plugin.stop", json!({})))
+            .await;
+        assert_eq!(core.recv().await.id.as_deref(), Some("1"));
+        task.await.unwrap().unwrap();
+    }
+}
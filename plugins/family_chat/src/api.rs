@@ -38,29 +38,10 @@ use utoipa::OpenApi;
 use utoipa_swagger_ui::SwaggerUi;
 use uuid::Uuid;
 
-#[derive(Clone)]
-pub struct FileMeta {
-    pub mime: String,
-    pub name: String,
-    #[allow(dead_code)]
-    pub thumb: Option<ThumbMeta>,
-}
-
-#[derive(Clone)]
-#[allow(dead_code)]
-pub struct ThumbMeta {
-    pub id: String,
-    pub mime: String,
-    pub width: u32,
-    pub height: u32,
-}
-
 #[derive(Clone)]
 pub struct AppState {
-    #[allow(dead_code)]
     pub pool: Pool<SqliteConnectionManager>,
     pub file_dir: PathBuf,
-    pub files: std::sync::Arc<Mutex<HashMap<String, FileMeta>>>,
     pub upload_limits: std::sync::Arc<Mutex<HashMap<u32, (u32, Instant)>>>,
     pub event_tx: broadcast::Sender<String>,
     pub config: Config,
@@ -76,11 +57,12 @@ impl AppState {
     pub async fn new(config: Config) -> Result<Self> {
         let file_dir = config.data_dir.join("files");
         tokio::fs::create_dir_all(&file_dir).await?;
-        let manager = SqliteConnectionManager::memory();
+        let manager = SqliteConnectionManager::file(config.data_dir.join(db::DB_FILE))
+            .with_init(db::configure);
         let pool = Pool::new(manager)?;
         {
-            let conn = pool.get()?;
-            conn.execute_batch(db::SCHEMA)?;
+            let mut conn = pool.get()?;
+            db::migrate(&mut conn)?;
         }
         let (tx, _rx) = broadcast::channel(100);
         let auth_file = config.data_dir.join("auth.json");
@@ -120,7 +102,6 @@ impl AppState {
         Ok(Self {
             pool,
             file_dir,
-            files: std::sync::Arc::new(Mutex::new(HashMap::new())),
             upload_limits: std::sync::Arc::new(Mutex::new(HashMap::new())),
             event_tx: tx,
             config,
@@ -321,6 +302,31 @@ struct MessageResp {
     edited_at: Option<i64>,
     reply_to: Option<Uuid>,
     user: ChatUser,
+    attachments: Vec<AttachmentResp>,
+}
+
+#[derive(Serialize)]
+struct AttachmentResp {
+    id: Uuid,
+    file_id: String,
+    name: String,
+    mime: Option<String>,
+    size: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thumb: Option<model::Thumbnail>,
+}
+
+impl From<model::Attachment> for AttachmentResp {
+    fn from(a: model::Attachment) -> Self {
+        Self {
+            id: a.id,
+            file_id: a.file_id,
+            name: a.file_name,
+            mime: a.mime,
+            size: a.size_bytes,
+            thumb: a.thumb,
+        }
+    }
 }
 
 #[derive(Serialize)]
@@ -329,7 +335,11 @@ struct SearchResultResp {
     highlights: Vec<String>,
 }
 
-fn msg_with_user(msg: model::Message, user: &auth::User) -> MessageResp {
+fn msg_with_user(
+    msg: model::Message,
+    user: &auth::User,
+    attachments: Vec<model::Attachment>,
+) -> MessageResp {
     MessageResp {
         id: msg.id,
         room_id: msg.room_id,
@@ -342,6 +352,7 @@ fn msg_with_user(msg: model::Message, user: &auth::User) -> MessageResp {
             username: user.username.clone(),
             display_name: user.display_name.clone(),
         },
+        attachments: attachments.into_iter().map(Into::into).collect(),
     }
 }
 
@@ -622,6 +633,12 @@ async fn update_user(
 #[derive(Serialize)]
 struct UploadResp {
     file_id: String,
+    name: String,
+    size: i64,
+    sha256: String,
+    mime: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thumb: Option<model::Thumbnail>,
 }
 
 async fn upload_file(
@@ -632,7 +649,7 @@ async fn upload_file(
     if !state.check_upload_limit(user.id) {
         return Err(StatusCode::TOO_MANY_REQUESTS);
     }
-    let mut id = None;
+    let mut stored = None;
     if let Some(field) = multipart.next_field().await.unwrap_or(None) {
         let name_raw = field
             .file_name()
@@ -650,7 +667,7 @@ async fn upload_file(
         let mut thumb = None;
         if let Ok(Some((thumb_bytes, w, h))) = files::generate_thumbnail(&data) {
             if let Ok(tid) = files::save_file(&state.file_dir, Bytes::from(thumb_bytes)).await {
-                thumb = Some(ThumbMeta {
+                thumb = Some(model::Thumbnail {
                     id: tid,
                     mime: "image/png".into(),
                     width: w,
@@ -658,14 +675,35 @@ async fn upload_file(
                 });
             }
         }
-        state
-            .files
-            .lock()
-            .insert(file_id.clone(), FileMeta { mime, name, thumb });
-        id = Some(file_id);
+        let file = model::StoredFile {
+            id: file_id,
+            name,
+            mime,
+            size_bytes: data.len() as i64,
+            uploader_id: user.id,
+            uploaded_at: OffsetDateTime::now_utc().unix_timestamp(),
+            thumb,
+        };
+        let conn = state
+            .pool
+            .get()
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        files::record_file(&conn, &file).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        // identical content keeps its first name, so answer with the stored row
+        stored = files::get_file(&conn, &file.id).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
     }
-    if let Some(file_id) = id {
-        Ok((StatusCode::OK, axum::Json(UploadResp { file_id })))
+    if let Some(file) = stored {
+        Ok((
+            StatusCode::OK,
+            axum::Json(UploadResp {
+                sha256: file.id.clone(),
+                file_id: file.id,
+                name: file.name,
+                size: file.size_bytes,
+                mime: file.mime,
+                thumb: file.thumb,
+            }),
+        ))
     } else {
         Err(StatusCode::BAD_REQUEST)
     }
@@ -700,12 +738,23 @@ async fn download_file(
     Path(id): Path<String>,
     headers: HeaderMap,
 ) -> Result<impl IntoResponse, StatusCode> {
-    let meta = state
-        .files
-        .lock()
-        .get(&id)
-        .cloned()
-        .ok_or(StatusCode::NOT_FOUND)?;
+    let (mime, name) = {
+        let conn = state
+            .pool
+            .get()
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        if let Some(file) =
+            files::get_file(&conn, &id).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        {
+            (file.mime, file.name)
+        } else if let Some(thumb) =
+            files::get_thumbnail(&conn, &id).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        {
+            (thumb.mime, "thumbnail.png".to_string())
+        } else {
+            return Err(StatusCode::NOT_FOUND);
+        }
+    };
     let path = files::file_path(&state.file_dir, &id);
     let mut file = tokio::fs::File::open(path)
         .await
@@ -745,11 +794,11 @@ async fn download_file(
     }
     resp_headers.insert(
         header::CONTENT_TYPE,
-        header::HeaderValue::from_str(&meta.mime).unwrap(),
+        header::HeaderValue::from_str(&mime).unwrap(),
     );
     resp_headers.insert(
         header::CONTENT_DISPOSITION,
-        header::HeaderValue::from_str(&format!("inline; filename=\"{}\"", meta.name)).unwrap(),
+        header::HeaderValue::from_str(&format!("inline; filename=\"{}\"", name)).unwrap(),
     );
     resp_headers.insert(
         header::CACHE_CONTROL,
@@ -891,14 +940,22 @@ struct CreateMessageReq {
     reply_to: Option<Uuid>,
     #[serde(default)]
     message_idempotency_key: Option<String>,
+    /// Ids of previously uploaded files to attach.
+    #[serde(default)]
+    attachments: Vec<String>,
 }
 
+const MAX_ATTACHMENTS: usize = 10;
+
 async fn post_message(
     State(state): State<AppState>,
     Extension(user): Extension<auth::User>,
     Json(req): Json<CreateMessageReq>,
 ) -> Result<impl IntoResponse, (StatusCode, Json<ErrorResp>)> {
-    let conn = state
+    if req.attachments.len() > MAX_ATTACHMENTS {
+        return Err(err(StatusCode::BAD_REQUEST, "too_many_attachments"));
+    }
+    let mut conn = state
         .pool
         .get()
         .map_err(|_| err(StatusCode::INTERNAL_SERVER_ERROR, "db"))?;
@@ -907,8 +964,12 @@ async fn post_message(
     if !allowed {
         return Err(err(StatusCode::FORBIDDEN, "forbidden"));
     }
+    // message and attachments are stored together or not at all
+    let tx = conn
+        .transaction()
+        .map_err(|_| err(StatusCode::INTERNAL_SERVER_ERROR, "db"))?;
     let msg = messages::create_message(
-        &conn,
+        &tx,
         &req.room_id,
         user.id,
         &req.text_md,
@@ -919,9 +980,19 @@ async fn post_message(
         "empty_message" => err(StatusCode::BAD_REQUEST, "empty_message"),
         _ => err(StatusCode::INTERNAL_SERVER_ERROR, "db"),
     })?;
+    let attachments =
+        messages::add_attachments(&tx, &msg.id, user.id, &req.attachments).map_err(|e| match e
+            .to_string()
+            .as_str()
+        {
+            "unknown_file" => err(StatusCode::BAD_REQUEST, "unknown_file"),
+            _ => err(StatusCode::INTERNAL_SERVER_ERROR, "db"),
+        })?;
+    tx.commit()
+        .map_err(|_| err(StatusCode::INTERNAL_SERVER_ERROR, "db"))?;
     reads::set_read_pointer(&conn, user.id, &req.room_id, msg.created_at)
         .map_err(|_| err(StatusCode::INTERNAL_SERVER_ERROR, "db"))?;
-    let out = msg_with_user(msg.clone(), &user);
+    let out = msg_with_user(msg.clone(), &user, attachments);
     let _ = state
         .event_tx
         .send(serde_json::json!({"t":"message","room_id":req.room_id,"message":out}).to_string());
@@ -980,6 +1051,9 @@ async fn list_messages(
     };
     let msgs = messages::list_messages(&conn, &params.room_id, before, limit)
         .map_err(|_| err(StatusCode::INTERNAL_SERVER_ERROR, "db"))?;
+    let ids: Vec<Uuid> = msgs.iter().map(|m| m.id).collect();
+    let mut attachments = messages::attachments_for(&conn, &ids)
+        .map_err(|_| err(StatusCode::INTERNAL_SERVER_ERROR, "db"))?;
     let auth = state.auth.lock().await;
     let user_map: HashMap<u32, auth::User> = auth
         .as_ref()
@@ -987,7 +1061,12 @@ async fn list_messages(
         .unwrap_or_default();
     let out: Vec<MessageResp> = msgs
         .into_iter()
-        .filter_map(|m| user_map.get(&m.author_id).map(|u| msg_with_user(m, u)))
+        .filter_map(|m| {
+            let atts = attachments.remove(&m.id).unwrap_or_default();
+            user_map
+                .get(&m.author_id)
+                .map(|u| msg_with_user(m, u, atts))
+        })
         .collect();
     Ok(Json(out))
 }
@@ -1013,7 +1092,11 @@ async fn edit_message(
             _ => err(StatusCode::INTERNAL_SERVER_ERROR, "db"),
         }
     })?;
-    let out = msg_with_user(msg.clone(), &user);
+    let attachments = messages::attachments_for(&conn, &[msg.id])
+        .map_err(|_| err(StatusCode::INTERNAL_SERVER_ERROR, "db"))?
+        .remove(&msg.id)
+        .unwrap_or_default();
+    let out = msg_with_user(msg.clone(), &user, attachments);
     let _ = state.event_tx.send(
         serde_json::json!({"t":"message_edit","room_id":msg.room_id,"message":out}).to_string(),
     );
@@ -1057,6 +1140,9 @@ async fn search_messages(
         .map_err(|_| err(StatusCode::INTERNAL_SERVER_ERROR, "db"))?;
     let res = messages::search_messages(&conn, &params.q, params.room_id.as_ref())
         .map_err(|_| err(StatusCode::INTERNAL_SERVER_ERROR, "db"))?;
+    let ids: Vec<Uuid> = res.iter().map(|r| r.message.id).collect();
+    let mut attachments = messages::attachments_for(&conn, &ids)
+        .map_err(|_| err(StatusCode::INTERNAL_SERVER_ERROR, "db"))?;
     let auth = state.auth.lock().await;
     let user_map: HashMap<u32, auth::User> = auth
         .as_ref()
@@ -1065,10 +1151,11 @@ async fn search_messages(
     let out: Vec<SearchResultResp> = res
         .into_iter()
         .filter_map(|r| {
+            let atts = attachments.remove(&r.message.id).unwrap_or_default();
             user_map
                 .get(&r.message.author_id)
                 .map(|u| SearchResultResp {
-                    message: msg_with_user(r.message, u),
+                    message: msg_with_user(r.message, u, atts),
                     highlights: r.highlights,
                 })
         })
@@ -1197,6 +1284,7 @@ async fn handle_socket(stream: WebSocket, state: AppState, user: auth::User) {
 pub async fn run_http_server(config: Config) -> Result<()> {
     let addr: SocketAddr = config.bind.parse()?;
     let state = AppState::new(config).await?;
+    crate::housekeeping::run_housekeeping(state.clone()).await;
     axum::Server::bind(&addr)
         .serve(build_router(state).into_make_service())
         .await?;
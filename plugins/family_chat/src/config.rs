@@ -82,6 +82,23 @@ struct FileLogging {
     enabled: bool,
 }
 
+/// Settings the core passes in `plugin.init`, as described by
+/// `settings.schema.json`. They use the layout of the config file.
+#[derive(Deserialize, Default)]
+pub struct CoreSettings {
+    #[serde(default)]
+    bootstrap: Option<FileBootstrap>,
+    #[serde(default)]
+    server: Option<CoreServer>,
+    #[serde(default)]
+    max_upload_mb: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct CoreServer {
+    port: Option<u16>,
+}
+
 fn default_port() -> u16 {
     8787
 }
@@ -186,6 +203,31 @@ impl Config {
         })
     }
 
+    /// Apply the settings and data directory the core passed in
+    /// `plugin.init`. They take precedence over the file, env and CLI.
+    pub fn apply_core(&mut self, settings: CoreSettings, data_dir: Option<PathBuf>) -> Result<()> {
+        if let Some(port) = settings.server.and_then(|s| s.port) {
+            if port < 1024 {
+                anyhow::bail!("invalid_port");
+            }
+            let host = self.bind.rsplit_once(':').map_or("127.0.0.1", |(h, _)| h);
+            self.bind = format!("{host}:{port}");
+        }
+        if let Some(b) = settings.bootstrap {
+            self.bootstrap = Some(Bootstrap {
+                username: b.username,
+                password: b.password,
+            });
+        }
+        if let Some(mb) = settings.max_upload_mb {
+            self.max_upload_mb = mb;
+        }
+        if let Some(dir) = data_dir {
+            self.data_dir = dir;
+        }
+        Ok(())
+    }
+
     /// Helper to return the upload limit in bytes.
     pub fn max_upload_bytes(&self) -> u64 {
         self.max_upload_mb * 1024 * 1024
@@ -309,4 +351,31 @@ mod tests {
         let cfg = Config::load(&cli).unwrap();
         assert!(!cfg.logging_enabled);
     }
+
+    #[test]
+    #[serial]
+    fn core_settings_override_file() {
+        std::env::remove_var("FAMILY_CHAT_PORT");
+        std::env::remove_var("FAMILY_CHAT_LOGGING");
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cfg.toml");
+        fs::write(&path, "[server]\nport=4444\n").unwrap();
+        let cli = Cli {
+            config: Some(path),
+            bind: Some("0.0.0.0:4444".into()),
+            ..Default::default()
+        };
+        let mut cfg = Config::load(&cli).unwrap();
+        let settings: CoreSettings = serde_json::from_value(serde_json::json!({
+            "server": {"port": 5555},
+            "bootstrap": {"username": "ada", "password": "pw"},
+        }))
+        .unwrap();
+        cfg.apply_core(settings, Some(dir.path().join("data")))
+            .unwrap();
+        assert_eq!(cfg.bind, "0.0.0.0:5555");
+        assert_eq!(cfg.bootstrap.unwrap().username, "ada");
+        assert_eq!(cfg.data_dir, dir.path().join("data"));
+        assert_eq!(cfg.max_upload_mb, 5);
+    }
 }
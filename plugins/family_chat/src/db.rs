@@ -1,17 +1,86 @@
 #![allow(dead_code)]
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use rusqlite::Connection;
 use std::path::Path;
+use std::time::Duration;
 
-/// Initialize the SQLite database and run migrations.
+/// File name of the SQLite database inside the plugin data directory.
+pub const DB_FILE: &str = "family_chat.db";
+
+/// A numbered schema change. Migrations are applied in ascending `version`
+/// order and the highest applied version is recorded in `PRAGMA user_version`.
+pub struct Migration {
+    pub version: i64,
+    pub name: &'static str,
+    pub sql: &'static str,
+}
+
+/// All known migrations. Never edit an entry once it has shipped; append a new
+/// one instead so existing installs are upgraded in place.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "initial_schema",
+        sql: V1_INITIAL_SCHEMA,
+    },
+    Migration {
+        version: 2,
+        name: "file_metadata",
+        sql: V2_FILE_METADATA,
+    },
+];
+
+/// Open (or create) the SQLite database at `path`, configure it and run migrations.
 pub fn init_db<P: AsRef<Path>>(path: P) -> Result<Connection> {
-    let conn = Connection::open(path)?;
-    conn.execute_batch(SCHEMA)?;
+    let mut conn = Connection::open(path)?;
+    configure(&mut conn)?;
+    migrate(&mut conn)?;
     Ok(conn)
 }
 
-pub const SCHEMA: &str = r#"
+/// Per-connection settings. Foreign keys are off by default in SQLite and must
+/// be enabled on every connection; WAL lets readers proceed while a writer is
+/// active.
+pub fn configure(conn: &mut Connection) -> rusqlite::Result<()> {
+    conn.busy_timeout(Duration::from_secs(5))?;
+    conn.pragma_update_and_check(None, "journal_mode", "WAL", |row| row.get::<_, String>(0))?;
+    conn.pragma_update(None, "foreign_keys", "ON")?;
+    Ok(())
+}
+
+/// Current schema version stored in the database.
+pub fn schema_version(conn: &Connection) -> Result<i64> {
+    Ok(conn.query_row("PRAGMA user_version", [], |row| row.get(0))?)
+}
+
+/// Apply all pending migrations, each in its own transaction.
+pub fn migrate(conn: &mut Connection) -> Result<()> {
+    let current = schema_version(conn)?;
+    let latest = MIGRATIONS.last().map(|m| m.version).unwrap_or(0);
+    if current > latest {
+        bail!(
+            "database schema version {} is newer than supported version {}",
+            current,
+            latest
+        );
+    }
+    for m in MIGRATIONS.iter().filter(|m| m.version > current) {
+        let tx = conn.transaction()?;
+        tx.execute_batch(m.sql)
+            .map_err(|e| anyhow::anyhow!("migration {} ({}) failed: {}", m.version, m.name, e))?;
+        tx.pragma_update(None, "user_version", m.version)?;
+        tx.commit()?;
+        tracing::info!(
+            version = m.version,
+            name = m.name,
+            "applied database migration"
+        );
+    }
+    Ok(())
+}
+
+const V1_INITIAL_SCHEMA: &str = r#"
 CREATE TABLE IF NOT EXISTS users (
   id TEXT PRIMARY KEY,
   username TEXT UNIQUE NOT NULL,
@@ -112,3 +181,86 @@ CREATE TRIGGER IF NOT EXISTS messages_au AFTER UPDATE ON messages BEGIN
   INSERT INTO messages_fts(rowid, text_md) VALUES (new.rowid, new.text_md);
 END;
 "#;
+
+const V2_FILE_METADATA: &str = r#"
+CREATE TABLE files (
+  id TEXT PRIMARY KEY,
+  name TEXT NOT NULL,
+  mime TEXT NOT NULL,
+  size_bytes INTEGER NOT NULL,
+  uploader_id INTEGER NOT NULL,
+  uploaded_at INTEGER NOT NULL,
+  thumb_id TEXT,
+  thumb_mime TEXT,
+  thumb_width INTEGER,
+  thumb_height INTEGER
+);
+
+CREATE TABLE file_uploaders (
+  file_id TEXT NOT NULL REFERENCES files(id) ON DELETE CASCADE,
+  user_id INTEGER NOT NULL,
+  PRIMARY KEY (file_id, user_id)
+);
+
+CREATE INDEX idx_files_thumb ON files(thumb_id);
+CREATE INDEX idx_attachments_message ON attachments(message_id);
+CREATE INDEX idx_attachments_file ON attachments(file_id);
+"#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn migrations_are_ordered() {
+        for pair in MIGRATIONS.windows(2) {
+            assert!(pair[0].version < pair[1].version);
+        }
+        assert_eq!(MIGRATIONS[0].version, 1);
+    }
+
+    #[test]
+    fn migrate_is_idempotent() {
+        let mut conn = init_db(":memory:").unwrap();
+        let latest = MIGRATIONS.last().unwrap().version;
+        assert_eq!(schema_version(&conn).unwrap(), latest);
+        migrate(&mut conn).unwrap();
+        assert_eq!(schema_version(&conn).unwrap(), latest);
+    }
+
+    #[test]
+    fn refuses_newer_schema() {
+        let mut conn = init_db(":memory:").unwrap();
+        conn.pragma_update(None, "user_version", 9999).unwrap();
+        assert!(migrate(&mut conn).is_err());
+    }
+
+    #[test]
+    fn data_survives_reopen() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join(DB_FILE);
+        {
+            let conn = init_db(&path).unwrap();
+            conn.execute(
+                "INSERT INTO rooms (id, slug, name, is_dm, created_at) VALUES ('r1', 'general', 'General', 0, 0)",
+                [],
+            )
+            .unwrap();
+        }
+        let conn = init_db(&path).unwrap();
+        let name: String = conn
+            .query_row("SELECT name FROM rooms WHERE id = 'r1'", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(name, "General");
+        let mode: String = conn
+            .query_row("PRAGMA journal_mode", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(mode, "wal");
+        let fk: i64 = conn
+            .query_row("PRAGMA foreign_keys", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(fk, 1);
+    }
+}
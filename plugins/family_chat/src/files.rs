@@ -1,10 +1,13 @@
 #![allow(dead_code)]
 
+use crate::model::{StoredFile, Thumbnail};
 use anyhow::Result;
 use bytes::Bytes;
+use rusqlite::{params, Connection, OptionalExtension};
 use sha2::{Digest, Sha256};
 use std::collections::HashSet;
 use std::path::{Path, PathBuf};
+use std::time::SystemTime;
 use tokio::fs;
 
 /// Sanitize an incoming filename to avoid path traversal and control characters.
@@ -63,27 +66,138 @@ pub fn file_path<P: AsRef<Path>>(base: P, id: &str) -> PathBuf {
     base.as_ref().join(sub).join(id)
 }
 
-/// Remove files from the content store that are not referenced in the provided set.
-pub async fn cleanup_orphans<P: AsRef<Path>>(base: P, keep: &HashSet<String>) -> Result<()> {
+/// Record metadata for an uploaded blob. Uploading identical content again
+/// only refreshes the upload time, which the GC grace period is based on;
+/// the original uploader and name are kept, but every uploader is remembered
+/// so each of them may attach the blob.
+pub fn record_file(conn: &Connection, file: &StoredFile) -> Result<()> {
+    let thumb = file.thumb.as_ref();
+    conn.execute(
+        "INSERT INTO files (id, name, mime, size_bytes, uploader_id, uploaded_at, thumb_id, thumb_mime, thumb_width, thumb_height) \
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10) \
+         ON CONFLICT(id) DO UPDATE SET uploaded_at = excluded.uploaded_at",
+        params![
+            file.id,
+            file.name,
+            file.mime,
+            file.size_bytes,
+            file.uploader_id,
+            file.uploaded_at,
+            thumb.map(|t| t.id.as_str()),
+            thumb.map(|t| t.mime.as_str()),
+            thumb.map(|t| t.width),
+            thumb.map(|t| t.height),
+        ],
+    )?;
+    conn.execute(
+        "INSERT OR IGNORE INTO file_uploaders (file_id, user_id) VALUES (?1, ?2)",
+        params![file.id, file.uploader_id],
+    )?;
+    Ok(())
+}
+
+/// Whether `user_id` has uploaded the blob `id` at least once.
+pub fn uploaded_by(conn: &Connection, id: &str, user_id: u32) -> Result<bool> {
+    let mut stmt =
+        conn.prepare("SELECT 1 FROM file_uploaders WHERE file_id = ?1 AND user_id = ?2")?;
+    Ok(stmt
+        .query_row(params![id, user_id], |row| row.get::<_, i64>(0))
+        .optional()?
+        .is_some())
+}
+
+fn row_to_file(row: &rusqlite::Row<'_>) -> rusqlite::Result<StoredFile> {
+    let thumb = match row.get::<_, Option<String>>(6)? {
+        Some(id) => Some(Thumbnail {
+            id,
+            mime: row.get(7)?,
+            width: row.get(8)?,
+            height: row.get(9)?,
+        }),
+        None => None,
+    };
+    Ok(StoredFile {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        mime: row.get(2)?,
+        size_bytes: row.get(3)?,
+        uploader_id: row.get(4)?,
+        uploaded_at: row.get(5)?,
+        thumb,
+    })
+}
+
+/// Look up metadata for an uploaded blob.
+pub fn get_file(conn: &Connection, id: &str) -> Result<Option<StoredFile>> {
+    let mut stmt = conn.prepare(
+        "SELECT id, name, mime, size_bytes, uploader_id, uploaded_at, thumb_id, thumb_mime, thumb_width, thumb_height FROM files WHERE id = ?1",
+    )?;
+    Ok(stmt.query_row([id], row_to_file).optional()?)
+}
+
+/// Look up a generated thumbnail by its blob id.
+pub fn get_thumbnail(conn: &Connection, id: &str) -> Result<Option<Thumbnail>> {
+    let mut stmt = conn.prepare(
+        "SELECT thumb_id, thumb_mime, thumb_width, thumb_height FROM files WHERE thumb_id = ?1 LIMIT 1",
+    )?;
+    let thumb = stmt
+        .query_row([id], |row| {
+            Ok(Thumbnail {
+                id: row.get(0)?,
+                mime: row.get(1)?,
+                width: row.get(2)?,
+                height: row.get(3)?,
+            })
+        })
+        .optional()?;
+    Ok(thumb)
+}
+
+/// Outcome of sweeping the content store.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SweepStats {
+    pub removed: usize,
+    pub freed_bytes: u64,
+}
+
+/// Remove blobs that are not in `keep` and were last written before `cutoff`.
+/// The age check protects blobs whose upload is still in flight and has not
+/// been recorded in the database yet.
+pub async fn sweep_store<P: AsRef<Path>>(
+    base: P,
+    keep: &HashSet<String>,
+    cutoff: SystemTime,
+) -> Result<SweepStats> {
+    let mut stats = SweepStats::default();
     let mut dirs = fs::read_dir(base).await?;
     while let Some(dir) = dirs.next_entry().await? {
-        if dir.file_type().await?.is_dir() {
-            let mut files = fs::read_dir(dir.path()).await?;
-            while let Some(f) = files.next_entry().await? {
-                let name = f.file_name().to_string_lossy().to_string();
-                if !keep.contains(&name) {
-                    let _ = fs::remove_file(f.path()).await;
-                }
+        if !dir.file_type().await?.is_dir() {
+            continue;
+        }
+        let mut entries = fs::read_dir(dir.path()).await?;
+        while let Some(f) = entries.next_entry().await? {
+            let name = f.file_name().to_string_lossy().to_string();
+            if keep.contains(&name) {
+                continue;
+            }
+            let meta = f.metadata().await?;
+            if !meta.is_file() || meta.modified()? >= cutoff {
+                continue;
+            }
+            if fs::remove_file(f.path()).await.is_ok() {
+                stats.removed += 1;
+                stats.freed_bytes += meta.len();
             }
         }
     }
-    Ok(())
+    Ok(stats)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use std::collections::HashSet;
+    use std::time::Duration;
 
     #[tokio::test]
     async fn saves_and_paths_file() {
@@ -125,15 +239,84 @@ mod tests {
         assert!(thumb.is_some());
     }
 
+    #[test]
+    fn records_and_refreshes_metadata() {
+        let conn = crate::db::init_db(":memory:").unwrap();
+        let mut file = StoredFile {
+            id: "ab12".into(),
+            name: "a.png".into(),
+            mime: "image/png".into(),
+            size_bytes: 3,
+            uploader_id: 1,
+            uploaded_at: 10,
+            thumb: Some(Thumbnail {
+                id: "cd34".into(),
+                mime: "image/png".into(),
+                width: 1,
+                height: 1,
+            }),
+        };
+        record_file(&conn, &file).unwrap();
+        assert_eq!(get_file(&conn, "ab12").unwrap().as_ref(), Some(&file));
+        assert_eq!(get_thumbnail(&conn, "cd34").unwrap(), file.thumb);
+        file.uploaded_at = 20;
+        record_file(&conn, &file).unwrap();
+        assert_eq!(get_file(&conn, "ab12").unwrap().unwrap().uploaded_at, 20);
+        assert!(get_file(&conn, "missing").unwrap().is_none());
+    }
+
+    #[test]
+    fn second_uploader_does_not_take_over_file() {
+        let conn = crate::db::init_db(":memory:").unwrap();
+        let first = StoredFile {
+            id: "ab12".into(),
+            name: "holiday.png".into(),
+            mime: "image/png".into(),
+            size_bytes: 3,
+            uploader_id: 1,
+            uploaded_at: 10,
+            thumb: None,
+        };
+        record_file(&conn, &first).unwrap();
+        let second = StoredFile {
+            name: "copy.png".into(),
+            uploader_id: 2,
+            uploaded_at: 30,
+            ..first.clone()
+        };
+        record_file(&conn, &second).unwrap();
+        let stored = get_file(&conn, "ab12").unwrap().unwrap();
+        assert_eq!(stored.uploader_id, 1);
+        assert_eq!(stored.name, "holiday.png");
+        assert_eq!(stored.uploaded_at, 30);
+        assert!(uploaded_by(&conn, "ab12", 1).unwrap());
+        assert!(uploaded_by(&conn, "ab12", 2).unwrap());
+        assert!(!uploaded_by(&conn, "ab12", 3).unwrap());
+    }
+
     #[tokio::test]
-    async fn cleans_orphans() {
+    async fn sweep_respects_keep_set_and_age() {
         let tmp = tempfile::tempdir().unwrap();
-        let id = save_file(tmp.path(), Bytes::from_static(b"hello"))
+        let kept = save_file(tmp.path(), Bytes::from_static(b"kept"))
+            .await
+            .unwrap();
+        let orphan = save_file(tmp.path(), Bytes::from_static(b"orphan"))
             .await
             .unwrap();
-        let path = file_path(tmp.path(), &id);
-        let keep = HashSet::new();
-        cleanup_orphans(tmp.path(), &keep).await.unwrap();
-        assert!(!path.exists());
+        let keep: HashSet<String> = [kept.clone()].into_iter().collect();
+
+        // nothing is old enough yet
+        let stats = sweep_store(tmp.path(), &keep, SystemTime::UNIX_EPOCH)
+            .await
+            .unwrap();
+        assert_eq!(stats, SweepStats::default());
+        assert!(file_path(tmp.path(), &orphan).exists());
+
+        let later = SystemTime::now() + Duration::from_secs(1);
+        let stats = sweep_store(tmp.path(), &keep, later).await.unwrap();
+        assert_eq!(stats.removed, 1);
+        assert_eq!(stats.freed_bytes, 6);
+        assert!(file_path(tmp.path(), &kept).exists());
+        assert!(!file_path(tmp.path(), &orphan).exists());
     }
 }
@@ -1,18 +1,306 @@
-use crate::{api::AppState, files};
-use std::collections::HashSet;
-use tokio::time::{interval, Duration};
+use crate::{api::AppState, auth, files};
+use anyhow::Result;
+use r2d2::Pool;
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::{params, Connection};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time::interval;
+use url::Url;
 
-/// Periodically remove orphaned files from the content store.
-#[allow(dead_code)]
+/// How often the file store is garbage collected.
+pub const GC_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Unreferenced uploads younger than this are kept, so a file uploaded for a
+/// message that is still being written is not removed before it is attached.
+pub const GC_GRACE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Summary of a garbage collection run.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct GcReport {
+    /// Distinct blobs referenced by attachments, thumbnails or avatars.
+    pub referenced: usize,
+    /// Upload records dropped because they were never attached.
+    pub expired_uploads: usize,
+    /// Blobs deleted from the store.
+    pub removed_blobs: usize,
+    /// Bytes freed on disk.
+    pub freed_bytes: u64,
+}
+
+/// Extract blob ids from avatar URLs that point at `/api/files/:id`.
+pub fn avatar_blob_ids(users: &[auth::User]) -> HashSet<String> {
+    users
+        .iter()
+        .filter_map(|u| u.avatar_url.as_deref())
+        .filter_map(|u| Url::parse(u).ok())
+        .filter_map(|url| {
+            let segments: Vec<&str> = url.path_segments()?.collect();
+            match segments.as_slice() {
+                [.., "api", "files", id] if !id.is_empty() => Some(id.to_string()),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/// Count live references to each blob. Attachments and avatars reference a
+/// blob directly; a thumbnail is referenced once for every referenced file it
+/// belongs to. Identical uploads share one blob, so its count is the sum over
+/// all of them.
+pub fn reference_counts(
+    conn: &Connection,
+    avatars: &HashSet<String>,
+) -> Result<HashMap<String, usize>> {
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    let mut stmt = conn.prepare("SELECT file_id, COUNT(*) FROM attachments GROUP BY file_id")?;
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        let id: String = row.get(0)?;
+        let n: i64 = row.get(1)?;
+        *counts.entry(id).or_default() += n as usize;
+    }
+    for id in avatars {
+        *counts.entry(id.clone()).or_default() += 1;
+    }
+    let mut stmt = conn.prepare("SELECT id, thumb_id FROM files WHERE thumb_id IS NOT NULL")?;
+    let thumbs = stmt
+        .query_map([], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    for (file_id, thumb_id) in thumbs {
+        if let Some(&n) = counts.get(&file_id) {
+            *counts.entry(thumb_id).or_default() += n;
+        }
+    }
+    Ok(counts)
+}
+
+/// Mark phase: compute the set of blobs to keep and drop upload records that
+/// were never referenced and are older than `cutoff` (unix seconds).
+fn mark(
+    conn: &Connection,
+    avatars: &HashSet<String>,
+    cutoff: i64,
+) -> Result<(HashSet<String>, GcReport)> {
+    let counts = reference_counts(conn, avatars)?;
+    let mut keep: HashSet<String> = counts.keys().cloned().collect();
+    let mut report = GcReport {
+        referenced: keep.len(),
+        ..Default::default()
+    };
+    let mut stmt = conn.prepare("SELECT id, thumb_id, uploaded_at FROM files")?;
+    let rows = stmt
+        .query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, Option<String>>(1)?,
+                row.get::<_, i64>(2)?,
+            ))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    for (id, thumb_id, uploaded_at) in rows {
+        if counts.contains_key(&id) {
+            continue;
+        }
+        if uploaded_at >= cutoff {
+            keep.insert(id);
+            keep.extend(thumb_id);
+        } else {
+            conn.execute("DELETE FROM files WHERE id = ?1", params![id])?;
+            report.expired_uploads += 1;
+        }
+    }
+    Ok((keep, report))
+}
+
+/// Run one mark-and-sweep pass over the content store. Blobs and upload
+/// records last touched before `cutoff` are eligible for removal.
+pub async fn collect_garbage(
+    pool: &Pool<SqliteConnectionManager>,
+    dir: &Path,
+    avatars: &HashSet<String>,
+    cutoff: SystemTime,
+) -> Result<GcReport> {
+    let cutoff_ts = cutoff
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0);
+    let (keep, mut report) = {
+        let conn = pool.get()?;
+        mark(&conn, avatars, cutoff_ts)?
+    };
+    let stats = files::sweep_store(dir, &keep, cutoff).await?;
+    report.removed_blobs = stats.removed;
+    report.freed_bytes = stats.freed_bytes;
+    Ok(report)
+}
+
+/// Periodically garbage collect the content store.
 pub async fn run_housekeeping(state: AppState) {
-    let files = state.files.clone();
-    let dir = state.file_dir.clone();
     tokio::spawn(async move {
-        let mut tick = interval(Duration::from_secs(300));
+        let mut tick = interval(GC_INTERVAL);
         loop {
             tick.tick().await;
-            let keep: HashSet<String> = files.lock().keys().cloned().collect();
-            let _ = files::cleanup_orphans(&dir, &keep).await;
+            let avatars = {
+                let guard = state.auth.lock().await;
+                guard
+                    .as_ref()
+                    .map(|cfg| avatar_blob_ids(&cfg.users))
+                    .unwrap_or_default()
+            };
+            let cutoff = SystemTime::now() - GC_GRACE;
+            match collect_garbage(&state.pool, &state.file_dir, &avatars, cutoff).await {
+                Ok(report) => tracing::info!(
+                    referenced = report.referenced,
+                    expired_uploads = report.expired_uploads,
+                    removed_blobs = report.removed_blobs,
+                    freed_bytes = report.freed_bytes,
+                    "file store garbage collection finished"
+                ),
+                Err(e) => tracing::warn!("file store garbage collection failed: {e}"),
+            }
         }
     });
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{db, messages, model};
+    use bytes::Bytes;
+    use uuid::Uuid;
+
+    fn pool(dir: &Path) -> Pool<SqliteConnectionManager> {
+        let manager = SqliteConnectionManager::file(dir.join(db::DB_FILE)).with_init(db::configure);
+        let pool = Pool::new(manager).unwrap();
+        db::migrate(&mut pool.get().unwrap()).unwrap();
+        pool
+    }
+
+    async fn upload(
+        pool: &Pool<SqliteConnectionManager>,
+        store: &Path,
+        data: &'static [u8],
+        uploaded_at: i64,
+        thumb: Option<&'static [u8]>,
+    ) -> (String, Option<String>) {
+        let id = files::save_file(store, Bytes::from_static(data))
+            .await
+            .unwrap();
+        let thumb_id = match thumb {
+            Some(t) => Some(
+                files::save_file(store, Bytes::from_static(t))
+                    .await
+                    .unwrap(),
+            ),
+            None => None,
+        };
+        files::record_file(
+            &pool.get().unwrap(),
+            &model::StoredFile {
+                id: id.clone(),
+                name: "f".into(),
+                mime: "image/png".into(),
+                size_bytes: data.len() as i64,
+                uploader_id: 1,
+                uploaded_at,
+                thumb: thumb_id.clone().map(|id| model::Thumbnail {
+                    id,
+                    mime: "image/png".into(),
+                    width: 1,
+                    height: 1,
+                }),
+            },
+        )
+        .unwrap();
+        (id, thumb_id)
+    }
+
+    #[tokio::test]
+    async fn collects_only_unreferenced_blobs() {
+        let tmp = tempfile::tempdir().unwrap();
+        let store = tmp.path().join("files");
+        std::fs::create_dir_all(&store).unwrap();
+        let pool = pool(tmp.path());
+        let far_future = i64::MAX / 2;
+
+        let (shared, shared_thumb) = upload(&pool, &store, b"shared", 0, Some(b"thumb")).await;
+        let (stale, stale_thumb) = upload(&pool, &store, b"stale", 0, Some(b"stale-thumb")).await;
+        let (fresh, _) = upload(&pool, &store, b"fresh", far_future, None).await;
+        let (avatar, _) = upload(&pool, &store, b"avatar", 0, None).await;
+
+        let room_id = Uuid::new_v4();
+        let (m1, m2) = {
+            let conn = pool.get().unwrap();
+            conn.execute(
+                "INSERT INTO rooms (id, slug, name, is_dm, created_at) VALUES (?1, 'r', 'R', 0, 0)",
+                params![room_id.to_string()],
+            )
+            .unwrap();
+            // the same blob attached twice, as happens with deduplicated uploads
+            let m1 = messages::create_message(&conn, &room_id, 1, "a", None, None).unwrap();
+            let m2 = messages::create_message(&conn, &room_id, 1, "b", None, None).unwrap();
+            messages::add_attachments(&conn, &m1.id, 1, std::slice::from_ref(&shared)).unwrap();
+            messages::add_attachments(&conn, &m2.id, 1, std::slice::from_ref(&shared)).unwrap();
+            (m1, m2)
+        };
+        let avatars: HashSet<String> = [avatar.clone()].into_iter().collect();
+        {
+            let counts = reference_counts(&pool.get().unwrap(), &avatars).unwrap();
+            assert_eq!(counts[&shared], 2);
+            assert_eq!(counts[shared_thumb.as_ref().unwrap()], 2);
+        }
+
+        let cutoff = SystemTime::now() + Duration::from_secs(1);
+        let report = collect_garbage(&pool, &store, &avatars, cutoff)
+            .await
+            .unwrap();
+        assert_eq!(report.expired_uploads, 1);
+        assert_eq!(report.removed_blobs, 2);
+        assert_eq!(
+            report.freed_bytes,
+            (b"stale".len() + b"stale-thumb".len()) as u64
+        );
+        for id in [&shared, shared_thumb.as_ref().unwrap(), &fresh, &avatar] {
+            assert!(files::file_path(&store, id).exists());
+        }
+        assert!(!files::file_path(&store, &stale).exists());
+        assert!(!files::file_path(&store, stale_thumb.as_ref().unwrap()).exists());
+
+        // one reference left keeps the shared blob alive
+        messages::delete_message(&pool.get().unwrap(), &m1.id, 1).unwrap();
+        let report = collect_garbage(&pool, &store, &avatars, cutoff)
+            .await
+            .unwrap();
+        assert_eq!(report.removed_blobs, 0);
+
+        messages::delete_message(&pool.get().unwrap(), &m2.id, 1).unwrap();
+        let report = collect_garbage(&pool, &store, &avatars, cutoff)
+            .await
+            .unwrap();
+        assert_eq!(report.removed_blobs, 2);
+        assert!(!files::file_path(&store, &shared).exists());
+        assert!(files::file_path(&store, &avatar).exists());
+    }
+
+    #[test]
+    fn avatar_urls_pointing_at_the_store() {
+        let user = |url: &str| auth::User {
+            id: 1,
+            username: "a".into(),
+            display_name: "A".into(),
+            admin: false,
+            disabled: false,
+            avatar_url: Some(url.into()),
+            must_change_password: false,
+        };
+        let ids = avatar_blob_ids(&[
+            user("http://pi.local:8787/api/files/abc"),
+            user("https://example.com/me.png"),
+        ]);
+        assert_eq!(ids, ["abc".to_string()].into_iter().collect());
+    }
+}
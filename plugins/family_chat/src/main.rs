@@ -27,6 +27,10 @@ async fn main() -> Result<()> {
     } else {
         tracing::Level::WARN
     };
-    tracing_subscriber::fmt().with_max_level(level).init();
+    // in stdio mode stdout carries the plugin protocol
+    tracing_subscriber::fmt()
+        .with_max_level(level)
+        .with_writer(std::io::stderr)
+        .init();
     plugin::run(cli.stdio, cfg).await
 }
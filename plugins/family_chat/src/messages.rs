@@ -1,8 +1,10 @@
-use crate::model::{Message, SearchResult};
+use crate::model::{Attachment, Message, SearchResult, StoredFile, Thumbnail};
+use crate::{files, rooms};
 use anyhow::{anyhow, Result};
 use once_cell::sync::Lazy;
 use regex::Regex;
 use rusqlite::{params, Connection, OptionalExtension};
+use std::collections::HashMap;
 use time::OffsetDateTime;
 use uuid::Uuid;
 
@@ -29,7 +31,7 @@ pub fn create_message(
     }
     if let Some(key) = idem_key {
         let mut stmt = conn.prepare(
-            "SELECT id, room_id, author_id, text_md, created_at, edited_at FROM messages WHERE author_id = ?1 AND idempotency_key = ?2",
+            "SELECT id, room_id, author_id, text_md, created_at, edited_at, reply_to FROM messages WHERE author_id = ?1 AND idempotency_key = ?2",
         )?;
         if let Some(existing) = stmt
             .query_row(params![author_id.to_string(), key], row_to_msg)
@@ -165,6 +167,11 @@ pub fn delete_message(conn: &Connection, message_id: &Uuid, author_id: u32) -> R
     if author.parse::<u32>().unwrap_or_default() != author_id {
         anyhow::bail!("forbidden");
     }
+    // replies keep their text but lose the link to the removed parent
+    conn.execute(
+        "UPDATE messages SET reply_to = NULL WHERE reply_to = ?1",
+        [message_id.to_string()],
+    )?;
     conn.execute(
         "DELETE FROM messages WHERE id = ?1",
         [message_id.to_string()],
@@ -176,6 +183,103 @@ pub fn delete_message(conn: &Connection, message_id: &Uuid, author_id: u32) -> R
     Ok(Uuid::parse_str(&room_id).unwrap())
 }
 
+/// Attach previously uploaded files to a message. Attachment ids are derived
+/// from the message and file ids so a retried request does not duplicate them.
+/// The author may only attach files they uploaded themselves or can already
+/// see attached in one of their rooms; anything else is `unknown_file`.
+pub fn add_attachments(
+    conn: &Connection,
+    message_id: &Uuid,
+    author_id: u32,
+    file_ids: &[String],
+) -> Result<Vec<Attachment>> {
+    for file_id in file_ids {
+        let file = files::get_file(conn, file_id)?.ok_or_else(|| anyhow!("unknown_file"))?;
+        if !can_attach(conn, &file, author_id)? {
+            return Err(anyhow!("unknown_file"));
+        }
+        let id = Uuid::new_v5(message_id, file.id.as_bytes());
+        conn.execute(
+            "INSERT OR IGNORE INTO attachments (id, message_id, file_id, file_name, mime, size_bytes) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                id.to_string(),
+                message_id.to_string(),
+                file.id,
+                file.name,
+                file.mime,
+                file.size_bytes
+            ],
+        )?;
+    }
+    Ok(attachments_for(conn, &[*message_id])?
+        .remove(message_id)
+        .unwrap_or_default())
+}
+
+fn can_attach(conn: &Connection, file: &StoredFile, author_id: u32) -> Result<bool> {
+    if files::uploaded_by(conn, &file.id, author_id)? {
+        return Ok(true);
+    }
+    let mut stmt = conn.prepare(
+        "SELECT DISTINCT m.room_id FROM attachments a JOIN messages m ON m.id = a.message_id WHERE a.file_id = ?1",
+    )?;
+    let room_ids = stmt
+        .query_map([&file.id], |row| row.get::<_, String>(0))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    for room_id in room_ids {
+        let Ok(room_id) = Uuid::parse_str(&room_id) else {
+            continue;
+        };
+        if rooms::user_can_access_room(conn, &room_id, author_id)? {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Load attachments for the given messages, keyed by message id.
+pub fn attachments_for(
+    conn: &Connection,
+    message_ids: &[Uuid],
+) -> Result<HashMap<Uuid, Vec<Attachment>>> {
+    let mut out: HashMap<Uuid, Vec<Attachment>> = HashMap::new();
+    if message_ids.is_empty() {
+        return Ok(out);
+    }
+    let placeholders = vec!["?"; message_ids.len()].join(", ");
+    let sql = format!(
+        "SELECT a.id, a.message_id, a.file_id, a.file_name, a.mime, a.size_bytes, f.thumb_id, f.thumb_mime, f.thumb_width, f.thumb_height \
+         FROM attachments a LEFT JOIN files f ON f.id = a.file_id \
+         WHERE a.message_id IN ({}) ORDER BY a.rowid",
+        placeholders
+    );
+    let mut stmt = conn.prepare(&sql)?;
+    let ids: Vec<String> = message_ids.iter().map(Uuid::to_string).collect();
+    let mut rows = stmt.query(rusqlite::params_from_iter(ids.iter()))?;
+    while let Some(row) = rows.next()? {
+        let thumb = match row.get::<_, Option<String>>(6)? {
+            Some(id) => Some(Thumbnail {
+                id,
+                mime: row.get(7)?,
+                width: row.get(8)?,
+                height: row.get(9)?,
+            }),
+            None => None,
+        };
+        let att = Attachment {
+            id: Uuid::parse_str(row.get::<_, String>(0)?.as_str()).unwrap(),
+            message_id: Uuid::parse_str(row.get::<_, String>(1)?.as_str()).unwrap(),
+            file_id: row.get(2)?,
+            file_name: row.get(3)?,
+            mime: row.get(4)?,
+            size_bytes: row.get(5)?,
+            thumb,
+        };
+        out.entry(att.message_id).or_default().push(att);
+    }
+    Ok(out)
+}
+
 pub fn search_messages(
     conn: &Connection,
     q: &str,
@@ -304,4 +408,101 @@ mod tests {
         delete_message(&conn, &m.id, 1).unwrap();
         assert_eq!(search_messages(&conn, "bye", None).unwrap().len(), 0);
     }
+
+    #[test]
+    fn delete_parent_keeps_replies() {
+        let conn = db::init_db(":memory:").unwrap();
+        let room_id = Uuid::new_v4();
+        conn.execute(
+            "INSERT INTO rooms (id, slug, name, is_dm, created_at) VALUES (?1, 'r', 'R', 0, 0)",
+            params![room_id.to_string()],
+        )
+        .unwrap();
+        let parent = create_message(&conn, &room_id, 1, "parent", None, None).unwrap();
+        let reply = create_message(&conn, &room_id, 2, "reply", Some(&parent.id), None).unwrap();
+        delete_message(&conn, &parent.id, 1).unwrap();
+        let all = list_messages(&conn, &room_id, None, 10).unwrap();
+        assert_eq!(all.len(), 1);
+        assert_eq!(all[0].id, reply.id);
+        assert_eq!(all[0].reply_to, None);
+    }
+
+    #[test]
+    fn attachments_roundtrip() {
+        let conn = db::init_db(":memory:").unwrap();
+        let room_id = Uuid::new_v4();
+        conn.execute(
+            "INSERT INTO rooms (id, slug, name, is_dm, created_at) VALUES (?1, 'r', 'R', 0, 0)",
+            params![room_id.to_string()],
+        )
+        .unwrap();
+        files::record_file(
+            &conn,
+            &crate::model::StoredFile {
+                id: "f1".into(),
+                name: "a.txt".into(),
+                mime: "text/plain".into(),
+                size_bytes: 2,
+                uploader_id: 1,
+                uploaded_at: 0,
+                thumb: None,
+            },
+        )
+        .unwrap();
+        let m = create_message(&conn, &room_id, 1, "see file", None, None).unwrap();
+        assert!(add_attachments(&conn, &m.id, 1, &["nope".into()]).is_err());
+        let atts = add_attachments(&conn, &m.id, 1, &["f1".into()]).unwrap();
+        assert_eq!(atts.len(), 1);
+        assert_eq!(atts[0].file_name, "a.txt");
+        // retrying does not duplicate
+        let again = add_attachments(&conn, &m.id, 1, &["f1".into()]).unwrap();
+        assert_eq!(again, atts);
+        let map = attachments_for(&conn, &[m.id, Uuid::new_v4()]).unwrap();
+        assert_eq!(map.len(), 1);
+        delete_message(&conn, &m.id, 1).unwrap();
+        assert!(attachments_for(&conn, &[m.id]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn files_of_other_users_cannot_be_attached() {
+        let conn = db::init_db(":memory:").unwrap();
+        for id in ["f1", "f2"] {
+            files::record_file(
+                &conn,
+                &crate::model::StoredFile {
+                    id: id.into(),
+                    name: format!("{id}.txt"),
+                    mime: "text/plain".into(),
+                    size_bytes: 2,
+                    uploader_id: 1,
+                    uploaded_at: 0,
+                    thumb: None,
+                },
+            )
+            .unwrap();
+        }
+        let dm = rooms::get_or_create_dm_room(&conn, 1, 3).unwrap();
+        let public = rooms::create_public_room(&conn, "General", Some("general")).unwrap();
+
+        let theirs = create_message(&conn, &public.id, 2, "mine now", None, None).unwrap();
+        let err = add_attachments(&conn, &theirs.id, 2, &["f1".into()]).unwrap_err();
+        assert_eq!(err.to_string(), "unknown_file");
+
+        // once shared where user 2 can see it, the file may be passed on
+        let shared = create_message(&conn, &public.id, 1, "f1", None, None).unwrap();
+        add_attachments(&conn, &shared.id, 1, &["f1".into()]).unwrap();
+        assert_eq!(
+            add_attachments(&conn, &theirs.id, 2, &["f1".into()])
+                .unwrap()
+                .len(),
+            1
+        );
+
+        // a direct message between others does not count
+        let private = create_message(&conn, &dm.id, 1, "f2", None, None).unwrap();
+        add_attachments(&conn, &private.id, 1, &["f2".into()]).unwrap();
+        assert!(add_attachments(&conn, &theirs.id, 2, &["f2".into()]).is_err());
+        let reply = create_message(&conn, &dm.id, 3, "thanks", None, None).unwrap();
+        assert!(add_attachments(&conn, &reply.id, 3, &["f2".into()]).is_ok());
+    }
 }
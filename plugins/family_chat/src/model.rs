@@ -30,7 +30,7 @@ pub struct Message {
     pub reply_to: Option<Uuid>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
 pub struct Attachment {
     pub id: Uuid,
     pub message_id: Uuid,
@@ -38,6 +38,30 @@ pub struct Attachment {
     pub file_name: String,
     pub mime: Option<String>,
     pub size_bytes: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub thumb: Option<Thumbnail>,
+}
+
+/// Metadata for a blob in the content-addressed file store.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct StoredFile {
+    /// SHA-256 of the content, also the blob's name in the store.
+    pub id: String,
+    pub name: String,
+    pub mime: String,
+    pub size_bytes: i64,
+    pub uploader_id: u32,
+    pub uploaded_at: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub thumb: Option<Thumbnail>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Thumbnail {
+    pub id: String,
+    pub mime: String,
+    pub width: u32,
+    pub height: u32,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
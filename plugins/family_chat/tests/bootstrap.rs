@@ -1,6 +1,7 @@
 use family_chat::{
     api::{build_router, AppState},
     config::{Bootstrap, Config},
+    db, rooms,
 };
 use std::net::{SocketAddr, TcpListener};
 use tokio::task::JoinHandle;
@@ -54,3 +55,27 @@ async fn bootstrap_creates_admin_and_is_idempotent() {
     let guard = state2.auth.lock().await;
     assert_eq!(guard.as_ref().unwrap().users.len(), 1);
 }
+
+#[tokio::test]
+async fn database_persists_across_restart() {
+    let tmp = tempfile::tempdir().unwrap();
+    let cfg = Config {
+        bind: "127.0.0.1:0".into(),
+        data_dir: tmp.path().to_path_buf(),
+        max_upload_mb: 5,
+        logging_enabled: true,
+        bootstrap: None,
+    };
+    {
+        let state = AppState::new(cfg.clone()).await.unwrap();
+        let conn = state.pool.get().unwrap();
+        rooms::create_public_room(&conn, "General", Some("general")).unwrap();
+    }
+    assert!(tmp.path().join(db::DB_FILE).exists());
+
+    let state = AppState::new(cfg).await.unwrap();
+    let conn = state.pool.get().unwrap();
+    let rooms = rooms::list_rooms_for_user(&conn, 1).unwrap();
+    assert_eq!(rooms.len(), 1);
+    assert_eq!(rooms[0].slug, "general");
+}
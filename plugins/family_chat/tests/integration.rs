@@ -215,7 +215,16 @@ async fn upload_download_and_auth() {
     assert!(resp.status().is_success());
     let v: serde_json::Value = resp.json().await.unwrap();
     let img_id = v["file_id"].as_str().unwrap().to_string();
-    assert!(state.files.lock().get(&img_id).unwrap().thumb.is_some());
+    assert!(v["thumb"].is_object());
+    {
+        let conn = state.pool.get().unwrap();
+        let meta = family_chat::files::get_file(&conn, &img_id)
+            .unwrap()
+            .unwrap();
+        assert_eq!(meta.mime, "image/png");
+        assert_eq!(meta.uploader_id, 1);
+        assert!(meta.thumb.is_some());
+    }
 
     // download
     let resp = client
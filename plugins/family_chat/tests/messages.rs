@@ -328,3 +328,158 @@ async fn message_flow_and_pagination() {
 
     server.abort();
 }
+
+#[tokio::test]
+async fn attachments_are_linked_and_survive_restart() {
+    let (addr, server, state, _tmp) = spawn_server().await;
+    let client = reqwest::Client::new();
+
+    let body = serde_json::json!({
+        "passphrase": "supersecret",
+        "users": [
+            {"username": "alice", "display_name": "Alice", "admin": true},
+            {"username": "bob", "display_name": "Bob", "admin": false}
+        ]
+    });
+    client
+        .post(format!("http://{}/api/bootstrap", addr))
+        .json(&body)
+        .send()
+        .await
+        .unwrap();
+    let token = client
+        .post(format!("http://{}/api/login", addr))
+        .json(&serde_json::json!({"username":"alice","passphrase":"supersecret"}))
+        .send()
+        .await
+        .unwrap()
+        .json::<serde_json::Value>()
+        .await
+        .unwrap()["token"]
+        .as_str()
+        .unwrap()
+        .to_string();
+    let room: serde_json::Value = client
+        .post(format!("http://{}/api/rooms", addr))
+        .bearer_auth(&token)
+        .json(&serde_json::json!({"name":"General","slug":"general"}))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let room_id = room["id"].as_str().unwrap().to_string();
+
+    // upload
+    let form = reqwest::multipart::Form::new().part(
+        "file",
+        reqwest::multipart::Part::bytes(b"shopping list".to_vec()).file_name("list.txt"),
+    );
+    let upload: serde_json::Value = client
+        .post(format!("http://{}/api/files", addr))
+        .bearer_auth(&token)
+        .multipart(form)
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let file_id = upload["file_id"].as_str().unwrap().to_string();
+    assert_eq!(upload["name"], "list.txt");
+    assert_eq!(upload["size"], 13);
+    assert_eq!(upload["sha256"], file_id);
+
+    // websocket join
+    let mut req = format!("ws://{}/ws", addr).into_client_request().unwrap();
+    req.headers_mut().append(
+        "Authorization",
+        format!("Bearer {}", token).parse().unwrap(),
+    );
+    let (mut ws, _) = connect_async(req).await.unwrap();
+    ws.next().await.unwrap().unwrap(); // hello
+    ws.send(WsMessage::Text(format!(
+        "{{\"action\":\"join\",\"room_id\":\"{}\"}}",
+        room_id
+    )))
+    .await
+    .unwrap();
+    loop {
+        if let Some(Ok(WsMessage::Text(t))) = ws.next().await {
+            let v: serde_json::Value = serde_json::from_str(&t).unwrap();
+            if v["t"] == "snapshot" {
+                break;
+            }
+        }
+    }
+
+    // unknown file id is rejected and nothing is stored
+    let resp = client
+        .post(format!("http://{}/api/messages", addr))
+        .bearer_auth(&token)
+        .json(&serde_json::json!({"room_id":room_id,"text_md":"x","attachments":["nope"]}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+    let msg: serde_json::Value = client
+        .post(format!("http://{}/api/messages", addr))
+        .bearer_auth(&token)
+        .json(&serde_json::json!({"room_id":room_id,"text_md":"see attached","attachments":[file_id]}))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(msg["attachments"][0]["file_id"], file_id);
+    assert_eq!(msg["attachments"][0]["name"], "list.txt");
+
+    let event = loop {
+        if let Some(Ok(WsMessage::Text(t))) = ws.next().await {
+            let v: serde_json::Value = serde_json::from_str(&t).unwrap();
+            if v["t"] == "message" {
+                break v;
+            }
+        }
+    };
+    assert_eq!(event["message"]["attachments"][0]["file_id"], file_id);
+
+    let listed: Vec<serde_json::Value> = client
+        .get(format!("http://{}/api/messages?room_id={}", addr, room_id))
+        .bearer_auth(&token)
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(listed.len(), 1);
+    assert_eq!(listed[0]["attachments"][0]["size"], 13);
+    server.abort();
+
+    // a fresh server on the same data dir still serves the upload
+    let restarted = AppState::new(state.config.clone()).await.unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr2 = listener.local_addr().unwrap();
+    let app = build_router(restarted);
+    let server2 = tokio::spawn(async move {
+        axum::Server::from_tcp(listener)
+            .unwrap()
+            .serve(app.into_make_service())
+            .await
+            .unwrap();
+    });
+    let resp = client
+        .get(format!("http://{}/api/files/{}", addr2, file_id))
+        .bearer_auth(&token)
+        .send()
+        .await
+        .unwrap();
+    assert!(resp.status().is_success());
+    assert_eq!(resp.headers()["content-type"], "text/plain");
+    assert_eq!(resp.text().await.unwrap(), "shopping list");
+    server2.abort();
+}
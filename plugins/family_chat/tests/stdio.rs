@@ -0,0 +1,19 @@
+use mock_core::MockCore;
+use tokio::process::Command;
+
+#[tokio::test]
+async fn handshakes_and_stops_under_the_core() {
+    let tmp = tempfile::tempdir().unwrap();
+    let mut cmd = Command::new(env!("CARGO_BIN_EXE_family_chat"));
+    cmd.args(["--stdio", "--bind", "127.0.0.1:0", "--config"])
+        .arg(tmp.path().join("missing.toml"))
+        .env("DATA_DIR", tmp.path());
+    let core = MockCore::spawn_command(cmd).unwrap();
+
+    let metadata = core.started().await.unwrap();
+    assert_eq!(metadata.id, "family_chat");
+    assert!(metadata.needs.iter().any(|n| n == "storage"));
+
+    let status = core.stop().await.unwrap().unwrap();
+    assert!(status.success());
+}
@@ -61,7 +61,7 @@ export const api = {
     if (before) params.append('before', before);
     return request<Message[]>(`/api/messages?${params.toString()}`);
   },
-  sendMessage(payload: { room_id: string; text_md: string; reply_to?: string; attachments?: any[] }) {
+  sendMessage(payload: { room_id: string; text_md: string; reply_to?: string; attachments?: string[] }) {
     return request<Message>('/api/messages', {
       method: 'POST',
       body: JSON.stringify(payload),
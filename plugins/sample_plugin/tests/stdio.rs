@@ -0,0 +1,21 @@
+use mock_core::MockCore;
+use serde_json::json;
+
+#[tokio::test]
+async fn logs_ticks_and_answers_pings() {
+    let core = MockCore::spawn(env!("CARGO_BIN_EXE_sample_plugin")).unwrap();
+    let metadata = core.started().await.unwrap();
+    assert_eq!(metadata.provides, vec!["sample.*"]);
+
+    let timer = core.wait_for_call("timer.set_interval").await.unwrap();
+    assert_eq!(timer, json!({"id": "sample", "millis": 1000}));
+    core.tick("sample").await.unwrap();
+    let log = core.wait_for_log("tick from sample_plugin").await.unwrap();
+    assert_eq!(log.level, "INFO");
+
+    let pong = core.request("sample.ping", json!({"text": "hi"})).await;
+    assert_eq!(pong, Ok(json!({"text": "hi"})));
+
+    let status = core.stop().await.unwrap().unwrap();
+    assert!(status.success());
+}